@@ -0,0 +1,33 @@
+//! Hashing throughput, mostly for MD5 as used to verify content. Run with
+//! and without `--features asm` to compare implementations.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+use rustycasc::hash;
+
+fn bench_hashes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash");
+    for size in [4 << 10, 1 << 20, 16 << 20] {
+        let data = (0..size).map(|i| (i * 31 % 251) as u8).collect::<Vec<_>>();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("md5", size), &data, |b, data| {
+            b.iter(|| hash::md5(data))
+        });
+        group.bench_with_input(BenchmarkId::new("md5-chunked", size), &data, |b, data| {
+            b.iter(|| {
+                let mut h = hash::Md5Hasher::new();
+                for chunk in data.chunks(64 << 10) {
+                    h.update(chunk);
+                }
+                h.finish()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("lookup3", size), &data, |b, data| {
+            b.iter(|| hash::lookup3(data))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_hashes);
+criterion_main!(benches);
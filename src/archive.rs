@@ -1,96 +1,631 @@
-use std::{collections::HashMap, convert::TryInto};
+use std::{
+    borrow::Borrow,
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+};
 
-use anyhow::{ensure, Result};
+use anyhow::{ensure, Context, Result};
 use bytes::Buf;
+use derive_more::Display;
 
+use crate::limits::Limits;
 use crate::types::{ArchiveKey, EncodingKey};
-use crate::util;
+use crate::{diag, hash};
 
 #[derive(Debug)]
-pub(crate) struct Index {
-    pub(crate) map: HashMap<EncodingKey, (ArchiveKey, usize, usize)>,
+pub struct Index {
+    pub map: HashMap<EncodingKey, (ArchiveKey, usize, usize)>,
 }
 
-pub(crate) fn parse_index(name: ArchiveKey, data: &[u8]) -> Result<Index> {
-    ensure!(data.len() >= 28, "truncated archive index data");
-    let non_footer_size = data.len() - 28;
-    let bytes_per_block = 4096 + 24;
-    let num_blocks = non_footer_size / bytes_per_block;
-    ensure!(
-        non_footer_size % bytes_per_block == 0,
-        "invalid archive index format"
-    );
-    let mut footer = &data[non_footer_size..];
-    ensure!(util::md5hash(footer) == name.0, "bad footer name");
-    let toc_size = num_blocks * 24;
-    let toc = &data[non_footer_size - toc_size..non_footer_size];
-    ensure!(
-        (util::md5hash(toc) >> 64) as u64 == footer.get_u64(),
-        "archive index toc checksum"
-    );
-    ensure!(footer.get_u8() == 1, "unexpected archive index version");
+/// Bytes per entry in the [`Index::to_bytes`] form.
+const CACHED_ENTRY_SIZE: usize = 48;
+
+impl Index {
+    /// A compact form for caching a parsed index: each entry's encoding key,
+    /// archive, size and offset, in encoding key order.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        use bytes::BufMut;
+        let mut entries = self.map.iter().collect::<Vec<_>>();
+        entries.sort_unstable_by_key(|(ekey, _)| ekey.0);
+        let mut data = Vec::with_capacity(entries.len() * CACHED_ENTRY_SIZE);
+        for (ekey, (archive, size, offset)) in entries {
+            data.put_u128(ekey.0);
+            data.put_u128(archive.0);
+            data.put_u64(*size as u64);
+            data.put_u64(*offset as u64);
+        }
+        data
+    }
+
+    /// Reads back the [`Index::to_bytes`] form.
+    pub fn from_bytes(mut data: &[u8]) -> Result<Index> {
+        ensure!(
+            data.len() % CACHED_ENTRY_SIZE == 0,
+            "truncated cached archive index"
+        );
+        let mut map = HashMap::with_capacity(data.len() / CACHED_ENTRY_SIZE);
+        while data.has_remaining() {
+            let ekey = EncodingKey(data.get_u128());
+            let archive = ArchiveKey(data.get_u128());
+            let size = data.get_u64().try_into()?;
+            let offset = data.get_u64().try_into()?;
+            map.insert(ekey, (archive, size, offset));
+        }
+        Ok(Index { map })
+    }
+}
+
+/// How an index sizes its blocks, entries and checksums, as its footer says.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Layout {
+    block_size: usize,
+    offset_bytes: usize,
+    size_bytes: usize,
+    key_size: usize,
+    checksum_size: usize,
+}
+
+impl Layout {
+    /// The layout of CDN indices.
+    const CDN: Layout = Layout {
+        block_size: 4096,
+        offset_bytes: 4,
+        size_bytes: 4,
+        key_size: 16,
+        checksum_size: 8,
+    };
+
+    fn entry_size(&self) -> usize {
+        self.key_size + self.size_bytes + self.offset_bytes
+    }
+
+    fn toc_entry_size(&self) -> usize {
+        self.key_size + self.checksum_size
+    }
+
+    /// A checksum either side of the version, field sizes and entry count.
+    fn footer_size(&self) -> usize {
+        2 * self.checksum_size + 12
+    }
+
+    /// How many blocks an index of `len` bytes has, if that size fits.
+    fn num_blocks(&self, len: usize) -> Option<usize> {
+        let rest = len.checked_sub(self.footer_size())?;
+        let per_block = self.block_size + self.toc_entry_size();
+        (rest % per_block == 0).then_some(rest / per_block)
+    }
+}
+
+/// Whether `checksum` is a prefix of the MD5 of `data`.
+fn checksum_matches(data: &[u8], checksum: &[u8]) -> bool {
+    hash::md5(data).to_be_bytes()[..checksum.len()] == *checksum
+}
+
+/// Reads a key of `size` bytes, left-aligned so that shorter keys compare
+/// as prefixes of full ones.
+fn get_key(p: &mut &[u8], size: usize) -> u128 {
+    let mut key = [0; 16];
+    p.copy_to_slice(&mut key[..size]);
+    u128::from_be_bytes(key)
+}
+
+/// Parses the footer at the end of `data`, returning the layout and entry
+/// count it gives. How long the footer is depends on the checksum size it
+/// holds, so each possible length is tried against the index name, which
+/// is the footer's MD5. Only version 1 footers, the only revision CDN
+/// indices use, are understood.
+fn parse_footer(name: ArchiveKey, data: &[u8]) -> Result<(Layout, usize)> {
+    let footer = (1..=16)
+        .filter_map(|size| data.len().checked_sub(2 * size + 12))
+        .map(|start| &data[start..])
+        .find(|footer| hash::md5(footer) == name.0)
+        .context("bad footer name")?;
+    let checksum_size = (footer.len() - 12) / 2;
+    let mut p = &footer[checksum_size..];
+    let version = p.get_u8();
     ensure!(
-        footer.get_u8() == 0,
-        "unexpected archive index nonzero byte"
+        version == 1,
+        "unsupported archive index version {}",
+        version
     );
+    ensure!(p.get_u16() == 0, "unexpected archive index nonzero byte");
+    let layout = Layout {
+        block_size: usize::from(p.get_u8()) * 1024,
+        offset_bytes: p.get_u8().into(),
+        size_bytes: p.get_u8().into(),
+        key_size: p.get_u8().into(),
+        checksum_size: p.get_u8().into(),
+    };
     ensure!(
-        footer.get_u8() == 0,
-        "unexpected archive index nonzero byte"
+        layout.checksum_size == checksum_size,
+        "unexpected archive index checksum size"
     );
-    ensure!(footer.get_u8() == 4, "unexpected archive index block size");
     ensure!(
-        footer.get_u8() == 4,
-        "unexpected archive index offset bytes"
+        layout.block_size > 0
+            && (1..=16).contains(&layout.key_size)
+            && (1..=8).contains(&layout.size_bytes)
+            && layout.offset_bytes <= 8,
+        "unsupported archive index layout {:?}",
+        layout
     );
-    ensure!(footer.get_u8() == 4, "unexpected archive index size bytes");
-    ensure!(footer.get_u8() == 16, "unexpected archive index key size");
+    let num_elements = p.get_u32_le().try_into()?;
+    let mut footer_to_check = footer[checksum_size..footer.len() - checksum_size].to_vec();
+    footer_to_check.resize(footer.len() - checksum_size, 0);
     ensure!(
-        footer.get_u8() == 8,
-        "unexpected archive index checksum size"
+        checksum_matches(&footer_to_check, p),
+        "archive index footer checksum"
     );
-    let num_elements = footer.get_u32_le().try_into()?;
-    let footer_checksum = footer.get_u64();
-    assert!(!footer.has_remaining());
-    {
-        let mut footer_to_check = data[non_footer_size + 8..non_footer_size + 20].to_vec();
-        footer_to_check.resize(20, 0);
+    Ok((layout, num_elements))
+}
+
+/// Parses an archive index as it downloads. When its length fits the CDN
+/// layout, blocks are parsed as soon as they are complete, so only the toc
+/// and footer are ever buffered; otherwise it's buffered whole until the
+/// footer says how to read it. The checks that need the toc and footer run
+/// once everything has arrived.
+pub struct IndexParser {
+    name: ArchiveKey,
+    len: usize,
+    /// The layout blocks are parsed with, and how many there are, once
+    /// known.
+    layout: Option<(Layout, usize)>,
+    received: usize,
+    /// Bytes fed but not yet parsed, starting at `received - pending.len()`.
+    pending: Vec<u8>,
+    block_checksums: Vec<u128>,
+    last_ekeys: Vec<Option<EncodingKey>>,
+    map: HashMap<EncodingKey, (ArchiveKey, usize, usize)>,
+}
+
+impl IndexParser {
+    /// Starts parsing an index of `len` bytes, which the block layout
+    /// depends on.
+    pub fn new(name: ArchiveKey, len: usize) -> Result<IndexParser> {
+        IndexParser::with_limits(name, len, &Limits::default())
+    }
+
+    /// Like new, but refusing indices larger than `limits` allow.
+    pub fn with_limits(name: ArchiveKey, len: usize, limits: &Limits) -> Result<IndexParser> {
+        limits.bytes("archive index", len as u64)?;
+        ensure!(len >= 14, "truncated archive index data");
+        Ok(IndexParser {
+            name,
+            len,
+            layout: Layout::CDN
+                .num_blocks(len)
+                .map(|num_blocks| (Layout::CDN, num_blocks)),
+            received: 0,
+            pending: Vec::new(),
+            block_checksums: Vec::new(),
+            last_ekeys: Vec::new(),
+            map: HashMap::new(),
+        })
+    }
+
+    fn pending_start(&self) -> usize {
+        self.received - self.pending.len()
+    }
+
+    fn locate(&self, structure: &'static str, offset: usize, err: anyhow::Error) -> anyhow::Error {
+        err.context(diag::Location::windowed(
+            structure,
+            &self.pending,
+            self.pending_start(),
+            offset,
+        ))
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> Result<()> {
         ensure!(
-            (util::md5hash(&footer_to_check) >> 64) as u64 == footer_checksum,
-            "archive index footer checksum"
+            self.len - self.received >= data.len(),
+            "archive index longer than expected"
         );
-    };
-    let mut map = HashMap::<EncodingKey, (ArchiveKey, usize, usize)>::new();
-    let mut p = &data[..non_footer_size - toc_size];
-    let mut entries = &toc[..(16 * num_blocks)];
-    let mut blockhashes = &toc[(16 * num_blocks)..];
-    for _ in 0..num_blocks {
-        let mut block = &p[..4096];
-        let block_checksum = blockhashes.get_u64();
+        self.received += data.len();
+        self.pending.extend_from_slice(data);
+        self.parse_blocks()
+    }
+
+    /// Parses whatever complete blocks are pending, if the layout is known.
+    fn parse_blocks(&mut self) -> Result<()> {
+        let (layout, num_blocks) = match self.layout {
+            Some(layout) => layout,
+            None => return Ok(()),
+        };
+        let mut consumed = 0;
+        while self.block_checksums.len() < num_blocks
+            && self.pending.len() - consumed >= layout.block_size
+        {
+            let block = &self.pending[consumed..consumed + layout.block_size];
+            let last_ekey = parse_block(self.name, block, &layout, &mut self.map)
+                .map_err(|e| self.locate("archive index block", consumed, e))?;
+            self.block_checksums.push(hash::md5(block));
+            self.last_ekeys.push(last_ekey);
+            consumed += layout.block_size;
+        }
+        self.pending.drain(..consumed);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<Index> {
+        ensure!(self.received == self.len, "truncated archive index data");
+        let footer_start = self.pending.len().saturating_sub(Layout::CDN.footer_size());
+        let (layout, num_elements) = parse_footer(self.name, &self.pending)
+            .map_err(|e| self.locate("archive index footer", footer_start, e))?;
+        let num_blocks = layout
+            .num_blocks(self.len)
+            .context("invalid archive index format")?;
+        match self.layout {
+            Some((streamed, _)) => ensure!(
+                streamed == layout,
+                "archive index footer doesn't match its length"
+            ),
+            None => {
+                self.layout = Some((layout, num_blocks));
+                self.parse_blocks()?;
+            }
+        }
+        let toc_size = num_blocks * layout.toc_entry_size();
+        let toc = &self.pending[..toc_size];
+        ensure!(
+            checksum_matches(toc, &self.pending[toc_size..][..layout.checksum_size]),
+            "archive index toc checksum"
+        );
+        let (mut entries, mut blockhashes) = toc.split_at(layout.key_size * num_blocks);
+        for i in 0..num_blocks {
+            let last_ekey = EncodingKey(get_key(&mut entries, layout.key_size));
+            let (block_checksum, rest) = blockhashes.split_at(layout.checksum_size);
+            blockhashes = rest;
+            let check = || -> Result<()> {
+                ensure!(
+                    self.block_checksums[i].to_be_bytes()[..layout.checksum_size]
+                        == *block_checksum,
+                    "archive index block {} checksum",
+                    i
+                );
+                ensure!(
+                    self.last_ekeys[i] == Some(last_ekey),
+                    "last ekey mismatch in block {}",
+                    i
+                );
+                Ok(())
+            };
+            check().map_err(|e| self.locate("archive index toc", layout.key_size * i, e))?;
+        }
+        ensure!(
+            self.map.len() == num_elements,
+            "num_elements wrong in index"
+        );
+        Ok(Index { map: self.map })
+    }
+}
+
+/// Adds a block's entries to `map`, returning the last key; blocks are
+/// padded with zeros after it.
+fn parse_block(
+    name: ArchiveKey,
+    mut block: &[u8],
+    layout: &Layout,
+    map: &mut HashMap<EncodingKey, (ArchiveKey, usize, usize)>,
+) -> Result<Option<EncodingKey>> {
+    let mut last = None;
+    while block.remaining() >= layout.entry_size() {
+        let ekey = EncodingKey(get_key(&mut block, layout.key_size));
+        if ekey.0 == 0 {
+            break;
+        }
+        let size = block.get_uint(layout.size_bytes).try_into()?;
+        let offset = block.get_uint(layout.offset_bytes).try_into()?;
         ensure!(
-            (util::md5hash(block) >> 64) as u64 == block_checksum,
-            "archive index block checksum"
+            map.insert(ekey, (name, size, offset)).is_none(),
+            "duplicate key in index"
         );
-        let last_ekey = EncodingKey(entries.get_u128());
-        let mut found = false;
-        while block.remaining() >= 24 {
-            let ekey = EncodingKey(block.get_u128());
-            let size = block.get_u32().try_into()?;
-            let offset = block.get_u32().try_into()?;
-            ensure!(
-                map.insert(ekey, (name, size, offset)).is_none(),
-                "duplicate key in index"
-            );
-            if ekey == last_ekey {
-                found = true;
-                break;
+        last = Some(ekey);
+    }
+    Ok(last)
+}
+
+/// Which archive wins when an encoding key appears in several indices.
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum DuplicatePolicy {
+    /// The archive listed first in the CDN config.
+    #[display("first")]
+    First,
+    /// The archive with the least data, to keep range requests small.
+    #[display("smallest")]
+    Smallest,
+    /// An archive also listed as a patch archive, then the first.
+    #[display("prefer-patch")]
+    PreferPatch,
+}
+
+/// An encoding key found in more than one archive.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Conflict {
+    pub ekey: EncodingKey,
+    pub kept: ArchiveKey,
+    pub dropped: ArchiveKey,
+}
+
+impl Index {
+    /// Bytes spanned by the archive's entries, assuming a single archive.
+    fn archive_size(&self) -> usize {
+        self.map
+            .values()
+            .map(|(_, size, offset)| offset + size)
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+/// Merges per-archive indices, given in CDN config order, settling
+/// duplicate keys by `policy`. The indices may be shared, e.g. as
+/// `Arc<Index>` when several builds use the same archives.
+pub fn merge<I: Borrow<Index>>(
+    indices: Vec<I>,
+    policy: DuplicatePolicy,
+    patch_archives: &HashSet<ArchiveKey>,
+) -> (Index, Vec<Conflict>) {
+    // Lower ranks win; ties go to the earlier archive.
+    let rank = |index: &Index, archive: ArchiveKey| match policy {
+        DuplicatePolicy::First => 0,
+        DuplicatePolicy::Smallest => index.archive_size(),
+        DuplicatePolicy::PreferPatch => usize::from(!patch_archives.contains(&archive)),
+    };
+    let mut map = HashMap::<EncodingKey, (ArchiveKey, usize, usize)>::new();
+    let mut ranks = HashMap::<ArchiveKey, usize>::new();
+    let mut conflicts = Vec::new();
+    for index in indices {
+        let index = index.borrow();
+        for (&ekey, &location) in &index.map {
+            let archive = location.0;
+            let new_rank = *ranks.entry(archive).or_insert_with(|| rank(index, archive));
+            match map.get_mut(&ekey) {
+                None => {
+                    map.insert(ekey, location);
+                }
+                Some(existing) => {
+                    let dropped = if new_rank < ranks[&existing.0] {
+                        std::mem::replace(existing, location).0
+                    } else {
+                        archive
+                    };
+                    conflicts.push(Conflict {
+                        ekey,
+                        kept: map[&ekey].0,
+                        dropped,
+                    });
+                }
+            }
+        }
+    }
+    (Index { map }, conflicts)
+}
+
+/// What fetching a set of files costs for a single archive.
+#[derive(Debug, Eq, PartialEq)]
+pub struct ArchivePlan {
+    pub archive: ArchiveKey,
+    pub files: usize,
+    pub bytes: usize,
+    /// Number of HTTP ranges left after merging adjacent or overlapping ones.
+    pub ranges: usize,
+}
+
+impl ArchivePlan {
+    pub fn coalescable(&self) -> bool {
+        self.ranges < self.files
+    }
+}
+
+/// Groups `(archive, size, offset)` locations by archive, largest first.
+pub fn plan(locations: impl IntoIterator<Item = (ArchiveKey, usize, usize)>) -> Vec<ArchivePlan> {
+    let mut by_archive = HashMap::<ArchiveKey, Vec<(usize, usize)>>::new();
+    for (archive, size, offset) in locations {
+        by_archive.entry(archive).or_default().push((offset, size));
+    }
+    let mut plans = by_archive
+        .into_iter()
+        .map(|(archive, mut spans)| {
+            spans.sort_unstable();
+            let mut ranges = 0;
+            let mut end = None;
+            for &(offset, size) in &spans {
+                if end.is_none_or(|end| offset > end) {
+                    ranges += 1;
+                }
+                end = Some(end.map_or(offset + size, |end: usize| end.max(offset + size)));
+            }
+            ArchivePlan {
+                archive,
+                files: spans.len(),
+                bytes: spans.iter().map(|(_, size)| size).sum(),
+                ranges,
             }
+        })
+        .collect::<Vec<_>>();
+    plans.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.archive.0.cmp(&b.archive.0)));
+    plans
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let (a, b) = (ArchiveKey(1), ArchiveKey(2));
+        assert_eq!(
+            plan(vec![(a, 10, 0), (b, 5, 100), (a, 10, 10), (a, 4, 40)]),
+            vec![
+                ArchivePlan {
+                    archive: a,
+                    files: 3,
+                    bytes: 24,
+                    ranges: 2,
+                },
+                ArchivePlan {
+                    archive: b,
+                    files: 1,
+                    bytes: 5,
+                    ranges: 1,
+                },
+            ]
+        );
+        assert!(plan(vec![(a, 1, 0), (a, 1, 1)])[0].coalescable());
+    }
+
+    fn parse_index(name: ArchiveKey, data: &[u8]) -> Result<Index> {
+        let mut parser = IndexParser::new(name, data.len())?;
+        parser.feed(data)?;
+        parser.finish()
+    }
+
+    /// An index with one block holding `entries`.
+    fn index(entries: &[(EncodingKey, u32, u32)]) -> (ArchiveKey, Vec<u8>) {
+        index_with(&Layout::CDN, entries)
+    }
+
+    fn index_with(layout: &Layout, entries: &[(EncodingKey, u32, u32)]) -> (ArchiveKey, Vec<u8>) {
+        use bytes::BufMut;
+        let checksum = |data: &[u8]| hash::md5(data).to_be_bytes()[..layout.checksum_size].to_vec();
+        let mut data = Vec::new();
+        for (ekey, size, offset) in entries {
+            data.put_slice(&ekey.0.to_be_bytes()[..layout.key_size]);
+            data.put_uint((*size).into(), layout.size_bytes);
+            data.put_uint((*offset).into(), layout.offset_bytes);
         }
-        ensure!(found, "last ekey mismatch");
-        p.advance(4096);
-    }
-    assert!(!p.has_remaining());
-    assert!(!entries.has_remaining());
-    assert!(!blockhashes.has_remaining());
-    ensure!(map.len() == num_elements, "num_elements wrong in index");
-    Ok(Index { map })
+        data.resize(layout.block_size, 0);
+        let mut toc = Vec::new();
+        toc.put_slice(&entries.last().unwrap().0 .0.to_be_bytes()[..layout.key_size]);
+        toc.put_slice(&checksum(&data));
+        let mut footer = checksum(&toc);
+        footer.put_slice(&[
+            1,
+            0,
+            0,
+            (layout.block_size / 1024) as u8,
+            layout.offset_bytes as u8,
+            layout.size_bytes as u8,
+            layout.key_size as u8,
+            layout.checksum_size as u8,
+        ]);
+        footer.put_u32_le(entries.len() as u32);
+        let mut footer_to_check = footer[layout.checksum_size..].to_vec();
+        footer_to_check.resize(footer_to_check.len() + layout.checksum_size, 0);
+        footer.put_slice(&checksum(&footer_to_check));
+        let name = ArchiveKey(hash::md5(&footer));
+        data.extend(toc);
+        data.extend(footer);
+        (name, data)
+    }
+
+    #[test]
+    fn test_parse_index() {
+        let (x, y) = (EncodingKey(10), EncodingKey(20));
+        let (name, data) = index(&[(x, 5, 0), (y, 7, 5)]);
+        let whole = parse_index(name, &data).unwrap();
+        assert_eq!(whole.map[&y], (name, 7, 5));
+        let mut parser = IndexParser::new(name, data.len()).unwrap();
+        for chunk in data.chunks(1000) {
+            parser.feed(chunk).unwrap();
+        }
+        assert_eq!(parser.finish().unwrap().map, whole.map);
+        let mut corrupt = data.clone();
+        corrupt[30] ^= 1;
+        let err = parse_index(name, &corrupt).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("block 0 checksum"),
+            "{:#}",
+            err
+        );
+        let mut parser = IndexParser::new(name, data.len()).unwrap();
+        parser.feed(&data[..5000]).unwrap();
+        assert!(parser.finish().is_err());
+        assert!(parse_index(name, &data[1..]).is_err());
+        let limits = Limits {
+            max_bytes: data.len() - 1,
+            ..Limits::default()
+        };
+        assert!(IndexParser::with_limits(name, data.len(), &limits).is_err());
+    }
+
+    #[test]
+    fn test_index_layouts() {
+        let layout = Layout {
+            block_size: 1024,
+            offset_bytes: 5,
+            size_bytes: 3,
+            key_size: 9,
+            checksum_size: 16,
+        };
+        let x = EncodingKey(0xaaaaaaaa_aaaaaaaa_aa000000_00000000);
+        let (name, data) = index_with(&layout, &[(x, 5, 1 << 20)]);
+        assert_eq!(
+            parse_index(name, &data).unwrap().map[&x],
+            (name, 5, 1 << 20)
+        );
+        let (name, mut data) = index(&[(x, 5, 0)]);
+        let version = data.len() - 20;
+        data[version] = 2;
+        let name = ArchiveKey(hash::md5(&data[data.len() - 28..]));
+        let err = parse_index(name, &data).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("unsupported archive index version 2"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_cached_index() {
+        let (name, data) = index(&[(EncodingKey(10), 5, 0), (EncodingKey(20), 7, 5)]);
+        let index = parse_index(name, &data).unwrap();
+        let cached = index.to_bytes();
+        assert_eq!(cached.len(), 2 * CACHED_ENTRY_SIZE);
+        assert_eq!(Index::from_bytes(&cached).unwrap().map, index.map);
+        assert!(Index::from_bytes(&cached[1..]).is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let (a, b) = (ArchiveKey(1), ArchiveKey(2));
+        let (x, y) = (EncodingKey(10), EncodingKey(20));
+        let indices = || {
+            vec![
+                Index {
+                    map: vec![(x, (a, 10, 1000)), (y, (a, 10, 0))]
+                        .into_iter()
+                        .collect(),
+                },
+                Index {
+                    map: vec![(x, (b, 10, 0))].into_iter().collect(),
+                },
+            ]
+        };
+        let archive_of = |index: &Index, ekey| index.map[&ekey].0;
+        let (index, conflicts) = merge(indices(), DuplicatePolicy::First, &HashSet::new());
+        assert_eq!((archive_of(&index, x), archive_of(&index, y)), (a, a));
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                ekey: x,
+                kept: a,
+                dropped: b,
+            }]
+        );
+        let (index, _) = merge(indices(), DuplicatePolicy::Smallest, &HashSet::new());
+        assert_eq!(archive_of(&index, x), b);
+        let patch = vec![b].into_iter().collect();
+        let (index, conflicts) = merge(indices(), DuplicatePolicy::PreferPatch, &patch);
+        assert_eq!(archive_of(&index, x), b);
+        assert_eq!(conflicts[0].dropped, a);
+        let (index, _) = merge(indices(), DuplicatePolicy::PreferPatch, &HashSet::new());
+        assert_eq!(archive_of(&index, x), a);
+        // Shared indices merge the same as owned ones.
+        let shared = indices().into_iter().map(std::sync::Arc::new).collect();
+        let (index, _) =
+            merge::<std::sync::Arc<Index>>(shared, DuplicatePolicy::Smallest, &HashSet::new());
+        assert_eq!(archive_of(&index, x), b);
+    }
 }
@@ -0,0 +1,159 @@
+//! A synchronous wrapper over the fetch layer, for build scripts and tools
+//! that aren't async. As with reqwest's blocking client, each call drives an
+//! internal runtime to completion, so it must not be called from within an
+//! async runtime.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+
+use crate::archive::{Index, IndexParser};
+use crate::blte;
+use crate::cache::CacheStore;
+use crate::fetch::{BytesFetcher, CdnBytesFetcher, HasCdnPrefixes, TextFetcher};
+use crate::types::{ArchiveKey, EncodingKey};
+
+struct Cdn {
+    fetcher: Arc<dyn BytesFetcher + Send + Sync>,
+    prefixes: Vec<String>,
+    cache: Option<Arc<dyn CacheStore + Send + Sync>>,
+}
+
+#[async_trait]
+impl BytesFetcher for Cdn {
+    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes> {
+        self.fetcher.fetch_bytes(url, range).await
+    }
+}
+
+impl HasCdnPrefixes for Cdn {
+    fn cdn_prefixes(&self) -> &Vec<String> {
+        &self.prefixes
+    }
+    fn cache(&self) -> Option<&(dyn CacheStore + Send + Sync)> {
+        self.cache.as_deref()
+    }
+}
+
+/// Fetches from a CDN, trying each of its prefixes (such as
+/// `http://level3.blizzard.com/tpr/wow`) in turn.
+pub struct Client {
+    runtime: tokio::runtime::Runtime,
+    cdn: Cdn,
+}
+
+impl Client {
+    pub fn new(cdn_prefixes: Vec<String>) -> Result<Client> {
+        Client::with_fetcher(Arc::new(reqwest::Client::new()), cdn_prefixes)
+    }
+
+    /// Fetches through `fetcher` rather than a default HTTP client.
+    pub fn with_fetcher(
+        fetcher: Arc<dyn BytesFetcher + Send + Sync>,
+        cdn_prefixes: Vec<String>,
+    ) -> Result<Client> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("starting runtime")?;
+        Ok(Client {
+            runtime,
+            cdn: Cdn {
+                fetcher,
+                prefixes: cdn_prefixes,
+                cache: None,
+            },
+        })
+    }
+
+    /// Keeps whole files fetched by hash in `cache`, such as a
+    /// [`crate::cache::FsCache`], and serves them from there later.
+    pub fn with_cache(mut self, cache: Arc<dyn CacheStore + Send + Sync>) -> Client {
+        self.cdn.cache = Some(cache);
+        self
+    }
+
+    pub fn fetch_bytes(&self, url: &str) -> Result<Bytes> {
+        self.runtime
+            .block_on(self.cdn.fetch_bytes(url.to_string(), None))
+    }
+
+    pub fn fetch_text(&self, url: &str) -> Result<String> {
+        self.runtime.block_on(self.cdn.fetch_text(url.to_string()))
+    }
+
+    /// Fetches a file stored under `tag` (`config`, `data` or `patch`) by hash.
+    pub fn fetch_cdn_bytes(
+        &self,
+        tag: &str,
+        hash: u128,
+        range: Option<(usize, usize)>,
+    ) -> Result<Bytes> {
+        self.runtime
+            .block_on(self.cdn.fetch_cdn_bytes(tag, hash, None, range))
+    }
+
+    /// Fetches a build or CDN config by hash.
+    pub fn fetch_config(&self, hash: u128) -> Result<String> {
+        let data = self.fetch_cdn_bytes("config", hash, None)?;
+        Ok(std::str::from_utf8(&data)?.to_string())
+    }
+
+    /// Fetches and parses the index of a content archive.
+    pub fn fetch_index(&self, archive: ArchiveKey) -> Result<Index> {
+        let data = self.runtime.block_on(self.cdn.fetch_cdn_bytes(
+            "data",
+            archive.0,
+            Some(".index"),
+            None,
+        ))?;
+        let mut parser = IndexParser::new(archive, data.len())?;
+        parser.feed(&data)?;
+        parser
+            .finish()
+            .with_context(|| format!("parsing index {}", archive))
+    }
+
+    /// Fetches and decodes a file stored outside the archives, such as the
+    /// encoding table, checking it against its encoding key.
+    pub fn fetch_decoded(&self, ekey: EncodingKey) -> Result<Vec<u8>> {
+        blte::parse(Some(ekey.0), &self.fetch_cdn_bytes("data", ekey.0, None)?)
+            .with_context(|| format!("decoding {}", ekey))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::{cdn_path, Fixture};
+
+    #[test]
+    fn test_client() {
+        let encoded = blte::encode(b"hello", blte::Mode::Zlib, Some(2));
+        let ekey = EncodingKey(blte::header_checksum(&encoded).unwrap());
+        let fixture = Fixture(
+            vec![
+                (
+                    format!("http://b/{}", cdn_path("config", 1, None)),
+                    Bytes::from_static(b"root = 2"),
+                ),
+                (
+                    format!("http://b/{}", cdn_path("data", ekey.0, None)),
+                    Bytes::from(encoded),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let client = Client::with_fetcher(
+            Arc::new(fixture),
+            vec!["http://a".to_string(), "http://b".to_string()],
+        )
+        .unwrap();
+        assert_eq!(client.fetch_config(1).unwrap(), "root = 2");
+        assert_eq!(client.fetch_decoded(ekey).unwrap(), b"hello");
+        assert!(client.fetch_decoded(EncodingKey(3)).is_err());
+    }
+}
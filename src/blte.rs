@@ -1,9 +1,18 @@
-use crate::util;
-use anyhow::{anyhow, bail, ensure, Result};
+use crate::keyring::KeyStore;
+use crate::{hash, salsa20};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
+use derive_more::Display;
 use std::convert::TryInto;
 
-fn parse_blte_chunk(data: &[u8]) -> Result<bytes::Bytes> {
+/// Decoding stopped at a chunk encrypted with a key no keyring provides.
+#[derive(Debug, Display)]
+#[display("chunk encrypted with unknown key {:016x}", _0)]
+pub struct MissingKey(pub u64);
+
+impl std::error::Error for MissingKey {}
+
+fn parse_blte_chunk(data: &[u8], keys: Option<&KeyStore>, index: usize) -> Result<bytes::Bytes> {
     let inflate = miniz_oxide::inflate::decompress_to_vec_zlib;
     let chunk_data = &data[1..];
     Ok(match data[0] {
@@ -11,40 +20,439 @@ fn parse_blte_chunk(data: &[u8]) -> Result<bytes::Bytes> {
         b'Z' => {
             Bytes::from(inflate(chunk_data).map_err(|s| anyhow!(format!("inflate error {:?}", s)))?)
         }
+        b'E' => {
+            let name = encrypted_chunk_key(data).context("truncated encrypted chunk")?;
+            let (_, key) = keys.and_then(|k| k.get(name)).ok_or(MissingKey(name))?;
+            let mut p = &data[10..];
+            ensure!(p.remaining() >= 1, "truncated encrypted chunk");
+            let iv_size = usize::from(p.get_u8());
+            ensure!(
+                iv_size <= 8 && p.remaining() > iv_size,
+                "bad encrypted chunk iv"
+            );
+            let mut nonce = [0u8; 8];
+            nonce[..iv_size].copy_from_slice(&p[..iv_size]);
+            p.advance(iv_size);
+            // The chunk index is mixed into the iv so each chunk has its own nonce.
+            for (i, b) in nonce.iter_mut().take(4).enumerate() {
+                *b ^= (index >> (8 * i)) as u8;
+            }
+            ensure!(p.get_u8() == b'S', "unsupported chunk encryption type");
+            let mut decrypted = p.to_vec();
+            salsa20::apply(&key.to_be_bytes(), &nonce, &mut decrypted);
+            ensure!(!decrypted.is_empty(), "empty encrypted chunk");
+            parse_blte_chunk(&decrypted, keys, index)?
+        }
         _ => bail!("invalid encoding"),
     })
 }
 
-pub(crate) fn parse(checksum: u128, data: &[u8]) -> Result<Vec<u8>> {
+/// The key name of an encrypted (`E`) chunk, including its mode byte.
+fn encrypted_chunk_key(data: &[u8]) -> Option<u64> {
+    match data {
+        [b'E', 8, name @ ..] if name.len() >= 8 => {
+            Some(u64::from_le_bytes(name[..8].try_into().ok()?))
+        }
+        _ => None,
+    }
+}
+
+/// The key name protecting the first chunk of a BLTE stream, if encrypted.
+pub fn encryption_key(data: &[u8]) -> Option<u64> {
+    let mut p = data;
+    if p.remaining() < 8 || &p.get_u32().to_be_bytes() != b"BLTE" {
+        return None;
+    }
+    let header_size: usize = p.get_u32().try_into().ok()?;
+    encrypted_chunk_key(data.get(header_size.max(8)..)?)
+}
+
+/// Decodes a BLTE stream. When `checksum` is `None`, neither the header nor the
+/// per-chunk checksums are verified.
+pub fn parse(checksum: Option<u128>, data: &[u8]) -> Result<Vec<u8>> {
+    decode(checksum, data, None, None, None)
+}
+
+/// Like parse, but decrypts encrypted chunks with keys from `keys`.
+pub fn parse_keyed(checksum: Option<u128>, keys: &KeyStore, data: &[u8]) -> Result<Vec<u8>> {
+    decode(checksum, data, None, Some(keys), None)
+}
+
+/// Like parse_keyed, but also returns each chunk's table entry and framing.
+/// A headerless stream is described as one chunk, with the checksum it would
+/// have had in a chunk table.
+pub fn parse_chunked(
+    checksum: Option<u128>,
+    keys: &KeyStore,
+    data: &[u8],
+) -> Result<(Vec<u8>, Vec<Chunk>)> {
+    let mut chunks = Vec::new();
+    let result = decode(checksum, data, None, Some(keys), Some(&mut chunks))?;
+    Ok((result, chunks))
+}
+
+/// Like parse_keyed, but also returns the MD5 of the decoded content,
+/// computed chunk by chunk as it is decoded.
+pub fn parse_hashed(
+    checksum: Option<u128>,
+    keys: &KeyStore,
+    data: &[u8],
+) -> Result<(Vec<u8>, u128)> {
+    let mut hasher = hash::Md5Hasher::new();
+    let result = decode(checksum, data, Some(&mut hasher), Some(keys), None)?;
+    Ok((result, hasher.finish()))
+}
+
+/// The MD5 of a BLTE stream's header, which is what its encoding key is.
+/// Single-chunk streams have no header, so the whole stream is hashed.
+pub fn header_checksum(data: &[u8]) -> Option<u128> {
+    let mut p = data;
+    if p.remaining() < 8 || &p.get_u32().to_be_bytes() != b"BLTE" {
+        return None;
+    }
+    let header_size: usize = p.get_u32().try_into().ok()?;
+    match header_size {
+        0 => Some(hash::md5(data)),
+        size => Some(hash::md5(data.get(..size)?)),
+    }
+}
+
+/// One entry of a BLTE chunk table.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ChunkInfo {
+    pub compressed_size: usize,
+    pub decoded_size: usize,
+    pub checksum: u128,
+}
+
+/// How a chunk's data is stored, from its first byte.
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+pub enum Frame {
+    /// Stored as is (`N`).
+    #[display("raw")]
+    Raw,
+    /// zlib compressed (`Z`).
+    #[display("zlib")]
+    Zlib,
+    /// Encrypted (`E`), framing another chunk.
+    #[display("encrypted")]
+    Encrypted,
+}
+
+/// A decoded chunk: its table entry and framing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Chunk {
+    pub info: ChunkInfo,
+    pub frame: Frame,
+}
+
+fn frame(data: &[u8]) -> Result<Frame> {
+    Ok(match data.first() {
+        Some(b'N') => Frame::Raw,
+        Some(b'Z') => Frame::Zlib,
+        Some(b'E') => Frame::Encrypted,
+        _ => bail!("invalid encoding"),
+    })
+}
+
+/// Parses the header and chunk table at the start of a multi-chunk BLTE file.
+/// `data` need not contain anything beyond the header.
+pub fn parse_header(checksum: Option<u128>, data: &[u8]) -> Result<Vec<ChunkInfo>> {
+    let mut p = data;
+    ensure!(p.remaining() >= 12, "truncated header");
+    ensure!(&p.get_u32().to_be_bytes() == b"BLTE", "not BLTE format");
+    let header_size: usize = p.get_u32().try_into()?;
+    ensure!(header_size != 0, "BLTE file has no chunk table");
+    ensure!(data.len() >= header_size, "truncated chunk table");
+    if let Some(checksum) = checksum {
+        ensure!(hash::md5(&data[0..header_size]) == checksum);
+    }
+    ensure!(p.get_u8() == 0xf, "bad flag byte");
+    let chunk_count: usize = ((u32::from(p.get_u8()) << 16) | u32::from(p.get_u16())).try_into()?;
+    ensure!(header_size == chunk_count * 24 + 12, "header size mismatch");
+    let mut chunks = Vec::with_capacity(chunk_count);
+    for _ in 0..chunk_count {
+        chunks.push(ChunkInfo {
+            compressed_size: p.get_u32().try_into()?,
+            decoded_size: p.get_u32().try_into()?,
+            checksum: p.get_u128(),
+        });
+    }
+    Ok(chunks)
+}
+
+/// Decodes one chunk described by `info`, verifying its checksum if asked.
+pub fn decode_chunk(info: &ChunkInfo, verify: bool, data: &[u8]) -> Result<Bytes> {
+    decode_chunk_inner(info, verify, None, 0, data)
+}
+
+/// Like decode_chunk, but decrypts with keys from `keys`; encrypted chunks
+/// need their `index` in the stream, which their nonce depends on.
+pub fn decode_chunk_keyed(
+    info: &ChunkInfo,
+    verify: bool,
+    keys: &KeyStore,
+    index: usize,
+    data: &[u8],
+) -> Result<Bytes> {
+    decode_chunk_inner(info, verify, Some(keys), index, data)
+}
+
+fn decode_chunk_inner(
+    info: &ChunkInfo,
+    verify: bool,
+    keys: Option<&KeyStore>,
+    index: usize,
+    data: &[u8],
+) -> Result<Bytes> {
+    ensure!(data.len() == info.compressed_size, "truncated chunk");
+    if verify {
+        ensure!(info.checksum == hash::md5(data), "chunk checksum error");
+    }
+    let decoded = parse_blte_chunk(data, keys, index)?;
+    ensure!(
+        decoded.len() == info.decoded_size,
+        "invalid uncompressed size"
+    );
+    Ok(decoded)
+}
+
+/// How each chunk of an encoded BLTE file is framed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Mode {
+    /// Stored as is (`N`).
+    Raw,
+    /// zlib compressed (`Z`).
+    Zlib,
+}
+
+fn encode_chunk(mode: Mode, data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 1);
+    match mode {
+        Mode::Raw => {
+            chunk.push(b'N');
+            chunk.extend_from_slice(data);
+        }
+        Mode::Zlib => {
+            chunk.push(b'Z');
+            chunk.extend(miniz_oxide::deflate::compress_to_vec_zlib(data, 6));
+        }
+    }
+    chunk
+}
+
+/// Builds a BLTE file from `data`. Without a `chunk_size` the result is a
+/// single headerless chunk; otherwise `data` is split into chunks of at most
+/// that many bytes, listed with their checksums in a chunk table. The
+/// encoding key of the result is its header_checksum.
+pub fn encode(data: &[u8], mode: Mode, chunk_size: Option<usize>) -> Vec<u8> {
+    let mut result = b"BLTE".to_vec();
+    let chunk_size = match chunk_size {
+        Some(size) => size.max(1),
+        None => {
+            result.put_u32(0);
+            result.extend(encode_chunk(mode, data));
+            return result;
+        }
+    };
+    let chunks = if data.is_empty() {
+        vec![encode_chunk(mode, data)]
+    } else {
+        data.chunks(chunk_size)
+            .map(|c| encode_chunk(mode, c))
+            .collect::<Vec<_>>()
+    };
+    let decoded_sizes = data.chunks(chunk_size).map(<[u8]>::len).chain(
+        // An empty input still gets one (empty) chunk.
+        std::iter::once(0).filter(|_| data.is_empty()),
+    );
+    result.put_u32((chunks.len() * 24 + 12) as u32);
+    result.put_u8(0xf);
+    result.put_u8((chunks.len() >> 16) as u8);
+    result.put_u16(chunks.len() as u16);
+    for (chunk, decoded_size) in chunks.iter().zip(decoded_sizes) {
+        result.put_u32(chunk.len() as u32);
+        result.put_u32(decoded_size as u32);
+        result.put_u128(hash::md5(chunk));
+    }
+    chunks.iter().for_each(|c| result.extend_from_slice(c));
+    result
+}
+
+fn decode(
+    checksum: Option<u128>,
+    data: &[u8],
+    mut hasher: Option<&mut hash::Md5Hasher>,
+    keys: Option<&KeyStore>,
+    mut chunks: Option<&mut Vec<Chunk>>,
+) -> Result<Vec<u8>> {
     let mut p = data;
     ensure!(p.remaining() >= 12, "truncated header");
     ensure!(&p.get_u32().to_be_bytes() == b"BLTE", "not BLTE format");
     let header_size = p.get_u32().try_into()?;
     if header_size == 0 {
-        ensure!(util::md5hash(data) == checksum);
-        return Ok(parse_blte_chunk(p)?.to_vec());
+        if let Some(checksum) = checksum {
+            ensure!(hash::md5(data) == checksum);
+        }
+        let chunk = parse_blte_chunk(p, keys, 0)?;
+        if let Some(hasher) = hasher {
+            hasher.update(&chunk);
+        }
+        if let Some(chunks) = chunks {
+            chunks.push(Chunk {
+                info: ChunkInfo {
+                    compressed_size: p.len(),
+                    decoded_size: chunk.len(),
+                    checksum: hash::md5(p),
+                },
+                frame: frame(p)?,
+            });
+        }
+        return Ok(chunk.to_vec());
     }
     ensure!(p.remaining() >= header_size - 8);
-    ensure!(util::md5hash(&data[0..header_size]) == checksum);
+    if let Some(checksum) = checksum {
+        ensure!(hash::md5(&data[0..header_size]) == checksum);
+    }
     ensure!(p.get_u8() == 0xf, "bad flag byte");
     let chunk_count: usize = ((u32::from(p.get_u8()) << 16) | u32::from(p.get_u16())).try_into()?;
     ensure!(header_size == chunk_count * 24 + 12, "header size mismatch");
-    let mut chunkinfo = Vec::<(usize, usize, u128)>::new();
+    let mut chunkinfo = Vec::<ChunkInfo>::new();
     for _ in 0..chunk_count {
-        let compressed_size = p.get_u32().try_into()?;
-        let uncompressed_size = p.get_u32().try_into()?;
-        let checksum = p.get_u128();
-        chunkinfo.push((compressed_size, uncompressed_size, checksum))
-    }
-    let mut result = BytesMut::with_capacity(chunkinfo.iter().map(|x| x.1).sum::<usize>());
-    for (compressed_size, uncompressed_size, checksum) in chunkinfo {
-        let chunk = &p[0..compressed_size];
-        ensure!(checksum == util::md5hash(chunk), "chunk checksum error");
-        let data = parse_blte_chunk(chunk)?;
-        ensure!(data.len() == uncompressed_size, "invalid uncompressed size");
+        chunkinfo.push(ChunkInfo {
+            compressed_size: p.get_u32().try_into()?,
+            decoded_size: p.get_u32().try_into()?,
+            checksum: p.get_u128(),
+        });
+    }
+    let mut result = BytesMut::with_capacity(chunkinfo.iter().map(|c| c.decoded_size).sum());
+    for (index, info) in chunkinfo.into_iter().enumerate() {
+        let chunk = &p[0..info.compressed_size];
+        if checksum.is_some() {
+            ensure!(info.checksum == hash::md5(chunk), "chunk checksum error");
+        }
+        let data = parse_blte_chunk(chunk, keys, index)?;
+        ensure!(data.len() == info.decoded_size, "invalid uncompressed size");
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(&data);
+        }
+        if let Some(chunks) = chunks.as_deref_mut() {
+            chunks.push(Chunk {
+                info,
+                frame: frame(chunk)?,
+            });
+        }
         result.put(data);
-        p.advance(compressed_size)
+        p.advance(info.compressed_size)
     }
     ensure!(!p.has_remaining(), "trailing blte data");
     Ok(result.to_vec())
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_encryption_key() {
+        let mut data = b"BLTE\0\0\0\0E\x08".to_vec();
+        data.extend_from_slice(&0xfa50_5078_126a_cb3eu64.to_le_bytes());
+        data.extend_from_slice(b"\x04\0\0\0\0S");
+        assert_eq!(super::encryption_key(&data), Some(0xfa50_5078_126a_cb3e));
+        assert!(super::parse(None, &data).is_err());
+        assert_eq!(super::encryption_key(b"BLTE\0\0\0\0Nabc"), None);
+    }
+
+    #[test]
+    fn test_encode() {
+        let data = (0..1000u32)
+            .flat_map(|i| (i % 7).to_le_bytes())
+            .collect::<Vec<u8>>();
+        for mode in [super::Mode::Raw, super::Mode::Zlib] {
+            for chunk_size in [None, Some(1), Some(999), Some(4096)] {
+                for input in [&data[..], &b""[..]] {
+                    let encoded = super::encode(input, mode, chunk_size);
+                    let checksum = super::header_checksum(&encoded);
+                    assert!(checksum.is_some());
+                    assert_eq!(
+                        super::parse(checksum, &encoded).unwrap(),
+                        input,
+                        "{:?} {:?}",
+                        mode,
+                        chunk_size
+                    );
+                }
+            }
+        }
+        let chunks =
+            super::parse_header(None, &super::encode(&data, super::Mode::Raw, Some(999))).unwrap();
+        assert_eq!(
+            chunks.iter().map(|c| c.decoded_size).collect::<Vec<_>>(),
+            vec![999, 999, 999, 999, 4]
+        );
+    }
+
+    #[test]
+    fn test_parse_chunked() {
+        let data = vec![7u8; 2500];
+        let keys = crate::keyring::KeyStore::default();
+        let encoded = super::encode(&data, super::Mode::Zlib, Some(1000));
+        let (decoded, chunks) = super::parse_chunked(None, &keys, &encoded).unwrap();
+        assert_eq!(decoded, data);
+        assert_eq!(
+            chunks.iter().map(|c| c.info).collect::<Vec<_>>(),
+            super::parse_header(None, &encoded).unwrap()
+        );
+        assert!(chunks.iter().all(|c| c.frame == super::Frame::Zlib));
+        let encoded = super::encode(b"abc", super::Mode::Raw, None);
+        let (_, chunks) = super::parse_chunked(None, &keys, &encoded).unwrap();
+        assert_eq!(
+            chunks,
+            vec![super::Chunk {
+                info: super::ChunkInfo {
+                    compressed_size: 4,
+                    decoded_size: 3,
+                    checksum: crate::hash::md5(b"Nabc"),
+                },
+                frame: super::Frame::Raw,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_parse_keyed() {
+        let key = 0x0123_4567_89ab_cdef_0123_4567_89ab_cdef_u128;
+        let mut payload = b"Nhello".to_vec();
+        crate::salsa20::apply(
+            &key.to_be_bytes(),
+            b"\x01\x02\x03\x04\0\0\0\0",
+            &mut payload,
+        );
+        let mut data = b"BLTE\0\0\0\0E\x08".to_vec();
+        data.extend_from_slice(&7u64.to_le_bytes());
+        data.extend_from_slice(b"\x04\x01\x02\x03\x04S");
+        data.extend_from_slice(&payload);
+        let mut keys = crate::keyring::KeyStore::default();
+        let err = super::parse_keyed(None, &keys, &data).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<super::MissingKey>().map(|k| k.0),
+            Some(7)
+        );
+        keys.add("test", vec![(7, key)].into_iter().collect());
+        assert_eq!(super::parse_keyed(None, &keys, &data).unwrap(), b"hello");
+        // Decoded on its own, the chunk still gets its nonce from its index.
+        let chunk = &data[8..];
+        let info = super::ChunkInfo {
+            compressed_size: chunk.len(),
+            decoded_size: 5,
+            checksum: crate::hash::md5(chunk),
+        };
+        assert!(super::decode_chunk(&info, true, chunk).is_err());
+        assert_eq!(
+            super::decode_chunk_keyed(&info, true, &keys, 0, chunk).unwrap(),
+            &b"hello"[..]
+        );
+        assert!(super::decode_chunk_keyed(&info, true, &keys, 1, chunk)
+            .map_or(true, |d| d != &b"hello"[..]));
+        assert_eq!(super::header_checksum(&data), Some(crate::hash::md5(&data)));
+    }
+}
@@ -0,0 +1,117 @@
+//! Where fetched and parsed data is kept between runs. Everything cached is
+//! addressed by content hash, so entries never go stale and stores need no
+//! expiry. The filesystem store is the default; embedders can plug in
+//! anything else, such as sled or redis, by implementing [`CacheStore`].
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use async_trait::async_trait;
+
+/// A key-value store for cached bytes. Keys are relative, slash-separated
+/// paths such as `data/ab/cd/abcd...`.
+#[async_trait]
+pub trait CacheStore {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+}
+
+/// Keeps each entry as a file named by its key under a directory.
+pub struct FsCache {
+    dir: PathBuf,
+}
+
+impl FsCache {
+    pub fn new(dir: impl Into<PathBuf>) -> FsCache {
+        FsCache { dir: dir.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        ensure!(
+            !key.starts_with('/') && !key.split('/').any(|part| part.is_empty() || part == ".."),
+            "invalid cache key {}",
+            key
+        );
+        Ok(self.dir.join(key))
+    }
+}
+
+#[async_trait]
+impl CacheStore for FsCache {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+        match tokio::fs::read(&path).await {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// Writes beside the entry and renames into place, so concurrent
+    /// readers never see part of one.
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("creating {}", parent.display()))?;
+        }
+        let mut part = path.as_os_str().to_owned();
+        part.push(".part");
+        tokio::fs::write(&part, data)
+            .await
+            .with_context(|| format!("writing {}", path.display()))?;
+        tokio::fs::rename(&part, &path)
+            .await
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Keeps entries in memory for the life of the process.
+#[derive(Default)]
+pub struct MemoryCache(Mutex<HashMap<String, Vec<u8>>>);
+
+#[async_trait]
+impl CacheStore for MemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.0.lock().expect("cache lock").get(key).cloned())
+    }
+
+    async fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        self.0
+            .lock()
+            .expect("cache lock")
+            .insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fs_cache() {
+        let dir = std::env::temp_dir().join(format!("rustycasc-cache-{}", std::process::id()));
+        let cache = FsCache::new(&dir);
+        assert_eq!(cache.get("data/ab/cd/abcd").await.unwrap(), None);
+        cache.put("data/ab/cd/abcd", b"hello").await.unwrap();
+        assert_eq!(
+            cache.get("data/ab/cd/abcd").await.unwrap().as_deref(),
+            Some(&b"hello"[..])
+        );
+        assert!(cache.put("../escape", b"x").await.is_err());
+        assert!(cache.get("/etc/passwd").await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_cache() {
+        let cache = MemoryCache::default();
+        assert_eq!(cache.get("a").await.unwrap(), None);
+        cache.put("a", b"x").await.unwrap();
+        assert_eq!(cache.get("a").await.unwrap(), Some(b"x".to_vec()));
+    }
+}
@@ -1,24 +1,68 @@
-use std::{collections::HashMap, convert::TryInto};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::{TryFrom, TryInto},
+    sync::Arc,
+};
 
-use anyhow::{ensure, Context, Error, Result};
+use anyhow::{ensure, Context, Result};
 use bytes::Buf;
 use nom_derive::{nom, NomLE, Parse};
 
+use crate::diag;
+use crate::limits::Limits;
+use crate::locale::Locale;
+
+/// Header flag set when record IDs are stored in a separate id_list rather
+/// than inline in each record.
+const FLAG_ID_LIST: u16 = 0x4;
+
+/// Header flag set when records are variable-length, found through each
+/// section's offset map, with their strings inline.
+const FLAG_OFFSET_MAP: u16 = 0x1;
+
+/// Field storage types whose values sit directly in the record's bits.
+const STORAGE_NONE: u32 = 0;
+const STORAGE_BITPACKED: u32 = 1;
+
+/// Reads a little-endian field of up to 32 bits starting `offset` bits into
+/// `data`.
+fn read_bits(data: &[u8], offset: usize, size: usize) -> Result<u32> {
+    ensure!(size <= 32, "field too wide");
+    let start = offset / 8;
+    let end = (offset + size).div_ceil(8);
+    ensure!(end <= data.len(), "field out of record bounds");
+    let mut buf = [0u8; 8];
+    buf[..end - start].copy_from_slice(&data[start..end]);
+    let value = u64::from_le_bytes(buf) >> (offset % 8);
+    Ok((value & ((1u64 << size) - 1)) as u32)
+}
+
+/// WDC5 inserts a version and a schema string after the magic; the WDC3 and
+/// WDC4 headers go straight on to the record count.
+fn schema_size(magic: &[u8; 4]) -> usize {
+    match magic {
+        b"WDC5" => 132,
+        _ => 0,
+    }
+}
+
 #[derive(Debug, NomLE)]
 struct Header {
+    #[nom(Verify = "[*b\"WDC3\", *b\"WDC4\", *b\"WDC5\"].contains(magic)")]
     magic: [u8; 4],
-    _unused: [u8; 132],
-    _record_count: u32,
-    _field_count: u32,
+    #[nom(Count = "schema_size(&magic)")]
+    _schema: Vec<u8>,
+    record_count: u32,
+    field_count: u32,
     record_size: u32,
-    _string_table_size: u32,
-    _table_hash: u32,
-    _layout_hash: u32,
-    _min_id: u32,
-    _max_id: u32,
-    _locale: u32,
+    string_table_size: u32,
+    table_hash: u32,
+    layout_hash: u32,
+    min_id: u32,
+    max_id: u32,
+    locale: u32,
     flags: u16,
-    _id_index: u16,
+    id_index: u16,
     total_field_count: u32,
     _bitpacked_data_offset: u32,
     _lookup_column_count: u32,
@@ -28,13 +72,59 @@ struct Header {
     section_count: u32,
 }
 
+/// Size in bytes of the largest fixed db2 header, WDC5's.
+pub const HEADER_SIZE: usize = 204;
+
+/// The interesting parts of a db2 header.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Info {
+    pub magic: String,
+    pub record_count: u32,
+    pub field_count: u32,
+    pub record_size: u32,
+    pub string_table_size: u32,
+    pub table_hash: u32,
+    pub layout_hash: u32,
+    pub min_id: u32,
+    pub max_id: u32,
+    pub locale: u32,
+    /// The locale whose strings the file holds, when `locale` is a known one.
+    pub locale_name: Option<Locale>,
+    pub flags: u16,
+    pub id_index: u16,
+    pub section_count: u32,
+}
+
+/// Parses just the header of a db2, which is all `data` needs to contain.
+pub fn info(data: &[u8]) -> Result<Info> {
+    let h = Header::parse(data)
+        .map_err(|e| diag::nom_error("db2 header", data, e))?
+        .1;
+    Ok(Info {
+        magic: String::from_utf8_lossy(&h.magic).into_owned(),
+        record_count: h.record_count,
+        field_count: h.field_count,
+        record_size: h.record_size,
+        string_table_size: h.string_table_size,
+        table_hash: h.table_hash,
+        layout_hash: h.layout_hash,
+        min_id: h.min_id,
+        max_id: h.max_id,
+        locale: h.locale,
+        locale_name: Locale::from_db2(h.locale),
+        flags: h.flags,
+        id_index: h.id_index,
+        section_count: h.section_count,
+    })
+}
+
 #[derive(Debug, NomLE)]
 struct SectionHeader {
     _tact_key_hash: u64,
-    _file_offset: u32,
+    file_offset: u32,
     record_count: u32,
     string_table_size: u32,
-    _offset_records_end: u32,
+    offset_records_end: u32,
     id_list_size: u32,
     relationship_data_size: u32,
     offset_map_id_count: u32,
@@ -49,10 +139,10 @@ struct FieldStructure {
 
 #[derive(Debug, NomLE)]
 struct FieldStorageInfo {
-    _field_offset_bits: u16,
-    _field_size_bits: u16,
+    field_offset_bits: u16,
+    field_size_bits: u16,
     _additional_data_size: u32,
-    _storage_type: u32,
+    storage_type: u32,
     _compression1: u32,
     _compression2: u32,
     _compression3: u32,
@@ -73,28 +163,60 @@ struct CopyTableEntry {
 
 #[derive(Debug, NomLE)]
 struct OffsetMapEntry {
-    _offset: u32,
-    _size: u16,
+    /// Absolute file offset of the record.
+    offset: u32,
+    size: u16,
+}
+
+impl Header {
+    fn sparse(&self) -> bool {
+        self.flags & FLAG_OFFSET_MAP != 0
+    }
+}
+
+impl SectionHeader {
+    /// Fixed-size records, or none when records are addressed by offset map.
+    fn fixed_record_count(&self, header: &Header) -> u32 {
+        if header.sparse() {
+            0
+        } else {
+            self.record_count
+        }
+    }
+
+    /// Bytes of variable-length record data, which replace the fixed records
+    /// and string table in sparse tables.
+    fn variable_data_size(&self, header: &Header) -> u32 {
+        if header.sparse() {
+            self.offset_records_end.saturating_sub(self.file_offset)
+        } else {
+            0
+        }
+    }
 }
 
 #[derive(Debug, NomLE)]
 #[nom(ExtraArgs(header: &Header, section_header: &SectionHeader))]
 struct Section {
     #[nom(
-        Count = "section_header.record_count",
+        Count = "section_header.fixed_record_count(header)",
         Parse = "|i| Record::parse(i, header)"
     )]
     records: Vec<Record>,
     #[nom(Count = "section_header.string_table_size")]
     string_table: Vec<u8>,
+    #[nom(Count = "section_header.variable_data_size(header)")]
+    _variable_data: Vec<u8>,
     #[nom(Count = "(section_header.id_list_size / 4) as usize")]
     id_list: Vec<u32>,
     #[nom(Count = "section_header.copy_table_count")]
     _copy_table: Vec<CopyTableEntry>,
     #[nom(Count = "section_header.offset_map_id_count")]
-    _offset_map: Vec<OffsetMapEntry>,
+    offset_map: Vec<OffsetMapEntry>,
     #[nom(Count = "section_header.relationship_data_size")]
-    _relationship_data: Vec<u8>,
+    relationship_data: Vec<u8>,
+    #[nom(Count = "section_header.offset_map_id_count")]
+    offset_map_id_list: Vec<u32>,
 }
 
 fn parse_sections<'a>(
@@ -104,6 +226,15 @@ fn parse_sections<'a>(
 ) -> nom::IResult<&'a [u8], Vec<Section>> {
     let mut v = Vec::<Section>::new();
     for h in section_headers {
+        // Zero-size records consume nothing, so a huge count would
+        // otherwise parse without ever running out of input.
+        let records = u64::from(h.fixed_record_count(header));
+        if records * u64::from(header.record_size.max(1)) > i.len() as u64 {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                i,
+                nom::error::ErrorKind::TooLarge,
+            )));
+        }
         let (j, section) = Section::parse(i, header, h)?;
         v.push(section);
         i = j;
@@ -120,7 +251,7 @@ struct File {
     #[nom(Count = "header.total_field_count")]
     _fields: Vec<FieldStructure>,
     #[nom(Count = "header.total_field_count")]
-    _field_info: Vec<FieldStorageInfo>,
+    field_info: Vec<FieldStorageInfo>,
     #[nom(Count = "header.pallet_data_size")]
     _pallet_data: Vec<u8>,
     #[nom(Count = "header.common_data_size")]
@@ -129,51 +260,446 @@ struct File {
     sections: Vec<Section>,
 }
 
-pub(crate) fn strings(data: &[u8]) -> Result<HashMap<u32, Vec<String>>> {
+pub fn strings(data: &[u8]) -> Result<HashMap<u32, Vec<String>>> {
+    strings_limited(data, &Limits::default())
+}
+
+/// Checks the header's counts and sizes against `limits`, and that the
+/// fixed-size records it claims fit in the file.
+fn check_header(data: &[u8], limits: &Limits) -> Result<()> {
+    let h = Header::parse(data)
+        .map_err(|e| diag::nom_error("db2 header", data, e))?
+        .1;
+    limits.entries("db2 records", h.record_count.into())?;
+    limits.entries("db2 fields", h.total_field_count.into())?;
+    limits.entries("db2 sections", h.section_count.into())?;
+    limits.bytes("db2 record", h.record_size.into())?;
+    limits.bytes("db2 string table", h.string_table_size.into())?;
+    limits.bytes("db2 pallet data", h.pallet_data_size.into())?;
+    limits.bytes("db2 common data", h.common_data_size.into())?;
+    if !h.sparse() {
+        let records = u64::from(h.record_count) * u64::from(h.record_size);
+        ensure!(
+            records <= data.len() as u64,
+            "db2 records need {} bytes, but the file is {}",
+            records,
+            data.len()
+        );
+    }
+    Ok(())
+}
+
+/// Like strings, but with the header's counts and sizes held to `limits`.
+pub fn strings_limited(data: &[u8], limits: &Limits) -> Result<HashMap<u32, Vec<String>>> {
+    Ok(strings_shared(data, limits)?
+        .into_iter()
+        .map(|(id, values)| (id, values.iter().map(|v| v.to_string()).collect()))
+        .collect())
+}
+
+/// The string table, split lazily: each offset a record points at is read
+/// once, and records sharing it share the string.
+struct StringTable<'a> {
+    data: &'a [u8],
+    by_offset: HashMap<usize, Arc<str>>,
+}
+
+impl<'a> StringTable<'a> {
+    fn new(data: &'a [u8]) -> StringTable<'a> {
+        StringTable {
+            data,
+            by_offset: HashMap::new(),
+        }
+    }
+
+    /// The NUL-terminated string at `start`. Offsets past the end, which
+    /// non-string fields read as strings produce, are empty.
+    fn get(&mut self, start: usize) -> Result<Arc<str>> {
+        if let Some(s) = self.by_offset.get(&start) {
+            return Ok(s.clone());
+        }
+        let rest = self.data.get(start..).unwrap_or_default();
+        let end = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+        let s: Arc<str> = std::str::from_utf8(&rest[..end])
+            .context("wdc3 string field parsing")?
+            .into();
+        self.by_offset.insert(start, s.clone());
+        Ok(s)
+    }
+}
+
+/// Like strings_limited, but with each distinct string allocated once and
+/// shared between the records holding it, for tables with millions of rows.
+pub fn strings_shared(data: &[u8], limits: &Limits) -> Result<HashMap<u32, Vec<Arc<str>>>> {
+    check_header(data, limits)?;
     let File {
         mut sections,
+        field_info,
         header:
             Header {
-                magic,
                 flags,
+                id_index,
                 record_size,
+                total_field_count,
                 ..
             },
         ..
-    } = File::parse(data).map_err(|_| Error::msg("parse error"))?.1;
-    ensure!(magic == *b"WDC5", "unsupported magic");
-    ensure!(flags == 4, "unsupported flags");
+    } = File::parse(data)
+        .map_err(|e| diag::nom_error("db2 file", data, e))?
+        .1;
+    ensure!(
+        flags & !(FLAG_ID_LIST | FLAG_OFFSET_MAP) == 0,
+        "unsupported flags"
+    );
     ensure!(sections.len() == 1, "unsupported number of sections");
     let Section {
         records,
         id_list,
         string_table,
+        offset_map,
+        offset_map_id_list,
         ..
     } = sections.remove(0);
+    if flags & FLAG_OFFSET_MAP != 0 {
+        let fields = usize::try_from(total_field_count)?;
+        return sparse_strings(data, &offset_map, &offset_map_id_list, fields);
+    }
     let num_records = records.len();
-    ensure!(id_list.len() == num_records, "unexpected record count");
     let rsize: usize = record_size.try_into()?;
     ensure!(rsize % 4 == 0, "unexpected record size");
+    // Without an id_list, the ID is a record field which isn't a string.
+    let (ids, id_bytes) = if flags & FLAG_ID_LIST != 0 {
+        ensure!(id_list.len() == num_records, "unexpected record count");
+        (id_list, 0..0)
+    } else {
+        inline_ids(&records, &field_info, id_index)?
+    };
+    let mut table = StringTable::new(&string_table);
     let values = records
         .into_iter()
         .enumerate()
         .map(|(k, rec)| {
             (0..rsize)
                 .step_by(4)
+                .filter(|offset| !id_bytes.contains(offset))
                 .map(|offset| {
                     let value: usize = (&rec.data.as_slice()[offset..]).get_u32_le().try_into()?;
-                    String::from_utf8(
-                        string_table
-                            .iter()
-                            .skip(value - (num_records - k) * rsize + offset)
-                            .take_while(|&b| *b != 0)
-                            .cloned()
-                            .collect(),
-                    )
-                    .context("wdc3 string field parsing")
+                    let start = value
+                        .checked_add(offset)
+                        .and_then(|v| v.checked_sub((num_records - k) * rsize))
+                        .context("string offset before the string table")?;
+                    table.get(start)
                 })
                 .collect::<Result<Vec<_>>>()
         })
         .collect::<Result<Vec<_>>>()?;
-    Ok(id_list.into_iter().zip(values).collect())
+    Ok(ids.into_iter().zip(values).collect())
+}
+
+/// Reads each record's ID from its `id_index` field, returning them with the
+/// bytes of the record the field covers.
+fn inline_ids(
+    records: &[Record],
+    field_info: &[FieldStorageInfo],
+    id_index: u16,
+) -> Result<(Vec<u32>, std::ops::Range<usize>)> {
+    let info = field_info
+        .get(usize::from(id_index))
+        .context("id_index out of range")?;
+    ensure!(
+        info.storage_type == STORAGE_NONE || info.storage_type == STORAGE_BITPACKED,
+        "unsupported id field storage type {}",
+        info.storage_type
+    );
+    let (offset, size) = (
+        usize::from(info.field_offset_bits),
+        usize::from(info.field_size_bits),
+    );
+    let ids = records
+        .iter()
+        .map(|rec| read_bits(&rec.data, offset, size))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((ids, offset / 8..(offset + size).div_ceil(8)))
+}
+
+/// Each record's foreign key from the relationship map, by record ID: the
+/// ID of the row it belongs to in the parent table. Records the map doesn't
+/// cover, and tables without one, have none.
+pub fn relationships(data: &[u8], limits: &Limits) -> Result<HashMap<u32, u32>> {
+    check_header(data, limits)?;
+    let File {
+        mut sections,
+        field_info,
+        header,
+        ..
+    } = File::parse(data)
+        .map_err(|e| diag::nom_error("db2 file", data, e))?
+        .1;
+    ensure!(sections.len() == 1, "unsupported number of sections");
+    let section = sections.remove(0);
+    let mut p = section.relationship_data.as_slice();
+    if !p.has_remaining() {
+        return Ok(HashMap::new());
+    }
+    let ids = if header.sparse() {
+        section.offset_map_id_list
+    } else if header.flags & FLAG_ID_LIST != 0 {
+        section.id_list
+    } else {
+        inline_ids(&section.records, &field_info, header.id_index)?.0
+    };
+    ensure!(p.remaining() >= 12, "truncated relationship map");
+    let count = limits.entries("db2 relationship map", p.get_u32_le().into())?;
+    // The min and max IDs aren't needed to read the entries.
+    p.advance(8);
+    ensure!(p.remaining() / 8 >= count, "truncated relationship map");
+    (0..count)
+        .map(|_| {
+            let foreign_id = p.get_u32_le();
+            let index = usize::try_from(p.get_u32_le())?;
+            let id = ids
+                .get(index)
+                .context("relationship map record index out of range")?;
+            Ok((*id, foreign_id))
+        })
+        .collect()
+}
+
+/// Reads sparse records, each a run of `fields` NUL-terminated strings.
+fn sparse_strings(
+    data: &[u8],
+    offset_map: &[OffsetMapEntry],
+    ids: &[u32],
+    fields: usize,
+) -> Result<HashMap<u32, Vec<Arc<str>>>> {
+    // Sparse records carry their strings inline, so share them by value.
+    let mut interned = HashSet::<Arc<str>>::new();
+    offset_map
+        .iter()
+        .zip(ids)
+        .map(|(entry, id)| {
+            let start = usize::try_from(entry.offset)?;
+            let end = start
+                .checked_add(usize::from(entry.size))
+                .context("sparse record out of bounds")?;
+            let record = data
+                .get(start..end)
+                .context("sparse record out of bounds")?;
+            let mut values = record.split(|b| *b == 0);
+            let strings = (0..fields)
+                .map(|_| {
+                    let value = values.next().context("truncated sparse record")?;
+                    let value =
+                        std::str::from_utf8(value).context("sparse string field parsing")?;
+                    if let Some(s) = interned.get(value) {
+                        return Ok(s.clone());
+                    }
+                    let s: Arc<str> = value.into();
+                    interned.insert(s.clone());
+                    Ok(s)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok((*id, strings))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut;
+
+    /// Builds a one-section db2 from raw records. `field_info` gives each
+    /// field's offset and size in bits; an empty `ids` means inline IDs.
+    fn db2(
+        magic: &[u8; 4],
+        id_index: u16,
+        field_info: &[(u16, u16)],
+        record_size: u32,
+        records: &[u8],
+        strings: &[u8],
+        ids: &[u32],
+    ) -> Vec<u8> {
+        let n = records.len() as u32 / record_size;
+        let fields = field_info.len() as u32;
+        let mut data = magic.to_vec();
+        data.resize(4 + super::schema_size(magic), 0);
+        for v in [n, fields, record_size, strings.len() as u32, 0, 0, 0, 0, 0] {
+            data.put_u32_le(v);
+        }
+        data.put_u16_le(if ids.is_empty() { 0 } else { 4 });
+        data.put_u16_le(id_index);
+        for v in [fields, 0, 0, fields * 24, 0, 0, 1] {
+            data.put_u32_le(v);
+        }
+        data.put_u64_le(0);
+        for v in [0, n, strings.len() as u32, 0, ids.len() as u32 * 4, 0, 0, 0] {
+            data.put_u32_le(v);
+        }
+        for &(offset, size) in field_info {
+            data.put_i16_le(32 - size as i16);
+            data.put_u16_le(offset / 8);
+        }
+        for &(offset, size) in field_info {
+            data.put_u16_le(offset);
+            data.put_u16_le(size);
+            data.extend_from_slice(&[0; 20]);
+        }
+        data.extend_from_slice(records);
+        data.extend_from_slice(strings);
+        ids.iter().for_each(|id| data.put_u32_le(*id));
+        data
+    }
+
+    /// Builds a one-section db2 whose records are each a single string field.
+    fn string_db2(magic: &[u8; 4], ids: &[u32], values: &[&str]) -> Vec<u8> {
+        let n = values.len() as u32;
+        let mut strings = Vec::new();
+        let mut records = Vec::new();
+        for (k, v) in values.iter().enumerate() {
+            records.put_u32_le(strings.len() as u32 + (n - k as u32) * 4);
+            strings.extend_from_slice(v.as_bytes());
+            strings.push(0);
+        }
+        db2(magic, 0, &[(0, 32)], 4, &records, &strings, ids)
+    }
+
+    #[test]
+    fn test_sparse() {
+        let records: [&[u8]; 2] = [b"a\0b\0", b"cd\0\0"];
+        let mut data = b"WDC3".to_vec();
+        for v in [2, 2, 0, 0, 0, 0, 0, 0, 0] {
+            data.put_u32_le(v);
+        }
+        data.put_u16_le(1);
+        data.put_u16_le(0);
+        for v in [2, 0, 0, 2 * 24, 0, 0, 1] {
+            data.put_u32_le(v);
+        }
+        let file_offset = data.len() as u32 + 40 + 2 * 4 + 2 * 24;
+        let size = records.iter().map(|r| r.len() as u32).sum::<u32>();
+        data.put_u64_le(0);
+        for v in [file_offset, 2, 0, file_offset + size, 0, 0, 2, 0] {
+            data.put_u32_le(v);
+        }
+        data.extend_from_slice(&[0; 2 * 4 + 2 * 24]);
+        records.iter().for_each(|r| data.extend_from_slice(r));
+        let mut offset = file_offset;
+        for r in &records {
+            data.put_u32_le(offset);
+            data.put_u16_le(r.len() as u16);
+            offset += r.len() as u32;
+        }
+        data.put_u32_le(5);
+        data.put_u32_le(3);
+        assert_eq!(
+            super::strings(&data).unwrap(),
+            vec![
+                (5, vec!["a".to_string(), "b".to_string()]),
+                (3, vec!["cd".to_string(), "".to_string()]),
+            ]
+            .into_iter()
+            .collect::<std::collections::HashMap<_, _>>()
+        );
+    }
+
+    #[test]
+    fn test_inline_ids() {
+        // Each record is a 32-bit ID followed by a string offset.
+        let mut records = Vec::new();
+        records.put_u32_le(7);
+        records.put_u32_le(2 * 8 - 4);
+        records.put_u32_le(9);
+        records.put_u32_le(2 + 8 - 4);
+        let data = db2(
+            b"WDC5",
+            0,
+            &[(0, 32), (32, 32)],
+            8,
+            &records,
+            b"x\0yz\0",
+            &[],
+        );
+        assert_eq!(
+            super::strings(&data).unwrap(),
+            vec![(7, vec!["x".to_string()]), (9, vec!["yz".to_string()])]
+                .into_iter()
+                .collect::<std::collections::HashMap<_, _>>()
+        );
+        assert_eq!(
+            super::read_bits(&[0xab, 0xcd, 0xef], 4, 16).unwrap(),
+            0xfcda
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        for magic in [b"WDC3", b"WDC4", b"WDC5"] {
+            let data = string_db2(magic, &[10, 20], &["a", "bc"]);
+            assert_eq!(
+                super::strings(&data).unwrap(),
+                vec![(10, vec!["a".to_string()]), (20, vec!["bc".to_string()])]
+                    .into_iter()
+                    .collect::<std::collections::HashMap<_, _>>(),
+                "{:?}",
+                magic
+            );
+            let info = super::info(&data).unwrap();
+            assert_eq!(info.magic, String::from_utf8_lossy(magic));
+            assert_eq!(info.record_count, 2);
+        }
+        assert!(super::strings(&string_db2(b"WDC2", &[1], &["a"])).is_err());
+    }
+
+    #[test]
+    fn test_string_table() {
+        let mut table = super::StringTable::new(b"ab\0c\0d");
+        let first = table.get(0).unwrap();
+        assert_eq!(&*first, "ab");
+        assert!(std::sync::Arc::ptr_eq(&first, &table.get(0).unwrap()));
+        assert_eq!(&*table.get(1).unwrap(), "b");
+        assert_eq!(&*table.get(5).unwrap(), "d");
+        assert_eq!(&*table.get(100).unwrap(), "");
+        assert!(super::StringTable::new(b"\xff\0").get(0).is_err());
+    }
+
+    #[test]
+    fn test_relationships() {
+        let mut data = string_db2(b"WDC3", &[10, 20], &["a", "bc"]);
+        assert!(super::relationships(&data, &Default::default())
+            .unwrap()
+            .is_empty());
+        let mut map = Vec::new();
+        for v in [2, 100, 200, 200, 1, 100, 0] {
+            map.put_u32_le(v);
+        }
+        // The relationship map size, in the only section header.
+        data[100..104].copy_from_slice(&(map.len() as u32).to_le_bytes());
+        data.extend(map);
+        assert_eq!(
+            super::relationships(&data, &Default::default()).unwrap(),
+            vec![(20, 200), (10, 100)].into_iter().collect()
+        );
+        assert_eq!(super::strings(&data).unwrap().len(), 2);
+        data.truncate(data.len() - 4);
+        assert!(super::relationships(&data, &Default::default()).is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let data = string_db2(b"WDC3", &[10, 20], &["a", "bc"]);
+        let limits = crate::limits::Limits {
+            max_entries: 1,
+            ..Default::default()
+        };
+        assert!(super::strings_limited(&data, &limits).is_err());
+        // A record count far beyond what the file holds.
+        let mut corrupt = data.clone();
+        corrupt[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(super::strings(&corrupt).is_err());
+        // A string offset pointing before the string table.
+        let mut corrupt = data;
+        let records = corrupt.len() - 4 * 2 - 5 - 4 * 2;
+        corrupt[records..records + 4].copy_from_slice(&0u32.to_le_bytes());
+        assert!(super::strings(&corrupt).is_err());
+    }
 }
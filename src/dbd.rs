@@ -0,0 +1,125 @@
+use anyhow::{bail, Context, Result};
+
+/// One version block of a WoWDBDefs definition: the layout hashes it
+/// describes and the builds or build ranges it applies to.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Version {
+    pub layouts: Vec<u32>,
+    pub builds: Vec<String>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Definition {
+    pub versions: Vec<Version>,
+}
+
+/// Parses the version blocks of a `.dbd` file. Column definitions are not
+/// needed for layout checks and are skipped.
+pub fn parse(text: &str) -> Result<Definition> {
+    let mut versions = Vec::new();
+    let text = text.replace("\r\n", "\n");
+    let mut blocks = text.split("\n\n").map(str::trim).filter(|b| !b.is_empty());
+    match blocks.next() {
+        Some(b) if b.starts_with("COLUMNS") => {}
+        _ => bail!("dbd does not start with COLUMNS"),
+    }
+    for block in blocks {
+        let mut version = Version {
+            layouts: Vec::new(),
+            builds: Vec::new(),
+        };
+        for line in block.lines() {
+            if let Some(rest) = line.strip_prefix("LAYOUT ") {
+                for hash in rest.split(',').map(str::trim) {
+                    version.layouts.push(
+                        u32::from_str_radix(hash, 16)
+                            .with_context(|| format!("dbd layout hash {}", hash))?,
+                    );
+                }
+            } else if let Some(rest) = line.strip_prefix("BUILD ") {
+                version
+                    .builds
+                    .extend(rest.split(',').map(|b| b.trim().to_string()));
+            }
+        }
+        versions.push(version);
+    }
+    Ok(Definition { versions })
+}
+
+impl Definition {
+    /// Checks that `layout_hash` is one the definition describes, listing
+    /// the known layouts and their builds if not.
+    pub fn check_layout(&self, layout_hash: u32) -> Result<&Version> {
+        if let Some(v) = self
+            .versions
+            .iter()
+            .find(|v| v.layouts.contains(&layout_hash))
+        {
+            return Ok(v);
+        }
+        let expected = self
+            .versions
+            .iter()
+            .filter(|v| !v.layouts.is_empty())
+            .map(|v| {
+                format!(
+                    "{} (builds {})",
+                    v.layouts
+                        .iter()
+                        .map(|h| format!("{:08X}", h))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    v.builds.join(", ")
+                )
+            })
+            .collect::<Vec<_>>();
+        bail!(
+            "layout hash {:08X} is not in the definition; expected one of:\n  {}",
+            layout_hash,
+            expected.join("\n  ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    const DBD: &str = "COLUMNS\n\
+                       int ID\n\
+                       string Name_lang\n\
+                       \n\
+                       LAYOUT 0E84A21C, 1234ABCD\n\
+                       BUILD 9.0.1.35078-9.0.1.36230\n\
+                       BUILD 9.0.2.36949\n\
+                       $id$ID<32>\n\
+                       Name_lang\n\
+                       \n\
+                       BUILD 1.12.1.5875\n\
+                       Name_lang\n";
+
+    #[test]
+    fn test_parse() {
+        let def = super::parse(DBD).unwrap();
+        assert_eq!(
+            def.versions,
+            vec![
+                super::Version {
+                    layouts: vec![0x0E84_A21C, 0x1234_ABCD],
+                    builds: vec![
+                        "9.0.1.35078-9.0.1.36230".to_string(),
+                        "9.0.2.36949".to_string()
+                    ],
+                },
+                super::Version {
+                    layouts: vec![],
+                    builds: vec!["1.12.1.5875".to_string()],
+                },
+            ]
+        );
+        assert!(def.check_layout(0x1234_ABCD).is_ok());
+        let err = def.check_layout(0xDEAD_BEEF).unwrap_err().to_string();
+        assert!(err.contains("DEADBEEF"), "{}", err);
+        assert!(err.contains("0E84A21C, 1234ABCD (builds 9.0.1.35078-9.0.1.36230, 9.0.2.36949)"));
+        assert!(super::parse("LAYOUT 1\n").is_err());
+    }
+}
@@ -0,0 +1,124 @@
+use std::fmt;
+
+/// Bytes shown on either side of a parse failure.
+const CONTEXT: usize = 8;
+
+/// Where in a binary file parsing failed, with the surrounding bytes.
+#[derive(Debug)]
+pub struct Location {
+    structure: &'static str,
+    offset: usize,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+impl Location {
+    pub fn new(structure: &'static str, data: &[u8], offset: usize) -> Location {
+        let offset = offset.min(data.len());
+        Location {
+            structure,
+            offset,
+            before: data[offset.saturating_sub(CONTEXT)..offset].to_vec(),
+            after: data[offset..(offset + CONTEXT).min(data.len())].to_vec(),
+        }
+    }
+
+    /// Like new, for a window of a stream that starts at byte `start`.
+    pub fn windowed(
+        structure: &'static str,
+        window: &[u8],
+        start: usize,
+        offset: usize,
+    ) -> Location {
+        let mut location = Location::new(structure, window, offset);
+        location.offset += start;
+        location
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "parsing {} at offset {} (0x{:x}): {} | {}",
+            self.structure,
+            self.offset,
+            self.offset,
+            hex(&self.before),
+            hex(&self.after)
+        )
+    }
+}
+
+/// Follows a parser through `data`, remembering the structure it is in and
+/// how far it has got, so failures can be reported with a Location.
+pub struct Tracker<'a> {
+    data: &'a [u8],
+    structure: &'static str,
+    offset: usize,
+}
+
+impl<'a> Tracker<'a> {
+    pub fn new(data: &'a [u8], structure: &'static str) -> Tracker<'a> {
+        Tracker {
+            data,
+            structure,
+            offset: 0,
+        }
+    }
+
+    /// Notes that `structure` starts where `rest`, a suffix of the data, does.
+    pub fn at(&mut self, structure: &'static str, rest: &[u8]) {
+        self.structure = structure;
+        self.offset = self.data.len() - rest.len();
+    }
+
+    pub fn locate(&self, err: anyhow::Error) -> anyhow::Error {
+        err.context(Location::new(self.structure, self.data, self.offset))
+    }
+}
+
+/// Adds the failure location to a nom error, given what it failed on.
+pub fn nom_error(
+    structure: &'static str,
+    data: &[u8],
+    err: nom::Err<nom::error::Error<&[u8]>>,
+) -> anyhow::Error {
+    let (offset, kind) = match &err {
+        nom::Err::Incomplete(_) => (data.len(), "incomplete".to_string()),
+        nom::Err::Error(e) | nom::Err::Failure(e) => {
+            (data.len() - e.input.len(), format!("{:?}", e.code))
+        }
+    };
+    anyhow::anyhow!("{} parse error: {}", structure, kind)
+        .context(Location::new(structure, data, offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker() {
+        let data = (0..32u8).collect::<Vec<_>>();
+        let mut t = Tracker::new(&data, "header");
+        t.at("body", &data[20..]);
+        let err = t.locate(anyhow::anyhow!("bad"));
+        assert_eq!(
+            err.to_string(),
+            "parsing body at offset 20 (0x14): 0c 0d 0e 0f 10 11 12 13 | 14 15 16 17 18 19 1a 1b"
+        );
+        assert_eq!(
+            Location::new("x", &data[..2], 9).to_string(),
+            "parsing x at offset 2 (0x2): 00 01 | "
+        );
+    }
+}
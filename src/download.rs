@@ -0,0 +1,202 @@
+use std::convert::TryInto;
+
+use anyhow::{ensure, Context, Result};
+use bytes::Buf;
+
+use crate::types::EncodingKey;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub ekey: EncodingKey,
+    pub size: u64,
+    pub priority: i8,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Tag {
+    pub name: String,
+    pub kind: u16,
+    mask: Vec<u8>,
+}
+
+impl Tag {
+    /// Whether the entry at `index` carries this tag.
+    pub fn contains(&self, index: usize) -> bool {
+        self.mask
+            .get(index / 8)
+            .is_some_and(|b| b & (0x80 >> (index % 8)) != 0)
+    }
+}
+
+/// Which of `len` entries a client configured with the `wanted` tags gets,
+/// as the launcher decides: within each kind of tag (platform, arch,
+/// locale, ...) an entry needs one of the wanted tags, and kinds with no
+/// wanted tags don't narrow anything down. Tag names match ignoring case.
+pub fn select(tags: &[Tag], wanted: &[&str], len: usize) -> Result<Vec<bool>> {
+    let mut kinds = Vec::<(u16, Vec<&Tag>)>::new();
+    for name in wanted {
+        let tag = tags
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(name))
+            .with_context(|| format!("unknown tag {}", name))?;
+        match kinds.iter_mut().find(|(kind, _)| *kind == tag.kind) {
+            Some((_, group)) => group.push(tag),
+            None => kinds.push((tag.kind, vec![tag])),
+        }
+    }
+    Ok((0..len)
+        .map(|i| {
+            kinds
+                .iter()
+                .all(|(_, group)| group.iter().any(|t| t.contains(i)))
+        })
+        .collect())
+}
+
+/// Parses the tag table that follows the entries in download and install
+/// manifests.
+pub(crate) fn parse_tags(p: &mut &[u8], count: u16, entry_count: usize) -> Result<Vec<Tag>> {
+    let mask_size = entry_count.div_ceil(8);
+    let mut tags = Vec::new();
+    for _ in 0..count {
+        let end = p
+            .iter()
+            .position(|b| *b == 0)
+            .context("unterminated tag name")?;
+        let name = String::from_utf8(p[..end].to_vec()).context("tag name")?;
+        p.advance(end + 1);
+        ensure!(p.remaining() >= 2 + mask_size, "truncated tag");
+        let kind = p.get_u16();
+        let mask = p[..mask_size].to_vec();
+        p.advance(mask_size);
+        tags.push(Tag { name, kind, mask });
+    }
+    Ok(tags)
+}
+
+/// A download manifest: the files the launcher fetches ahead of install,
+/// most urgent (lowest priority) first.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Download {
+    pub entries: Vec<Entry>,
+    pub tags: Vec<Tag>,
+}
+
+impl Download {
+    pub fn tags_of(&self, index: usize) -> impl Iterator<Item = &str> {
+        self.tags
+            .iter()
+            .filter(move |t| t.contains(index))
+            .map(|t| t.name.as_str())
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<Download> {
+    let mut p = data;
+    ensure!(p.remaining() >= 11, "truncated download header");
+    ensure!(&p.get_u16().to_be_bytes() == b"DL", "not download format");
+    let version = p.get_u8();
+    ensure!((1..=3).contains(&version), "unsupported download version");
+    ensure!(p.get_u8() == 16, "unsupported ekey hash size");
+    let has_checksum = p.get_u8() != 0;
+    let entry_count: usize = p.get_u32().try_into()?;
+    let tag_count = p.get_u16();
+    let flag_bytes = if version >= 2 {
+        ensure!(p.remaining() >= 1, "truncated download header");
+        usize::from(p.get_u8())
+    } else {
+        0
+    };
+    let base_priority = if version >= 3 {
+        ensure!(p.remaining() >= 4, "truncated download header");
+        let base = p.get_i8();
+        p.advance(3);
+        base
+    } else {
+        0
+    };
+    let entry_size = 16 + 5 + 1 + if has_checksum { 4 } else { 0 } + flag_bytes;
+    ensure!(
+        p.remaining() / entry_size >= entry_count,
+        "truncated download entries"
+    );
+    let mut entries = Vec::with_capacity(entry_count);
+    for _ in 0..entry_count {
+        let ekey = EncodingKey(p.get_u128());
+        let size = (u64::from(p.get_u8()) << 32) | u64::from(p.get_u32());
+        let priority = p.get_i8().wrapping_sub(base_priority);
+        if has_checksum {
+            p.advance(4);
+        }
+        p.advance(flag_bytes);
+        entries.push(Entry {
+            ekey,
+            size,
+            priority,
+        });
+    }
+    let tags = parse_tags(&mut p, tag_count, entry_count).context("download tags")?;
+    ensure!(!p.has_remaining(), "trailing data in download manifest");
+    Ok(Download { entries, tags })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut data = b"DL\x01\x10\x00\x00\x00\x00\x02\x00\x01".to_vec();
+        data.extend_from_slice(&1u128.to_be_bytes());
+        data.extend_from_slice(b"\x00\x00\x00\x01\x00\x00");
+        data.extend_from_slice(&2u128.to_be_bytes());
+        data.extend_from_slice(b"\x01\x00\x00\x00\x00\x02");
+        data.extend_from_slice(b"Windows\x00\x00\x01\x40");
+        let download = parse(&data).unwrap();
+        assert_eq!(
+            download.entries,
+            vec![
+                Entry {
+                    ekey: EncodingKey(1),
+                    size: 256,
+                    priority: 0,
+                },
+                Entry {
+                    ekey: EncodingKey(2),
+                    size: 1 << 32,
+                    priority: 2,
+                },
+            ]
+        );
+        assert_eq!(download.tags_of(0).count(), 0);
+        assert_eq!(download.tags_of(1).collect::<Vec<_>>(), vec!["Windows"]);
+        assert!(parse(b"DL\x01").is_err());
+    }
+
+    #[test]
+    fn test_select() {
+        let tag = |name: &str, kind, mask| Tag {
+            name: name.to_string(),
+            kind,
+            mask: vec![mask],
+        };
+        let tags = [
+            tag("Windows", 1, 0b1100_0000),
+            tag("OSX", 1, 0b0010_0000),
+            tag("enUS", 3, 0b1010_0000),
+            tag("deDE", 3, 0b0100_0000),
+        ];
+        let select = |wanted: &[&str]| select(&tags, wanted, 4).unwrap();
+        assert_eq!(select(&[]), vec![true; 4]);
+        assert_eq!(select(&["windows"]), vec![true, true, false, false]);
+        assert_eq!(
+            select(&["Windows", "enUS"]),
+            vec![true, false, false, false]
+        );
+        assert_eq!(
+            select(&["Windows", "OSX", "enUS"]),
+            vec![true, false, true, false]
+        );
+        assert!(super::select(&tags, &["Linux"], 4).is_err());
+    }
+}
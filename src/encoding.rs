@@ -3,19 +3,56 @@ use std::{collections::HashMap, convert::TryInto};
 use anyhow::{ensure, Context, Result};
 use bytes::Buf;
 
+use crate::limits::Limits;
 use crate::types::{ContentKey, EncodingKey};
-use crate::util;
+use crate::{diag, hash};
+
+/// The fields of an encoding header that say how to read the rest.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    /// Bytes per content key.
+    pub ckey_size: u8,
+    /// Bytes per encoding key.
+    pub ekey_size: u8,
+}
+
+impl Header {
+    /// The only layout known.
+    const VERSION: u8 = 1;
+}
+
+/// An encoding table this parser doesn't know how to read. Keys longer than
+/// 16 bytes don't fit the key types, so are unsupported in any version.
+#[derive(Debug, Eq, PartialEq)]
+pub struct UnsupportedVersion(pub Header);
+
+impl std::fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unsupported encoding version {} with {}-byte content keys and {}-byte encoding keys",
+            self.0.version, self.0.ckey_size, self.0.ekey_size
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedVersion {}
 
 #[derive(Debug)]
-pub(crate) struct Encoding {
-    _especs: Vec<String>,
+pub struct Encoding {
+    header: Header,
+    especs: Vec<String>,
     cmap: HashMap<ContentKey, (Vec<EncodingKey>, u64)>,
-    _emap: HashMap<u128, (usize, u64)>,
+    emap: HashMap<u128, (usize, u64)>,
     _espec: String,
 }
 
 impl Encoding {
-    pub(crate) fn c2e(&self, c: ContentKey) -> Result<EncodingKey> {
+    pub fn header(&self) -> Header {
+        self.header
+    }
+    pub fn c2e(&self, c: ContentKey) -> Result<EncodingKey> {
         Ok(*self
             .cmap
             .get(&c)
@@ -24,85 +61,238 @@ impl Encoding {
             .first()
             .context(format!("missing encoding key for content key {}", c))?)
     }
+    /// All encoding keys for `c`; c2e picks the first.
+    pub fn ekeys(&self, c: ContentKey) -> Option<&[EncodingKey]> {
+        self.cmap.get(&c).map(|(ekeys, _)| ekeys.as_slice())
+    }
+    /// The encoded size of the file with encoding key `e`.
+    pub fn encoded_size(&self, e: EncodingKey) -> Option<u64> {
+        self.emap.get(&e.0).map(|(_, size)| *size)
+    }
+    /// The decoded size of the file with content key `c`.
+    pub fn content_size(&self, c: ContentKey) -> Option<u64> {
+        self.cmap.get(&c).map(|(_, size)| *size)
+    }
+    /// Every content key with its encoding keys and decoded size, in no
+    /// particular order.
+    pub fn entries(&self) -> impl Iterator<Item = (ContentKey, &[EncodingKey], u64)> + '_ {
+        self.cmap
+            .iter()
+            .map(|(c, (ekeys, size))| (*c, ekeys.as_slice(), *size))
+    }
+    /// The number of content keys in the table.
+    pub fn len(&self) -> usize {
+        self.cmap.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cmap.is_empty()
+    }
+    /// The encoding specification string used for `e`.
+    pub fn espec(&self, e: EncodingKey) -> Option<&str> {
+        let (index, _) = self.emap.get(&e.0)?;
+        self.especs.get(*index).map(String::as_str)
+    }
 }
 
-pub(crate) fn parse(data: &[u8]) -> Result<Encoding> {
+pub fn parse(data: &[u8]) -> Result<Encoding> {
+    parse_limited(data, &Limits::default())
+}
+
+/// Like parse, but with the header's page counts and sizes held to `limits`.
+pub fn parse_limited(data: &[u8], limits: &Limits) -> Result<Encoding> {
+    let mut t = diag::Tracker::new(data, "encoding header");
+    parse_tracked(data, limits, &mut t).map_err(|e| t.locate(e))
+}
+
+/// Reads a key of `size` bytes, left-aligned so that shorter keys compare
+/// as prefixes of full ones.
+fn get_key(p: &mut impl Buf, size: usize) -> u128 {
+    let mut key = [0; 16];
+    p.copy_to_slice(&mut key[..size]);
+    u128::from_be_bytes(key)
+}
+
+fn parse_tracked(data: &[u8], limits: &Limits, t: &mut diag::Tracker) -> Result<Encoding> {
     let mut p = data;
     ensure!(p.remaining() >= 16, "truncated encoding header");
     ensure!(&p.get_u16().to_be_bytes() == b"EN", "not encoding format");
-    ensure!(p.get_u8() == 1, "unsupported encoding version");
-    ensure!(p.get_u8() == 16, "unsupported ckey hash size");
-    ensure!(p.get_u8() == 16, "unsupported ekey hash size");
-    let cpagekb: usize = p.get_u16().into();
-    let epagekb: usize = p.get_u16().into();
-    let ccount: usize = p.get_u32().try_into()?;
-    let ecount: usize = p.get_u32().try_into()?;
+    let header = Header {
+        version: p.get_u8(),
+        ckey_size: p.get_u8(),
+        ekey_size: p.get_u8(),
+    };
+    if header.version != Header::VERSION
+        || !(1..=16).contains(&header.ckey_size)
+        || !(1..=16).contains(&header.ekey_size)
+    {
+        return Err(UnsupportedVersion(header).into());
+    }
+    let (csize, esize) = (usize::from(header.ckey_size), usize::from(header.ekey_size));
+    let cpagesize = limits.bytes("encoding content page", u64::from(p.get_u16()) * 1024)?;
+    let epagesize = limits.bytes("encoding encoded page", u64::from(p.get_u16()) * 1024)?;
+    let ccount = limits.entries("encoding content page index", p.get_u32().into())?;
+    let ecount = limits.entries("encoding encoded page index", p.get_u32().into())?;
     ensure!(p.get_u8() == 0, "unexpected nonzero byte in header");
-    let espec_size = p.get_u32().try_into()?;
+    let espec_size = limits.bytes("encoding espec table", p.get_u32().into())?;
+    t.at("encoding espec table", p);
     ensure!(p.remaining() >= espec_size, "truncated espec table");
     let especs = p[0..espec_size]
         .split(|b| *b == 0)
         .map(|s| String::from_utf8(s.to_vec()).context("parsing encoding espec"))
         .collect::<Result<Vec<String>>>()?;
     p.advance(espec_size);
-    ensure!(p.remaining() >= ccount * 32);
-    let mut cpages = Vec::<(ContentKey, u128)>::new();
+    t.at("encoding content page index", p);
+    ensure!(
+        p.remaining() / (csize + 16) >= ccount,
+        "truncated content page index"
+    );
+    let mut cpages = Vec::<(ContentKey, u128)>::with_capacity(ccount);
     for _ in 0..ccount {
-        cpages.push((ContentKey(p.get_u128()), p.get_u128()));
+        cpages.push((ContentKey(get_key(&mut p, csize)), p.get_u128()));
     }
     let mut cmap = HashMap::<ContentKey, (Vec<EncodingKey>, u64)>::new();
     for (first_key, hash) in cpages {
-        let pagesize = cpagekb * 1024;
-        ensure!(
-            hash == util::md5hash(&p[0..pagesize]),
-            "content page checksum"
-        );
-        let mut page = p.take(pagesize);
+        t.at("encoding content page", p);
+        ensure!(p.remaining() >= cpagesize, "truncated content page");
+        ensure!(hash == hash::md5(&p[0..cpagesize]), "content page checksum");
+        let mut page = p.take(cpagesize);
         let mut first = true;
-        while page.remaining() >= 22 && page.chunk()[0] != b'0' {
+        while page.remaining() >= 6 + csize && page.chunk()[0] != b'0' {
             let key_count = page.get_u8().into();
             let file_size = (u64::from(page.get_u8()) << 32) | u64::from(page.get_u32());
-            let ckey = ContentKey(page.get_u128());
+            let ckey = ContentKey(get_key(&mut page, csize));
             ensure!(!first || first_key == ckey, "first key mismatch in content");
             first = false;
-            ensure!(page.remaining() >= key_count * 16_usize);
+            ensure!(page.remaining() >= key_count * esize);
             let mut ekeys = Vec::<EncodingKey>::new();
             for _ in 0..key_count {
-                ekeys.push(EncodingKey(page.get_u128()));
+                ekeys.push(EncodingKey(get_key(&mut page, esize)));
             }
             cmap.insert(ckey, (ekeys, file_size));
         }
-        p.advance(pagesize)
+        p.advance(cpagesize)
     }
-    ensure!(p.remaining() >= ecount * 32);
-    let mut epages = Vec::<(u128, u128)>::new();
+    t.at("encoding encoded page index", p);
+    ensure!(
+        p.remaining() / (esize + 16) >= ecount,
+        "truncated encoded page index"
+    );
+    let mut epages = Vec::<(u128, u128)>::with_capacity(ecount);
     for _ in 0..ecount {
-        epages.push((p.get_u128(), p.get_u128()));
+        epages.push((get_key(&mut p, esize), p.get_u128()));
     }
     let mut emap = HashMap::<u128, (usize, u64)>::new();
     for (first_key, hash) in epages {
-        let pagesize = epagekb * 1024;
+        t.at("encoding encoded page", p);
+        ensure!(p.remaining() >= epagesize, "truncated encoded page");
         ensure!(
-            hash == util::md5hash(&p[0..pagesize]),
+            hash == hash::md5(&p[0..epagesize]),
             "encoding page checksum"
         );
-        let mut page = p.take(pagesize);
+        let mut page = p.take(epagesize);
         let mut first = true;
-        while page.remaining() >= 25 && page.chunk()[0] != b'0' {
-            let ekey = page.get_u128();
+        while page.remaining() >= esize + 9 && page.chunk()[0] != b'0' {
+            let ekey = get_key(&mut page, esize);
             let index = page.get_u32().try_into()?;
             let file_size = (u64::from(page.get_u8()) << 32) | u64::from(page.get_u32());
             ensure!(!first || first_key == ekey, "first key mismatch in content");
             first = false;
             emap.insert(ekey, (index, file_size));
         }
-        p.advance(pagesize)
+        p.advance(epagesize)
     }
+    t.at("encoding file espec", p);
     let espec = String::from_utf8(p.to_vec())?;
     Ok(Encoding {
-        _especs: especs,
+        header,
+        especs,
         cmap,
-        _emap: emap,
+        emap,
         _espec: espec,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use bytes::BufMut;
+
+    use super::*;
+
+    fn table(version: u8, ckey: &[u8], ekey: &[u8]) -> Vec<u8> {
+        let mut cpage = Vec::new();
+        cpage.put_u8(1);
+        cpage.put_u8(0);
+        cpage.put_u32(100);
+        cpage.put_slice(ckey);
+        cpage.put_slice(ekey);
+        cpage.resize(1024, 0);
+        let mut epage = Vec::new();
+        epage.put_slice(ekey);
+        epage.put_u32(0);
+        epage.put_u8(0);
+        epage.put_u32(50);
+        epage.resize(1024, 0);
+        let mut data = Vec::new();
+        data.put_slice(b"EN");
+        data.put_slice(&[version, ckey.len() as u8, ekey.len() as u8]);
+        data.put_u16(1);
+        data.put_u16(1);
+        data.put_u32(1);
+        data.put_u32(1);
+        data.put_u8(0);
+        data.put_u32(2);
+        data.put_slice(b"n\0");
+        data.put_slice(ckey);
+        data.put_u128(hash::md5(&cpage));
+        data.put_slice(&cpage);
+        data.put_slice(ekey);
+        data.put_u128(hash::md5(&epage));
+        data.put_slice(&epage);
+        data
+    }
+
+    #[test]
+    fn test_key_sizes() {
+        let encoding = parse(&table(1, &[0xaa; 9], &[0xbb; 12])).unwrap();
+        assert_eq!(
+            encoding.header(),
+            Header {
+                version: 1,
+                ckey_size: 9,
+                ekey_size: 12,
+            }
+        );
+        let ckey = ContentKey(0xaaaaaaaa_aaaaaaaa_aa000000_00000000);
+        let ekey = EncodingKey(0xbbbbbbbb_bbbbbbbb_bbbbbbbb_00000000);
+        assert_eq!(encoding.c2e(ckey).unwrap(), ekey);
+        assert_eq!(encoding.content_size(ckey), Some(100));
+        assert_eq!(encoding.encoded_size(ekey), Some(50));
+        assert_eq!(encoding.espec(ekey), Some("n"));
+        assert_eq!(
+            encoding.entries().collect::<Vec<_>>(),
+            [(ckey, &[ekey][..], 100)]
+        );
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let tests = [
+            ("version", table(2, &[0; 16], &[0; 16]), 2, 16, 16),
+            ("long keys", table(1, &[0; 20], &[0; 16]), 1, 20, 16),
+            ("empty keys", table(1, &[0; 16], &[]), 1, 16, 0),
+        ];
+        for (name, data, version, ckey_size, ekey_size) in tests {
+            let err = parse(&data).unwrap_err();
+            assert_eq!(
+                err.downcast_ref::<UnsupportedVersion>(),
+                Some(&UnsupportedVersion(Header {
+                    version,
+                    ckey_size,
+                    ekey_size,
+                })),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,281 @@
+use anyhow::{anyhow, Result};
+
+/// A parsed encoding specification, describing how a file is BLTE-encoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ESpec {
+    /// `n`: stored as is.
+    None,
+    /// `z`, with optional level and window bits that we don't care about.
+    Zip,
+    /// `e:{key,iv,spec}`: encrypted with the named key, wrapping another spec.
+    Encrypted { key: u64, spec: Box<ESpec> },
+    /// `b:{...}`: split into blocks, each with its own spec.
+    Blocks(Vec<Block>),
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BlockSize {
+    /// `*`: whatever is left of the file.
+    Rest,
+    /// `size`, `size*` or `size*count`; a count of None repeats to the end.
+    Fixed { size: u64, count: Option<u64> },
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Block {
+    pub size: BlockSize,
+    pub spec: ESpec,
+}
+
+mod parsers {
+    use nom::{
+        branch::alt,
+        bytes::complete::tag,
+        character::complete::{char, digit1, hex_digit1, none_of, one_of},
+        combinator::{map, map_res, opt, recognize, value},
+        multi::{many0, separated_list1},
+        sequence::{delimited, preceded, tuple},
+        IResult,
+    };
+
+    use super::{Block, BlockSize, ESpec};
+
+    fn number(s: &str) -> IResult<&str, u64> {
+        map_res(digit1, |s: &str| s.parse::<u64>())(s)
+    }
+
+    fn size(s: &str) -> IResult<&str, u64> {
+        map(tuple((number, opt(one_of("KM")))), |(n, unit)| match unit {
+            Some('K') => n << 10,
+            Some('M') => n << 20,
+            _ => n,
+        })(s)
+    }
+
+    fn block(s: &str) -> IResult<&str, Block> {
+        map(
+            tuple((
+                alt((
+                    value(BlockSize::Rest, char('*')),
+                    map(
+                        tuple((size, opt(preceded(char('*'), opt(number))))),
+                        |(size, repeat)| BlockSize::Fixed {
+                            size,
+                            count: match repeat {
+                                None => Some(1),
+                                Some(count) => count,
+                            },
+                        },
+                    ),
+                )),
+                preceded(char('='), espec),
+            )),
+            |(size, spec)| Block { size, spec },
+        )(s)
+    }
+
+    pub fn espec(s: &str) -> IResult<&str, ESpec> {
+        alt((
+            value(ESpec::None, char('n')),
+            value(
+                ESpec::Zip,
+                tuple((
+                    char('z'),
+                    opt(preceded(
+                        char(':'),
+                        alt((
+                            digit1,
+                            recognize(delimited(char('{'), many0(none_of("}")), char('}'))),
+                        )),
+                    )),
+                )),
+            ),
+            map(
+                delimited(
+                    tag("e:{"),
+                    tuple((
+                        map_res(hex_digit1, |s| u64::from_str_radix(s, 16)),
+                        char(','),
+                        hex_digit1,
+                        char(','),
+                        espec,
+                    )),
+                    char('}'),
+                ),
+                |(key, _, _, _, spec)| ESpec::Encrypted {
+                    key,
+                    spec: Box::new(spec),
+                },
+            ),
+            map(
+                preceded(
+                    tag("b:"),
+                    alt((
+                        delimited(char('{'), separated_list1(char(','), block), char('}')),
+                        map(block, |b| vec![b]),
+                    )),
+                ),
+                ESpec::Blocks,
+            ),
+        ))(s)
+    }
+}
+
+pub fn parse(s: &str) -> Result<ESpec> {
+    match parsers::espec(s) {
+        Ok(("", spec)) => Ok(spec),
+        Ok((rest, _)) => Err(anyhow!("trailing data in espec: {}", rest)),
+        Err(e) => Err(anyhow!("invalid espec {}: {}", s, e)),
+    }
+}
+
+impl ESpec {
+    /// Names of the keys needed to decrypt a file under this spec.
+    pub fn encryption_keys(&self) -> Vec<u64> {
+        match self {
+            ESpec::None | ESpec::Zip => Vec::new(),
+            ESpec::Encrypted { key, spec } => {
+                let mut keys = spec.encryption_keys();
+                keys.insert(0, *key);
+                keys
+            }
+            ESpec::Blocks(blocks) => blocks
+                .iter()
+                .flat_map(|b| b.spec.encryption_keys())
+                .collect(),
+        }
+    }
+
+    /// The decoded sizes of the BLTE chunks a file of `file_size` bytes is
+    /// split into under this spec.
+    pub fn chunk_layout(&self, file_size: u64) -> Vec<u64> {
+        let blocks = match self {
+            ESpec::Blocks(blocks) => blocks,
+            _ => return vec![file_size],
+        };
+        let mut chunks = Vec::new();
+        let mut remaining = file_size;
+        for block in blocks {
+            match block.size {
+                BlockSize::Rest => {
+                    if remaining > 0 {
+                        chunks.push(remaining);
+                        remaining = 0;
+                    }
+                }
+                BlockSize::Fixed { size, count } => {
+                    let mut count = count.unwrap_or(u64::MAX);
+                    while count > 0 && remaining > 0 && size > 0 {
+                        let chunk = size.min(remaining);
+                        chunks.push(chunk);
+                        remaining -= chunk;
+                        count -= 1;
+                    }
+                }
+            }
+        }
+        chunks
+    }
+}
+
+/// The part of a BLTE file needed to decode a prefix of its content.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Plan {
+    /// Length of the BLTE header, including its chunk table.
+    pub header_len: usize,
+    /// Total number of chunks in the file.
+    pub chunk_count: usize,
+    /// Number of leading chunks that cover the requested prefix.
+    pub needed: usize,
+}
+
+/// Plans the fetch of the first `len` decoded bytes of a file of `file_size`
+/// bytes encoded with `spec`.
+pub fn plan(spec: &ESpec, file_size: u64, len: u64) -> Plan {
+    let layout = spec.chunk_layout(file_size);
+    let mut covered = 0;
+    let needed = layout
+        .iter()
+        .take_while(|&&size| {
+            let more = covered < len;
+            covered += size;
+            more
+        })
+        .count();
+    Plan {
+        header_len: 12 + 24 * layout.len(),
+        chunk_count: layout.len(),
+        needed,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Block, BlockSize, ESpec};
+    use velcro::vec as v;
+
+    #[test]
+    fn parse() {
+        let tests = [
+            ("none", "n", ESpec::None),
+            ("zip", "z", ESpec::Zip),
+            ("zip level", "z:{9,mpq}", ESpec::Zip),
+            (
+                "encrypted",
+                "e:{0123456789ABCDEF,01234567,z}",
+                ESpec::Encrypted {
+                    key: 0x0123_4567_89AB_CDEF,
+                    spec: Box::new(ESpec::Zip),
+                },
+            ),
+            (
+                "blocks",
+                "b:{256K*=z,*=n}",
+                ESpec::Blocks(v![
+                    Block {
+                        size: BlockSize::Fixed {
+                            size: 256 << 10,
+                            count: None
+                        },
+                        spec: ESpec::Zip,
+                    },
+                    Block {
+                        size: BlockSize::Rest,
+                        spec: ESpec::None,
+                    },
+                ]),
+            ),
+        ];
+        for (name, input, output) in tests {
+            assert_eq!(super::parse(input).ok(), Some(output), "{}", name);
+        }
+    }
+
+    #[test]
+    fn encryption_keys() -> anyhow::Result<()> {
+        assert_eq!(super::parse("b:{1K=n,*=z}")?.encryption_keys(), v![]);
+        assert_eq!(
+            super::parse("b:{1K=e:{00000000000000AB,01,n},*=e:{CD,02,z}}")?.encryption_keys(),
+            v![0xAB, 0xCD]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn plan() -> anyhow::Result<()> {
+        let spec = super::parse("b:{1K=n,2K*2=z,*=z}")?;
+        assert_eq!(
+            spec.chunk_layout(10 << 10),
+            v![1 << 10, 2 << 10, 2 << 10, 5 << 10]
+        );
+        assert_eq!(
+            super::plan(&spec, 10 << 10, 1500),
+            super::Plan {
+                header_len: 12 + 24 * 4,
+                chunk_count: 4,
+                needed: 2,
+            }
+        );
+        Ok(())
+    }
+}
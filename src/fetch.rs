@@ -0,0 +1,697 @@
+//! The HTTP side of the pipeline, behind traits so tests and embedders can
+//! supply their own fetcher.
+
+use std::collections::HashMap;
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{trace, warn};
+
+use crate::cache::CacheStore;
+
+/// Receives a response body as it arrives.
+pub trait ChunkSink {
+    /// Called once before any chunks, with the body length if known.
+    fn start(&mut self, len: Option<u64>) -> Result<()>;
+    fn chunk(&mut self, data: &[u8]) -> Result<()>;
+}
+
+/// Fetches the body of a URL. This is the one thing a caller needs to
+/// implement to run the pipeline over their own transport or fixture data.
+#[async_trait]
+pub trait BytesFetcher {
+    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes>;
+
+    /// Streams the body of `url` into `sink`. The default fetches the whole
+    /// body and hands it over in one chunk.
+    async fn fetch_streamed(&self, url: String, sink: &mut (dyn ChunkSink + Send)) -> Result<()> {
+        let data = self.fetch_bytes(url, None).await?;
+        sink.start(Some(data.len() as u64))?;
+        sink.chunk(&data)
+    }
+}
+
+#[async_trait]
+impl<T: BytesFetcher + Send + Sync + ?Sized> BytesFetcher for Arc<T> {
+    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes> {
+        (**self).fetch_bytes(url, range).await
+    }
+    async fn fetch_streamed(&self, url: String, sink: &mut (dyn ChunkSink + Send)) -> Result<()> {
+        (**self).fetch_streamed(url, sink).await
+    }
+}
+
+/// A host answered 429 or 503, perhaps saying when to come back.
+#[derive(Debug)]
+pub struct Throttled {
+    pub status: u16,
+    pub retry_after: Option<Duration>,
+}
+
+impl std::fmt::Display for Throttled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "throttled with status {}", self.status)?;
+        if let Some(after) = self.retry_after {
+            write!(f, ", retry after {}s", after.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Throttled {}
+
+/// Parses a Retry-After value. Only the delta-seconds form is understood;
+/// HTTP dates get the default bench time.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// How long a throttled host is left alone when it doesn't say.
+const DEFAULT_BENCH: Duration = Duration::from_secs(30);
+
+/// Longest wait for a benched host, whatever it asks for.
+const MAX_BENCH: Duration = Duration::from_secs(300);
+
+/// Failures in a row after which a host is benched.
+pub const DEFAULT_TRIP_AFTER: usize = 3;
+
+/// How long a host that keeps failing is benched.
+pub const DEFAULT_COOL_DOWN: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct HostState {
+    /// When the host may be tried again, if it's benched.
+    until: Option<Instant>,
+    /// Benched for failing rather than for throttling us, so not worth
+    /// waiting on.
+    tripped: bool,
+    /// Failures since the host last served a fetch.
+    failures: usize,
+    last_error: Option<String>,
+}
+
+/// A circuit breaker per CDN host: hosts that throttle us, or that fail
+/// `trip_after` fetches in a row, are skipped until their bench time is up.
+pub struct HostBench {
+    hosts: Mutex<HashMap<String, HostState>>,
+    trip_after: usize,
+    cool_down: Duration,
+}
+
+impl Default for HostBench {
+    fn default() -> HostBench {
+        HostBench::new(DEFAULT_TRIP_AFTER, DEFAULT_COOL_DOWN)
+    }
+}
+
+impl HostBench {
+    pub fn new(trip_after: usize, cool_down: Duration) -> HostBench {
+        HostBench {
+            hosts: Mutex::default(),
+            trip_after: trip_after.max(1),
+            cool_down,
+        }
+    }
+
+    /// How many times one fetch tries a host before giving up on it.
+    pub fn trip_after(&self) -> usize {
+        self.trip_after
+    }
+
+    /// Counts a failure of `host`, benching it for as long as it asks if
+    /// `err` is a Throttled response, or for the cool-down if it has now
+    /// failed `trip_after` times in a row.
+    pub fn failed(&self, host: &str, err: &anyhow::Error) {
+        let mut hosts = self.hosts.lock().expect("bench lock");
+        let state = hosts.entry(host.to_string()).or_default();
+        state.failures += 1;
+        state.last_error = Some(format!("{:#}", err));
+        if let Some(t) = err.downcast_ref::<Throttled>() {
+            let time = t.retry_after.unwrap_or(DEFAULT_BENCH).min(MAX_BENCH);
+            warn!("benching {} for {}s: {}", host, time.as_secs(), t);
+            state.until = Some(Instant::now() + time);
+            state.tripped = false;
+        } else if state.failures % self.trip_after == 0 {
+            warn!(
+                "benching {} for {}s after {} failures in a row: {:#}",
+                host,
+                self.cool_down.as_secs(),
+                state.failures,
+                err
+            );
+            state.until = Some(Instant::now() + self.cool_down);
+            state.tripped = true;
+        }
+    }
+
+    /// Closes `host`'s breaker again once it serves a fetch.
+    pub fn succeeded(&self, host: &str) {
+        self.hosts.lock().expect("bench lock").remove(host);
+    }
+
+    pub fn is_benched(&self, host: &str) -> bool {
+        self.hosts
+            .lock()
+            .expect("bench lock")
+            .get(host)
+            .and_then(|state| state.until)
+            .is_some_and(|until| until > Instant::now())
+    }
+
+    /// How long until the first of `hosts` comes back, when all are benched
+    /// and only for throttling us. Hosts benched for failing aren't waited
+    /// on: the fetch fails instead.
+    pub fn wait(&self, hosts: &[String]) -> Option<Duration> {
+        let bench = self.hosts.lock().expect("bench lock");
+        let now = Instant::now();
+        let mut waits = Vec::new();
+        for host in hosts {
+            let state = bench.get(host)?;
+            match state
+                .until
+                .and_then(|until| until.checked_duration_since(now))
+            {
+                Some(wait) if !wait.is_zero() && !state.tripped => waits.push(wait),
+                _ => return None,
+            }
+        }
+        waits.into_iter().min()
+    }
+
+    /// How each of `hosts` has been failing, for the error when none of
+    /// them will serve a file.
+    pub fn describe(&self, hosts: &[String]) -> String {
+        let bench = self.hosts.lock().expect("bench lock");
+        let now = Instant::now();
+        hosts
+            .iter()
+            .map(|host| match bench.get(host) {
+                Some(state) if state.failures > 0 => {
+                    let benched = state
+                        .until
+                        .and_then(|until| until.checked_duration_since(now))
+                        .map(|wait| format!(", benched for {}s", wait.as_secs()))
+                        .unwrap_or_default();
+                    format!(
+                        "{} failed {} times in a row{}, last with {}",
+                        host,
+                        state.failures,
+                        benched,
+                        state.last_error.as_deref().unwrap_or_default()
+                    )
+                }
+                _ => format!("{} has not failed", host),
+            })
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// A run-wide allowance of failed fetch attempts, shared by every fetch so a
+/// badly degraded CDN ends the run rather than each file retrying at length.
+#[derive(Default)]
+pub struct RetryBudget {
+    failures: AtomicUsize,
+    first_failure: Mutex<Option<Instant>>,
+    /// Failed attempts allowed in all; zero fails on the first.
+    max_failures: Option<usize>,
+    /// How long after the first failure retries may go on.
+    max_time: Option<Duration>,
+}
+
+impl RetryBudget {
+    pub fn new(max_failures: Option<usize>, max_time: Option<Duration>) -> RetryBudget {
+        RetryBudget {
+            max_failures,
+            max_time,
+            ..RetryBudget::default()
+        }
+    }
+
+    /// Counts a failed attempt, returning `err` if it overdraws the budget.
+    pub fn spend(&self, err: anyhow::Error) -> Result<()> {
+        let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(max) = self.max_failures.filter(|max| failures > *max) {
+            return Err(err.context(format!("retry budget of {} failed attempts exhausted", max)));
+        }
+        if let Some(max_time) = self.max_time {
+            let first = *self
+                .first_failure
+                .lock()
+                .expect("retry budget lock")
+                .get_or_insert_with(Instant::now);
+            if first.elapsed() > max_time {
+                return Err(
+                    err.context(format!("retry budget of {}s exhausted", max_time.as_secs()))
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Failed attempts so far.
+    pub fn failures(&self) -> usize {
+        self.failures.load(Ordering::Relaxed)
+    }
+}
+
+async fn send(
+    client: &reqwest::Client,
+    url: &str,
+    range: Option<(usize, usize)>,
+) -> Result<reqwest::Response> {
+    let mut req = client.get(url);
+    if let Some((start, end)) = range {
+        req = req.header("Range", format!("bytes={}-{}", start, end));
+    }
+    trace!("starting fetch of {}", url);
+    let response = req
+        .send()
+        .await
+        .context(format!("sending request to {}", url))?;
+    let status = response.status();
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        || status == reqwest::StatusCode::SERVICE_UNAVAILABLE
+    {
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_retry_after);
+        return Err(anyhow::Error::new(Throttled {
+            status: status.as_u16(),
+            retry_after,
+        })
+        .context(format!("http error on {}", url)));
+    }
+    ensure!(status.is_success(), format!("http error on {}", url));
+    trace!("receiving content on {}", url);
+    Ok(response)
+}
+
+#[async_trait]
+impl BytesFetcher for reqwest::Client {
+    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes> {
+        let data = send(self, &url, range)
+            .await?
+            .bytes()
+            .await
+            .context(format!("receiving content on {}", url))?;
+        trace!("done retrieving {}", url);
+        Ok(data)
+    }
+    async fn fetch_streamed(&self, url: String, sink: &mut (dyn ChunkSink + Send)) -> Result<()> {
+        let mut response = send(self, &url, None).await?;
+        sink.start(response.content_length())?;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .context(format!("receiving content on {}", url))?
+        {
+            sink.chunk(&chunk)?;
+        }
+        trace!("done retrieving {}", url);
+        Ok(())
+    }
+}
+
+#[async_trait]
+pub trait TextFetcher {
+    async fn fetch_text(&self, url: String) -> Result<String>;
+}
+
+#[async_trait]
+impl<T: BytesFetcher + Sync> TextFetcher for T {
+    async fn fetch_text(&self, url: String) -> Result<String> {
+        Ok(from_utf8(&self.fetch_bytes(url, None).await?)?.to_string())
+    }
+}
+
+pub trait HasCdnPrefixes {
+    fn cdn_prefixes(&self) -> &Vec<String>;
+
+    /// Hosts to skip for now, shared across fetches. Without one, throttled
+    /// hosts are only skipped for the rest of the fetch.
+    fn host_bench(&self) -> Option<&HostBench> {
+        None
+    }
+
+    /// Where whole files fetched by hash are kept between runs, if anywhere.
+    fn cache(&self) -> Option<&(dyn CacheStore + Send + Sync)> {
+        None
+    }
+
+    /// Called when a fetch is served from the cache instead of a host.
+    fn cache_hit(&self) {}
+
+    /// Called when a host fails a fetch that will be retried elsewhere; an
+    /// error stops the retries.
+    fn fetch_failed(&self, err: anyhow::Error) -> Result<()> {
+        warn!("fetch failed: {:#}", err);
+        Ok(())
+    }
+}
+
+pub fn cdn_path(tag: &str, hash: u128, suffix: Option<&str>) -> String {
+    let h = format!("{:032x}", hash);
+    format!(
+        "{}/{}/{}/{}{}",
+        tag,
+        &h[0..2],
+        &h[2..4],
+        h,
+        suffix.unwrap_or("")
+    )
+}
+
+/// The URL of a file on the mirror at `prefix`. Normally the prefix is
+/// followed by the usual `tag/ab/cd/hash` layout; a prefix naming `{hash}`
+/// is instead a template for mirrors laid out differently, in which
+/// `{tag}`, `{ab}`, `{cd}`, `{hash}` and `{suffix}` are filled in, e.g.
+/// `http://mirror/blobs/{hash}{suffix}` for a flat one.
+pub fn cdn_url(prefix: &str, tag: &str, hash: u128, suffix: Option<&str>) -> String {
+    if !prefix.contains("{hash}") {
+        return format!("{}/{}", prefix, cdn_path(tag, hash, suffix));
+    }
+    let h = format!("{:032x}", hash);
+    prefix
+        .replace("{tag}", tag)
+        .replace("{ab}", &h[0..2])
+        .replace("{cd}", &h[2..4])
+        .replace("{hash}", &h)
+        .replace("{suffix}", suffix.unwrap_or(""))
+}
+
+#[async_trait]
+pub trait CdnBytesFetcher {
+    async fn fetch_cdn_bytes(
+        &self,
+        tag: &str,
+        hash: u128,
+        suffix: Option<&str>,
+        range: Option<(usize, usize)>,
+    ) -> Result<Bytes>;
+}
+
+#[async_trait]
+impl<T: BytesFetcher + HasCdnPrefixes + Sync> CdnBytesFetcher for T {
+    async fn fetch_cdn_bytes(
+        &self,
+        tag: &str,
+        hash: u128,
+        suffix: Option<&str>,
+        range: Option<(usize, usize)>,
+    ) -> Result<Bytes> {
+        let path = cdn_path(tag, hash, suffix);
+        trace!("cdn fetch {}", path);
+        // Ranges are left uncached; they're only taken out of archives,
+        // which are too big to keep whole.
+        let cache = self.cache().filter(|_| range.is_none());
+        if let Some(cache) = cache {
+            match cache.get(&path).await {
+                Ok(Some(data)) => {
+                    self.cache_hit();
+                    return Ok(Bytes::from(data));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("cache read failed: {:#}", e),
+            }
+        }
+        let local = HostBench::default();
+        let bench = self.host_bench().unwrap_or(&local);
+        let mut attempts = vec![0; self.cdn_prefixes().len()];
+        loop {
+            let mut tried = false;
+            for (cdn_prefix, attempts) in self.cdn_prefixes().iter().zip(&mut attempts) {
+                if *attempts >= bench.trip_after() || bench.is_benched(cdn_prefix) {
+                    continue;
+                }
+                *attempts += 1;
+                tried = true;
+                let url = cdn_url(cdn_prefix, tag, hash, suffix);
+                match self.fetch_bytes(url, range).await {
+                    Ok(data) => {
+                        bench.succeeded(cdn_prefix);
+                        if let Some(cache) = cache {
+                            if let Err(e) = cache.put(&path, &data).await {
+                                warn!("cache write failed: {:#}", e);
+                            }
+                        }
+                        return Ok(data);
+                    }
+                    Err(e) => {
+                        bench.failed(cdn_prefix, &e);
+                        self.fetch_failed(e)?
+                    }
+                }
+            }
+            match bench.wait(self.cdn_prefixes()) {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None if tried => {}
+                None => break,
+            }
+        }
+        bail!(
+            "fetch failed on all hosts: {} ({})",
+            path,
+            bench.describe(self.cdn_prefixes())
+        )
+    }
+}
+
+/// Serves canned bodies by URL.
+#[cfg(test)]
+pub(crate) struct Fixture(pub(crate) HashMap<String, Bytes>);
+
+#[cfg(test)]
+#[async_trait]
+impl BytesFetcher for Fixture {
+    async fn fetch_bytes(&self, url: String, _: Option<(usize, usize)>) -> Result<Bytes> {
+        self.0.get(&url).cloned().context("no fixture")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::MemoryCache;
+
+    struct Collect(Option<u64>, Vec<u8>);
+
+    impl ChunkSink for Collect {
+        fn start(&mut self, len: Option<u64>) -> Result<()> {
+            self.0 = len;
+            Ok(())
+        }
+        fn chunk(&mut self, data: &[u8]) -> Result<()> {
+            self.1.extend_from_slice(data);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fixture() {
+        let fetcher: Arc<dyn BytesFetcher + Send + Sync> = Arc::new(Fixture(
+            vec![("http://x/a".to_string(), Bytes::from_static(b"hello"))]
+                .into_iter()
+                .collect(),
+        ));
+        assert_eq!(
+            fetcher.fetch_text("http://x/a".to_string()).await.unwrap(),
+            "hello"
+        );
+        let mut sink = Collect(None, Vec::new());
+        fetcher
+            .fetch_streamed("http://x/a".to_string(), &mut sink)
+            .await
+            .unwrap();
+        assert_eq!((sink.0, sink.1.as_slice()), (Some(5), &b"hello"[..]));
+        assert!(fetcher.fetch_text("http://x/b".to_string()).await.is_err());
+    }
+
+    #[test]
+    fn test_cdn_url() {
+        let hash = 0xabcd0000000000000000000000000001;
+        assert_eq!(
+            cdn_url("http://a/tpr/wow", "data", hash, Some(".index")),
+            "http://a/tpr/wow/data/ab/cd/abcd0000000000000000000000000001.index"
+        );
+        assert_eq!(
+            cdn_url("http://m/blobs/{hash}{suffix}", "config", hash, None),
+            "http://m/blobs/abcd0000000000000000000000000001"
+        );
+        assert_eq!(
+            cdn_url("http://m/{tag}/{ab}{cd}/{hash}", "data", hash, None),
+            "http://m/data/abcd/abcd0000000000000000000000000001"
+        );
+    }
+
+    #[test]
+    fn test_retry_budget() {
+        let budget = RetryBudget::new(Some(2), None);
+        assert!(budget.spend(anyhow::anyhow!("a")).is_ok());
+        assert!(budget.spend(anyhow::anyhow!("b")).is_ok());
+        let err = budget.spend(anyhow::anyhow!("c")).unwrap_err();
+        assert!(format!("{:#}", err).contains("budget of 2"), "{:#}", err);
+        assert_eq!(budget.failures(), 3);
+        assert!(RetryBudget::new(Some(0), None)
+            .spend(anyhow::anyhow!("a"))
+            .is_err());
+        let timed = RetryBudget::new(None, Some(Duration::ZERO));
+        assert!(timed.spend(anyhow::anyhow!("a")).is_ok());
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(timed.spend(anyhow::anyhow!("b")).is_err());
+        assert!(RetryBudget::default().spend(anyhow::anyhow!("a")).is_ok());
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        assert_eq!(parse_retry_after(" 120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+
+    /// Throttles every request to host a.
+    struct Throttling(Vec<String>, HostBench);
+
+    #[async_trait]
+    impl BytesFetcher for Throttling {
+        async fn fetch_bytes(&self, url: String, _: Option<(usize, usize)>) -> Result<Bytes> {
+            if url.starts_with("http://a/") {
+                return Err(Throttled {
+                    status: 429,
+                    retry_after: Some(Duration::from_secs(60)),
+                }
+                .into());
+            }
+            Ok(Bytes::from_static(b"ok"))
+        }
+    }
+
+    impl HasCdnPrefixes for Throttling {
+        fn cdn_prefixes(&self) -> &Vec<String> {
+            &self.0
+        }
+        fn host_bench(&self) -> Option<&HostBench> {
+            Some(&self.1)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_throttled() {
+        let cdn = Throttling(
+            vec!["http://a".to_string(), "http://b".to_string()],
+            HostBench::default(),
+        );
+        let data = cdn.fetch_cdn_bytes("data", 1, None, None).await.unwrap();
+        assert_eq!(&data[..], b"ok");
+        assert!(cdn.1.is_benched("http://a"));
+        assert!(!cdn.1.is_benched("http://b"));
+        assert!(cdn.1.wait(&cdn.0).is_none());
+        let wait = cdn.1.wait(&cdn.0[..1]).unwrap();
+        assert!(wait > Duration::from_secs(50) && wait <= Duration::from_secs(60));
+    }
+
+    /// Fails every request to host a, and serves b only while it's up.
+    struct Failing(Vec<String>, HostBench, std::sync::atomic::AtomicBool);
+
+    #[async_trait]
+    impl BytesFetcher for Failing {
+        async fn fetch_bytes(&self, url: String, _: Option<(usize, usize)>) -> Result<Bytes> {
+            ensure!(!url.starts_with("http://a/"), "connection refused");
+            ensure!(self.2.load(std::sync::atomic::Ordering::Relaxed), "reset");
+            Ok(Bytes::from_static(b"ok"))
+        }
+    }
+
+    impl HasCdnPrefixes for Failing {
+        fn cdn_prefixes(&self) -> &Vec<String> {
+            &self.0
+        }
+        fn host_bench(&self) -> Option<&HostBench> {
+            Some(&self.1)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_circuit_breaker() {
+        let cdn = Failing(
+            vec!["http://a".to_string(), "http://b".to_string()],
+            HostBench::new(2, Duration::from_secs(60)),
+            true.into(),
+        );
+        cdn.fetch_cdn_bytes("data", 1, None, None).await.unwrap();
+        assert!(!cdn.1.is_benched("http://a"));
+        cdn.fetch_cdn_bytes("data", 1, None, None).await.unwrap();
+        assert!(cdn.1.is_benched("http://a"));
+        cdn.2.store(false, std::sync::atomic::Ordering::Relaxed);
+        let err = cdn
+            .fetch_cdn_bytes("data", 1, None, None)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(cdn.1.is_benched("http://b"));
+        assert!(
+            err.contains("http://a failed 2 times in a row, benched for")
+                && err.contains("http://b failed 2 times in a row")
+                && err.contains("last with reset"),
+            "{}",
+            err
+        );
+        // Both hosts are down, so the next fetch fails without trying them.
+        cdn.2.store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(cdn.fetch_cdn_bytes("data", 1, None, None).await.is_err());
+        cdn.1.succeeded("http://b");
+        cdn.fetch_cdn_bytes("data", 1, None, None).await.unwrap();
+    }
+
+    /// Serves one file until it's switched off.
+    struct Flaky(Vec<String>, std::sync::atomic::AtomicBool, MemoryCache);
+
+    #[async_trait]
+    impl BytesFetcher for Flaky {
+        async fn fetch_bytes(&self, _: String, _: Option<(usize, usize)>) -> Result<Bytes> {
+            ensure!(self.1.load(std::sync::atomic::Ordering::Relaxed), "down");
+            Ok(Bytes::from_static(b"ok"))
+        }
+    }
+
+    impl HasCdnPrefixes for Flaky {
+        fn cdn_prefixes(&self) -> &Vec<String> {
+            &self.0
+        }
+        fn cache(&self) -> Option<&(dyn CacheStore + Send + Sync)> {
+            Some(&self.2)
+        }
+        fn fetch_failed(&self, err: anyhow::Error) -> Result<()> {
+            Err(err)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cached() {
+        let cdn = Flaky(
+            vec!["http://a".to_string()],
+            true.into(),
+            MemoryCache::default(),
+        );
+        assert_eq!(
+            &cdn.fetch_cdn_bytes("data", 1, None, None).await.unwrap()[..],
+            b"ok"
+        );
+        cdn.1.store(false, std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(
+            &cdn.fetch_cdn_bytes("data", 1, None, None).await.unwrap()[..],
+            b"ok"
+        );
+        assert!(cdn.fetch_cdn_bytes("data", 2, None, None).await.is_err());
+        assert!(cdn
+            .fetch_cdn_bytes("data", 1, None, Some((0, 1)))
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,198 @@
+//! Hash functions used across TACT and CASC formats.
+
+/// MD5 as a big-endian u128, the form content and encoding keys take. With
+/// the `asm` feature this uses an assembly implementation on x86 and x86_64,
+/// which matters when verifying gigabytes of content.
+pub fn md5(p: &[u8]) -> u128 {
+    let mut h = Md5Hasher::new();
+    h.update(p);
+    h.finish()
+}
+
+/// Incremental counterpart of md5, for data that arrives in pieces.
+#[derive(Clone, Default)]
+pub struct Md5Hasher(md5::Md5);
+
+impl Md5Hasher {
+    pub fn new() -> Md5Hasher {
+        Md5Hasher::default()
+    }
+    pub fn update(&mut self, data: &[u8]) {
+        md5::Digest::update(&mut self.0, data)
+    }
+    pub fn finish(self) -> u128 {
+        u128::from_be_bytes(md5::Digest::finalize(self.0).into())
+    }
+}
+
+impl std::io::Write for Md5Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+pub fn sha256(p: &[u8]) -> [u8; 32] {
+    let mut h = Sha256Hasher::new();
+    h.update(p);
+    h.finish()
+}
+
+/// Incremental SHA-256, as used by Ribbit response trailers.
+#[derive(Clone, Default)]
+pub struct Sha256Hasher(sha2::Sha256);
+
+impl Sha256Hasher {
+    pub fn new() -> Sha256Hasher {
+        Sha256Hasher::default()
+    }
+    pub fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data)
+    }
+    pub fn finish(self) -> [u8; 32] {
+        sha2::Digest::finalize(self.0).into()
+    }
+}
+
+impl std::io::Write for Sha256Hasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Jenkins lookup3 (hashlittle2 with zero seeds), with the primary hash in
+/// the high word as CASC stores it.
+pub fn lookup3(data: &[u8]) -> u64 {
+    let hash: u64 = hashers::jenkins::lookup3(data);
+    hash.rotate_left(32)
+}
+
+/// Hashes a file path the way root's name hash table does: lookup3 of the
+/// uppercased path.
+pub fn name_hash(name: &str) -> u64 {
+    lookup3(name.to_uppercase().as_bytes())
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut c = i as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 != 0 {
+                0xedb8_8320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[i] = c;
+        i += 1;
+    }
+    table
+}
+
+/// CRC-32 (IEEE), as used by zlib.
+pub fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |c, &b| {
+        CRC32_TABLE[usize::from((c as u8) ^ b)] ^ (c >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_md5() {
+        // RFC 1321's test suite.
+        let tests = [
+            ("", 0xd41d8cd98f00b204e9800998ecf8427e),
+            ("a", 0x0cc175b9c0f1b6a831c399e269772661),
+            ("abc", 0x900150983cd24fb0d6963f7d28e17f72),
+            ("message digest", 0xf96b697d7cb7938d525a2f31aaf161d0),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                0xc3fcd3d76192e4007dfb496cca67e13b,
+            ),
+            (
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                0xd174ab98d277d9f5a5611c2c9f419d9f,
+            ),
+            (
+                "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                0x57edf4a22be3c955ac49da2e2107b67a,
+            ),
+        ];
+        for (input, want) in tests {
+            assert_eq!(super::md5(input.as_bytes()), want, "{}", input);
+            // Pieces that straddle the 64-byte blocks.
+            let mut h = super::Md5Hasher::new();
+            for piece in input.as_bytes().chunks(7) {
+                h.update(piece);
+            }
+            assert_eq!(h.finish(), want, "{}", input);
+            let mut h = super::Md5Hasher::new();
+            std::io::copy(&mut input.as_bytes(), &mut h).unwrap();
+            assert_eq!(h.finish(), want, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_sha256() {
+        // FIPS 180-2's examples, and the empty message.
+        let tests = [
+            (
+                "",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                "abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                "abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        let hex = |h: [u8; 32]| h.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        for (input, want) in tests {
+            assert_eq!(hex(super::sha256(input.as_bytes())), want, "{}", input);
+            let mut h = super::Sha256Hasher::new();
+            for piece in input.as_bytes().chunks(7) {
+                h.update(piece);
+            }
+            assert_eq!(hex(h.finish()), want, "{}", input);
+            let mut h = super::Sha256Hasher::new();
+            std::io::copy(&mut input.as_bytes(), &mut h).unwrap();
+            assert_eq!(hex(h.finish()), want, "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_lookup3() {
+        assert_eq!(
+            super::lookup3(b"Four score and seven years ago"),
+            0x1777_0551_ce72_26e6
+        );
+        assert_eq!(super::lookup3(b"a"), 0x58d6_8708_5826_47ac);
+        assert_eq!(
+            super::name_hash("Interface\\FrameXML\\UIParent.lua"),
+            0x0d87_602a_3b41_43c9
+        );
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(super::crc32(b""), 0);
+        assert_eq!(super::crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -0,0 +1,226 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::ribbit::{Versions, VersionsEntry};
+
+/// The newest build across all regions of a versions response.
+pub(crate) fn newest_build(versions: &Versions) -> Option<&VersionsEntry> {
+    versions.entries.values().max_by_key(|e| e.build_id)
+}
+
+/// Regions split by whether they serve the newest build, when they disagree.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct RegionSkew<'a> {
+    pub(crate) ahead: Vec<&'a VersionsEntry>,
+    pub(crate) behind: Vec<&'a VersionsEntry>,
+}
+
+/// Compares build id and build config across regions; None when they agree.
+pub(crate) fn region_skew(versions: &Versions) -> Option<RegionSkew<'_>> {
+    let newest = newest_build(versions)?;
+    let key = |e: &VersionsEntry| (e.build_id, e.build_config);
+    let (mut ahead, mut behind): (Vec<_>, Vec<_>) = versions
+        .entries
+        .values()
+        .partition(|e| key(*e) == key(newest));
+    if behind.is_empty() {
+        return None;
+    }
+    ahead.sort_by(|a, b| a.region.cmp(&b.region));
+    behind.sort_by(|a, b| a.region.cmp(&b.region));
+    Some(RegionSkew { ahead, behind })
+}
+
+/// Remembers the last seqns and builds seen: the summary's in the state dir,
+/// and one small file per product under `products`, so no product name can
+/// collide with the summary's.
+pub(crate) struct BuildState {
+    dir: PathBuf,
+}
+
+impl BuildState {
+    pub(crate) fn new(dir: &Path) -> Result<BuildState> {
+        let products = dir.join("products");
+        std::fs::create_dir_all(&products)
+            .with_context(|| format!("creating state dir {}", products.display()))?;
+        Ok(BuildState {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    fn read_u32(&self, name: &str) -> Option<u32> {
+        std::fs::read_to_string(self.dir.join(name))
+            .ok()?
+            .split_whitespace()
+            .next()?
+            .parse()
+            .ok()
+    }
+
+    fn write(&self, name: &str, contents: String) -> Result<()> {
+        crate::shutdown::write_file(&self.dir.join(name), contents.as_bytes())
+    }
+
+    /// The last versions seqn processed for `product`, or for the summary
+    /// itself when `product` is None.
+    pub(crate) fn seqn(&self, product: Option<&str>) -> Option<u32> {
+        self.read_u32(&Self::seqn_name(product))
+    }
+
+    pub(crate) fn set_seqn(&self, product: Option<&str>, seqn: u32) -> Result<()> {
+        self.write(&Self::seqn_name(product), format!("{}\n", seqn))
+    }
+
+    fn seqn_name(product: Option<&str>) -> String {
+        match product {
+            Some(product) => format!("products/{}.seqn", product),
+            None => "summary.seqn".to_string(),
+        }
+    }
+
+    pub(crate) fn last_build(&self, product: &str) -> Option<u32> {
+        self.read_u32(&format!("products/{}.build", product))
+    }
+
+    /// Records `entry` as the latest build of `product`, returning whether it
+    /// differs from a previously recorded one. The first build ever recorded
+    /// for a product does not count as new.
+    pub(crate) fn update(&self, product: &str, entry: &VersionsEntry) -> Result<bool> {
+        let last = self.last_build(product);
+        if last == Some(entry.build_id) {
+            return Ok(false);
+        }
+        self.write(
+            &format!("products/{}.build", product),
+            format!("{} {}\n", entry.build_id, entry.name),
+        )?;
+        Ok(last.is_some())
+    }
+}
+
+/// Runs `command` through the shell for a newly seen build, passing its details
+/// in RUSTYCASC_PRODUCT, RUSTYCASC_BUILD_ID, RUSTYCASC_VERSION and
+/// RUSTYCASC_REGION.
+pub(crate) fn run_exec(command: &str, product: &str, entry: &VersionsEntry) -> Result<()> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = std::process::Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = std::process::Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    let status = cmd
+        .arg(command)
+        .env("RUSTYCASC_PRODUCT", product)
+        .env("RUSTYCASC_BUILD_ID", entry.build_id.to_string())
+        .env("RUSTYCASC_VERSION", &entry.name)
+        .env("RUSTYCASC_REGION", &entry.region)
+        .status()
+        .with_context(|| format!("running {}", command))?;
+    if !status.success() {
+        log::warn!("exec hook for {} exited with {}", product, status);
+    }
+    Ok(())
+}
+
+/// POSTs a JSON description of a newly seen build to `url`.
+pub(crate) async fn post_webhook(
+    client: &reqwest::Client,
+    url: &str,
+    product: &str,
+    entry: &VersionsEntry,
+) -> Result<()> {
+    let body = serde_json::json!({
+        "product": product,
+        "build_id": entry.build_id,
+        "version": entry.name,
+        "region": entry.region,
+    });
+    let response = client
+        .post(url)
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .with_context(|| format!("posting webhook to {}", url))?;
+    anyhow::ensure!(
+        response.status().is_success(),
+        "webhook {} returned {}",
+        url,
+        response.status()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn entry(region: &str, build_id: u32, build_config: u128) -> VersionsEntry {
+        VersionsEntry {
+            region: region.to_string(),
+            build_config,
+            cdn_config: 0,
+            key_config: None,
+            build_id,
+            name: format!("1.0.{}", build_id),
+            product_config: None,
+        }
+    }
+
+    fn versions(entries: Vec<VersionsEntry>) -> Versions {
+        Versions {
+            seqn: 1,
+            entries: entries
+                .into_iter()
+                .map(|e| (e.region.clone(), e))
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn test_build_state() {
+        let dir = std::env::temp_dir().join(format!("rustycasc-state-{}", std::process::id()));
+        let state = BuildState::new(&dir).unwrap();
+        state.set_seqn(None, 1).unwrap();
+        state.set_seqn(Some("summary"), 2).unwrap();
+        assert_eq!(state.seqn(None), Some(1));
+        assert_eq!(state.seqn(Some("summary")), Some(2));
+        assert_eq!(state.seqn(Some("wow")), None);
+        assert!(!state.update("wow", &entry("us", 5, 1)).unwrap());
+        assert!(!state.update("wow", &entry("us", 5, 1)).unwrap());
+        assert!(state.update("wow", &entry("us", 6, 1)).unwrap());
+        assert_eq!(state.last_build("wow"), Some(6));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_region_skew() {
+        let agree = versions(vec![entry("us", 2, 7), entry("eu", 2, 7)]);
+        assert_eq!(region_skew(&agree), None);
+        let staggered = versions(vec![
+            entry("us", 2, 7),
+            entry("eu", 2, 7),
+            entry("kr", 1, 6),
+        ]);
+        let skew = region_skew(&staggered).unwrap();
+        assert_eq!(
+            skew.ahead
+                .iter()
+                .map(|e| e.region.as_str())
+                .collect::<Vec<_>>(),
+            vec!["eu", "us"]
+        );
+        assert_eq!(
+            skew.behind
+                .iter()
+                .map(|e| e.region.as_str())
+                .collect::<Vec<_>>(),
+            vec!["kr"]
+        );
+    }
+}
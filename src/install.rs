@@ -0,0 +1,87 @@
+use std::convert::TryInto;
+
+use anyhow::{ensure, Context, Result};
+use bytes::Buf;
+
+use crate::download::{parse_tags, Tag};
+use crate::types::ContentKey;
+
+#[derive(Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub ckey: ContentKey,
+    pub size: u32,
+}
+
+/// An install manifest: the files the launcher places outside CASC storage,
+/// such as executables. Its tags work as in download manifests.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Install {
+    pub entries: Vec<Entry>,
+    pub tags: Vec<Tag>,
+}
+
+pub fn parse(data: &[u8]) -> Result<Install> {
+    let mut p = data;
+    ensure!(p.remaining() >= 10, "truncated install header");
+    ensure!(&p.get_u16().to_be_bytes() == b"IN", "not install format");
+    ensure!(p.get_u8() == 1, "unsupported install version");
+    ensure!(p.get_u8() == 16, "unsupported ckey hash size");
+    let tag_count = p.get_u16();
+    let entry_count: usize = p.get_u32().try_into()?;
+    let tags = parse_tags(&mut p, tag_count, entry_count).context("install tags")?;
+    let mut entries = Vec::new();
+    for _ in 0..entry_count {
+        let end = p
+            .iter()
+            .position(|b| *b == 0)
+            .context("unterminated install file name")?;
+        let name = String::from_utf8(p[..end].to_vec()).context("install file name")?;
+        p.advance(end + 1);
+        ensure!(p.remaining() >= 20, "truncated install entry");
+        entries.push(Entry {
+            name,
+            ckey: ContentKey(p.get_u128()),
+            size: p.get_u32(),
+        });
+    }
+    ensure!(!p.has_remaining(), "trailing data in install manifest");
+    Ok(Install { entries, tags })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut data = b"IN\x01\x10\x00\x01\x00\x00\x00\x02".to_vec();
+        data.extend_from_slice(b"Windows\x00\x00\x01\x80");
+        data.extend_from_slice(b"Wow.exe\x00");
+        data.extend_from_slice(&1u128.to_be_bytes());
+        data.extend_from_slice(b"\x00\x00\x01\x00");
+        data.extend_from_slice(b"World of Warcraft.app\x00");
+        data.extend_from_slice(&2u128.to_be_bytes());
+        data.extend_from_slice(b"\x00\x00\x00\x02");
+        let install = parse(&data).unwrap();
+        assert_eq!(
+            install.entries,
+            vec![
+                Entry {
+                    name: "Wow.exe".to_string(),
+                    ckey: ContentKey(1),
+                    size: 256,
+                },
+                Entry {
+                    name: "World of Warcraft.app".to_string(),
+                    ckey: ContentKey(2),
+                    size: 2,
+                },
+            ]
+        );
+        assert_eq!(install.tags.len(), 1);
+        assert!(install.tags[0].contains(0));
+        assert!(!install.tags[0].contains(1));
+        assert!(parse(&data[..data.len() - 1]).is_err());
+    }
+}
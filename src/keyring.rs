@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+/// Parses a keyring config: `key-<name> = <key>` lines, both in hex.
+pub fn parse(data: &str) -> Result<HashMap<u64, u128>> {
+    data.lines()
+        .filter_map(|line| line.split_once(" = "))
+        .filter_map(|(k, v)| Some((k.strip_prefix("key-")?, v.trim())))
+        .map(|(name, key)| {
+            Ok((
+                u64::from_str_radix(name, 16).with_context(|| format!("key name {}", name))?,
+                u128::from_str_radix(key, 16).with_context(|| format!("key {}", name))?,
+            ))
+        })
+        .collect()
+}
+
+/// TACT keys from several sources, consulted in the order they were added.
+#[derive(Default)]
+pub struct KeyStore {
+    sources: Vec<(String, HashMap<u64, u128>)>,
+}
+
+impl KeyStore {
+    pub fn add(&mut self, source: impl Into<String>, keys: HashMap<u64, u128>) {
+        self.sources.push((source.into(), keys));
+    }
+
+    /// Looks `name` up in each source in turn, returning the first source
+    /// that has it along with the key.
+    pub fn get(&self, name: u64) -> Option<(&str, u128)> {
+        self.sources
+            .iter()
+            .find_map(|(source, keys)| Some((source.as_str(), *keys.get(&name)?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.sources.iter().map(|(_, keys)| keys.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sources.iter().all(|(_, keys)| keys.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_store() {
+        let keyring = parse(
+            "# Keyring\n\
+             key-fa505078126acb3e = bdc51862abed79b2de48c8e7e66c6200\n\
+             key-ff813f7d062ac0bc = aa0b5c77f088ccc2d39049bd267f066d\n",
+        )
+        .unwrap();
+        assert_eq!(keyring.len(), 2);
+        let mut store = KeyStore::default();
+        store.add("keyring", keyring);
+        store.add(
+            "extra",
+            vec![(1, 2), (0xfa50_5078_126a_cb3e, 3)]
+                .into_iter()
+                .collect(),
+        );
+        assert_eq!(store.len(), 4);
+        assert_eq!(
+            store.get(0xfa50_5078_126a_cb3e),
+            Some(("keyring", 0xbdc5_1862_abed_79b2_de48_c8e7_e66c_6200))
+        );
+        assert_eq!(store.get(1), Some(("extra", 2)));
+        assert_eq!(store.get(3), None);
+        assert!(parse("key-zz = 00").is_err());
+    }
+}
@@ -0,0 +1,39 @@
+//! Parsers for the CASC and TACT formats Blizzard games ship with. These
+//! need no networking; the `rustycasc` tool built on them lives in main.rs
+//! behind the `cli` feature.
+//!
+//! The parsers only work on byte slices and strings, with no file, network
+//! or thread access, so without default features the library builds for
+//! `wasm32-unknown-unknown`. Sizes read from headers are checked without
+//! multiplying them up, so they cannot overflow a 32-bit usize, and against
+//! [`limits::Limits`] before anything is allocated for them.
+//!
+//! The `net` feature adds the HTTP fetch layer and pluggable cache stores,
+//! along with a `blocking` client for callers that aren't async.
+
+pub mod archive;
+#[cfg(feature = "net")]
+pub mod blocking;
+pub mod blte;
+#[cfg(feature = "net")]
+pub mod cache;
+pub mod db2;
+pub mod dbd;
+pub mod diag;
+pub mod download;
+pub mod encoding;
+pub mod espec;
+#[cfg(feature = "net")]
+pub mod fetch;
+pub mod hash;
+pub mod install;
+pub mod keyring;
+pub mod limits;
+pub mod listfile;
+pub mod locale;
+#[cfg(feature = "python")]
+mod python;
+pub mod root;
+mod salsa20;
+pub mod text;
+pub mod types;
@@ -0,0 +1,69 @@
+//! Caps on the counts and sizes parsers will believe from length fields, so
+//! corrupt input fails with an error instead of an enormous allocation.
+
+use std::convert::TryFrom;
+
+use anyhow::{ensure, Result};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Limits {
+    /// The most entries of any one kind a header may claim: records, pages,
+    /// blocks and the like.
+    pub max_entries: usize,
+    /// The most bytes any one length field may claim.
+    pub max_bytes: usize,
+}
+
+impl Default for Limits {
+    /// Generous enough for any retail build, and small enough to allocate.
+    fn default() -> Limits {
+        Limits {
+            max_entries: 1 << 26,
+            max_bytes: 1 << 30,
+        }
+    }
+}
+
+impl Limits {
+    /// Checks a count of `what` read from a header.
+    pub fn entries(&self, what: &str, n: u64) -> Result<usize> {
+        ensure!(
+            n <= self.max_entries as u64,
+            "{} claims {} entries, over the limit of {}",
+            what,
+            n,
+            self.max_entries
+        );
+        Ok(usize::try_from(n)?)
+    }
+
+    /// Checks a size in bytes of `what` read from a header.
+    pub fn bytes(&self, what: &str, n: u64) -> Result<usize> {
+        ensure!(
+            n <= self.max_bytes as u64,
+            "{} claims {} bytes, over the limit of {}",
+            what,
+            n,
+            self.max_bytes
+        );
+        Ok(usize::try_from(n)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_entries: 10,
+            max_bytes: 100,
+        };
+        assert_eq!(limits.entries("records", 10).unwrap(), 10);
+        assert!(limits.entries("records", 11).is_err());
+        assert_eq!(limits.bytes("table", 100).unwrap(), 100);
+        let err = limits.bytes("table", u64::MAX).unwrap_err();
+        assert!(err.to_string().starts_with("table claims"), "{}", err);
+    }
+}
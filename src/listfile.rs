@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+
+use crate::types::FileDataID;
+
+/// Parses a community listfile of `fdid;path` lines.
+pub fn parse(text: &str) -> Result<HashMap<FileDataID, String>> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (fdid, path) = line
+                .split_once(';')
+                .with_context(|| format!("bad listfile line: {}", line))?;
+            let fdid = fdid
+                .parse()
+                .with_context(|| format!("bad listfile fdid: {}", fdid))?;
+            Ok((FileDataID(fdid), path.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::FileDataID;
+
+    #[test]
+    fn test_parse() {
+        let m = super::parse("1;a/b.lua\r\n\n22;Interface/X.xml\n").unwrap();
+        assert_eq!(m.len(), 2);
+        assert_eq!(m[&FileDataID(22)], "Interface/X.xml");
+        assert!(super::parse("x;y").is_err());
+        assert!(super::parse("12").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+use derive_more::Display;
+
+/// Client locales, as named by the game.
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Locale {
+    #[display("enUS")]
+    #[cfg_attr(feature = "cli", value(name = "enUS"))]
+    EnUs,
+    #[display("koKR")]
+    #[cfg_attr(feature = "cli", value(name = "koKR"))]
+    KoKr,
+    #[display("frFR")]
+    #[cfg_attr(feature = "cli", value(name = "frFR"))]
+    FrFr,
+    #[display("deDE")]
+    #[cfg_attr(feature = "cli", value(name = "deDE"))]
+    DeDe,
+    #[display("zhCN")]
+    #[cfg_attr(feature = "cli", value(name = "zhCN"))]
+    ZhCn,
+    #[display("zhTW")]
+    #[cfg_attr(feature = "cli", value(name = "zhTW"))]
+    ZhTw,
+    #[display("esES")]
+    #[cfg_attr(feature = "cli", value(name = "esES"))]
+    EsEs,
+    #[display("esMX")]
+    #[cfg_attr(feature = "cli", value(name = "esMX"))]
+    EsMx,
+    #[display("ruRU")]
+    #[cfg_attr(feature = "cli", value(name = "ruRU"))]
+    RuRu,
+    #[display("ptBR")]
+    #[cfg_attr(feature = "cli", value(name = "ptBR"))]
+    PtBr,
+    #[display("itIT")]
+    #[cfg_attr(feature = "cli", value(name = "itIT"))]
+    ItIt,
+}
+
+impl Locale {
+    pub const ALL: [Locale; 11] = [
+        Locale::EnUs,
+        Locale::KoKr,
+        Locale::FrFr,
+        Locale::DeDe,
+        Locale::ZhCn,
+        Locale::ZhTw,
+        Locale::EsEs,
+        Locale::EsMx,
+        Locale::RuRu,
+        Locale::PtBr,
+        Locale::ItIt,
+    ];
+
+    /// The bit marking this locale in root block locale flags.
+    pub fn root_flag(self) -> u32 {
+        match self {
+            Locale::EnUs => 0x2,
+            Locale::KoKr => 0x4,
+            Locale::FrFr => 0x10,
+            Locale::DeDe => 0x20,
+            Locale::ZhCn => 0x40,
+            Locale::EsEs => 0x80,
+            Locale::ZhTw => 0x100,
+            Locale::EsMx => 0x1000,
+            Locale::RuRu => 0x2000,
+            Locale::PtBr => 0x4000,
+            Locale::ItIt => 0x8000,
+        }
+    }
+
+    /// The locale whose strings a db2 holds, from its header's locale field.
+    pub fn from_db2(id: u32) -> Option<Locale> {
+        Some(match id {
+            0 => Locale::EnUs,
+            1 => Locale::KoKr,
+            2 => Locale::FrFr,
+            3 => Locale::DeDe,
+            4 => Locale::ZhCn,
+            5 => Locale::ZhTw,
+            6 => Locale::EsEs,
+            7 => Locale::EsMx,
+            8 => Locale::RuRu,
+            10 => Locale::PtBr,
+            11 => Locale::ItIt,
+            _ => return None,
+        })
+    }
+
+    /// Names the locales set in root block `flags`.
+    pub fn describe_flags(flags: u32) -> String {
+        let names = Locale::ALL
+            .iter()
+            .filter(|l| flags & l.root_flag() != 0)
+            .map(Locale::to_string)
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            "none".to_string()
+        } else {
+            names.join(",")
+        }
+    }
+
+    pub fn describe(locale: Option<Locale>) -> String {
+        locale.map_or_else(|| "any locale".to_string(), |l| l.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Locale;
+
+    #[test]
+    fn test_locale() {
+        assert_eq!(Locale::from_db2(0), Some(Locale::EnUs));
+        assert_eq!(Locale::from_db2(3), Some(Locale::DeDe));
+        assert_eq!(Locale::from_db2(10), Some(Locale::PtBr));
+        assert_eq!(Locale::from_db2(42), None);
+        assert_eq!(Locale::DeDe.root_flag() | Locale::EnUs.root_flag(), 0x22);
+        assert_eq!(Locale::DeDe.to_string(), "deDE");
+        assert_eq!(Locale::describe_flags(0x22), "enUS,deDE");
+        assert_eq!(Locale::describe_flags(0x1), "none");
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{ensure, Context, Result};
+use log::LevelFilter;
+
+/// A log file that is rotated once it grows past a size limit, keeping up to
+/// `keep` older generations as `path.1`, `path.2`, and so on.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+fn generation(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: &Path, max_size: u64, keep: usize) -> Result<RotatingFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("opening log file {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.keep == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                let from = generation(&self.path, n);
+                if from.exists() {
+                    std::fs::rename(from, generation(&self.path, n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, generation(&self.path, 1))?;
+            self.file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Parses one `module=level` directive of `--log-level`; a bare level sets
+/// the level of modules no directive names.
+pub(crate) fn parse_directive(s: &str) -> Result<(Option<String>, LevelFilter)> {
+    let (module, level) = match s.split_once('=') {
+        Some((module, level)) => (Some(module.trim().to_string()), level),
+        None => (None, s),
+    };
+    ensure!(module.as_deref() != Some(""), "empty module name in {}", s);
+    let level = level
+        .trim()
+        .parse()
+        .with_context(|| format!("unknown log level in {}", s))?;
+    Ok((module, level))
+}
+
+/// Console log levels by module, with the most specific directive winning.
+pub(crate) struct Filter {
+    crate_name: &'static str,
+    default: LevelFilter,
+    /// Module paths relative to the crate, e.g. `blte` or `fetch`.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// `verbose` is the `-v` count, which sets the default level.
+    pub(crate) fn new(
+        crate_name: &'static str,
+        verbose: u8,
+        directives: &[(Option<String>, LevelFilter)],
+    ) -> Filter {
+        let levels = [
+            LevelFilter::Error,
+            LevelFilter::Warn,
+            LevelFilter::Info,
+            LevelFilter::Debug,
+            LevelFilter::Trace,
+        ];
+        let mut filter = Filter {
+            crate_name,
+            default: levels[usize::from(verbose).min(levels.len() - 1)],
+            modules: Vec::new(),
+        };
+        for (module, level) in directives {
+            match module {
+                None => filter.default = *level,
+                Some(module) => {
+                    let prefix = format!("{}::", crate_name);
+                    let module = module.strip_prefix(&prefix).unwrap_or(module);
+                    filter.modules.push((module.to_string(), *level));
+                }
+            }
+        }
+        // Longest first, so the most specific module matches first.
+        filter
+            .modules
+            .sort_by_key(|(m, _)| std::cmp::Reverse(m.len()));
+        filter
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        let relative = target
+            .strip_prefix(self.crate_name)
+            .and_then(|t| t.strip_prefix("::"));
+        let relative = match relative {
+            Some(relative) => relative,
+            None => return self.default,
+        };
+        self.modules
+            .iter()
+            .find(|(m, _)| {
+                relative == m
+                    || relative
+                        .strip_prefix(m.as_str())
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    /// The most verbose level any module logs at.
+    fn max(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+/// Logs to the console at the levels its filter gives and, optionally, to a
+/// file at full verbosity as JSON lines.
+struct Logger {
+    console: stderrlog::StdErrLog,
+    filter: Filter,
+    file: Option<Mutex<RotatingFile>>,
+    module: &'static str,
+}
+
+impl Logger {
+    fn console_enabled(&self, metadata: &log::Metadata) -> bool {
+        self.filter.enabled(metadata) && self.console.enabled(metadata)
+    }
+
+    fn file_enabled(&self, metadata: &log::Metadata) -> bool {
+        self.file.is_some() && metadata.target().starts_with(self.module)
+    }
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.console_enabled(metadata) || self.file_enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if self.console_enabled(record.metadata()) {
+            self.console.log(record);
+        }
+        if !self.file_enabled(record.metadata()) {
+            return;
+        }
+        let ts = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis());
+        let line = serde_json::json!({
+            "ts": ts as u64,
+            "level": record.level().as_str(),
+            "target": record.target(),
+            "msg": record.args().to_string(),
+        })
+        .to_string();
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                // There is nowhere sensible left to report a failure to log.
+                let _ = file.write_line(&line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.file.flush();
+            }
+        }
+    }
+}
+
+/// Installs the global logger. Console output is filtered by `filter`, with
+/// `console` left to format it; when `file` is given, everything `module`
+/// logs also goes there.
+pub(crate) fn init(
+    mut console: stderrlog::StdErrLog,
+    filter: Filter,
+    file: Option<RotatingFile>,
+    module: &'static str,
+) -> Result<()> {
+    let max = filter.max();
+    console.verbosity((max as usize).saturating_sub(1));
+    log::set_max_level(if file.is_some() {
+        LevelFilter::Trace
+    } else {
+        max
+    });
+    log::set_boxed_logger(Box::new(Logger {
+        console,
+        filter,
+        file: file.map(Mutex::new),
+        module,
+    }))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let directives = ["blte=trace", "archive=warn", "rustycasc::fetch::http=off"]
+            .iter()
+            .map(|d| parse_directive(d).unwrap())
+            .collect::<Vec<_>>();
+        let filter = Filter::new("rustycasc", 2, &directives);
+        assert_eq!(filter.level("rustycasc::blte"), LevelFilter::Trace);
+        assert_eq!(filter.level("rustycasc::archive"), LevelFilter::Warn);
+        assert_eq!(filter.level("rustycasc::archived"), LevelFilter::Info);
+        assert_eq!(filter.level("rustycasc::fetch"), LevelFilter::Info);
+        assert_eq!(filter.level("rustycasc::fetch::http"), LevelFilter::Off);
+        assert_eq!(filter.level("rustycasc"), LevelFilter::Info);
+        assert_eq!(filter.max(), LevelFilter::Trace);
+        let quiet = Filter::new("rustycasc", 4, &[parse_directive("error").unwrap()]);
+        assert_eq!(quiet.level("rustycasc::blte"), LevelFilter::Error);
+        assert!(parse_directive("blte=loud").is_err());
+        assert!(parse_directive("=info").is_err());
+    }
+}
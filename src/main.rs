@@ -1,153 +1,361 @@
-mod archive;
-mod blte;
-mod db2;
-mod encoding;
+mod hooks;
+mod logging;
+mod metrics;
+#[cfg(feature = "parquet")]
+mod parquet_file;
+mod paths;
+mod product_config;
+mod progress;
 mod ribbit;
-mod root;
-mod types;
-mod util;
+mod shared;
+mod shutdown;
+mod spool;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod summary;
+#[cfg(feature = "upload")]
+mod upload;
+mod warnings;
 
+use rustycasc::{
+    archive, blte, cache, db2, dbd, download, encoding, espec, fetch, hash, install, keyring,
+    limits, listfile, locale, root, text, types,
+};
+
+use crate::cache::CacheStore;
+use crate::fetch::{BytesFetcher, CdnBytesFetcher, HasCdnPrefixes, TextFetcher};
 use crate::types::{ArchiveKey, ContentKey, EncodingKey, FileDataID};
 use anyhow::{bail, ensure, Context, Result};
 use async_trait::async_trait;
 use bytes::Bytes;
+use derive_more::Display;
 use futures::future::FutureExt;
-use log::{trace, warn};
-use std::collections::HashMap;
+use log::{info, trace, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::str::from_utf8;
 
-#[async_trait]
-trait BytesFetcher {
-    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes>;
+/// Regions to fall back on, in order, when the requested one is unavailable.
+const REGION_PRIORITY: [&str; 5] = ["us", "eu", "kr", "tw", "cn"];
+
+/// Picks the row whose `column` matches `region`, falling back through
+/// REGION_PRIORITY.
+fn select_region<'a>(
+    rows: Vec<HashMap<&'a str, &'a str>>,
+    column: &str,
+    region: &str,
+) -> Option<HashMap<&'a str, &'a str>> {
+    select_by_region(rows, region, |m| m.get(column).copied())
 }
 
-#[async_trait]
-impl BytesFetcher for reqwest::Client {
-    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes> {
-        let mut req = self.get(&url);
-        if let Some((start, end)) = range {
-            req = req.header("Range", format!("bytes={}-{}", start, end));
-        }
-        trace!("starting fetch of {}", url);
-        let response = req
-            .send()
-            .await
-            .context(format!("sending request to {}", url))?;
-        ensure!(
-            response.status().is_success(),
-            format!("http error on {}", url)
-        );
-        trace!("receiving content on {}", url);
-        let data = response
-            .bytes()
-            .await
-            .context(format!("receiving content on {}", url))?;
-        trace!("done retrieving {}", url);
-        Ok(data)
+/// Like select_region, for rows already parsed into structs.
+fn select_by_region<T>(
+    mut rows: Vec<T>,
+    region: &str,
+    region_of: impl Fn(&T) -> Option<&str>,
+) -> Option<T> {
+    std::iter::once(region)
+        .chain(REGION_PRIORITY.iter().copied())
+        .find_map(|r| rows.iter().position(|row| region_of(row) == Some(r)))
+        .map(|i| rows.swap_remove(i))
+}
+
+struct VersionInfo {
+    region: String,
+    build_config: u128,
+    cdn_config: u128,
+    key_config: Option<u128>,
+    product_config: Option<u128>,
+    /// The BuildId column, which bgdl and older tables may leave empty.
+    build_id: Option<u32>,
+    /// The VersionsName column, e.g. 1.14.3.49821.
+    name: Option<String>,
+}
+
+struct CdnInfo {
+    region: String,
+    hosts: Vec<String>,
+    path: String,
+    config_path: String,
+}
+
+impl CdnInfo {
+    fn prefixes(&self) -> Vec<String> {
+        self.hosts
+            .iter()
+            .map(|s| format!("http://{}/{}", s, self.path))
+            .collect()
     }
 }
 
+/// Picks `region`'s row out of a versions or bgdl table.
+fn parse_version(info: &str, region: &str) -> Result<VersionInfo> {
+    let version = select_region(parse_info(info), "Region", region)
+        .with_context(|| format!("missing {} version", region))?;
+    version_row(&version)
+}
+
+/// Every region's row of a versions or bgdl table, in table order.
+fn parse_versions(info: &str) -> Result<Vec<VersionInfo>> {
+    parse_info(info).iter().map(version_row).collect()
+}
+
+/// Every region's row of a cdns table, in table order.
+fn parse_cdns(info: &str) -> Result<Vec<CdnInfo>> {
+    parse_info(info).iter().map(cdn_row).collect()
+}
+
+fn cdn_row(cdn: &HashMap<&str, &str>) -> Result<CdnInfo> {
+    let used = cdn.get("Name").context("missing cdn name")?;
+    let hosts = cdn
+        .get("Hosts")
+        .with_context(|| format!("missing {} cdn hosts", used))?
+        .split(' ')
+        .map(str::to_string)
+        .collect();
+    let path = cdn
+        .get("Path")
+        .with_context(|| format!("missing {} cdn path", used))?;
+    let config_path = cdn
+        .get("ConfigPath")
+        .with_context(|| format!("missing {} cdn config path", used))?;
+    Ok(CdnInfo {
+        region: used.to_string(),
+        hosts,
+        path: path.to_string(),
+        config_path: config_path.to_string(),
+    })
+}
+
+fn version_row(version: &HashMap<&str, &str>) -> Result<VersionInfo> {
+    let used = version.get("Region").context("missing version region")?;
+    let build = parse_hash(
+        version
+            .get("BuildConfig")
+            .with_context(|| format!("missing {} build config version", used))?,
+    )?;
+    let cdn = parse_hash(
+        version
+            .get("CDNConfig")
+            .with_context(|| format!("missing {} cdn config version", used))?,
+    )?;
+    let optional_hash = |column| match version.get(column) {
+        Some(s) if !s.is_empty() => parse_hash(s).map(Some),
+        _ => Ok(None),
+    };
+    let build_id = match version.get("BuildId") {
+        Some(s) if !s.is_empty() => Some(
+            s.parse()
+                .with_context(|| format!("bad {} build id {}", used, s))?,
+        ),
+        _ => None,
+    };
+    Ok(VersionInfo {
+        region: used.to_string(),
+        build_config: build,
+        cdn_config: cdn,
+        key_config: optional_hash("KeyRing")?,
+        product_config: optional_hash("ProductConfig")?,
+        build_id,
+        name: version
+            .get("VersionsName")
+            .filter(|s| !s.is_empty())
+            .map(|s| s.to_string()),
+    })
+}
+
+/// Where a product's version and CDN tables come from.
 #[async_trait]
-trait TextFetcher {
-    async fn fetch_text(&self, url: String) -> Result<String>;
+trait VersionSource: Send + Sync {
+    /// Every region's version row.
+    async fn versions(&self, product: &str) -> Result<Vec<VersionInfo>>;
+    /// Every region's CDN row.
+    async fn cdns(&self, product: &str) -> Result<Vec<CdnInfo>>;
+}
+
+/// The HTTP endpoints, which serve the tables as bare text under
+/// `{base}/{product}`.
+struct HttpSource {
+    client: std::sync::Arc<dyn BytesFetcher + Send + Sync>,
+    base: &'static str,
 }
 
 #[async_trait]
-impl<T: BytesFetcher + Sync> TextFetcher for T {
-    async fn fetch_text(&self, url: String) -> Result<String> {
-        Ok(from_utf8(&self.fetch_bytes(url, None).await?)?.to_string())
+impl VersionSource for HttpSource {
+    async fn versions(&self, product: &str) -> Result<Vec<VersionInfo>> {
+        let url = format!("{}/{}/versions", self.base, product);
+        parse_versions(&self.client.fetch_text(url).await?)
+    }
+    async fn cdns(&self, product: &str) -> Result<Vec<CdnInfo>> {
+        let url = format!("{}/{}/cdns", self.base, product);
+        parse_cdns(&self.client.fetch_text(url).await?)
     }
 }
 
-#[async_trait]
-trait PatchDataFetcher {
-    async fn fetch_version(&self, suffix: &str) -> Result<(u128, u128)>;
-    async fn fetch_cdns(&self, suffix: &str) -> Result<Vec<String>>;
+/// The version sources `--version-source` can pick. Whichever is preferred,
+/// the same version of the other protocol is tried if it fails, so one
+/// backend's outage doesn't stop a run.
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq, clap::ValueEnum)]
+enum VersionSourceKind {
+    /// The patch server's HTTP endpoint on port 1119.
+    #[display("http-v1")]
+    HttpV1,
+    /// The HTTPS version server.
+    #[display("http-v2")]
+    HttpV2,
+    /// Ribbit v1, with signed and checksummed responses.
+    #[display("ribbit-v1")]
+    RibbitV1,
+    /// Ribbit v2, serving bare tables.
+    #[display("ribbit-v2")]
+    RibbitV2,
 }
 
-#[async_trait]
-impl<T: TextFetcher + Sync> PatchDataFetcher for T {
-    async fn fetch_version(&self, suffix: &str) -> Result<(u128, u128)> {
-        let info = self
-            .fetch_text(format!(
-                "http://us.patch.battle.net:1119/{}/versions",
-                suffix
-            ))
-            .await?;
-        let version = parse_info(&info)
-            .into_iter()
-            .find(|m| m.get("Region") == Some(&"us"))
-            .context("missing us version")?;
-        let build = parse_hash(
-            version
-                .get("BuildConfig")
-                .context("missing us build config version")?,
-        )?;
-        let cdn = parse_hash(
-            version
-                .get("CDNConfig")
-                .context("missing us cdn config version")?,
-        )?;
-        Ok((build, cdn))
+impl VersionSourceKind {
+    fn fallback(self) -> VersionSourceKind {
+        match self {
+            VersionSourceKind::HttpV1 => VersionSourceKind::RibbitV1,
+            VersionSourceKind::HttpV2 => VersionSourceKind::RibbitV2,
+            VersionSourceKind::RibbitV1 => VersionSourceKind::HttpV1,
+            VersionSourceKind::RibbitV2 => VersionSourceKind::HttpV2,
+        }
     }
-    async fn fetch_cdns(&self, suffix: &str) -> Result<Vec<String>> {
-        let info = self
-            .fetch_text(format!("http://us.patch.battle.net:1119/{}/cdns", suffix))
-            .await?;
-        let cdn = parse_info(&info)
-            .into_iter()
-            .find(|m| m.get("Name") == Some(&"us"))
-            .context("missing us cdn")?;
-        let hosts = cdn.get("Hosts").context("missing us cdn hosts")?.split(' ');
-        let path = cdn.get("Path").context("missing us cdn path")?;
-        Ok(hosts.map(|s| format!("http://{}/{}", s, path)).collect())
+
+    /// A source of this kind; HTTP ones fetch through `client`, Ribbit ones
+    /// through `ribbit`.
+    fn source(
+        self,
+        client: std::sync::Arc<dyn BytesFetcher + Send + Sync>,
+        ribbit: &ribbit::Ribbit,
+    ) -> Box<dyn VersionSource> {
+        match self {
+            VersionSourceKind::HttpV1 => Box::new(HttpSource {
+                client,
+                base: "http://us.patch.battle.net:1119",
+            }),
+            VersionSourceKind::HttpV2 => Box::new(HttpSource {
+                client,
+                base: "https://us.version.battle.net/v2/products",
+            }),
+            VersionSourceKind::RibbitV1 => Box::new(ribbit::Source::new(ribbit.clone(), false)),
+            VersionSourceKind::RibbitV2 => Box::new(ribbit::Source::new(ribbit.clone(), true)),
+        }
+    }
+}
+
+/// Picks `region`'s version and CDN rows from `source`.
+async fn fetch_version_from(
+    source: &dyn VersionSource,
+    product: &str,
+    region: &str,
+) -> Result<(VersionInfo, CdnInfo)> {
+    let (versions, cdns) =
+        futures::future::try_join(source.versions(product), source.cdns(product)).await?;
+    let version = select_by_region(versions, region, |v| Some(v.region.as_str()))
+        .with_context(|| format!("missing {} version", region))?;
+    let cdn = select_by_region(cdns, region, |c| Some(c.region.as_str()))
+        .with_context(|| format!("missing {} cdn", region))?;
+    Ok((version, cdn))
+}
+
+/// Resolves `product`'s version and CDNs from `preferred`, falling back to
+/// its counterpart; returns the source that answered.
+async fn resolve_version(
+    client: std::sync::Arc<dyn BytesFetcher + Send + Sync>,
+    ribbit: &ribbit::Ribbit,
+    product: &str,
+    region: &str,
+    preferred: VersionSourceKind,
+) -> Result<(VersionInfo, CdnInfo, VersionSourceKind)> {
+    let source = preferred.source(client.clone(), ribbit);
+    match fetch_version_from(source.as_ref(), product, region).await {
+        Ok((version, cdns)) => Ok((version, cdns, preferred)),
+        Err(e) => {
+            let fallback = preferred.fallback();
+            warnings::report(
+                warnings::Issue::VersionFallback,
+                format!(
+                    "{} version lookup failed, trying {}: {:#}",
+                    preferred, fallback, e
+                ),
+            )?;
+            let (version, cdns) =
+                fetch_version_from(fallback.source(client, ribbit).as_ref(), product, region)
+                    .await
+                    .with_context(|| format!("{} version lookup failed too", fallback))?;
+            Ok((version, cdns, fallback))
+        }
     }
 }
 
-trait HasCdnPrefixes {
-    fn cdn_prefixes(&self) -> &Vec<String>;
+/// The versions or bgdl row of `product` serving `build_config`, from
+/// which a pinned build takes its key and product configs.
+async fn pinned_row(
+    client: std::sync::Arc<dyn BytesFetcher + Send + Sync>,
+    ribbit: &ribbit::Ribbit,
+    source: VersionSourceKind,
+    product: &str,
+    build_config: u128,
+) -> Option<VersionInfo> {
+    let versions = source
+        .source(client.clone(), ribbit)
+        .versions(product)
+        .await
+        .unwrap_or_default();
+    let bgdl = client
+        .fetch_text(format!("http://us.patch.battle.net:1119/{}/bgdl", product))
+        .await
+        .ok()
+        .and_then(|info| parse_versions(&info).ok())
+        .unwrap_or_default();
+    versions
+        .into_iter()
+        .chain(bgdl)
+        .find(|v| v.build_config == build_config)
 }
 
 #[async_trait]
-trait CdnBytesFetcher {
-    async fn fetch_cdn_bytes(
+trait PatchDataFetcher {
+    /// `region`'s version row for the build the launcher background-downloads.
+    async fn fetch_bgdl(&self, suffix: &str, region: &str) -> Result<VersionInfo>;
+    async fn fetch_product_config(
         &self,
-        tag: &str,
+        cdns: &CdnInfo,
         hash: u128,
-        suffix: Option<&str>,
-        range: Option<(usize, usize)>,
-    ) -> Result<Bytes>;
+    ) -> Result<product_config::ProductConfig>;
 }
 
 #[async_trait]
-impl<T: BytesFetcher + HasCdnPrefixes + Sync> CdnBytesFetcher for T {
-    async fn fetch_cdn_bytes(
+impl<T: TextFetcher + Sync> PatchDataFetcher for T {
+    async fn fetch_bgdl(&self, suffix: &str, region: &str) -> Result<VersionInfo> {
+        let info = self
+            .fetch_text(format!("http://us.patch.battle.net:1119/{}/bgdl", suffix))
+            .await?;
+        parse_version(&info, region)
+    }
+    async fn fetch_product_config(
         &self,
-        tag: &str,
+        cdns: &CdnInfo,
         hash: u128,
-        suffix: Option<&str>,
-        range: Option<(usize, usize)>,
-    ) -> Result<Bytes> {
+    ) -> Result<product_config::ProductConfig> {
         let h = format!("{:032x}", hash);
-        let path = format!(
-            "{}/{}/{}/{}{}",
-            tag,
-            &h[0..2],
-            &h[2..4],
-            h,
-            suffix.unwrap_or("")
-        );
-        trace!("cdn fetch {}", path);
-        for _ in 1..10 {
-            for cdn_prefix in self.cdn_prefixes() {
-                let url = format!("{}/{}", cdn_prefix, path);
-                match self.fetch_bytes(url, range).await {
-                    Ok(data) => return Ok(data),
-                    Err(msg) => warn!("fetch failed: {:#?}", msg),
-                }
+        for host in &cdns.hosts {
+            let url = format!(
+                "http://{}/{}/{}/{}/{}",
+                host,
+                cdns.config_path,
+                &h[0..2],
+                &h[2..4],
+                h
+            );
+            match self.fetch_text(url).await {
+                Ok(text) => return product_config::parse(&text),
+                Err(e) => warnings::report(
+                    warnings::Issue::MirrorFetch,
+                    format!("fetch failed: {:#}", e),
+                )?,
             }
         }
-        bail!("fetch failed on all hosts: {}", path)
+        bail!("product config fetch failed on all hosts: {}", h)
     }
 }
 
@@ -175,12 +383,55 @@ fn parse_config(s: &str) -> HashMap<&str, &str> {
 struct BuildConfig {
     root: ContentKey,
     encoding: EncodingKey,
+    download: Option<EncodingKey>,
+    install: Option<EncodingKey>,
+    encoding_size: Option<FileSizes>,
+    download_size: Option<FileSizes>,
+    install_size: Option<FileSizes>,
+}
+
+/// The decoded and encoded sizes a build config gives for one of its files.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct FileSizes {
+    content: u64,
+    encoded: u64,
+}
+
+/// Parses a `<name>-size` field, e.g. `encoding-size = 1234 1200`.
+fn parse_sizes(config: &HashMap<&str, &str>, name: &str) -> Result<Option<FileSizes>> {
+    let field = format!("{}-size", name);
+    config
+        .get(field.as_str())
+        .map(|s| {
+            let mut sizes = s.split(' ').map(str::parse::<u64>);
+            match (sizes.next(), sizes.next()) {
+                (Some(Ok(content)), Some(Ok(encoded))) => Ok(FileSizes { content, encoded }),
+                _ => bail!("build config: malformed {}: {}", field, s),
+            }
+        })
+        .transpose()
 }
 
 fn parse_hash(s: &str) -> Result<u128> {
     u128::from_str_radix(s, 16).context("parse hash")
 }
 
+/// Parses a product, optionally pinned to one of its builds as
+/// `product@<build config>:<cdn config>`.
+fn parse_build_spec(s: &str) -> Result<(String, Option<(u128, u128)>)> {
+    let (product, pin) = match s.split_once('@') {
+        Some((product, pin)) => (product, pin),
+        None => return Ok((s.to_string(), None)),
+    };
+    let (build, cdn) = pin
+        .split_once(':')
+        .with_context(|| format!("expected product@buildconfig:cdnconfig, got {}", s))?;
+    Ok((
+        product.to_string(),
+        Some((parse_hash(build)?, parse_hash(cdn)?)),
+    ))
+}
+
 fn parse_build_config(config: &HashMap<&str, &str>) -> Result<BuildConfig> {
     Ok(BuildConfig {
         root: ContentKey(parse_hash(
@@ -194,9 +445,38 @@ fn parse_build_config(config: &HashMap<&str, &str>) -> Result<BuildConfig> {
                 .nth(1)
                 .context("missing data in encoding field in buildinfo")?,
         )?),
+        download: config
+            .get("download")
+            .and_then(|s| s.split(' ').nth(1))
+            .map(parse_hash)
+            .transpose()?
+            .map(EncodingKey),
+        install: config
+            .get("install")
+            .and_then(|s| s.split(' ').nth(1))
+            .map(parse_hash)
+            .transpose()?
+            .map(EncodingKey),
+        encoding_size: parse_sizes(config, "encoding")?,
+        download_size: parse_sizes(config, "download")?,
+        install_size: parse_sizes(config, "install")?,
     })
 }
 
+/// Fails if a decoded file isn't the size its build config or encoding
+/// table entry says it should be.
+fn check_content_size(name: &str, data: &[u8], expected: Option<u64>) -> Result<()> {
+    match expected {
+        Some(expected) if data.len() as u64 != expected => bail!(
+            "{} decoded to {} bytes, but {} were expected",
+            name,
+            data.len(),
+            expected
+        ),
+        _ => Ok(()),
+    }
+}
+
 fn normalize_path(base: &str, file: &str) -> String {
     let base = base.replace('/', "\\");
     let file = file.replace('/', "\\");
@@ -215,314 +495,5065 @@ fn normalize_path(base: &str, file: &str) -> String {
     base.join("\\")
 }
 
-fn to_zip_archive_bytes(m: HashMap<String, Vec<u8>>) -> Result<Vec<u8>> {
-    let mut zipbuf = Vec::<u8>::new();
-    {
-        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(&mut zipbuf));
-        for (name, data) in m {
-            use std::io::Write;
-            zip.start_file(
-                name.replace('\\', "/"),
-                zip::write::SimpleFileOptions::default(),
-            )?;
-            zip.write_all(&data)?;
-        }
-        zip.finish().context("zip archive failed to close")?;
+/// The zip entry describing which build an export came from.
+const BUILD_METADATA_NAME: &str = "BUILD.json";
+
+fn build_metadata(build: &Build, product: &str) -> serde_json::Value {
+    let v = &build.version;
+    serde_json::json!({
+        "product": product,
+        "region": v.region,
+        "build_id": v.build_id,
+        "version": v.name,
+        "build_config": format!("{:032x}", v.build_config),
+        "cdn_config": format!("{:032x}", v.cdn_config),
+    })
+}
+
+/// The zip entry other CASC tools open the export's build from, in the
+/// format of a client install's `.build.info`.
+const BUILD_INFO_NAME: &str = ".build.info";
+
+/// A one-row `.build.info` for the build `v` names. Fields only an install
+/// knows, such as its tags and when it was last activated, are left empty.
+fn build_info(
+    v: &VersionInfo,
+    cdns: &CdnInfo,
+    install: Option<EncodingKey>,
+    product: &str,
+) -> String {
+    let header = [
+        "Branch!STRING:0",
+        "Active!DEC:1",
+        "Build Key!HEX:16",
+        "CDN Key!HEX:16",
+        "Install Key!HEX:16",
+        "IM Size!DEC:4",
+        "CDN Path!STRING:0",
+        "CDN Hosts!STRING:0",
+        "CDN Servers!STRING:0",
+        "Tags!STRING:0",
+        "Armadillo!STRING:0",
+        "Last Activated!STRING:0",
+        "Version!STRING:0",
+        "KeyRing!HEX:16",
+        "Product!STRING:0",
+    ];
+    let row = [
+        v.region.clone(),
+        "1".to_string(),
+        format!("{:032x}", v.build_config),
+        format!("{:032x}", v.cdn_config),
+        install.map(|k| k.to_string()).unwrap_or_default(),
+        String::new(),
+        cdns.path.clone(),
+        cdns.hosts.join(" "),
+        cdns.hosts
+            .iter()
+            .map(|host| format!("http://{}/?maxhosts=4", host))
+            .collect::<Vec<_>>()
+            .join(" "),
+        String::new(),
+        String::new(),
+        String::new(),
+        v.name.clone().unwrap_or_default(),
+        v.key_config
+            .map(|k| format!("{:032x}", k))
+            .unwrap_or_default(),
+        product.to_string(),
+    ];
+    format!("{}\n{}\n", header.join("|"), row.join("|"))
+}
+
+/// A `sha256sum -c` compatible listing of `files`, with the paths they
+/// have in the zip.
+fn checksums(files: &spool::Files) -> Result<String> {
+    files
+        .names()
+        .into_iter()
+        .map(|name| {
+            let data = files.get(name)?.context("file vanished")?;
+            Ok(format!(
+                "{}  {}\n",
+                hex::encode(hash::sha256(&data)),
+                name.replace('\\', "/")
+            ))
+        })
+        .collect()
+}
+
+/// Writes `m` out through `zip`, returning the underlying writer. Spooled
+/// files are read back one at a time.
+fn write_zip<W: std::io::Write + std::io::Seek>(
+    mut zip: zip::ZipWriter<W>,
+    m: &spool::Files,
+) -> Result<W> {
+    for name in m.names() {
+        use std::io::Write;
+        let data = m.get(name)?.context("file vanished")?;
+        zip.start_file(
+            name.replace('\\', "/"),
+            zip::write::SimpleFileOptions::default(),
+        )?;
+        zip.write_all(&data)?;
     }
-    Ok(zipbuf)
+    zip.finish().context("zip archive failed to close")
 }
 
-async fn process(product: &str) -> Result<()> {
-    let client = reqwest::Client::new();
-    let ((build_config, cdn_config), cdn_prefixes) =
-        futures::future::try_join(client.fetch_version(product), client.fetch_cdns(product))
-            .await?;
-    struct CdnClient {
-        client: reqwest::Client,
-        cdn_prefixes: Vec<String>,
-        throttle: tokio::sync::Semaphore,
+/// Writes `m` as a zip at `path`, streamed rather than built in memory.
+fn write_zip_file(path: &std::path::Path, m: &spool::Files) -> Result<()> {
+    shutdown::write_with(path, |file| {
+        let mut out = write_zip(zip::ZipWriter::new(std::io::BufWriter::new(file)), m)?;
+        Ok(std::io::Write::flush(&mut out)?)
+    })
+    .context("zip writing")
+}
+
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq, clap::ValueEnum)]
+enum VerifyLevel {
+    /// Skip all checksums.
+    #[display("none")]
+    None,
+    /// Verify BLTE checksums but not the final content key.
+    #[display("fast")]
+    Fast,
+    /// Verify BLTE checksums and content keys.
+    #[display("full")]
+    Full,
+}
+
+impl VerifyLevel {
+    fn blte_checksum(self, ekey: EncodingKey) -> Option<u128> {
+        (self != VerifyLevel::None).then_some(ekey.0)
     }
-    #[async_trait]
-    impl BytesFetcher for CdnClient {
-        async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes> {
-            let _ = self.throttle.acquire().await?;
-            self.client.fetch_bytes(url, range).await
+}
+
+/// Runs decoding and checksum work on blocking threads, at most `workers`
+/// at a time, so the tasks that fetched the data can get on with the next
+/// download instead of inflating and hashing between polls.
+struct VerifyPool {
+    /// None runs the work inline.
+    slots: Option<std::sync::Arc<tokio::sync::Semaphore>>,
+}
+
+impl VerifyPool {
+    fn new(workers: usize) -> VerifyPool {
+        VerifyPool {
+            slots: (workers > 0).then(|| std::sync::Arc::new(tokio::sync::Semaphore::new(workers))),
         }
     }
-    impl HasCdnPrefixes for CdnClient {
-        fn cdn_prefixes(&self) -> &Vec<String> {
-            &self.cdn_prefixes
+
+    async fn run<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T> + Send + 'static,
+    {
+        match &self.slots {
+            Some(slots) => {
+                let _permit = slots.acquire().await?;
+                tokio::task::spawn_blocking(f).await?
+            }
+            None => f(),
         }
     }
-    let cdn_client = &CdnClient {
-        client,
-        cdn_prefixes,
-        throttle: tokio::sync::Semaphore::new(5),
-    };
-    let do_cdn_fetch = |tag: &'static str,
-                        hash: u128,
-                        suffix: Option<&'static str>,
-                        range: Option<(usize, usize)>| async move {
-        cdn_client.fetch_cdn_bytes(tag, hash, suffix, range).await
-    };
-    let cdn_fetch =
-        |tag: &'static str, hash: u128| async move { do_cdn_fetch(tag, hash, None, None).await };
-    let archive_index = async {
-        let hashes = parse_config(from_utf8(&(cdn_fetch("config", cdn_config).await?))?)
-            .get("archives")
-            .context("missing archives in cdninfo")?
-            .split(' ')
-            .map(parse_hash)
-            .collect::<Result<Vec<_>>>()?;
-        let pb = &indicatif::ProgressBar::new(hashes.len() as u64);
-        Result::<_>::Ok(archive::Index {
-            map: futures::future::try_join_all(hashes.into_iter().map(|h| async move {
-                archive::parse_index(
-                    ArchiveKey(h),
-                    &(do_cdn_fetch("data", h, Some(".index"), None)
-                        .inspect(|_| pb.inc(1))
-                        .await?),
-                )
-            }))
-            .await?
-            .into_iter()
-            .flat_map(|archive::Index { map }| map)
-            .collect(),
-        })
-    };
-    let encoding_and_root = async {
-        let buildinfo = parse_build_config(&parse_config(from_utf8(
-            &(cdn_fetch("config", build_config).await?),
-        )?))?;
-        let encoding_key = buildinfo.encoding.0;
-        let encoding = encoding::parse(&blte::parse(
-            encoding_key,
-            &(cdn_fetch("data", encoding_key).await?),
-        )?)?;
-        let root_key = encoding.c2e(buildinfo.root)?.0;
-        let root = root::parse(&blte::parse(root_key, &cdn_fetch("data", root_key).await?)?)?;
-        Result::<_>::Ok((encoding, root))
-    };
-    let (archive_index, (encoding, root)) =
-        futures::future::try_join(archive_index, encoding_and_root).await?;
-    let (archive_index, encoding, root) = (&archive_index, &encoding, &root);
-    let fetch_content = |ckey| async move {
-        let ekey = encoding.c2e(ckey)?;
-        let (archive, size, offset) = archive_index.map.get(&ekey).context("missing index key")?;
-        let response = do_cdn_fetch(
-            "data",
-            archive.0,
-            None,
-            Some((*offset, *offset + *size - 1)),
-        )
-        .await?;
-        let bytes = blte::parse(ekey.0, &response)?;
-        ensure!(util::md5hash(&bytes) == ckey.0, "checksum fail on {}", ckey);
-        Ok(bytes)
-    };
-    let fetch_fdid = |fdid| async move { fetch_content(root.f2c(fdid)?).await };
-    let fdids = db2::strings(&fetch_fdid(FileDataID(1375801)).await?)?
-        .into_iter()
-        .map(|(k, v)| (v.join("").to_lowercase(), FileDataID(k)))
-        .collect::<HashMap<String, FileDataID>>();
-    tokio::fs::write(
-        format!("zips/{}.zip", product),
-        to_zip_archive_bytes({
-            let mut stack: Vec<String> = db2::strings(&fetch_fdid(FileDataID(1267335)).await?)?
-                .into_values()
-                .flatten()
-                .chain(["Interface\\FrameXML\\".to_string()])
-                .filter_map(|s| {
-                    let dirname = s[..s.len() - 1].split('\\').last()?;
-                    let toc1 = format!("{}{}_{}.toc", s, dirname, product);
-                    let toc2 = format!("{}{}.toc", s, dirname);
-                    root.n2c(&toc1)
-                        .and(Ok(toc1))
-                        .or_else(|_| root.n2c(&toc2).and(Ok(toc2)))
-                        .ok()
-                })
-                .collect();
-            let pb = &indicatif::ProgressBar::new(stack.len() as u64);
-            let mut result = HashMap::<String, Vec<u8>>::new();
-            while let Some(file) = stack.pop() {
-                let content = match root.n2c(&file).ok().or_else(|| {
-                    fdids
-                        .get(&file.to_lowercase())
-                        .and_then(|k| root.f2c(*k).ok())
-                }) {
-                    Some(ckey) => fetch_content(ckey).inspect(|_| pb.inc(1)).await?,
-                    None => {
-                        eprintln!("skipping file with no content key: {}", file);
-                        pb.inc(1);
-                        continue;
-                    }
-                };
-                if file.ends_with(".toc") {
-                    from_utf8(&content)?
-                        .lines()
-                        .map(|line| line.trim())
-                        .filter(|line| !line.is_empty())
-                        .filter(|line| !line.starts_with('#'))
-                        .for_each(|line| {
-                            pb.inc_length(1);
-                            stack.push(normalize_path(&file, line))
-                        });
-                } else if file.ends_with(".xml") {
-                    use xml::reader::{EventReader, XmlEvent::StartElement};
-                    let xml = &content.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&content);
-                    itertools::process_results(
-                        EventReader::new(std::io::Cursor::new(xml)),
-                        |iter| {
-                            iter.filter_map(|e| {
-                                if let StartElement {
-                                    name, attributes, ..
-                                } = e
-                                {
-                                    Some((name.local_name.to_lowercase(), attributes))
-                                } else {
-                                    None
-                                }
-                            })
-                            .filter(|(name, _)| name == "script" || name == "include")
-                            .flat_map(|(_, attrs)| attrs)
-                            .filter(|attr| attr.name.local_name == "file")
-                            .map(|attr| attr.value)
-                            .for_each(|value| {
-                                pb.inc_length(1);
-                                stack.push(normalize_path(&file, &value))
-                            })
-                        },
-                    )?;
-                }
-                result.insert(file, content);
-            }
-            Result::<_>::Ok(result)
-        }?)?,
-    )
-    .await
-    .context("zip writing")
 }
 
-fn ensuredir(dir: &str) -> Result<()> {
-    match std::fs::metadata(dir).map_or(None, |m| Some(m.is_dir())) {
-        Some(true) => Ok(()),
-        Some(false) => bail!("{} is not a directory", dir),
-        None => {
-            trace!("creating directory {}", dir);
-            std::fs::create_dir(dir).context(format!("error creating {}", dir))
-        }
+/// Decodes fetched BLTE `data` of `ckey`, checking it hashes to `ckey`
+/// when `full`.
+fn decode_content(
+    ckey: ContentKey,
+    checksum: Option<u128>,
+    full: bool,
+    keys: &keyring::KeyStore,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let explain = |e: anyhow::Error| match blte::encryption_key(data) {
+        Some(name) => e.context(format!("{} is encrypted with key {:016x}", ckey, name)),
+        None => e,
+    };
+    if !full {
+        return blte::parse_keyed(checksum, keys, data).map_err(explain);
     }
+    let (bytes, hash) = blte::parse_hashed(checksum, keys, data).map_err(explain)?;
+    ensure!(hash == ckey.0, "checksum fail on {}", ckey);
+    Ok(bytes)
 }
 
-#[derive(clap::Parser)]
-#[clap(version, about)]
-struct Cli {
-    #[clap(subcommand)]
-    command: CliCommands,
-    #[clap(short, long, action = clap::ArgAction::Count)]
-    verbose: u8,
+/// The key a failed fetch needed, when it failed for want of one; especs
+/// don't always declare the encryption up front.
+fn encrypted_with(err: &anyhow::Error) -> Option<u64> {
+    err.downcast_ref::<blte::MissingKey>().map(|key| key.0)
 }
 
-#[derive(clap::Subcommand)]
-enum CliCommands {
-    #[clap(name = "framexml")]
-    FrameXml(CliFrameXmlArgs),
-    #[clap(name = "ribbit")]
-    Ribbit(CliRibbitArgs),
+/// One verification worker per CPU.
+fn default_verify_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
 }
 
-#[derive(clap::Args)]
-struct CliFrameXmlArgs {
-    #[clap(value_parser)]
-    product: String,
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq, clap::ValueEnum)]
+enum IndexPolicy {
+    /// Abort the run if any archive index is unusable.
+    #[display("fail")]
+    Fail,
+    /// Warn about unusable archive indices and carry on without them.
+    #[display("skip")]
+    Skip,
 }
 
-#[derive(clap::Args)]
-struct CliRibbitArgs {
-    #[clap(subcommand)]
-    command: CliRibbitCommands,
+const MANIFEST_DATA_NAME: &str = "DBFilesClient\\ManifestInterfaceData.db2";
+const MANIFEST_TOC_NAME: &str = "DBFilesClient\\ManifestInterfaceTOCData.db2";
+
+/// The manifests' FileDataIDs, used when root doesn't name them; products
+/// that number them otherwise need --manifest-data-fdid and
+/// --manifest-toc-fdid.
+const MANIFEST_DATA_FDID: FileDataID = FileDataID(1375801);
+const MANIFEST_TOC_FDID: FileDataID = FileDataID(1267335);
+
+struct CdnClient {
+    client: std::sync::Arc<dyn BytesFetcher + Send + Sync>,
+    cdn_prefixes: Vec<String>,
+    throttle: tokio::sync::Semaphore,
+    bench: fetch::HostBench,
+    cache: Option<std::sync::Arc<dyn CacheStore + Send + Sync>>,
+    limits: limits::Limits,
+    /// Shared by every fetch of the run, however many builds it opens.
+    retry_budget: std::sync::Arc<fetch::RetryBudget>,
 }
 
-#[derive(clap::Subcommand)]
-enum CliRibbitCommands {
-    #[clap(name = "summary")]
-    Summary,
-    #[clap(name = "versions")]
-    Versions(CliRibbitVersionsArgs),
-    #[clap(name = "cdns")]
-    CDNs(CliRibbitCDNsArgs),
-    #[clap(name = "check")]
-    Check,
+#[async_trait]
+impl BytesFetcher for CdnClient {
+    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes> {
+        let _permit = self.throttle.acquire().await?;
+        self.client.fetch_bytes(url, range).await
+    }
+    async fn fetch_streamed(
+        &self,
+        url: String,
+        sink: &mut (dyn fetch::ChunkSink + Send),
+    ) -> Result<()> {
+        let _permit = self.throttle.acquire().await?;
+        self.client.fetch_streamed(url, sink).await
+    }
 }
 
-#[derive(clap::Args)]
-struct CliRibbitVersionsArgs {
-    #[clap(value_parser)]
-    product: String,
+/// A [`fetch::ChunkSink`] that a streamed CDN fetch can retry on other hosts.
+trait FetchSink: fetch::ChunkSink + Send {
+    /// Discards whatever an earlier host's attempt left behind.
+    fn reset(&mut self);
+    /// Checks a completed stream; an error moves on to the next host.
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// Takes an error that would recur on any host, ending the fetch.
+    fn take_fatal(&mut self) -> Option<anyhow::Error> {
+        None
+    }
 }
 
-#[derive(clap::Args)]
-struct CliRibbitCDNsArgs {
-    #[clap(value_parser)]
-    product: String,
+/// Feeds a streamed archive index to its parser, keeping parse errors apart
+/// from transport ones.
+struct IndexSink<'a> {
+    name: ArchiveKey,
+    limits: limits::Limits,
+    parser: Option<archive::IndexParser>,
+    failed: Option<anyhow::Error>,
+    progress: &'a progress::Progress,
+    // What this attempt has added to the progress total, to take back if
+    // it's abandoned.
+    expected: u64,
+    received: u64,
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    use clap::Parser;
-    let cli = Cli::parse();
-    stderrlog::new()
-        .module(module_path!())
-        .timestamp(stderrlog::Timestamp::Millisecond)
-        .verbosity(cli.verbose as usize)
-        .init()?;
-    match &cli.command {
-        CliCommands::FrameXml(args) => {
-            ensuredir("zips")?;
-            process(&args.product).await
-        }
-        CliCommands::Ribbit(args) => match &args.command {
-            CliRibbitCommands::Summary => {
-                println!("{:#?}", ribbit::Ribbit::new()?.summary()?);
-                Ok(())
-            }
-            CliRibbitCommands::Versions(args) => {
-                println!("{:#?}", ribbit::Ribbit::new()?.versions(&args.product)?);
-                Ok(())
-            }
-            CliRibbitCommands::CDNs(args) => {
-                println!("{:#?}", ribbit::Ribbit::new()?.cdns(&args.product)?);
-                Ok(())
-            }
-            CliRibbitCommands::Check => {
-                let mut ribbit = ribbit::Ribbit::new()?;
-                let summary = ribbit.summary()?;
-                println!("summary seqn = {}", summary.seqn);
-                for (k, v) in summary.entries {
-                    println!("looking at {}", k);
-                    if v.seqn.is_some() {
-                        println!("{} versions seqn = {}", k, ribbit.versions(&k)?.seqn);
-                    }
-                    if v.cdn.is_some() {
-                        println!("{} cdns seqn = {}", k, ribbit.cdns(&k)?.seqn);
-                    }
-                }
+impl IndexSink<'_> {
+    fn fail(&mut self, result: Result<()>) -> Result<()> {
+        result.map_err(|e| {
+            self.failed = Some(e);
+            anyhow::anyhow!("archive index {} is unparsable", self.name)
+        })
+    }
+}
+
+impl fetch::ChunkSink for IndexSink<'_> {
+    fn start(&mut self, len: Option<u64>) -> Result<()> {
+        let len = len.context("archive index has no content length")?;
+        self.progress.expect(len);
+        self.expected = len;
+        match archive::IndexParser::with_limits(self.name, len.try_into()?, &self.limits) {
+            Ok(parser) => {
+                self.parser = Some(parser);
                 Ok(())
             }
-        },
+            Err(e) => self.fail(Err(e)),
+        }
+    }
+    fn chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.progress.downloaded(data.len() as u64);
+        self.received += data.len() as u64;
+        let fed = match &mut self.parser {
+            Some(parser) => parser.feed(data),
+            None => Ok(()),
+        };
+        self.fail(fed)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use velcro::hash_map as m;
-    use velcro::vec as v;
+impl FetchSink for IndexSink<'_> {
+    fn reset(&mut self) {
+        self.parser = None;
+        self.progress.retract(self.expected, self.received);
+        self.expected = 0;
+        self.received = 0;
+    }
+    // Parse errors would recur anywhere; transport errors are left to the
+    // next host.
+    fn take_fatal(&mut self) -> Option<anyhow::Error> {
+        self.failed.take()
+    }
+}
 
-    #[test]
-    fn test_parse_info() {
-        let tests = [
-            ("empty string", "", v![]),
-            ("space", " ", v![]),
+/// Collects a file whose encoded size the build config gives, giving up on
+/// a host as soon as its response is known to be the wrong length.
+struct SizedSink<'a> {
+    path: String,
+    expected: Option<u64>,
+    data: Vec<u8>,
+    phase: &'a progress::Phase,
+}
+
+impl fetch::ChunkSink for SizedSink<'_> {
+    fn start(&mut self, len: Option<u64>) -> Result<()> {
+        match (self.expected, len) {
+            (Some(expected), Some(len)) if expected != len => bail!(
+                "{} is {} bytes, but the build config says {}",
+                self.path,
+                len,
+                expected
+            ),
+            (None, Some(len)) => self.phase.set_length(len),
+            _ => {}
+        }
+        self.data
+            .reserve(self.expected.or(len).unwrap_or(0).try_into()?);
+        Ok(())
+    }
+    fn chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.data.extend_from_slice(data);
+        self.phase.inc_bytes(data.len() as u64);
+        if let Some(expected) = self.expected {
+            ensure!(
+                self.data.len() as u64 <= expected,
+                "{} is longer than the {} bytes the build config says",
+                self.path,
+                expected
+            );
+        }
+        Ok(())
+    }
+}
+
+impl FetchSink for SizedSink<'_> {
+    fn reset(&mut self) {
+        self.data.clear();
+        self.phase.reset();
+    }
+    fn finish(&mut self) -> Result<()> {
+        match self.expected {
+            Some(expected) if self.data.len() as u64 != expected => bail!(
+                "{} is {} bytes, but the build config says {}",
+                self.path,
+                self.data.len(),
+                expected
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl CdnClient {
+    /// Streams a file into `sink` from the first host that serves it whole.
+    async fn fetch_cdn_streamed(
+        &self,
+        tag: &str,
+        hash: u128,
+        suffix: Option<&str>,
+        sink: &mut impl FetchSink,
+    ) -> Result<()> {
+        let mut attempts = vec![0; self.cdn_prefixes.len()];
+        loop {
+            let mut tried = false;
+            for (cdn_prefix, attempts) in self.cdn_prefixes.iter().zip(&mut attempts) {
+                if *attempts >= self.bench.trip_after() || self.bench.is_benched(cdn_prefix) {
+                    continue;
+                }
+                *attempts += 1;
+                tried = true;
+                let url = fetch::cdn_url(cdn_prefix, tag, hash, suffix);
+                sink.reset();
+                let result = match self.fetch_streamed(url, sink).await {
+                    Ok(()) => sink.finish(),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(()) => {
+                        self.bench.succeeded(cdn_prefix);
+                        return Ok(());
+                    }
+                    Err(e) => match sink.take_fatal() {
+                        Some(e) => return Err(e),
+                        None => {
+                            self.bench.failed(cdn_prefix, &e);
+                            self.fetch_failed(e)?
+                        }
+                    },
+                }
+            }
+            match self.bench.wait(&self.cdn_prefixes) {
+                Some(wait) => tokio::time::sleep(wait).await,
+                None if tried => {}
+                None => break,
+            }
+        }
+        bail!(
+            "fetch failed on all hosts: {} ({})",
+            fetch::cdn_path(tag, hash, suffix),
+            self.bench.describe(&self.cdn_prefixes)
+        )
+    }
+
+    /// Fetches and parses an archive index as it streams in, so only its
+    /// toc and footer are held in memory; its bytes count toward
+    /// `progress`'s total. `tag` is `data` for content archives and `patch`
+    /// for patch archives, whose indices share the format.
+    async fn fetch_index(
+        &self,
+        tag: &str,
+        name: ArchiveKey,
+        progress: &progress::Progress,
+    ) -> Result<archive::Index> {
+        let path = fetch::cdn_path(tag, name.0, Some(".index"));
+        trace!("cdn index fetch {}", path);
+        // The parsed form is cached, which is far smaller than the index
+        // and skips parsing it again.
+        let key = format!("{}.parsed", path);
+        if let Some(data) = self.cache_get(&key).await {
+            match archive::Index::from_bytes(&data) {
+                Ok(index) => {
+                    self.cache_hit();
+                    return Ok(index);
+                }
+                Err(e) => warn!("ignoring cached index {}: {:#}", name, e),
+            }
+        }
+        let mut sink = IndexSink {
+            name,
+            limits: self.limits,
+            parser: None,
+            failed: None,
+            progress,
+            expected: 0,
+            received: 0,
+        };
+        self.fetch_cdn_streamed(tag, name.0, Some(".index"), &mut sink)
+            .await?;
+        let index = sink
+            .parser
+            .context("archive index stream never started")?
+            .finish()?;
+        self.cache_put(&key, &index.to_bytes()).await;
+        Ok(index)
+    }
+
+    async fn cache_get(&self, key: &str) -> Option<Vec<u8>> {
+        match self.cache.as_ref()?.get(key).await {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("cache read failed: {:#}", e);
+                None
+            }
+        }
+    }
+
+    async fn cache_put(&self, key: &str, data: &[u8]) {
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.put(key, data).await {
+                warn!("cache write failed: {:#}", e);
+            }
+        }
+    }
+
+    /// Fetches a data file, checking it against the encoded size the build
+    /// config or encoding table gives, if any, as it streams into `phase`.
+    async fn fetch_sized(
+        &self,
+        ekey: EncodingKey,
+        expected: Option<u64>,
+        phase: &progress::Phase,
+    ) -> Result<Vec<u8>> {
+        let path = fetch::cdn_path("data", ekey.0, None);
+        trace!("cdn sized fetch {} ({:?} bytes)", path, expected);
+        if let Some(data) = self.cache_get(&path).await {
+            if expected.is_none_or(|e| e == data.len() as u64) {
+                self.cache_hit();
+                phase.finish();
+                return Ok(data);
+            }
+            warn!("ignoring cached {} of the wrong size", path);
+        }
+        if let Some(expected) = expected {
+            phase.set_length(expected);
+        }
+        let mut sink = SizedSink {
+            path: path.clone(),
+            expected,
+            data: Vec::new(),
+            phase,
+        };
+        self.fetch_cdn_streamed("data", ekey.0, None, &mut sink)
+            .await?;
+        phase.finish();
+        self.cache_put(&path, &sink.data).await;
+        Ok(sink.data)
+    }
+
+    /// Fetches and decodes a manifest, checked against the sizes its build
+    /// config gives.
+    async fn fetch_checked(
+        &self,
+        name: &str,
+        ekey: EncodingKey,
+        sizes: Option<FileSizes>,
+        verify: VerifyLevel,
+        phase: &progress::Phase,
+    ) -> Result<Vec<u8>> {
+        let data = self
+            .fetch_sized(ekey, sizes.map(|s| s.encoded), phase)
+            .await?;
+        let data = blte::parse(verify.blte_checksum(ekey), &data)?;
+        check_content_size(name, &data, sizes.map(|s| s.content))?;
+        Ok(data)
+    }
+
+    async fn fetch_download_manifest(
+        &self,
+        ekey: EncodingKey,
+        sizes: Option<FileSizes>,
+        verify: VerifyLevel,
+        phase: &progress::Phase,
+    ) -> Result<download::Download> {
+        download::parse(
+            &self
+                .fetch_checked("download manifest", ekey, sizes, verify, phase)
+                .await?,
+        )
+    }
+}
+
+impl HasCdnPrefixes for CdnClient {
+    fn cdn_prefixes(&self) -> &Vec<String> {
+        &self.cdn_prefixes
+    }
+    fn host_bench(&self) -> Option<&fetch::HostBench> {
+        Some(&self.bench)
+    }
+    fn cache(&self) -> Option<&(dyn CacheStore + Send + Sync)> {
+        self.cache.as_deref()
+    }
+    fn cache_hit(&self) {
+        metrics::inc(&metrics::METRICS.cache_hits, 1);
+    }
+    fn fetch_failed(&self, err: anyhow::Error) -> Result<()> {
+        warnings::report(
+            warnings::Issue::MirrorFetch,
+            format!("fetch failed: {:#}", err),
+        )?;
+        self.retry_budget.spend(err)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct OutputOptions {
+    quiet: bool,
+    progress: bool,
+}
+
+impl OutputOptions {
+    /// Reports informational output, which quiet runs only log.
+    fn note(&self, msg: impl std::fmt::Display) {
+        if self.quiet {
+            info!("{}", msg);
+        } else {
+            eprintln!("{}", msg);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq, clap::ValueEnum)]
+enum Http2Mode {
+    /// Use HTTP/2 where the server negotiates it.
+    #[display("auto")]
+    Auto,
+    /// Only ever speak HTTP/1.
+    #[display("off")]
+    Off,
+    /// Speak HTTP/2 without negotiation.
+    #[display("prior-knowledge")]
+    PriorKnowledge,
+}
+
+/// Parses a `host:ip` pin in the style of curl's `--resolve`. IPv6 addresses
+/// may optionally be bracketed.
+fn parse_resolve(s: &str) -> Result<(String, std::net::IpAddr)> {
+    let (host, ip) = s.split_once(':').context("expected host:ip")?;
+    ensure!(!host.is_empty(), "empty host in {}", s);
+    let ip = ip
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(ip);
+    Ok((host.to_string(), ip.parse().context("invalid ip address")?))
+}
+
+struct HttpOptions {
+    resolve: Vec<(String, std::net::IpAddr)>,
+    http2: Http2Mode,
+    pool_idle_timeout: Option<std::time::Duration>,
+    pool_max_idle_per_host: Option<usize>,
+    tcp_keepalive: Option<std::time::Duration>,
+    /// Version lookups through Ribbit go through this client.
+    ribbit: ribbit::Ribbit,
+}
+
+impl HttpOptions {
+    fn client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .tcp_keepalive(self.tcp_keepalive);
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        for (host, ip) in &self.resolve {
+            // The port is ignored; requests keep the one in their URL.
+            builder = builder.resolve(host, std::net::SocketAddr::new(*ip, 0));
+        }
+        builder = match self.http2 {
+            Http2Mode::Auto => builder,
+            Http2Mode::Off => builder.http1_only(),
+            Http2Mode::PriorKnowledge => builder.http2_prior_knowledge(),
+        };
+        builder.build().context("building http client")
+    }
+}
+
+struct BuildOptions {
+    output: OutputOptions,
+    http: HttpOptions,
+    region: String,
+    version_source: VersionSourceKind,
+    verify: VerifyLevel,
+    /// Background decoding and verification threads; zero works inline.
+    verify_workers: usize,
+    index_policy: IndexPolicy,
+    duplicate_policy: archive::DuplicatePolicy,
+    trace_lookups: bool,
+    /// Load the download manifest so files can be fetched by priority.
+    prioritize: bool,
+    /// Where CDN files and parsed indices are kept between runs.
+    cache: Option<std::sync::Arc<dyn CacheStore + Send + Sync>>,
+    /// Fetches through this instead of an HTTP client built from `http`.
+    fetcher: Option<std::sync::Arc<dyn BytesFetcher + Send + Sync>>,
+    /// Mirrors to fetch CDN files from instead of the product's CDN hosts,
+    /// as prefixes or path templates.
+    cdn_urls: Vec<String>,
+    /// Caps on what the encoding, root, index and db2 headers may claim.
+    limits: limits::Limits,
+    retry_budget: std::sync::Arc<fetch::RetryBudget>,
+    /// Failures in a row after which a CDN host is skipped.
+    host_failures: usize,
+    /// How long a CDN host that keeps failing is skipped.
+    host_cool_down: std::time::Duration,
+    /// Locales root lookups without one prefer, most preferred first.
+    preferred_locales: Vec<locale::Locale>,
+    /// Opens this build and CDN config instead of the ones the versions
+    /// table names, still fetching from the product's CDN.
+    pin: Option<(u128, u128)>,
+    /// Configs and archive indices fetched by any build opened with these
+    /// options, for the others to reuse.
+    shared: std::sync::Arc<shared::Fetches>,
+}
+
+/// A build resolved down to its archive index, encoding and root tables.
+struct Build {
+    /// The versions row the build was opened from.
+    version: VersionInfo,
+    /// The cdns row its hosts came from.
+    cdns: CdnInfo,
+    progress: progress::Progress,
+    cdn: CdnClient,
+    verify: VerifyLevel,
+    verify_pool: VerifyPool,
+    config: BuildConfig,
+    archive_index: archive::Index,
+    /// Indices skipped when the build was opened, fetched again the first
+    /// time a lookup misses every other index.
+    skipped_indices: Vec<(ArchiveKey, tokio::sync::OnceCell<Option<archive::Index>>)>,
+    encoding: encoding::Encoding,
+    root: root::Root,
+    /// Download manifest priorities by encoding key, when prioritizing.
+    priorities: HashMap<EncodingKey, i8>,
+    keys: std::sync::Arc<keyring::KeyStore>,
+    /// Add a stage-by-stage account of failed lookups to their errors.
+    trace_lookups: bool,
+}
+
+impl Build {
+    async fn open(product: &str, opts: &BuildOptions) -> Result<Build> {
+        let verify = opts.verify;
+        let output = opts.output;
+        output.note(format!("verification level: {}", verify));
+        let client: std::sync::Arc<dyn BytesFetcher + Send + Sync> = match &opts.fetcher {
+            Some(fetcher) => fetcher.clone(),
+            None => std::sync::Arc::new(metrics::Metered(opts.http.client()?)),
+        };
+        let (mut version, cdns, source) = resolve_version(
+            client.clone(),
+            &opts.http.ribbit,
+            product,
+            &opts.region,
+            opts.version_source,
+        )
+        .await?;
+        output.note(format!(
+            "using {} version config and {} cdns from {}",
+            version.region, cdns.region, source
+        ));
+        if let Some((build_config, cdn_config)) = opts.pin {
+            output.note(format!(
+                "pinned to build config {:032x} and cdn config {:032x}",
+                build_config, cdn_config
+            ));
+            // The versions row's configs are another build's; the pinned
+            // build's own are in whichever row still serves it, if any.
+            let own = pinned_row(
+                client.clone(),
+                &opts.http.ribbit,
+                source,
+                product,
+                build_config,
+            )
+            .await;
+            if own.is_none() {
+                output.note(
+                    "pinned build is in neither the versions nor the bgdl table; \
+                     opening it without key or product config",
+                );
+            }
+            version = VersionInfo {
+                region: version.region,
+                build_config,
+                cdn_config,
+                key_config: own.as_ref().and_then(|v| v.key_config),
+                product_config: own.as_ref().and_then(|v| v.product_config),
+                build_id: own.as_ref().and_then(|v| v.build_id),
+                name: own.and_then(|v| v.name),
+            };
+        }
+        if let (Some(build_id), Some(name)) = (version.build_id, &version.name) {
+            output.note(format!("build {} ({})", build_id, name));
+        }
+        if let Some(hash) = version.product_config {
+            match client.fetch_product_config(&cdns, hash).await {
+                Ok(config) => {
+                    if let Some(key) = config.decryption_key_name {
+                        output.note(format!("product is encrypted with armadillo key {}", key));
+                    }
+                }
+                Err(e) => warnings::report(
+                    warnings::Issue::ProductConfig,
+                    format!("unable to fetch product config: {:#}", e),
+                )?,
+            }
+        }
+        let (build_config, cdn_config) = (version.build_config, version.cdn_config);
+        let cdn = CdnClient {
+            client,
+            cdn_prefixes: if opts.cdn_urls.is_empty() {
+                cdns.prefixes()
+            } else {
+                opts.cdn_urls.clone()
+            },
+            throttle: tokio::sync::Semaphore::new(5),
+            bench: fetch::HostBench::new(opts.host_failures, opts.host_cool_down),
+            cache: match (&opts.cache, &paths::get().cache) {
+                (None, Some(template)) => {
+                    let vars = paths::Vars {
+                        product: Some(product),
+                        build: None,
+                    };
+                    Some(
+                        std::sync::Arc::new(cache::FsCache::new(paths::expand(template, &vars)?))
+                            as std::sync::Arc<dyn CacheStore + Send + Sync>,
+                    )
+                }
+                (cache, _) => cache.clone(),
+            },
+            limits: opts.limits,
+            retry_budget: opts.retry_budget.clone(),
+        };
+        let progress = progress::Progress::new(output.progress);
+        // Encoding and root decode off the task fetching the indices.
+        let verify_pool = VerifyPool::new(opts.verify_workers);
+        let cdn_client = &cdn;
+        let progress_ref = &progress;
+        let do_cdn_fetch = |tag: &'static str,
+                            hash: u128,
+                            suffix: Option<&'static str>,
+                            range: Option<(usize, usize)>| async move {
+            let data = cdn_client.fetch_cdn_bytes(tag, hash, suffix, range).await?;
+            progress_ref.downloaded(data.len() as u64);
+            Result::<_>::Ok(data)
+        };
+        // Only configs are fetched whole by hash, and builds of products on
+        // one CDN often share them.
+        let shared = &*opts.shared;
+        let cdn_fetch = move |tag: &'static str, hash: u128| {
+            shared
+                .configs
+                .get_or_try_init(hash, move || do_cdn_fetch(tag, hash, None, None))
+        };
+        let reused_indices = shared.indices.reused();
+        let archive_index = async {
+            let config_data = cdn_fetch("config", cdn_config).await?;
+            let config = parse_config(from_utf8(&config_data)?);
+            let hashes = config
+                .get("archives")
+                .context("missing archives in cdninfo")?
+                .split(' ')
+                .map(parse_hash)
+                .collect::<Result<Vec<_>>>()?;
+            let patch_archives = config
+                .get("patch-archives")
+                .copied()
+                .unwrap_or("")
+                .split_whitespace()
+                .map(|h| parse_hash(h).map(ArchiveKey))
+                .collect::<Result<HashSet<_>>>()?;
+            let phase = &progress.phase("indices", hashes.len() as u64);
+            let index_policy = opts.index_policy;
+            let results = futures::future::try_join_all(hashes.into_iter().map(|h| async move {
+                let index = shared
+                    .indices
+                    .get_or_try_init(ArchiveKey(h), || async {
+                        let index = cdn_client
+                            .fetch_index("data", ArchiveKey(h), progress_ref)
+                            .await?;
+                        Ok(std::sync::Arc::new(index))
+                    })
+                    .await;
+                phase.inc(0);
+                match (index, index_policy) {
+                    (Ok(index), _) => Ok(Ok(index)),
+                    (Err(e), IndexPolicy::Skip) => {
+                        warn!("skipping archive index {}: {:#}", ArchiveKey(h), e);
+                        Ok(Err(ArchiveKey(h)))
+                    }
+                    (Err(e), IndexPolicy::Fail) => Err(e),
+                }
+            }))
+            .await?;
+            let mut skipped = Vec::<ArchiveKey>::new();
+            let mut indices = Vec::new();
+            for result in results {
+                match result {
+                    Ok(index) => indices.push(index),
+                    Err(key) => skipped.push(key),
+                }
+            }
+            let (index, conflicts) =
+                archive::merge(indices, opts.duplicate_policy, &patch_archives);
+            for c in &conflicts {
+                trace!(
+                    "{} is in archives {} and {}; using {}",
+                    c.ekey,
+                    c.kept,
+                    c.dropped,
+                    c.kept
+                );
+            }
+            phase.finish();
+            if !conflicts.is_empty() {
+                output.note(format!(
+                    "{} encoding keys are in several archives; kept the {} one",
+                    conflicts.len(),
+                    opts.duplicate_policy
+                ));
+            }
+            let reused = shared.indices.reused() - reused_indices;
+            if reused > 0 {
+                output.note(format!(
+                    "reused {} archive indices from builds opened earlier",
+                    reused
+                ));
+            }
+            if !skipped.is_empty() {
+                output.note(format!(
+                    "skipped {} unusable archive indices",
+                    skipped.len()
+                ));
+            }
+            Result::<_>::Ok((index, skipped))
+        };
+        let encoding_and_root = async {
+            let buildinfo = parse_build_config(&parse_config(from_utf8(
+                &(cdn_fetch("config", build_config).await?),
+            )?))?;
+            let encoding_key = buildinfo.encoding;
+            let encoding_size = buildinfo.encoding_size;
+            let encoding_data = cdn_client
+                .fetch_sized(
+                    encoding_key,
+                    encoding_size.map(|s| s.encoded),
+                    &progress.bytes_phase("encoding", None),
+                )
+                .await?;
+            let (checksum, limits) = (verify.blte_checksum(encoding_key), opts.limits);
+            let encoding = verify_pool
+                .run(move || {
+                    let data = blte::parse(checksum, &encoding_data)?;
+                    check_content_size("encoding", &data, encoding_size.map(|s| s.content))?;
+                    encoding::parse_limited(&data, &limits)
+                })
+                .await?;
+            let root_key = encoding.c2e(buildinfo.root)?;
+            let root_data = cdn_client
+                .fetch_sized(
+                    root_key,
+                    encoding.encoded_size(root_key),
+                    &progress.bytes_phase("root", None),
+                )
+                .await?;
+            let (checksum, root_size) = (
+                verify.blte_checksum(root_key),
+                encoding.content_size(buildinfo.root),
+            );
+            let preferred = opts.preferred_locales.clone();
+            let root = verify_pool
+                .run(move || {
+                    let data = blte::parse(checksum, &root_data)?;
+                    check_content_size("root", &data, root_size)?;
+                    Ok(root::parse_limited(&data, &limits)?.with_preferred_locales(preferred))
+                })
+                .await?;
+            let priorities = match (opts.prioritize, buildinfo.download) {
+                (false, _) => HashMap::new(),
+                (true, None) => {
+                    output.note("build has no download manifest; fetching in walk order");
+                    HashMap::new()
+                }
+                (true, Some(key)) => cdn_client
+                    .fetch_download_manifest(
+                        key,
+                        buildinfo.download_size,
+                        verify,
+                        &progress.bytes_phase("download", None),
+                    )
+                    .await?
+                    .entries
+                    .into_iter()
+                    .map(|e| (e.ekey, e.priority))
+                    .collect(),
+            };
+            Result::<_>::Ok((buildinfo, encoding, root, priorities))
+        };
+        let keys = async {
+            let mut keys = keyring::KeyStore::default();
+            if let Some(hash) = version.key_config {
+                keys.add(
+                    "keyring",
+                    keyring::parse(from_utf8(&(cdn_fetch("config", hash).await?))?)?,
+                );
+                output.note(format!("keyring provides {} keys", keys.len()));
+            }
+            Result::<_>::Ok(keys)
+        };
+        let ((archive_index, skipped_indices), (config, encoding, root, priorities), keys) =
+            futures::future::try_join3(archive_index, encoding_and_root, keys).await?;
+        let build = Build {
+            version,
+            cdns,
+            progress,
+            cdn,
+            verify,
+            verify_pool,
+            config,
+            archive_index,
+            skipped_indices: skipped_indices
+                .into_iter()
+                .map(|archive| (archive, Default::default()))
+                .collect(),
+            encoding,
+            root,
+            priorities,
+            keys: std::sync::Arc::new(keys),
+            trace_lookups: opts.trace_lookups,
+        };
+        summary::add("builds", build_metadata(&build, product));
+        Ok(build)
+    }
+
+    /// Looks for `ekey` in the indices skipped when the build was opened,
+    /// fetching each again once. Returns where it is, if found, and how many
+    /// of them still couldn't be fetched.
+    async fn find_in_skipped(
+        &self,
+        ekey: EncodingKey,
+    ) -> (Option<(ArchiveKey, usize, usize)>, usize) {
+        let mut unavailable = 0;
+        for (archive, index) in &self.skipped_indices {
+            let index = index
+                .get_or_init(|| async {
+                    match self.cdn.fetch_index("data", *archive, &self.progress).await {
+                        Ok(index) => Some(index),
+                        Err(e) => {
+                            warn!("archive index {} is still unusable: {:#}", archive, e);
+                            None
+                        }
+                    }
+                })
+                .await;
+            match index {
+                Some(index) => {
+                    if let Some(&location) = index.map.get(&ekey) {
+                        return (Some(location), unavailable);
+                    }
+                }
+                None => unavailable += 1,
+            }
+        }
+        (None, unavailable)
+    }
+
+    /// Where the encoded content lives, as `(archive, size, offset)`.
+    fn location(&self, ckey: ContentKey) -> Option<(ArchiveKey, usize, usize)> {
+        let ekey = self.chosen_ekey(ckey)?;
+        self.archive_index.map.get(&ekey).copied()
+    }
+
+    /// The download manifest priority of `ckey`; lower is more urgent.
+    fn priority(&self, ckey: ContentKey) -> Option<i8> {
+        let ekeys = self.encoding.ekeys(ckey)?;
+        ekeys.iter().find_map(|e| self.priorities.get(e).copied())
+    }
+
+    /// How `ekey` is encoded; files without an espec are taken to be plain.
+    fn espec(&self, ekey: EncodingKey) -> Result<espec::ESpec> {
+        Ok(self
+            .encoding
+            .espec(ekey)
+            .map(espec::parse)
+            .transpose()?
+            .unwrap_or(espec::ESpec::None))
+    }
+
+    /// A key that `ekey`'s espec names but no keyring provides. Unparsable
+    /// especs are left for the fetch to trip over.
+    fn missing_key(&self, ekey: EncodingKey) -> Option<u64> {
+        self.espec(ekey)
+            .ok()?
+            .encryption_keys()
+            .into_iter()
+            .find(|name| self.keys.get(*name).is_none())
+    }
+
+    /// A missing key that stops every encoding of `ckey` being decrypted.
+    fn undecryptable(&self, ckey: ContentKey) -> Option<u64> {
+        let mut missing = self
+            .encoding
+            .ekeys(ckey)?
+            .iter()
+            .map(|&e| self.missing_key(e));
+        let first = missing.next()??;
+        missing.all(|name| name.is_some()).then_some(first)
+    }
+
+    /// The encoding of `ckey` that gets fetched: the first whose espec
+    /// names no key the keyrings lack, or else the first.
+    fn chosen_ekey(&self, ckey: ContentKey) -> Option<EncodingKey> {
+        let ekeys = self.encoding.ekeys(ckey)?;
+        ekeys
+            .iter()
+            .copied()
+            .find(|&e| self.missing_key(e).is_none())
+            .or_else(|| ekeys.first().copied())
+    }
+
+    /// Returns None for content whose archive index was skipped.
+    async fn fetch_content(&self, ckey: ContentKey) -> Result<Option<Vec<u8>>> {
+        // Without an encoding, c2e says why.
+        let ekey = self
+            .chosen_ekey(ckey)
+            .map_or_else(|| self.encoding.c2e(ckey), Ok)
+            .map_err(|e| self.traced(e, || self.trace_content(ckey)))?;
+        // The espec names any keys up front, so don't download what can't
+        // be decrypted.
+        if let Some(name) = self.undecryptable(ckey) {
+            bail!(
+                "{} is encrypted with key {:016x}, which no keyring provides",
+                ckey,
+                name
+            );
+        }
+        let (archive, size, offset) = match self.archive_index.map.get(&ekey) {
+            Some(&location) => location,
+            None => match self.find_in_skipped(ekey).await {
+                (Some(location), _) => location,
+                // It may be in an index that still can't be read.
+                (None, unavailable) if unavailable > 0 => return Ok(None),
+                (None, _) => {
+                    return Err(self
+                        .traced(anyhow::anyhow!("missing index key for {}", ekey), || {
+                            self.trace_content(ckey)
+                        }))
+                }
+            },
+        };
+        let response = self
+            .cdn
+            .fetch_cdn_bytes("data", archive.0, None, Some((offset, offset + size - 1)))
+            .await?;
+        self.progress.downloaded(response.len() as u64);
+        let checksum = self.verify.blte_checksum(ekey);
+        let full = self.verify == VerifyLevel::Full;
+        let keys = self.keys.clone();
+        let decode = move || decode_content(ckey, checksum, full, &keys, &response);
+        Ok(Some(self.verify_pool.run(decode).await?))
+    }
+
+    /// Resolves either a numeric FileDataID or a file path to a content key,
+    /// picking the root entry for `locale` when given.
+    fn resolve(&self, file: &str, locale: Option<locale::Locale>) -> Result<ContentKey> {
+        let ckey = match file.parse::<u32>() {
+            Ok(fdid) => self.root.f2c_in(FileDataID(fdid), locale),
+            Err(_) => self.root.n2c_in(file, locale),
+        };
+        ckey.map_err(|e| self.traced(e, || self.trace_file(file, locale)))
+    }
+
+    /// Adds the lines of a lookup trace to `err` when tracing is on.
+    fn traced(&self, err: anyhow::Error, trace: impl FnOnce() -> Vec<String>) -> anyhow::Error {
+        if !self.trace_lookups {
+            return err;
+        }
+        err.context(format!("lookup trace:\n  {}", trace().join("\n  ")))
+    }
+
+    /// Walks `file` through root, then on as trace_content does.
+    fn trace_file(&self, file: &str, locale: Option<locale::Locale>) -> Vec<String> {
+        let mut lines = Vec::new();
+        let (entries, chosen) = match file.parse::<u32>() {
+            Ok(fdid) => (
+                self.root.fdid_entries(FileDataID(fdid)),
+                self.root.fdid_entry(FileDataID(fdid), locale),
+            ),
+            Err(_) => {
+                lines.push(format!(
+                    "{} has name hash {:016x}",
+                    file,
+                    hash::name_hash(file)
+                ));
+                (
+                    self.root.name_entries(file),
+                    self.root.name_entry(file, locale),
+                )
+            }
+        };
+        if entries.is_empty() {
+            lines.push("root: no entry for it".to_string());
+            return lines;
+        }
+        lines.push(format!(
+            "root: {} entries, for locales {}",
+            entries.len(),
+            entries
+                .iter()
+                .map(|e| locale::Locale::describe_flags(e.locale_flags))
+                .collect::<Vec<_>>()
+                .join("; ")
+        ));
+        match chosen {
+            None => lines.push(format!(
+                "root: none of them are for {}",
+                locale::Locale::describe(locale)
+            )),
+            Some(e) => {
+                lines.push(format!("root: chose content key {}", e.content_key));
+                lines.extend(self.trace_content(e.content_key));
+            }
+        }
+        lines
+    }
+
+    /// Walks `ckey` through encoding and the archive indices.
+    fn trace_content(&self, ckey: ContentKey) -> Vec<String> {
+        let ekeys = match self.encoding.ekeys(ckey) {
+            Some(ekeys) => ekeys,
+            None => return vec![format!("encoding: no entry for content key {}", ckey)],
+        };
+        let mut lines = Vec::new();
+        let mut archived = 0;
+        for ekey in ekeys {
+            match self.archive_index.map.get(ekey) {
+                Some((archive, _, offset)) => {
+                    archived += 1;
+                    lines.push(format!(
+                        "encoding key {} is in archive {} at offset {}",
+                        ekey, archive, offset
+                    ))
+                }
+                None => lines.push(format!("encoding key {} is in no archive index", ekey)),
+            }
+        }
+        if archived == 0 && !self.skipped_indices.is_empty() {
+            lines.push(format!(
+                "{} archive indices were skipped and may hold it",
+                self.skipped_indices.len()
+            ));
+        }
+        lines
+    }
+
+    async fn fetch_file(&self, file: &str, locale: Option<locale::Locale>) -> Result<Vec<u8>> {
+        self.fetch_content(self.resolve(file, locale)?)
+            .await?
+            .with_context(|| format!("{} is unresolvable", file))
+    }
+
+    /// Fetches an interface manifest db2. Explicit overrides win, then
+    /// lookup by name, then the known FileDataIDs.
+    async fn fetch_manifest(
+        &self,
+        name: &str,
+        fdid: Option<FileDataID>,
+        known: FileDataID,
+    ) -> Result<Vec<u8>> {
+        let ckey = match fdid {
+            Some(fdid) => self.root.f2c(fdid),
+            None => self.root.n2c(name).or_else(|_| self.root.f2c(known)),
+        }?;
+        self.fetch_content(ckey)
+            .await?
+            .with_context(|| format!("{} is unresolvable", name))
+    }
+
+    /// Maps the lowercased path of every interface file to its FileDataID.
+    async fn interface_files(
+        &self,
+        manifest_fdid: Option<FileDataID>,
+    ) -> Result<HashMap<String, FileDataID>> {
+        let data = self
+            .fetch_manifest(MANIFEST_DATA_NAME, manifest_fdid, MANIFEST_DATA_FDID)
+            .await?;
+        Ok(db2::strings_shared(&data, &self.cdn.limits)?
+            .into_iter()
+            .map(|(k, v)| (v.concat().to_lowercase(), FileDataID(k)))
+            .collect())
+    }
+
+    /// Fetches at least the first `len` decoded bytes of a file, using its
+    /// espec to download only the BLTE chunks that cover them. Falls back to
+    /// fetching the whole file when the espec doesn't describe several chunks.
+    async fn fetch_content_prefix(&self, ckey: ContentKey, len: u64) -> Result<Vec<u8>> {
+        let ekey = self
+            .chosen_ekey(ckey)
+            .map_or_else(|| self.encoding.c2e(ckey), Ok)?;
+        let spec = self.espec(ekey)?;
+        let file_size = self
+            .encoding
+            .content_size(ckey)
+            .context("missing content size")?;
+        let plan = espec::plan(&spec, file_size, len);
+        // Content in no index loaded yet, or in no archive at all, is left
+        // to fetch_content, which knows where else to look.
+        let location = self.archive_index.map.get(&ekey);
+        let (archive, _, offset) = match location {
+            Some(location) if plan.chunk_count > 1 && plan.needed < plan.chunk_count => location,
+            _ => {
+                return self
+                    .fetch_content(ckey)
+                    .await?
+                    .context("content is unresolvable")
+            }
+        };
+        let header = self
+            .cdn
+            .fetch_cdn_bytes(
+                "data",
+                archive.0,
+                None,
+                Some((*offset, *offset + plan.header_len - 1)),
+            )
+            .await?;
+        self.progress.downloaded(header.len() as u64);
+        let chunks = blte::parse_header(self.verify.blte_checksum(ekey), &header)?;
+        ensure!(
+            chunks.len() == plan.chunk_count,
+            "espec chunk count mismatch"
+        );
+        let chunks = &chunks[..plan.needed];
+        let start = *offset + plan.header_len;
+        let compressed = chunks.iter().map(|c| c.compressed_size).sum::<usize>();
+        let data = self
+            .cdn
+            .fetch_cdn_bytes(
+                "data",
+                archive.0,
+                None,
+                Some((start, start + compressed - 1)),
+            )
+            .await?;
+        self.progress.downloaded(data.len() as u64);
+        let mut p = &data[..];
+        let mut result = Vec::new();
+        for (index, chunk) in chunks.iter().enumerate() {
+            ensure!(p.len() >= chunk.compressed_size, "truncated chunk data");
+            let (head, tail) = p.split_at(chunk.compressed_size);
+            result.extend_from_slice(&blte::decode_chunk_keyed(
+                chunk,
+                self.verify != VerifyLevel::None,
+                &self.keys,
+                index,
+                head,
+            )?);
+            p = tail;
+        }
+        Ok(result)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq, clap::ValueEnum)]
+enum ReportFormat {
+    #[display("human")]
+    Human,
+    #[display("json")]
+    Json,
+}
+
+fn print_plan(format: ReportFormat, plans: &[archive::ArchivePlan]) -> Result<()> {
+    match format {
+        ReportFormat::Human => {
+            for p in plans {
+                println!(
+                    "archive={} files={} bytes={} ranges={} coalescable={}",
+                    p.archive,
+                    p.files,
+                    p.bytes,
+                    p.ranges,
+                    p.coalescable()
+                );
+            }
+            println!(
+                "archives={} files={} bytes={} ranges={}",
+                plans.len(),
+                plans.iter().map(|p| p.files).sum::<usize>(),
+                plans.iter().map(|p| p.bytes).sum::<usize>(),
+                plans.iter().map(|p| p.ranges).sum::<usize>()
+            );
+        }
+        ReportFormat::Json => {
+            let archives = plans
+                .iter()
+                .map(|p| {
+                    serde_json::json!({
+                        "archive": p.archive.to_string(),
+                        "files": p.files,
+                        "bytes": p.bytes,
+                        "ranges": p.ranges,
+                        "coalescable": p.coalescable(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({ "archives": archives }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+/// What the framexml walk found besides file contents.
+#[derive(Default)]
+struct WalkReport {
+    skipped: usize,
+    /// Files no keyring can decrypt, with a key they need.
+    encrypted: Vec<(String, u64)>,
+    /// Included files root has no entry for, with the file including them.
+    dangling: Vec<(String, String)>,
+    /// Lua and XML files in the walked addon directories that nothing
+    /// includes, as lowercased manifest paths.
+    unreferenced: Vec<String>,
+    /// Files and bytes fetched at each download manifest priority, when
+    /// fetching by priority.
+    priorities: BTreeMap<i8, (usize, usize)>,
+}
+
+struct ProcessOptions {
+    build: BuildOptions,
+    manifest_data_fdid: Option<FileDataID>,
+    manifest_toc_fdid: Option<FileDataID>,
+    /// Print a per-archive download plan before extracting.
+    plan: Option<ReportFormat>,
+    /// Stop after printing the plan.
+    plan_only: bool,
+    /// Print what would be fetched and written instead of doing it.
+    dry_run: bool,
+    /// Rewrite text files in other encodings as plain UTF-8.
+    normalize_text: bool,
+    /// The most bytes of file content to hold in memory at once; past it,
+    /// files are fetched in smaller batches and spooled to disk.
+    max_memory: Option<usize>,
+    /// Where to write the zip, as a path template, `-` meaning stdout; None
+    /// uses the configured export path, or writes it into zips.
+    output: Option<String>,
+    /// Where to write the index.json, as a path template; None writes it
+    /// next to the zip, or next to the export path when the zip is streamed.
+    index: Option<String>,
+    /// Where to upload the finished zip.
+    #[cfg(feature = "upload")]
+    upload: Option<upload::Destination>,
+    /// Also upload each exported file, under a directory named for the product.
+    #[cfg(feature = "upload")]
+    upload_files: bool,
+}
+
+/// Walks the tocs the interface manifest names, and the files they include,
+/// getting each file's content from `fetch_content`, which is given its
+/// path too.
+///
+/// With a `max_memory` ceiling, half of it bounds each batch of fetches, by
+/// the decoded sizes the encoding table gives, and half the files kept in
+/// memory; the rest are spooled to disk.
+async fn walk<F, Fut>(
+    build: &Build,
+    product: &str,
+    fdids: &HashMap<String, FileDataID>,
+    toc_fdid: Option<FileDataID>,
+    max_memory: Option<usize>,
+    fetch_content: F,
+) -> Result<(spool::Files, WalkReport)>
+where
+    F: Fn(&str, ContentKey) -> Fut,
+    Fut: std::future::Future<Output = Result<Option<Vec<u8>>>>,
+{
+    let root = &build.root;
+    let fetch_content = &fetch_content;
+    let mut stack: Vec<String> = db2::strings_limited(
+        &build
+            .fetch_manifest(MANIFEST_TOC_NAME, toc_fdid, MANIFEST_TOC_FDID)
+            .await?,
+        &build.cdn.limits,
+    )?
+    .into_values()
+    .flatten()
+    .chain(["Interface\\FrameXML\\".to_string()])
+    .filter_map(|s| {
+        let dirname = s[..s.len() - 1].split('\\').last()?;
+        let toc1 = format!("{}{}_{}.toc", s, dirname, product);
+        let toc2 = format!("{}{}.toc", s, dirname);
+        root.n2c(&toc1)
+            .and(Ok(toc1))
+            .or_else(|_| root.n2c(&toc2).and(Ok(toc2)))
+            .ok()
+    })
+    .collect();
+    let addon_dirs = stack
+        .iter()
+        .filter_map(|toc| Some(toc[..=toc.rfind('\\')?].to_lowercase()))
+        .collect::<Vec<_>>();
+    let mut referenced = stack
+        .iter()
+        .map(|toc| toc.to_lowercase())
+        .collect::<HashSet<_>>();
+    let phase = &build.progress.phase("files", stack.len() as u64);
+    let mut result = spool::Files::new(max_memory.map(|m| m / 2));
+    let mut report = WalkReport::default();
+    // The first file to include each path, for reporting dangling ones.
+    let mut referrers = HashMap::<String, String>::new();
+    let resolve = |file: &str| resolve_file(root, fdids, file);
+    // Files are fetched a generation at a time, so downloads (and any
+    // background verification) overlap.
+    while !stack.is_empty() {
+        // Files no keyring can decrypt are reported rather than fetched.
+        let mut generation = Vec::new();
+        for file in std::mem::take(&mut stack) {
+            match resolve(&file).and_then(|c| build.undecryptable(c)) {
+                Some(key) => {
+                    warnings::report(
+                        warnings::Issue::SkippedFile,
+                        format!("skipping file encrypted with key {:016x}: {}", key, file),
+                    )?;
+                    report.encrypted.push((file, key));
+                    phase.inc(0);
+                }
+                None => generation.push(file),
+            }
+        }
+        // Requests queue for the CDN throttle in the order they're
+        // made, so the most urgent files go first; files the download
+        // manifest doesn't list go last.
+        generation.sort_by_key(|file| {
+            let priority = resolve(file).and_then(|c| build.priority(c));
+            (priority.is_none(), priority)
+        });
+        let batches = spool::batches(generation, max_memory.map(|m| m as u64 / 2), |file| {
+            resolve(file)
+                .and_then(|c| build.encoding.content_size(c))
+                .unwrap_or(0)
+        });
+        if batches.len() > 1 {
+            info!(
+                "fetching {} batches to stay under the memory ceiling",
+                batches.len()
+            );
+        }
+        for generation in batches {
+            build.progress.expect(
+                generation
+                    .iter()
+                    .filter_map(|file| build.location(resolve(file)?))
+                    .map(|(_, size, _)| size as u64)
+                    .sum(),
+            );
+            let fetched = futures::future::join_all(generation.iter().map(|file| {
+                let ckey = resolve(file);
+                async move {
+                    match ckey {
+                        Some(ckey) => fetch_content(file, ckey)
+                            .inspect(|r| {
+                                phase
+                                    .inc(r.as_ref().map_or(0, |c| c.as_ref().map_or(0, Vec::len))
+                                        as u64)
+                            })
+                            .await
+                            .map(Some),
+                        None => Ok(None),
+                    }
+                }
+            }))
+            .await;
+            for (file, fetched) in generation.into_iter().zip(fetched) {
+                let fetched = match fetched {
+                    Ok(fetched) => fetched,
+                    Err(e) => match encrypted_with(&e) {
+                        Some(key) => {
+                            warnings::report(
+                                warnings::Issue::SkippedFile,
+                                format!("skipping file encrypted with key {:016x}: {}", key, file),
+                            )?;
+                            report.encrypted.push((file, key));
+                            continue;
+                        }
+                        None => return Err(e),
+                    },
+                };
+                let content = match fetched {
+                    Some(fetched) => match fetched {
+                        Some(content) => content,
+                        None => {
+                            warnings::report(
+                                warnings::Issue::SkippedFile,
+                                format!("skipping unresolvable file: {}", file),
+                            )?;
+                            report.skipped += 1;
+                            continue;
+                        }
+                    },
+                    None => {
+                        warnings::report(
+                            warnings::Issue::SkippedFile,
+                            format!("skipping file with no content key: {}", file),
+                        )?;
+                        report.skipped += 1;
+                        phase.inc(0);
+                        let referrer = referrers.get(&file).cloned().unwrap_or_default();
+                        report.dangling.push((file, referrer));
+                        continue;
+                    }
+                };
+                let mut include = |path: String| {
+                    phase.inc_length(1);
+                    referenced.insert(path.to_lowercase());
+                    referrers
+                        .entry(path.clone())
+                        .or_insert_with(|| file.clone());
+                    stack.push(path);
+                };
+                if file.ends_with(".toc") {
+                    text::decode(&content)
+                        .with_context(|| format!("decoding {}", file))?
+                        .lines()
+                        .map(|line| line.trim())
+                        .filter(|line| !line.is_empty())
+                        .filter(|line| !line.starts_with('#'))
+                        .for_each(|line| include(normalize_path(&file, line)));
+                } else if file.ends_with(".xml") {
+                    use xml::reader::{EventReader, XmlEvent::StartElement};
+                    let xml = &content.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&content);
+                    itertools::process_results(
+                        EventReader::new(std::io::Cursor::new(xml)),
+                        |iter| {
+                            iter.filter_map(|e| {
+                                if let StartElement {
+                                    name, attributes, ..
+                                } = e
+                                {
+                                    Some((name.local_name.to_lowercase(), attributes))
+                                } else {
+                                    None
+                                }
+                            })
+                            .filter(|(name, _)| name == "script" || name == "include")
+                            .flat_map(|(_, attrs)| attrs)
+                            .filter(|attr| attr.name.local_name == "file")
+                            .map(|attr| attr.value)
+                            .for_each(|value| include(normalize_path(&file, &value)))
+                        },
+                    )?;
+                }
+                if let Some(priority) = resolve(&file).and_then(|c| build.priority(c)) {
+                    let (files, bytes) = report.priorities.entry(priority).or_default();
+                    *files += 1;
+                    *bytes += content.len();
+                }
+                result.insert(file, content)?;
+            }
+        }
+    }
+    phase.finish();
+    report.unreferenced = fdids
+        .keys()
+        .filter(|path| path.ends_with(".lua") || path.ends_with(".xml"))
+        .filter(|path| addon_dirs.iter().any(|dir| path.starts_with(dir)))
+        .filter(|path| !referenced.contains(*path))
+        .cloned()
+        .collect();
+    Ok((result, report))
+}
+
+/// Lists every file the interface manifest makes reachable, with where it
+/// would be downloaded from, and the zip it would go into. Which of them the
+/// toc walk includes depends on their contents, so all are listed.
+fn print_dry_run(
+    build: &Build,
+    product: &str,
+    fdids: &HashMap<String, FileDataID>,
+    zip: &str,
+) -> Result<()> {
+    let mut paths = fdids.iter().collect::<Vec<_>>();
+    paths.sort();
+    let (mut files, mut unresolved, mut bytes, mut encoded) = (0, 0, 0, 0);
+    for (path, &fdid) in paths {
+        let ckey = build.root.f2c(fdid).ok();
+        let size = ckey.and_then(|c| build.encoding.content_size(c));
+        match (ckey, size, ckey.and_then(|c| build.location(c))) {
+            (Some(ckey), Some(size), Some((archive, esize, _))) => {
+                println!(
+                    "would fetch file={} fdid={} ckey={} size={} archive={} encoded={}",
+                    path, fdid, ckey, size, archive, esize
+                );
+                files += 1;
+                bytes += size;
+                encoded += esize;
+            }
+            _ => {
+                println!("unresolvable file={} fdid={}", path, fdid);
+                unresolved += 1;
+            }
+        }
+    }
+    println!(
+        "product={} files={} unresolvable={} bytes={} encoded_bytes={} zip={}",
+        product, files, unresolved, bytes, encoded, zip
+    );
+    Ok(())
+}
+
+/// How many files and bytes each archive contributes to the walked `files`.
+fn walked_plan(
+    build: &Build,
+    fdids: &HashMap<String, FileDataID>,
+    files: &spool::Files,
+) -> Vec<archive::ArchivePlan> {
+    archive::plan(
+        files
+            .names()
+            .into_iter()
+            .filter_map(|file| resolve_file(&build.root, fdids, file))
+            .filter_map(|ckey| build.location(ckey)),
+    )
+}
+
+/// The content key of an exported file: by name, or else through the
+/// interface manifest's FileDataIDs.
+fn resolve_file(
+    root: &root::Root,
+    fdids: &HashMap<String, FileDataID>,
+    file: &str,
+) -> Option<ContentKey> {
+    root.n2c(file).ok().or_else(|| {
+        fdids
+            .get(&file.to_lowercase())
+            .and_then(|k| root.f2c(*k).ok())
+    })
+}
+
+/// Where each exported file came from in CASC, by its path in the zip.
+fn export_index(
+    build: &Build,
+    fdids: &HashMap<String, FileDataID>,
+    files: &spool::Files,
+    texts: &BTreeMap<String, (text::TextEncoding, bool)>,
+) -> serde_json::Map<String, serde_json::Value> {
+    files
+        .names()
+        .into_iter()
+        .map(|file| {
+            let ckey = resolve_file(&build.root, fdids, file);
+            let fdid = fdids.get(&file.to_lowercase()).copied().or_else(|| {
+                build
+                    .root
+                    .name_entries(file)
+                    .into_iter()
+                    .find(|e| Some(e.content_key) == ckey)
+                    .map(|e| e.fdid)
+            });
+            let location = ckey.and_then(|c| build.location(c));
+            let text = texts.get(file);
+            let normalized = text.is_some_and(|&(_, normalized)| normalized);
+            // Normalized text no longer has the size encoding gives.
+            let size = ckey
+                .and_then(|c| build.encoding.content_size(c))
+                .filter(|_| !normalized)
+                .unwrap_or(files.size(file).unwrap_or(0) as u64);
+            let entry = serde_json::json!({
+                "fdid": fdid.map(|f| f.0),
+                "ckey": ckey.map(|c| c.to_string()),
+                "ekey": ckey.and_then(|c| build.chosen_ekey(c)).map(|e| e.to_string()),
+                "size": size,
+                "encoding": text.map(|(encoding, _)| encoding.to_string()),
+                "normalized": normalized,
+                "archive": location.map(|(archive, _, _)| archive.to_string()),
+                "archive_offset": location.map(|(_, _, offset)| offset),
+                "encoded_size": location.map(|(_, size, _)| size),
+            });
+            (file.clone(), entry)
+        })
+        .collect()
+}
+
+/// The index.json written next to `zip`: wow.zip's is wow.index.json.
+fn index_path(zip: &str) -> String {
+    format!("{}.index.json", zip.strip_suffix(".zip").unwrap_or(zip))
+}
+
+async fn process(product: &str, opts: &ProcessOptions) -> Result<()> {
+    let build = &Build::open(product, &opts.build).await?;
+    let fdids = build.interface_files(opts.manifest_data_fdid).await?;
+    // Without an export to walk for it, the plan comes from a walk that
+    // fetches only the tocs and xml, which are all that decide what's
+    // included.
+    if let Some(format) = opts.plan.filter(|_| opts.plan_only || opts.dry_run) {
+        let (walked, _) = walk(
+            build,
+            product,
+            &fdids,
+            opts.manifest_toc_fdid,
+            opts.max_memory,
+            |file: &str, ckey: ContentKey| {
+                let follow = file.ends_with(".toc") || file.ends_with(".xml");
+                async move {
+                    match follow {
+                        true => build.fetch_content(ckey).await,
+                        false => Ok(Some(Vec::new())),
+                    }
+                }
+            },
+        )
+        .await?;
+        print_plan(format, &walked_plan(build, &fdids, &walked))?;
+        if opts.plan_only {
+            return Ok(());
+        }
+    }
+    let vars = path_vars(build, product);
+    let export = paths::get()
+        .export
+        .as_deref()
+        .unwrap_or("zips/{product}.zip");
+    let path = paths::expand(opts.output.as_deref().unwrap_or(export), &vars)?;
+    if opts.dry_run {
+        return print_dry_run(build, product, &fdids, &path);
+    }
+    let to_stdout = path == "-";
+    // A streamed zip has no path to put the index next to, so it goes
+    // where the zip would have been written.
+    let index_file = match &opts.index {
+        Some(template) => paths::expand(template, &vars)?,
+        None if to_stdout => index_path(&paths::expand(export, &vars)?),
+        None => index_path(&path),
+    };
+    for file in [&path, &index_file] {
+        if file == "-" {
+            continue;
+        }
+        if let Some(dir) = std::path::Path::new(file).parent() {
+            ensuredir(dir)?;
+        }
+    }
+    let (mut files, mut report) = walk(
+        build,
+        product,
+        &fdids,
+        opts.manifest_toc_fdid,
+        opts.max_memory,
+        |_: &str, ckey| build.fetch_content(ckey),
+    )
+    .await?;
+    if let Some(format) = opts.plan {
+        print_plan(format, &walked_plan(build, &fdids, &files))?;
+    }
+    // The detected encoding of each text file, and whether it was
+    // normalized to UTF-8.
+    let mut texts = BTreeMap::new();
+    let names = files.names().into_iter().cloned().collect::<Vec<_>>();
+    for file in names {
+        if ![".lua", ".xml", ".toc"]
+            .iter()
+            .any(|ext| file.to_lowercase().ends_with(ext))
+        {
+            continue;
+        }
+        let content = files.get(&file)?.context("file vanished")?;
+        let encoding = text::detect(&content);
+        if encoding == text::TextEncoding::Utf8 {
+            texts.insert(file, (encoding, false));
+            continue;
+        }
+        let decoded = opts.normalize_text.then(|| text::decode(&content));
+        drop(content);
+        let normalized = match decoded {
+            Some(Ok(decoded)) => {
+                files.insert(file.clone(), decoded.into_bytes())?;
+                true
+            }
+            Some(Err(e)) => {
+                warnings::report(
+                    warnings::Issue::TextNormalize,
+                    format!("unable to normalize {}: {:#}", file, e),
+                )?;
+                false
+            }
+            None => false,
+        };
+        texts.insert(file, (encoding, normalized));
+    }
+    let index = export_index(build, &fdids, &files, &texts);
+    // Only text that wasn't plain UTF-8 is worth reporting.
+    let encodings = texts
+        .iter()
+        .filter(|(_, (encoding, _))| *encoding != text::TextEncoding::Utf8)
+        .collect::<Vec<_>>();
+    let file_count = files.len();
+    let bytes = files.bytes();
+    if files.spooled() > 0 {
+        opts.build.output.note(format!(
+            "spooled {} of {} files to disk to stay under --max-memory",
+            files.spooled(),
+            file_count
+        ));
+    }
+    let sums = checksums(&files)?.into_bytes();
+    files.insert("CHECKSUMS.sha256".to_string(), sums)?;
+    files.insert(
+        BUILD_METADATA_NAME.to_string(),
+        serde_json::to_vec_pretty(&build_metadata(build, product))?,
+    )?;
+    files.insert(
+        BUILD_INFO_NAME.to_string(),
+        build_info(&build.version, &build.cdns, build.config.install, product).into_bytes(),
+    )?;
+    // Written synchronously, so an interrupt can't land mid-write. The zip
+    // is streamed out rather than built in memory, so spooled files never
+    // all come back at once.
+    if to_stdout {
+        let stdout = std::io::stdout().lock();
+        // Stdout can't seek back to fill in sizes, so entries are followed
+        // by data descriptors instead.
+        std::io::Write::flush(&mut write_zip(zip::ZipWriter::new_stream(stdout), &files)?)?;
+    } else {
+        write_zip_file(std::path::Path::new(&path), &files)?;
+    }
+    shutdown::write_file(
+        std::path::Path::new(&index_file),
+        &serde_json::to_vec_pretty(&index)?,
+    )
+    .context("index writing")?;
+    if to_stdout && opts.index.is_none() {
+        opts.build
+            .output
+            .note(format!("wrote the export index to {}", index_file));
+    }
+    #[cfg(feature = "upload")]
+    if let Some(dest) = &opts.upload {
+        let name = format!("{}.zip", product);
+        let uploaded = if to_stdout {
+            // The zip went to stdout, so a copy is written to upload from.
+            // It goes in the spool, which is removed however the export
+            // ends, even interrupted mid-upload.
+            let local = files.scratch_path(&name)?;
+            std::fs::File::create(&local)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| {
+                    let zip = zip::ZipWriter::new(std::io::BufWriter::new(file));
+                    Ok(std::io::Write::flush(&mut write_zip(zip, &files)?)?)
+                })
+                .with_context(|| format!("writing {}", local.display()))?;
+            let uploaded = dest.put_file_streamed(&name, &local).await?;
+            let _ = std::fs::remove_file(&local);
+            uploaded
+        } else {
+            dest.put_file_streamed(&name, std::path::Path::new(&path))
+                .await?
+        };
+        opts.build.output.note(format!("uploaded {}", uploaded));
+        if opts.upload_files {
+            dest.put_all(product, &files).await?;
+            opts.build
+                .output
+                .note(format!("uploaded {} files", files.len()));
+        }
+    }
+    let export = serde_json::json!({
+        "product": product,
+        "zip": path,
+        "files": file_count,
+        "bytes": bytes,
+        "skipped": report.skipped,
+        "encrypted": report
+            .encrypted
+            .iter()
+            .map(|(file, key)| serde_json::json!({"file": file, "key": format!("{:016x}", key)}))
+            .collect::<Vec<_>>(),
+        "dangling": report
+            .dangling
+            .iter()
+            .map(|(file, referrer)| serde_json::json!({"file": file, "referrer": referrer}))
+            .collect::<Vec<_>>(),
+        "text": encodings
+            .iter()
+            .map(|(file, (encoding, normalized))| {
+                serde_json::json!({
+                    "file": file,
+                    "encoding": encoding.to_string(),
+                    "normalized": normalized,
+                })
+            })
+            .collect::<Vec<_>>(),
+    });
+    // `export` describes the last product exported, as it did before runs
+    // could export several; `exports` lists them all.
+    summary::add("exports", export.clone());
+    summary::set("export", export);
+    // The report moves to stderr when stdout carries the zip. Quiet runs
+    // print only the first line, the machine-readable summary; the report
+    // file still gets every line.
+    let mut lines = Vec::new();
+    let quiet = opts.build.output.quiet;
+    let mut say = |line: String| {
+        if !quiet || lines.is_empty() {
+            if to_stdout {
+                eprintln!("{}", line)
+            } else {
+                println!("{}", line)
+            }
+        }
+        lines.push(line);
+    };
+    say(format!(
+        "product={} files={} skipped={} encrypted={} bytes={} zip={}",
+        product,
+        file_count,
+        report.skipped,
+        report.encrypted.len(),
+        bytes,
+        path
+    ));
+    if let Some(build_id) = build.version.build_id {
+        say(format!(
+            "build id={} version={} region={}",
+            build_id,
+            build.version.name.as_deref().unwrap_or_default(),
+            build.version.region
+        ));
+    }
+    report.encrypted.sort();
+    for (file, key) in report.encrypted {
+        say(format!("encrypted file={} key={:016x}", file, key));
+    }
+    report.dangling.sort();
+    for (file, referrer) in report.dangling {
+        say(format!("dangling file={} from={}", file, referrer));
+    }
+    report.unreferenced.sort();
+    for file in report.unreferenced {
+        say(format!("unreferenced file={}", file));
+    }
+    for (priority, (files, bytes)) in report.priorities {
+        say(format!(
+            "priority={} files={} bytes={}",
+            priority, files, bytes
+        ));
+    }
+    for (file, (encoding, normalized)) in encodings {
+        say(format!(
+            "text file={} encoding={} normalized={}",
+            file, encoding, normalized
+        ));
+    }
+    for (issue, msg) in warnings::take_collected() {
+        say(format!("warning issue={} {}", issue, msg));
+    }
+    if let Some(template) = &paths::get().report {
+        let report = std::path::PathBuf::from(paths::expand(template, &vars)?);
+        if let Some(dir) = report.parent() {
+            ensuredir(dir)?;
+        }
+        lines.push(String::new());
+        shutdown::write_file(&report, lines.join("\n").as_bytes())
+            .with_context(|| format!("writing {}", report.display()))?;
+    }
+    Ok(())
+}
+
+/// What output path templates are filled in with for `build`.
+fn path_vars<'a>(build: &Build, product: &'a str) -> paths::Vars<'a> {
+    paths::Vars {
+        product: Some(product),
+        build: Some(build.version.build_id.map_or_else(
+            || format!("{:032x}", build.version.build_config),
+            |id| id.to_string(),
+        )),
+    }
+}
+
+/// Creates `dir` and its parents, unless it's the current directory.
+fn ensuredir(dir: &std::path::Path) -> Result<()> {
+    if dir.as_os_str().is_empty() {
+        return Ok(());
+    }
+    match std::fs::metadata(dir).map_or(None, |m| Some(m.is_dir())) {
+        Some(true) => Ok(()),
+        Some(false) => bail!("{} is not a directory", dir.display()),
+        None => {
+            trace!("creating directory {}", dir.display());
+            std::fs::create_dir_all(dir).context(format!("error creating {}", dir.display()))
+        }
+    }
+}
+
+#[derive(clap::Parser)]
+#[clap(version, about)]
+struct Cli {
+    #[clap(subcommand)]
+    command: CliCommands,
+    /// Logs more; may be repeated, up to -vvvv for trace.
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Log levels by module, as module=level, e.g. blte=trace,archive=warn;
+    /// a bare level sets the default that -v otherwise does.
+    #[clap(long = "log-level", global = true, value_delimiter = ',', value_parser = logging::parse_directive)]
+    log_levels: Vec<(Option<String>, log::LevelFilter)>,
+    /// Suppresses progress bars and informational output.
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// Suppresses progress bars.
+    #[clap(long, global = true)]
+    no_progress: bool,
+    /// Also writes trace-level JSON logs to this file.
+    #[clap(long, global = true)]
+    log_file: Option<std::path::PathBuf>,
+    /// Size in bytes at which the log file is rotated.
+    #[clap(long, global = true, default_value_t = 10 << 20)]
+    log_file_max_size: u64,
+    /// Number of rotated log files to keep.
+    #[clap(long, global = true, default_value_t = 3)]
+    log_file_keep: usize,
+    /// What to do about a class of recoverable issue, as issue=action with
+    /// actions warn, collect (into the final report) or fail; may be
+    /// repeated. Issues are mirror-fetch, skipped-file, product-config,
+    /// text-normalize and version-fallback.
+    #[clap(long = "on", global = true, value_parser = warnings::parse_rule)]
+    warning_rules: Vec<(warnings::Issue, warnings::Action)>,
+    /// Reads output path templates from this file: export, cache, state and
+    /// report, as key = value lines, with {product}, {build} and {date}
+    /// filled in.
+    #[clap(long, global = true)]
+    config: Option<std::path::PathBuf>,
+    /// Writes a JSON summary of the run here when it ends, however it ends:
+    /// its arguments, the builds it opened, the files it wrote, bytes
+    /// downloaded, warnings, what an export left out, and how long it took.
+    #[clap(long, global = true)]
+    summary_out: Option<std::path::PathBuf>,
+}
+
+impl Cli {
+    fn output(&self) -> OutputOptions {
+        OutputOptions {
+            quiet: self.quiet,
+            progress: !self.quiet && !self.no_progress,
+        }
+    }
+}
+
+#[derive(clap::Subcommand)]
+enum CliCommands {
+    #[clap(name = "framexml")]
+    FrameXml(CliFrameXmlArgs),
+    #[clap(name = "ribbit")]
+    Ribbit(CliRibbitArgs),
+    #[clap(name = "product-config")]
+    ProductConfig(CliProductConfigArgs),
+    /// Prints a product's version rows from any version source.
+    #[clap(name = "versions")]
+    Versions(CliVersionsArgs),
+    /// Lists the files the launcher background-downloads for the next build.
+    #[clap(name = "bgdl")]
+    Bgdl(CliBgdlArgs),
+    #[clap(name = "db2")]
+    Db2(CliDb2Args),
+    #[clap(name = "hash")]
+    Hash(CliHashArgs),
+    #[clap(name = "blte")]
+    Blte(CliBlteArgs),
+    #[clap(name = "root")]
+    Root(CliRootArgs),
+    #[clap(name = "encoding")]
+    Encoding(CliEncodingArgs),
+    #[clap(name = "archive")]
+    Archive(CliArchiveArgs),
+    #[clap(name = "patch")]
+    Patch(CliPatchArgs),
+    #[clap(name = "install")]
+    Install(CliInstallArgs),
+    /// Summarizes a build's root, encoding and archives in one go.
+    #[clap(name = "report")]
+    Report(CliReportArgs),
+    /// Shows how a file resolves, from name hash down to archive offset.
+    #[clap(name = "fileinfo")]
+    FileInfo(CliFileInfoArgs),
+    /// Compares the files of two products by content key, e.g. wow and wowt.
+    #[clap(name = "diff")]
+    Diff(CliDiffArgs),
+    /// Checks a zip framexml wrote against a build, downloading only the
+    /// files the zip doesn't already hold.
+    #[clap(name = "verify-export")]
+    VerifyExport(CliVerifyExportArgs),
+    /// Watches Ribbit for new builds and runs notification hooks.
+    #[clap(name = "monitor")]
+    Monitor(CliMonitorArgs),
+    /// Lists every product in the Ribbit summary with its seqns.
+    #[clap(name = "products")]
+    Products(CliProductsArgs),
+    /// Shows which build a product serves now: version, configs, root and
+    /// encoding keys, and archive counts.
+    #[clap(name = "buildinfo")]
+    BuildInfo(CliBuildInfoArgs),
+}
+
+#[derive(clap::Args)]
+struct CliBuildInfoArgs {
+    #[clap(value_parser)]
+    product: String,
+    #[clap(long, default_value = "us")]
+    region: String,
+    #[clap(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+    #[clap(long, value_enum, default_value_t = VersionSourceKind::HttpV1)]
+    version_source: VersionSourceKind,
+    #[clap(flatten)]
+    http: CliHttpArgs,
+}
+
+/// Only the version tables and the build and CDN configs are fetched, so
+/// this answers in a couple of round trips.
+async fn buildinfo(args: &CliBuildInfoArgs) -> Result<()> {
+    let http = args.http.options();
+    let client: std::sync::Arc<dyn BytesFetcher + Send + Sync> =
+        std::sync::Arc::new(metrics::Metered(http.client()?));
+    let (version, cdns, _) = resolve_version(
+        client.clone(),
+        &http.ribbit,
+        &args.product,
+        &args.region,
+        args.version_source,
+    )
+    .await?;
+    let cdn = CdnClient {
+        client,
+        cdn_prefixes: cdns.prefixes(),
+        throttle: tokio::sync::Semaphore::new(5),
+        bench: fetch::HostBench::default(),
+        cache: None,
+        limits: limits::Limits::default(),
+        retry_budget: Default::default(),
+    };
+    let (build_data, cdn_data) = futures::future::try_join(
+        cdn.fetch_cdn_bytes("config", version.build_config, None, None),
+        cdn.fetch_cdn_bytes("config", version.cdn_config, None, None),
+    )
+    .await?;
+    let build_config = parse_config(from_utf8(&build_data)?);
+    let parsed = parse_build_config(&build_config)?;
+    let encoding_ckey = build_config
+        .get("encoding")
+        .and_then(|s| s.split(' ').next())
+        .map(parse_hash)
+        .transpose()?
+        .map(ContentKey);
+    let cdn_config = parse_config(from_utf8(&cdn_data)?);
+    let count = |field: &str| {
+        cdn_config
+            .get(field)
+            .map_or(0, |s| s.split_whitespace().count())
+    };
+    let hash = |h: Option<u128>| h.map(|h| format!("{:032x}", h));
+    let info = serde_json::json!({
+        "product": args.product,
+        "region": version.region,
+        "version": version.name,
+        "build_id": version.build_id,
+        "build_config": format!("{:032x}", version.build_config),
+        "cdn_config": format!("{:032x}", version.cdn_config),
+        "product_config": hash(version.product_config),
+        "root": parsed.root.to_string(),
+        "encoding_ckey": encoding_ckey.map(|c| c.to_string()),
+        "encoding_ekey": parsed.encoding.to_string(),
+        "archives": count("archives"),
+        "patch_archives": count("patch-archives"),
+    });
+    match args.format {
+        ReportFormat::Human => {
+            let field = |name: &str| match &info[name] {
+                serde_json::Value::String(s) => s.clone(),
+                serde_json::Value::Null => "-".to_string(),
+                v => v.to_string(),
+            };
+            println!(
+                "{} {} ({}), build {}",
+                args.product,
+                field("version"),
+                version.region,
+                field("build_id")
+            );
+            println!("build config:   {}", field("build_config"));
+            println!("cdn config:     {}", field("cdn_config"));
+            println!("product config: {}", field("product_config"));
+            println!("root:           {}", field("root"));
+            println!(
+                "encoding:       {} (ekey {})",
+                field("encoding_ckey"),
+                field("encoding_ekey")
+            );
+            println!(
+                "archives:       {} ({} patch)",
+                field("archives"),
+                field("patch_archives")
+            );
+        }
+        ReportFormat::Json => println!("{}", serde_json::to_string_pretty(&info)?),
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliProductsArgs {
+    /// Only list products matching this pattern, where * matches anything,
+    /// e.g. wow*; may be repeated.
+    #[clap(long)]
+    only: Vec<String>,
+    #[clap(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+}
+
+/// Matches `name` against a pattern in which `*` stands for any run of
+/// characters.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    let last = match parts.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+/// Products are listed in name order, with `-` for seqns the summary
+/// doesn't give. Rows with flags the summary parser doesn't know are listed
+/// as flags=seqn under `other`.
+async fn products(args: &CliProductsArgs) -> Result<()> {
+    let summary = tokio::task::spawn_blocking(|| ribbit::Ribbit::new().summary()).await??;
+    let rows = summary
+        .entries
+        .iter()
+        .filter(|(product, _)| {
+            args.only.is_empty() || args.only.iter().any(|p| glob_match(p, product))
+        })
+        .collect::<BTreeMap<_, _>>();
+    match args.format {
+        ReportFormat::Human => {
+            let seqn = |n: Option<u32>| n.map_or_else(|| "-".to_string(), |n| n.to_string());
+            let width = rows.keys().map(|p| p.len()).max().unwrap_or(0).max(7);
+            println!(
+                "{:<width$}  {:>10}  {:>10}  {:>10}  other",
+                "product",
+                "versions",
+                "cdns",
+                "bgdl",
+                width = width
+            );
+            for (product, entry) in &rows {
+                let other = entry
+                    .unknown
+                    .iter()
+                    .map(|(flags, n)| format!("{}={}", flags, n))
+                    .collect::<Vec<_>>();
+                println!(
+                    "{:<width$}  {:>10}  {:>10}  {:>10}  {}",
+                    product,
+                    seqn(entry.seqn),
+                    seqn(entry.cdn),
+                    seqn(entry.bgdl),
+                    if other.is_empty() {
+                        "-".to_string()
+                    } else {
+                        other.join(",")
+                    },
+                    width = width
+                );
+            }
+        }
+        ReportFormat::Json => {
+            let products = rows
+                .iter()
+                .map(|(product, entry)| {
+                    serde_json::json!({
+                        "product": product,
+                        "versions": entry.seqn,
+                        "cdns": entry.cdn,
+                        "bgdl": entry.bgdl,
+                        "other": entry.unknown,
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "seqn": summary.seqn,
+                    "products": products,
+                }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliMonitorArgs {
+    /// Where seqn and build state is persisted between runs; required
+    /// unless the config sets a state path.
+    #[clap(long)]
+    state_dir: Option<std::path::PathBuf>,
+    /// Seconds between polls of the Ribbit summary.
+    #[clap(long, default_value_t = 60)]
+    interval: u64,
+    /// Only watch these products; may be repeated.
+    #[clap(long)]
+    product: Vec<String>,
+    /// Shell command to run for each new build, as with ribbit check.
+    #[clap(long)]
+    exec: Option<String>,
+    /// URL to POST a JSON description of each new build to.
+    #[clap(long)]
+    webhook: Option<String>,
+    /// Address to serve Prometheus metrics on, e.g. 127.0.0.1:9100.
+    #[clap(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+}
+
+/// Polls Ribbit forever, reporting new builds through the configured hooks.
+/// Failures are logged and retried with backoff rather than ending the loop.
+async fn monitor(args: &CliMonitorArgs) -> Result<()> {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::task::spawn_blocking;
+
+    let state_dir = paths::resolve(
+        args.state_dir.as_deref(),
+        paths::get().state.as_deref(),
+        &paths::Vars::default(),
+    )?
+    .context("monitor needs --state-dir or a configured state path")?;
+    let state = hooks::BuildState::new(&state_dir)?;
+    if let Some(addr) = args.metrics_addr {
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(addr).await {
+                warn!("metrics endpoint failed: {:#}", e);
+            }
+        });
+    }
+    let client = reqwest::Client::new();
+    // Ribbit and the exec hook block, so they run off the runtime's threads.
+    // Versions responses are cached by seqn, so a product whose seqn was
+    // fetched but never recorded isn't asked for again.
+    let ribbit = Arc::new(Mutex::new(
+        ribbit::Ribbit::new().with_cache_dir(Some(state_dir.join("ribbit"))),
+    ));
+    let interval = Duration::from_secs(args.interval);
+    let mut failures = 0u32;
+    loop {
+        let delay = interval
+            .saturating_mul(1 << failures.min(4))
+            .min(Duration::from_secs(15 * 60));
+        let summary = {
+            let ribbit = ribbit.clone();
+            spawn_blocking(move || ribbit.lock().expect("ribbit lock").summary()).await?
+        };
+        let summary = match summary {
+            Ok(summary) => summary,
+            Err(e) => {
+                failures += 1;
+                warn!("ribbit summary failed ({} in a row): {:#}", failures, e);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+        };
+        failures = 0;
+        if state.seqn(None) != Some(summary.seqn) {
+            let mut complete = true;
+            for (product, entry) in &summary.entries {
+                if !args.product.is_empty() && !args.product.contains(product) {
+                    continue;
+                }
+                let seqn = match entry.seqn {
+                    Some(seqn) if state.seqn(Some(product)) != Some(seqn) => seqn,
+                    _ => continue,
+                };
+                let versions = {
+                    let (ribbit, product) = (ribbit.clone(), product.clone());
+                    spawn_blocking(move || {
+                        ribbit
+                            .lock()
+                            .expect("ribbit lock")
+                            .versions_at(&product, seqn)
+                    })
+                    .await?
+                };
+                let versions = match versions {
+                    Ok(versions) => versions,
+                    Err(e) => {
+                        warn!("ribbit versions for {} failed: {:#}", product, e);
+                        complete = false;
+                        continue;
+                    }
+                };
+                if let Some(build) = hooks::newest_build(&versions) {
+                    if state.update(product, build)? {
+                        metrics::inc(&metrics::METRICS.build_changes, 1);
+                        info!("{} new build {} ({})", product, build.build_id, build.name);
+                        if let Some(exec) = &args.exec {
+                            let (exec, product, build) =
+                                (exec.clone(), product.clone(), build.clone());
+                            let ran =
+                                spawn_blocking(move || hooks::run_exec(&exec, &product, &build))
+                                    .await?;
+                            if let Err(e) = ran {
+                                warn!("exec hook failed: {:#}", e);
+                            }
+                        }
+                        if let Some(url) = &args.webhook {
+                            if let Err(e) = hooks::post_webhook(&client, url, product, build).await
+                            {
+                                warn!("webhook failed: {:#}", e);
+                            }
+                        }
+                    }
+                }
+                state.set_seqn(Some(product), seqn)?;
+            }
+            // Leave the summary seqn unrecorded so failed products are
+            // retried on the next poll.
+            if complete {
+                state.set_seqn(None, summary.seqn)?;
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+#[derive(clap::Args)]
+struct CliHashArgs {
+    #[clap(subcommand)]
+    command: CliHashCommands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliHashCommands {
+    /// Prints the root name hash of a file path.
+    #[clap(name = "name")]
+    Name(CliHashNameArgs),
+    /// Prints the md5, lookup3 and CRC-32 of a file's contents.
+    #[clap(name = "file")]
+    File(CliHashFileArgs),
+}
+
+#[derive(clap::Args)]
+struct CliHashNameArgs {
+    #[clap(value_parser)]
+    path: String,
+}
+
+#[derive(clap::Args)]
+struct CliHashFileArgs {
+    #[clap(value_parser)]
+    path: std::path::PathBuf,
+}
+
+#[derive(clap::Args)]
+struct CliRootArgs {
+    #[clap(subcommand)]
+    command: CliRootCommands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliRootCommands {
+    /// Prints every root entry with its name hash and flags.
+    #[clap(name = "dump")]
+    Dump(CliRootDumpArgs),
+    /// Counts root entries, and files whose entries disagree on content.
+    #[clap(name = "stats")]
+    Stats(CliRootStatsArgs),
+    /// Counts the name hashes a listfile names, and lists the rest.
+    #[clap(name = "coverage")]
+    Coverage(CliRootCoverageArgs),
+    /// Writes every root entry to a SQLite database, with indices on the
+    /// FileDataID, content key and name hash.
+    #[cfg(feature = "sqlite")]
+    #[clap(name = "export")]
+    Export(CliRootExportArgs),
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum DumpFormat {
+    Csv,
+    Json,
+}
+
+#[derive(clap::Args)]
+struct CliEncodingArgs {
+    #[clap(subcommand)]
+    command: CliEncodingCommands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliEncodingCommands {
+    /// Writes the encoding table's content keys with their encoding keys,
+    /// sizes and especs, optionally filtered, as CSV or Parquet.
+    #[clap(name = "export")]
+    Export(CliEncodingExportArgs),
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum TableFormat {
+    Csv,
+    /// Needs a build with the parquet feature, and --output.
+    Parquet,
+}
+
+#[derive(clap::Args)]
+struct CliEncodingExportArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    #[clap(long, value_enum, default_value_t = TableFormat::Csv)]
+    format: TableFormat,
+    /// Where to write the table; CSV goes to stdout without it.
+    #[clap(short, long)]
+    output: Option<std::path::PathBuf>,
+    /// Only files of at least this many decoded bytes.
+    #[clap(long)]
+    min_size: Option<u64>,
+    /// Only files of at most this many decoded bytes.
+    #[clap(long)]
+    max_size: Option<u64>,
+    /// Only files whose espec matches this pattern, in which `*` stands
+    /// for any run of characters, e.g. `b:*z*`.
+    #[clap(long)]
+    espec: Option<String>,
+}
+
+/// A row of `encoding export`. The encoded size and espec are those of the
+/// first encoding key, which fetches use.
+struct EncodingRow<'a> {
+    ckey: ContentKey,
+    ekeys: &'a [EncodingKey],
+    size: u64,
+    encoded_size: Option<u64>,
+    espec: Option<&'a str>,
+}
+
+/// The rows of `encoding` that pass `args`' filters, by content key.
+fn encoding_rows<'a>(
+    encoding: &'a encoding::Encoding,
+    args: &CliEncodingExportArgs,
+) -> Vec<EncodingRow<'a>> {
+    let mut rows = encoding
+        .entries()
+        .filter(|(_, _, size)| args.min_size.map_or(true, |min| *size >= min))
+        .filter(|(_, _, size)| args.max_size.map_or(true, |max| *size <= max))
+        .map(|(ckey, ekeys, size)| {
+            let first = ekeys.first().copied();
+            EncodingRow {
+                ckey,
+                ekeys,
+                size,
+                encoded_size: first.and_then(|e| encoding.encoded_size(e)),
+                espec: first.and_then(|e| encoding.espec(e)),
+            }
+        })
+        .filter(|row| match &args.espec {
+            Some(pattern) => row.espec.is_some_and(|espec| glob_match(pattern, espec)),
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    rows.sort_by_key(|row| row.ckey);
+    rows
+}
+
+/// Quotes a CSV field if it needs it, as RFC 4180 does; especs and file
+/// names can hold commas.
+fn csv_field(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+fn write_encoding_csv(mut out: impl std::io::Write, rows: &[EncodingRow]) -> Result<()> {
+    use std::io::Write;
+    writeln!(out, "ckey,ekeys,size,encoded_size,espec")?;
+    for row in rows {
+        writeln!(
+            out,
+            "{},{},{},{},{}",
+            row.ckey,
+            row.ekeys
+                .iter()
+                .map(|e| e.to_string())
+                .collect::<Vec<_>>()
+                .join(" "),
+            row.size,
+            row.encoded_size.map(|n| n.to_string()).unwrap_or_default(),
+            csv_field(row.espec.unwrap_or_default())
+        )?;
+    }
+    Ok(out.flush()?)
+}
+
+async fn encoding_export(args: &CliEncodingExportArgs, output: OutputOptions) -> Result<()> {
+    let path = match (args.format, &args.output) {
+        (TableFormat::Csv, None) => None,
+        (TableFormat::Parquet, None) => bail!("parquet output needs --output"),
+        (_, Some(path)) => Some(path),
+    };
+    #[cfg(not(feature = "parquet"))]
+    ensure!(
+        !matches!(args.format, TableFormat::Parquet),
+        "parquet output needs a build with --features parquet"
+    );
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let rows = encoding_rows(&build.encoding, args);
+    let path = match path {
+        Some(path) => path,
+        None => return write_encoding_csv(std::io::stdout().lock(), &rows),
+    };
+    match args.format {
+        TableFormat::Csv => shutdown::write_with(path, |file| {
+            write_encoding_csv(std::io::BufWriter::new(file), &rows)
+        })?,
+        #[cfg(feature = "parquet")]
+        TableFormat::Parquet => {
+            shutdown::write_with(path, |file| parquet_file::write_encoding(file, &rows))?
+        }
+        #[cfg(not(feature = "parquet"))]
+        TableFormat::Parquet => unreachable!("checked before opening the build"),
+    }
+    output.note(format!(
+        "wrote {} of {} rows to {}",
+        rows.len(),
+        build.encoding.len(),
+        path.display()
+    ));
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliRootDumpArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    #[clap(long, value_enum, default_value_t = DumpFormat::Csv)]
+    format: DumpFormat,
+    /// Listfile of fdid;path lines to fill in names from.
+    #[clap(long)]
+    listfile: Option<std::path::PathBuf>,
+}
+
+fn write_root_csv(
+    mut out: impl std::io::Write,
+    entries: impl Iterator<Item = root::Entry>,
+    names: &HashMap<FileDataID, String>,
+) -> Result<()> {
+    use std::io::Write;
+    writeln!(out, "fdid,ckey,name_hash,content_flags,locale_flags,name")?;
+    for e in entries {
+        writeln!(
+            out,
+            "{},{},{},{:08x},{:08x},{}",
+            e.fdid,
+            e.content_key,
+            e.name_hash
+                .map(|h| format!("{:016x}", h))
+                .unwrap_or_default(),
+            e.content_flags,
+            e.locale_flags,
+            csv_field(names.get(&e.fdid).map_or("", String::as_str))
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+async fn root_dump(args: &CliRootDumpArgs, output: OutputOptions) -> Result<()> {
+    let names = match &args.listfile {
+        Some(path) => listfile::parse(
+            &tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("reading {}", path.display()))?,
+        )?,
+        None => HashMap::new(),
+    };
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let entries = build.root.entries();
+    match args.format {
+        DumpFormat::Csv => write_root_csv(std::io::stdout().lock(), entries, &names)?,
+        DumpFormat::Json => {
+            let rows = entries
+                .map(|e| {
+                    serde_json::json!({
+                        "fdid": u32::from(e.fdid),
+                        "ckey": e.content_key.to_string(),
+                        "name_hash": e.name_hash.map(|h| format!("{:016x}", h)),
+                        "content_flags": e.content_flags,
+                        "locale_flags": e.locale_flags,
+                        "name": names.get(&e.fdid),
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "sqlite")]
+#[derive(clap::Args)]
+struct CliRootExportArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    /// The database to write; one already there is replaced.
+    #[clap(long)]
+    sqlite: std::path::PathBuf,
+}
+
+#[cfg(feature = "sqlite")]
+async fn root_export(args: &CliRootExportArgs, output: OutputOptions) -> Result<()> {
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let rows = sqlite::write_root(&args.sqlite, build.root.entries())?;
+    println!("rows={} sqlite={}", rows, args.sqlite.display());
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliRootStatsArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    #[clap(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+    /// Also list each conflicting file, with the entry lookups resolve to.
+    #[clap(long)]
+    conflicts: bool,
+}
+
+/// Conflicts are files with entries of different content keys, typically
+/// localized files; which one wins follows --prefer-locale.
+async fn root_stats(args: &CliRootStatsArgs, output: OutputOptions) -> Result<()> {
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let root = &build.root;
+    let (mut entries, mut named) = (0, 0);
+    let mut fdids = HashSet::new();
+    for e in root.entries() {
+        entries += 1;
+        named += usize::from(e.name_hash.is_some());
+        fdids.insert(e.fdid);
+    }
+    let conflicts = root.conflicts().collect::<Vec<_>>();
+    let describe = |e: &root::Entry| {
+        format!(
+            "{}:{}:{:08x}",
+            e.content_key,
+            locale::Locale::describe_flags(e.locale_flags),
+            e.content_flags
+        )
+    };
+    match args.format {
+        ReportFormat::Human => {
+            if args.conflicts {
+                for c in &conflicts {
+                    println!(
+                        "conflict fdid={} kept={} others={}",
+                        c.kept.fdid,
+                        describe(&c.kept),
+                        c.others.iter().map(describe).collect::<Vec<_>>().join(";")
+                    );
+                }
+            }
+            println!(
+                "entries={} fdids={} named={} conflicts={}",
+                entries,
+                fdids.len(),
+                named,
+                conflicts.len()
+            );
+        }
+        ReportFormat::Json => {
+            let entry = |e: &root::Entry| {
+                serde_json::json!({
+                    "ckey": e.content_key.to_string(),
+                    "locale_flags": e.locale_flags,
+                    "content_flags": e.content_flags,
+                })
+            };
+            let mut report = serde_json::json!({
+                "entries": entries,
+                "fdids": fdids.len(),
+                "named": named,
+                "conflicts": conflicts.len(),
+            });
+            if args.conflicts {
+                report["conflicting_files"] = conflicts
+                    .iter()
+                    .map(|c| {
+                        serde_json::json!({
+                            "fdid": u32::from(c.kept.fdid),
+                            "kept": entry(&c.kept),
+                            "others": c.others.iter().map(entry).collect::<Vec<_>>(),
+                        })
+                    })
+                    .collect();
+            }
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliRootCoverageArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    /// Listfile of fdid;path lines whose paths are hashed and matched
+    /// against the root's name hashes.
+    #[clap(long)]
+    listfile: std::path::PathBuf,
+    #[clap(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+}
+
+/// How much of a root's name hashes a listfile accounts for.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct NameCoverage {
+    /// Entries without a name hash, which no listfile can match.
+    unhashed: usize,
+    /// Distinct name hashes a listfile path hashes to.
+    matched: usize,
+    /// Distinct name hashes no listfile path hashes to, with the lowest
+    /// FileDataID of their entries, in FileDataID order.
+    unmatched: Vec<(u64, FileDataID)>,
+}
+
+fn name_coverage<'a>(
+    entries: impl Iterator<Item = root::Entry>,
+    names: impl Iterator<Item = &'a str>,
+) -> NameCoverage {
+    // Listfiles separate directories with slashes; the hashed names use
+    // backslashes.
+    let known = names
+        .map(|name| hash::name_hash(&name.replace('/', "\\")))
+        .collect::<HashSet<_>>();
+    let mut coverage = NameCoverage::default();
+    let mut hashes = HashMap::<u64, FileDataID>::new();
+    for e in entries {
+        match e.name_hash {
+            Some(h) => {
+                let fdid = hashes.entry(h).or_insert(e.fdid);
+                *fdid = (*fdid).min(e.fdid);
+            }
+            None => coverage.unhashed += 1,
+        }
+    }
+    for (h, fdid) in hashes {
+        if known.contains(&h) {
+            coverage.matched += 1;
+        } else {
+            coverage.unmatched.push((h, fdid));
+        }
+    }
+    coverage.unmatched.sort_by_key(|&(h, fdid)| (fdid, h));
+    coverage
+}
+
+async fn root_coverage(args: &CliRootCoverageArgs, output: OutputOptions) -> Result<()> {
+    let names = listfile::parse(
+        &tokio::fs::read_to_string(&args.listfile)
+            .await
+            .with_context(|| format!("reading {}", args.listfile.display()))?,
+    )?;
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let coverage = name_coverage(build.root.entries(), names.values().map(String::as_str));
+    let hashes = coverage.matched + coverage.unmatched.len();
+    match args.format {
+        ReportFormat::Human => {
+            for (h, fdid) in &coverage.unmatched {
+                println!("unmatched name_hash={:016x} fdid={}", h, fdid);
+            }
+            println!(
+                "hashes={} matched={} unmatched={} unhashed_entries={} listfile_names={}",
+                hashes,
+                coverage.matched,
+                coverage.unmatched.len(),
+                coverage.unhashed,
+                names.len()
+            );
+        }
+        ReportFormat::Json => {
+            let unmatched = coverage
+                .unmatched
+                .iter()
+                .map(|(h, fdid)| {
+                    serde_json::json!({
+                        "name_hash": format!("{:016x}", h),
+                        "fdid": u32::from(*fdid),
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "hashes": hashes,
+                    "matched": coverage.matched,
+                    "unhashed_entries": coverage.unhashed,
+                    "listfile_names": names.len(),
+                    "unmatched": unmatched,
+                }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliFileInfoArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    /// A FileDataID or a file path.
+    #[clap(value_parser)]
+    file: String,
+    /// Resolve the entry for this locale rather than the preferred one.
+    #[clap(long, value_enum)]
+    locale: Option<locale::Locale>,
+}
+
+async fn fileinfo(args: &CliFileInfoArgs, output: OutputOptions) -> Result<()> {
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let entries = match args.file.parse::<u32>() {
+        Ok(fdid) => build.root.fdid_entries(FileDataID(fdid)),
+        Err(_) => {
+            println!("name hash:    {:016x}", hash::name_hash(&args.file));
+            build.root.name_entries(&args.file)
+        }
+    };
+    for e in &entries {
+        println!(
+            "root entry:   fdid {} ckey {} name hash {} locales {} flags {:08x}",
+            e.fdid,
+            e.content_key,
+            e.name_hash
+                .map_or_else(|| "none".to_string(), |h| format!("{:016x}", h)),
+            locale::Locale::describe_flags(e.locale_flags),
+            e.content_flags
+        );
+    }
+    let ckey = build.resolve(&args.file, args.locale)?;
+    println!("content key:  {}", ckey);
+    if let Some(size) = build.encoding.content_size(ckey) {
+        println!("content size: {}", size);
+    }
+    let ekeys = build
+        .encoding
+        .ekeys(ckey)
+        .with_context(|| format!("no encoding key for content key {}", ckey))?;
+    let chosen = build.chosen_ekey(ckey);
+    for &ekey in ekeys {
+        println!(
+            "encoding key: {}{}",
+            ekey,
+            if Some(ekey) == chosen {
+                " (chosen)"
+            } else {
+                " (alternate)"
+            }
+        );
+        if let Some(size) = build.encoding.encoded_size(ekey) {
+            println!("  encoded size: {}", size);
+        }
+        if let Some(spec) = build.encoding.espec(ekey) {
+            println!("  espec:        {}", spec);
+        }
+        match build.archive_index.map.get(&ekey) {
+            Some((archive, size, offset)) => {
+                println!(
+                    "  archive:      {} offset {} size {}",
+                    archive, offset, size
+                )
+            }
+            None => println!("  archive:      none"),
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliDiffArgs {
+    /// The product to compare from, e.g. wow, or one of its builds pinned
+    /// as wow@<build config>:<cdn config>.
+    #[clap(value_parser)]
+    old: String,
+    /// The product to compare to, e.g. wowt, or a pinned build.
+    #[clap(value_parser)]
+    new: String,
+    #[clap(flatten)]
+    options: CliBuildOptions,
+    /// Names files from this listfile of fdid;path lines. Alone, compares
+    /// the files it names instead of each product's interface files.
+    #[clap(long)]
+    listfile: Option<std::path::PathBuf>,
+    /// Compare every file in each build's root. Files the listfile doesn't
+    /// name are called unknown\<fdid>.
+    #[clap(long, conflicts_with = "fdids")]
+    root: bool,
+    /// Compare the FileDataIDs listed in this file, one per line.
+    #[clap(long)]
+    fdids: Option<std::path::PathBuf>,
+    /// Only compare paths starting with this, ignoring case.
+    #[clap(long)]
+    prefix: Option<String>,
+    /// Only compare paths matching this pattern, ignoring case, in which `*`
+    /// stands for any run of characters; may be repeated.
+    #[clap(long)]
+    glob: Vec<String>,
+    #[clap(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+    /// Writes the added and changed files, as the new build has them, to
+    /// this zip, with the removed paths listed in REMOVED.txt.
+    #[clap(long)]
+    export: Option<std::path::PathBuf>,
+    /// Keep at most this much exported file content in memory, like 512M
+    /// or 2G, fetching in batches and spooling the rest to disk.
+    #[clap(long, requires = "export", value_parser = spool::parse_size)]
+    max_memory: Option<usize>,
+}
+
+/// Paths are compared lowercased and with backslashes, as the interface
+/// manifest has them.
+fn normalize_name(path: &str) -> String {
+    path.replace('/', "\\").to_lowercase()
+}
+
+/// How a file set changed between two builds, in path order.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct FileDiff {
+    added: Vec<(String, ContentKey)>,
+    removed: Vec<(String, ContentKey)>,
+    changed: Vec<(String, ContentKey, ContentKey)>,
+}
+
+fn diff_files(
+    old: &std::collections::BTreeMap<String, ContentKey>,
+    new: &std::collections::BTreeMap<String, ContentKey>,
+) -> FileDiff {
+    let mut diff = FileDiff::default();
+    for (path, &ckey) in old {
+        match new.get(path) {
+            None => diff.removed.push((path.clone(), ckey)),
+            Some(&other) if other != ckey => diff.changed.push((path.clone(), ckey, other)),
+            Some(_) => {}
+        }
+    }
+    for (path, &ckey) in new {
+        if !old.contains_key(path) {
+            diff.added.push((path.clone(), ckey));
+        }
+    }
+    diff
+}
+
+/// Which files of each build `diff` compares.
+enum DiffScope {
+    /// The interface manifest's files.
+    Interface,
+    /// The files the listfile names.
+    Listed,
+    /// Every file in root.
+    Root,
+    /// The files an --fdids list names.
+    Fdids(Vec<FileDataID>),
+}
+
+/// Which files `diff` compares, and what it calls them.
+struct DiffSelection {
+    scope: DiffScope,
+    /// Names from the listfile.
+    names: HashMap<FileDataID, String>,
+    prefix: Option<String>,
+    globs: Vec<String>,
+}
+
+impl DiffSelection {
+    fn name(&self, fdid: FileDataID) -> String {
+        match self.names.get(&fdid) {
+            Some(name) => normalize_name(name),
+            None => format!("unknown\\{}", fdid),
+        }
+    }
+
+    /// Whether `path`, normalized, passes the prefix and glob filters.
+    fn matches(&self, path: &str) -> bool {
+        self.prefix.as_deref().is_none_or(|p| path.starts_with(p))
+            && (self.globs.is_empty() || self.globs.iter().any(|g| glob_match(g, path)))
+    }
+
+    /// The content key of each selected file of `build`.
+    async fn keys(&self, build: &Build) -> Result<std::collections::BTreeMap<String, ContentKey>> {
+        let files: Vec<(String, FileDataID)> = match &self.scope {
+            DiffScope::Interface => build.interface_files(None).await?.into_iter().collect(),
+            DiffScope::Listed => self.names.keys().map(|&f| (self.name(f), f)).collect(),
+            DiffScope::Root => build
+                .root
+                .entries()
+                .map(|e| e.fdid)
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .map(|f| (self.name(f), f))
+                .collect(),
+            DiffScope::Fdids(fdids) => fdids.iter().map(|&f| (self.name(f), f)).collect(),
+        };
+        Ok(files
+            .into_iter()
+            .filter(|(path, _)| self.matches(path))
+            .filter_map(|(path, fdid)| Some((path, build.root.f2c(fdid).ok()?)))
+            .collect())
+    }
+}
+
+/// Parses a list of FileDataIDs, one per line; blank lines and lines
+/// starting with # are ignored.
+fn parse_fdid_list(text: &str) -> Result<Vec<FileDataID>> {
+    text.lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            line.parse()
+                .map(FileDataID)
+                .with_context(|| format!("line {}: bad FileDataID {}", n + 1, line))
+        })
+        .collect()
+}
+
+/// The zip entry of a delta export listing the paths the new build dropped.
+const REMOVED_NAME: &str = "REMOVED.txt";
+
+/// Writes the files `diff` added or changed, as `build` has them, to a zip
+/// at `path`. Like `walk`, a `max_memory` ceiling is split between the
+/// batch being fetched and the files kept in memory.
+async fn export_delta(
+    build: &Build,
+    product: &str,
+    diff: &FileDiff,
+    path: &std::path::Path,
+    max_memory: Option<usize>,
+    output: OutputOptions,
+) -> Result<()> {
+    let wanted = diff
+        .added
+        .iter()
+        .map(|(name, ckey)| (name, *ckey))
+        .chain(diff.changed.iter().map(|(name, _, ckey)| (name, *ckey)))
+        .collect::<Vec<_>>();
+    let batches = spool::batches(wanted, max_memory.map(|m| m as u64 / 2), |&(_, ckey)| {
+        build.encoding.content_size(ckey).unwrap_or(0)
+    });
+    let mut files = spool::Files::new(max_memory.map(|m| m / 2));
+    for batch in batches {
+        let fetched = futures::future::join_all(
+            batch
+                .into_iter()
+                .map(|(name, ckey)| build.fetch_content(ckey).map(move |r| (name, r))),
+        )
+        .await;
+        for (name, content) in fetched {
+            match content? {
+                Some(content) => files.insert(name.clone(), content)?,
+                None => warnings::report(
+                    warnings::Issue::SkippedFile,
+                    format!("skipping unresolvable file: {}", name),
+                )?,
+            }
+        }
+    }
+    let count = files.len();
+    let sums = checksums(&files)?.into_bytes();
+    files.insert("CHECKSUMS.sha256".to_string(), sums)?;
+    files.insert(
+        BUILD_METADATA_NAME.to_string(),
+        serde_json::to_vec_pretty(&build_metadata(build, product))?,
+    )?;
+    files.insert(
+        REMOVED_NAME.to_string(),
+        diff.removed
+            .iter()
+            .map(|(name, _)| format!("{}\n", name.replace('\\', "/")))
+            .collect::<String>()
+            .into_bytes(),
+    )?;
+    if let Some(dir) = path.parent() {
+        ensuredir(dir)?;
+    }
+    write_zip_file(path, &files)?;
+    output.note(format!(
+        "exported {} added and changed files to {}",
+        count,
+        path.display()
+    ));
+    Ok(())
+}
+
+/// The builds are opened one after the other, so their progress bars
+/// don't interleave.
+async fn diff(args: &CliDiffArgs, output: OutputOptions) -> Result<()> {
+    let read = |path: &std::path::Path| {
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))
+    };
+    let names = match &args.listfile {
+        Some(path) => listfile::parse(&read(path)?)?,
+        None => HashMap::new(),
+    };
+    let scope = match &args.fdids {
+        _ if args.root => DiffScope::Root,
+        Some(path) => DiffScope::Fdids(
+            parse_fdid_list(&read(path)?).with_context(|| format!("parsing {}", path.display()))?,
+        ),
+        None if args.listfile.is_some() => DiffScope::Listed,
+        None => DiffScope::Interface,
+    };
+    let selection = DiffSelection {
+        scope,
+        names,
+        prefix: args.prefix.as_deref().map(normalize_name),
+        globs: args.glob.iter().map(|g| normalize_name(g)).collect(),
+    };
+    let (old_product, old_pin) = parse_build_spec(&args.old)?;
+    let (new_product, new_pin) = parse_build_spec(&args.new)?;
+    let mut opts = args.options.options(output);
+    opts.shared = std::sync::Arc::new(shared::Fetches::new(args.max_memory));
+    opts.pin = old_pin;
+    let old = selection
+        .keys(&Build::open(&old_product, &opts).await?)
+        .await?;
+    opts.pin = new_pin;
+    let new_build = Build::open(&new_product, &opts).await?;
+    let new = selection.keys(&new_build).await?;
+    let diff = diff_files(&old, &new);
+    if let Some(path) = &args.export {
+        export_delta(
+            &new_build,
+            &new_product,
+            &diff,
+            path,
+            args.max_memory,
+            output,
+        )
+        .await?;
+    }
+    match args.format {
+        ReportFormat::Human => {
+            for (path, ckey) in &diff.added {
+                println!("+ {} {}", path, ckey);
+            }
+            for (path, ckey) in &diff.removed {
+                println!("- {} {}", path, ckey);
+            }
+            for (path, old, new) in &diff.changed {
+                println!("M {} {} -> {}", path, old, new);
+            }
+            println!(
+                "added={} removed={} changed={} unchanged={}",
+                diff.added.len(),
+                diff.removed.len(),
+                diff.changed.len(),
+                old.len() - diff.removed.len() - diff.changed.len()
+            );
+        }
+        ReportFormat::Json => {
+            let files = |files: &[(String, ContentKey)]| {
+                files
+                    .iter()
+                    .map(|(path, ckey)| serde_json::json!({ "path": path, "ckey": ckey.to_string() }))
+                    .collect::<Vec<_>>()
+            };
+            let changed = diff
+                .changed
+                .iter()
+                .map(|(path, old, new)| {
+                    serde_json::json!({
+                        "path": path,
+                        "old": old.to_string(),
+                        "new": new.to_string(),
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "old": args.old,
+                    "new": args.new,
+                    "added": files(&diff.added),
+                    "removed": files(&diff.removed),
+                    "changed": changed,
+                }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliVerifyExportArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    /// The zip to check.
+    #[clap(value_parser)]
+    zip: std::path::PathBuf,
+    /// FileDataID of ManifestInterfaceData.db2, overriding lookup by name.
+    #[clap(long)]
+    manifest_data_fdid: Option<u32>,
+    /// FileDataID of ManifestInterfaceTOCData.db2, overriding lookup by name.
+    #[clap(long)]
+    manifest_toc_fdid: Option<u32>,
+    #[clap(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+}
+
+/// How a zip's entries compare to the files a build exports, in path order.
+#[derive(Debug, Default, Eq, PartialEq)]
+struct ExportCheck {
+    matching: usize,
+    /// Entries that are intact but hold other content, such as an older
+    /// build's.
+    stale: Vec<String>,
+    /// Exported files the zip lacks.
+    missing: Vec<String>,
+    /// Entries that don't match the checksum the zip itself records.
+    corrupted: Vec<String>,
+    /// Entries the build no longer exports.
+    extra: Vec<String>,
+}
+
+impl ExportCheck {
+    fn is_ok(&self) -> bool {
+        self.stale.is_empty()
+            && self.missing.is_empty()
+            && self.corrupted.is_empty()
+            && self.extra.is_empty()
+    }
+}
+
+/// Compares zip entries to the files a build exports, both keyed by zip
+/// path. Entries may hold the file as shipped or normalized to UTF-8. An
+/// entry is corrupted rather than stale when the zip's CHECKSUMS.sha256
+/// disagrees with it; zips without one can only have stale entries.
+fn check_export(
+    expected: &HashMap<String, Vec<u8>>,
+    zip: &HashMap<String, Vec<u8>>,
+) -> ExportCheck {
+    let recorded = zip
+        .get("CHECKSUMS.sha256")
+        .map(|sums| {
+            String::from_utf8_lossy(sums)
+                .lines()
+                .filter_map(|line| line.split_once("  "))
+                .map(|(sum, name)| (name.to_string(), sum.to_string()))
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+    let mut check = ExportCheck::default();
+    for (name, content) in expected {
+        let entry = match zip.get(name) {
+            Some(entry) => entry,
+            None => {
+                check.missing.push(name.clone());
+                continue;
+            }
+        };
+        let is_text = [".lua", ".xml", ".toc"]
+            .iter()
+            .any(|ext| name.to_lowercase().ends_with(ext));
+        if entry == content
+            || is_text && text::decode(content).is_ok_and(|t| t.as_bytes() == entry.as_slice())
+        {
+            check.matching += 1;
+        } else if recorded
+            .get(name)
+            .is_some_and(|sum| *sum != hex::encode(hash::sha256(entry)))
+        {
+            check.corrupted.push(name.clone());
+        } else {
+            check.stale.push(name.clone());
+        }
+    }
+    check.extra = zip
+        .keys()
+        .filter(|name| {
+            *name != "CHECKSUMS.sha256"
+                && *name != BUILD_METADATA_NAME
+                && *name != BUILD_INFO_NAME
+                && !expected.contains_key(*name)
+        })
+        .cloned()
+        .collect();
+    for names in [
+        &mut check.stale,
+        &mut check.missing,
+        &mut check.corrupted,
+        &mut check.extra,
+    ] {
+        names.sort();
+    }
+    check
+}
+
+fn read_zip(path: &std::path::Path) -> Result<HashMap<String, Vec<u8>>> {
+    let file = std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut zip =
+        zip::ZipArchive::new(file).with_context(|| format!("reading {}", path.display()))?;
+    let mut files = HashMap::new();
+    for i in 0..zip.len() {
+        let mut entry = zip.by_index(i)?;
+        if entry.is_dir() {
+            continue;
+        }
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut entry, &mut data)
+            .with_context(|| format!("reading {} from {}", entry.name(), path.display()))?;
+        files.insert(entry.name().to_string(), data);
+    }
+    Ok(files)
+}
+
+/// Walks the build as framexml does, taking each file whose content key
+/// some zip entry already hashes to from the zip instead of the CDN.
+async fn verify_export(args: &CliVerifyExportArgs, output: OutputOptions) -> Result<()> {
+    let zip = read_zip(&args.zip)?;
+    let product = &args.build.product;
+    let build = &Build::open(product, &args.build.options(output)).await?;
+    let fdids = build
+        .interface_files(args.manifest_data_fdid.map(FileDataID))
+        .await?;
+    let by_ckey = &zip
+        .values()
+        .map(|data| (hash::md5(data), data))
+        .collect::<HashMap<_, _>>();
+    let downloaded = &std::sync::atomic::AtomicUsize::new(0);
+    let (files, _) = walk(
+        build,
+        product,
+        &fdids,
+        args.manifest_toc_fdid.map(FileDataID),
+        None,
+        |_: &str, ckey: ContentKey| async move {
+            if let Some(data) = by_ckey.get(&ckey.0) {
+                return Ok(Some(data.to_vec()));
+            }
+            downloaded.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            build.fetch_content(ckey).await
+        },
+    )
+    .await?;
+    let expected = files
+        .into_memory()?
+        .into_iter()
+        .map(|(name, content)| (name.replace('\\', "/"), content))
+        .collect();
+    let check = check_export(&expected, &zip);
+    let downloaded = downloaded.load(std::sync::atomic::Ordering::Relaxed);
+    match args.format {
+        ReportFormat::Human => {
+            for (kind, names) in [
+                ("stale", &check.stale),
+                ("missing", &check.missing),
+                ("corrupted", &check.corrupted),
+                ("extra", &check.extra),
+            ] {
+                for name in names {
+                    println!("{} file={}", kind, name);
+                }
+            }
+            println!(
+                "zip={} product={} matching={} stale={} missing={} corrupted={} extra={} downloaded={}",
+                args.zip.display(),
+                product,
+                check.matching,
+                check.stale.len(),
+                check.missing.len(),
+                check.corrupted.len(),
+                check.extra.len(),
+                downloaded
+            );
+        }
+        ReportFormat::Json => println!(
+            "{}",
+            serde_json::to_string_pretty(&serde_json::json!({
+                "zip": args.zip.display().to_string(),
+                "product": product,
+                "matching": check.matching,
+                "stale": check.stale,
+                "missing": check.missing,
+                "corrupted": check.corrupted,
+                "extra": check.extra,
+                "downloaded": downloaded,
+            }))?
+        ),
+    }
+    ensure!(
+        check.is_ok(),
+        "{} doesn't match {}",
+        args.zip.display(),
+        product
+    );
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliArchiveArgs {
+    #[clap(subcommand)]
+    command: CliArchiveCommands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliArchiveCommands {
+    /// Prints where each encoding key lives in the build's archives.
+    #[clap(name = "dump")]
+    Dump(CliArchiveDumpArgs),
+}
+
+#[derive(clap::Args)]
+struct CliArchiveDumpArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    #[clap(long, value_enum, default_value_t = DumpFormat::Csv)]
+    format: DumpFormat,
+    /// Only list entries in this archive.
+    #[clap(long, value_parser = parse_hash)]
+    archive: Option<u128>,
+}
+
+/// Keys in several archives appear once, in the archive the duplicate
+/// policy picked.
+async fn archive_dump(args: &CliArchiveDumpArgs, output: OutputOptions) -> Result<()> {
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let mut rows = build
+        .archive_index
+        .map
+        .iter()
+        .filter(|(_, (archive, _, _))| args.archive.is_none_or(|a| archive.0 == a))
+        .map(|(ekey, &(archive, size, offset))| (archive.0, offset, size, *ekey))
+        .collect::<Vec<_>>();
+    rows.sort_unstable_by_key(|&(archive, offset, _, _)| (archive, offset));
+    match args.format {
+        DumpFormat::Csv => {
+            println!("ekey,archive,offset,size");
+            for (archive, offset, size, ekey) in rows {
+                println!("{},{},{},{}", ekey, ArchiveKey(archive), offset, size);
+            }
+        }
+        DumpFormat::Json => {
+            let rows = rows
+                .into_iter()
+                .map(|(archive, offset, size, ekey)| {
+                    serde_json::json!({
+                        "ekey": ekey.to_string(),
+                        "archive": ArchiveKey(archive).to_string(),
+                        "offset": offset,
+                        "size": size,
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliPatchArgs {
+    #[clap(subcommand)]
+    command: CliPatchCommands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliPatchCommands {
+    /// Lists the patches in the build's patch archives, groundwork for
+    /// incremental updates.
+    #[clap(name = "list")]
+    List(CliPatchListArgs),
+}
+
+#[derive(clap::Args)]
+struct CliPatchListArgs {
+    #[clap(value_parser)]
+    product: String,
+    #[clap(long, default_value = "us")]
+    region: String,
+    #[clap(long, value_enum, default_value_t = DumpFormat::Csv)]
+    format: DumpFormat,
+    #[clap(long, value_enum, default_value_t = VersionSourceKind::HttpV1)]
+    version_source: VersionSourceKind,
+    #[clap(flatten)]
+    http: CliHttpArgs,
+}
+
+/// Only the CDN config and patch archive indices are fetched, so this is
+/// much cheaper than opening the build.
+async fn patch_list(args: &CliPatchListArgs, output: OutputOptions) -> Result<()> {
+    let http = args.http.options();
+    let client: std::sync::Arc<dyn BytesFetcher + Send + Sync> =
+        std::sync::Arc::new(metrics::Metered(http.client()?));
+    let (version, cdns, _) = resolve_version(
+        client.clone(),
+        &http.ribbit,
+        &args.product,
+        &args.region,
+        args.version_source,
+    )
+    .await?;
+    let cdn = CdnClient {
+        client,
+        cdn_prefixes: cdns.prefixes(),
+        throttle: tokio::sync::Semaphore::new(5),
+        bench: fetch::HostBench::default(),
+        cache: None,
+        limits: limits::Limits::default(),
+        retry_budget: Default::default(),
+    };
+    let config_data = cdn
+        .fetch_cdn_bytes("config", version.cdn_config, None, None)
+        .await?;
+    let config = parse_config(from_utf8(&config_data)?);
+    let archives = config
+        .get("patch-archives")
+        .copied()
+        .unwrap_or("")
+        .split_whitespace()
+        .map(|h| parse_hash(h).map(ArchiveKey))
+        .collect::<Result<Vec<_>>>()?;
+    let progress = &progress::Progress::new(output.progress);
+    let phase = &progress.phase("patches", archives.len() as u64);
+    let cdn = &cdn;
+    let indices = futures::future::try_join_all(archives.iter().map(|&a| async move {
+        let index = cdn.fetch_index("patch", a, progress).await;
+        phase.inc(0);
+        index
+    }))
+    .await?;
+    phase.finish();
+    let mut rows = indices
+        .iter()
+        .flat_map(|index| index.map.iter())
+        .map(|(ekey, &(archive, size, offset))| (archive.0, offset, size, *ekey))
+        .collect::<Vec<_>>();
+    rows.sort_unstable_by_key(|&(archive, offset, _, _)| (archive, offset));
+    output.note(format!(
+        "{} patches in {} patch archives",
+        rows.len(),
+        archives.len()
+    ));
+    match args.format {
+        DumpFormat::Csv => {
+            println!("ekey,archive,offset,size");
+            for (archive, offset, size, ekey) in rows {
+                println!("{},{},{},{}", ekey, ArchiveKey(archive), offset, size);
+            }
+        }
+        DumpFormat::Json => {
+            let rows = rows
+                .into_iter()
+                .map(|(archive, offset, size, ekey)| {
+                    serde_json::json!({
+                        "ekey": ekey.to_string(),
+                        "archive": ArchiveKey(archive).to_string(),
+                        "offset": offset,
+                        "size": size,
+                    })
+                })
+                .collect::<Vec<_>>();
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliReportArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    #[clap(long, value_enum, default_value_t = ReportFormat::Human)]
+    format: ReportFormat,
+}
+
+/// Sizes are decoded sizes from the encoding table; an entry with several
+/// content flags counts towards each of them, and one with none towards
+/// `none`.
+async fn report(args: &CliReportArgs, output: OutputOptions) -> Result<()> {
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let (mut entries, mut named, mut unknown_size) = (0, 0, 0);
+    let mut flags = BTreeMap::<String, (usize, u64)>::new();
+    for e in build.root.entries() {
+        entries += 1;
+        named += usize::from(e.name_hash.is_some());
+        let size = build.encoding.content_size(e.content_key);
+        unknown_size += usize::from(size.is_none());
+        let mut names = root::content_flag_names(e.content_flags);
+        if names.is_empty() {
+            names.push("none".to_string());
+        }
+        for name in names {
+            let (files, bytes) = flags.entry(name).or_default();
+            *files += 1;
+            *bytes += size.unwrap_or(0);
+        }
+    }
+    let archives = build
+        .archive_index
+        .map
+        .values()
+        .map(|(archive, _, _)| *archive)
+        .collect::<HashSet<_>>()
+        .len();
+    match args.format {
+        ReportFormat::Human => {
+            println!(
+                "product={} root_entries={} named={} unnamed={} unknown_size={} archives={} skipped_archives={} encoding_entries={}",
+                args.build.product,
+                entries,
+                named,
+                entries - named,
+                unknown_size,
+                archives,
+                build.skipped_indices.len(),
+                build.encoding.len()
+            );
+            for (flag, (files, bytes)) in &flags {
+                println!("flag={} files={} bytes={}", flag, files, bytes);
+            }
+        }
+        ReportFormat::Json => {
+            let flags = flags
+                .iter()
+                .map(|(flag, (files, bytes))| {
+                    serde_json::json!({ "flag": flag, "files": files, "bytes": bytes })
+                })
+                .collect::<Vec<_>>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&serde_json::json!({
+                    "product": args.build.product,
+                    "root_entries": entries,
+                    "named": named,
+                    "unnamed": entries - named,
+                    "unknown_size": unknown_size,
+                    "archives": archives,
+                    "skipped_archives": build.skipped_indices.len(),
+                    "encoding_entries": build.encoding.len(),
+                    "flags": flags,
+                }))?
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliInstallArgs {
+    #[clap(subcommand)]
+    command: CliInstallCommands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliInstallCommands {
+    /// Lists the files a fresh client install with the given tags would
+    /// contain, from the install and download manifests.
+    #[clap(name = "simulate")]
+    Simulate(CliInstallSimulateArgs),
+}
+
+#[derive(clap::Args)]
+struct CliInstallSimulateArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    /// Tags the client is configured with, e.g. Windows,x86_64,enUS; kinds
+    /// of tag left out don't narrow the file set.
+    #[clap(long, value_delimiter = ',')]
+    tags: Vec<String>,
+    /// Also fetch the install manifest's files into this directory.
+    #[clap(long)]
+    fetch: Option<std::path::PathBuf>,
+    /// Prints where each file would be fetched from and written, without
+    /// fetching or writing any.
+    #[clap(long, requires = "fetch")]
+    dry_run: bool,
+}
+
+/// Install manifest files are listed one per line; download manifest data
+/// lives in CASC storage, so only its totals are shown.
+async fn install_simulate(args: &CliInstallSimulateArgs, output: OutputOptions) -> Result<()> {
+    let build = Build::open(&args.build.product, &args.build.options(output)).await?;
+    let tags = args.tags.iter().map(String::as_str).collect::<Vec<_>>();
+    let install_key = build
+        .config
+        .install
+        .context("build config has no install manifest")?;
+    let install = install::parse(
+        &build
+            .cdn
+            .fetch_checked(
+                "install manifest",
+                install_key,
+                build.config.install_size,
+                build.verify,
+                &build.progress.bytes_phase("install", None),
+            )
+            .await?,
+    )?;
+    let selected = download::select(&install.tags, &tags, install.entries.len())?;
+    let files = install
+        .entries
+        .iter()
+        .zip(selected)
+        .filter_map(|(entry, selected)| selected.then_some(entry))
+        .collect::<Vec<_>>();
+    let (download_files, download_bytes) = match build.config.download {
+        Some(key) => {
+            let download = build
+                .cdn
+                .fetch_download_manifest(
+                    key,
+                    build.config.download_size,
+                    build.verify,
+                    &build.progress.bytes_phase("download", None),
+                )
+                .await?;
+            let selected = download::select(&download.tags, &tags, download.entries.len())?;
+            download
+                .entries
+                .iter()
+                .zip(selected)
+                .filter(|(_, selected)| *selected)
+                .fold((0usize, 0u64), |(n, size), (e, _)| (n + 1, size + e.size))
+        }
+        None => {
+            output.note("build config has no download manifest");
+            (0, 0)
+        }
+    };
+    if let Some(dir) = &args.fetch {
+        let phase = build.progress.phase("fetch", files.len() as u64);
+        if !args.dry_run {
+            build.progress.expect(
+                files
+                    .iter()
+                    .filter_map(|entry| build.location(entry.ckey))
+                    .map(|(_, size, _)| size as u64)
+                    .sum(),
+            );
+        }
+        for entry in &files {
+            ensure!(
+                !entry.name.starts_with(['\\', '/'])
+                    && !entry.name.split(['\\', '/']).any(|part| part == ".."),
+                "refusing to write {} outside {}",
+                entry.name,
+                dir.display()
+            );
+            let path = dir.join(entry.name.replace('\\', "/"));
+            if args.dry_run {
+                let (archive, encoded) = match build.location(entry.ckey) {
+                    Some((archive, size, _)) => (archive.to_string(), size.to_string()),
+                    None => ("-".to_string(), "-".to_string()),
+                };
+                println!(
+                    "would fetch file={} archive={} encoded={} dest={}",
+                    entry.name,
+                    archive,
+                    encoded,
+                    path.display()
+                );
+                continue;
+            }
+            let data = build
+                .fetch_content(entry.ckey)
+                .await?
+                .with_context(|| format!("{} is in a skipped archive", entry.name))?;
+            phase.inc(data.len() as u64);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("creating {}", parent.display()))?;
+            }
+            shutdown::write_file(&path, &data)?;
+        }
+        phase.finish();
+    }
+    for entry in &files {
+        println!(
+            "file name={} ckey={} size={}",
+            entry.name, entry.ckey, entry.size
+        );
+    }
+    let install_bytes = files.iter().map(|e| u64::from(e.size)).sum::<u64>();
+    println!(
+        "product={} tags={} install_files={} install_bytes={} download_files={} download_bytes={} total_bytes={}",
+        args.build.product,
+        args.tags.join(","),
+        files.len(),
+        install_bytes,
+        download_files,
+        download_bytes,
+        install_bytes + download_bytes
+    );
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliBlteArgs {
+    #[clap(subcommand)]
+    command: CliBlteCommands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliBlteCommands {
+    /// Decodes a BLTE file from disk.
+    #[clap(name = "decode")]
+    Decode(CliBlteDecodeArgs),
+    /// Encodes a file as BLTE and prints its encoding key.
+    #[clap(name = "encode")]
+    Encode(CliBlteEncodeArgs),
+}
+
+#[derive(clap::Args)]
+struct CliBlteEncodeArgs {
+    #[clap(value_parser)]
+    input: std::path::PathBuf,
+    #[clap(long, short)]
+    output: std::path::PathBuf,
+    #[clap(long, value_enum, default_value_t = blte::Mode::Zlib)]
+    mode: blte::Mode,
+    /// Split into chunks of this many bytes; a single chunk if omitted.
+    #[clap(long)]
+    chunk_size: Option<usize>,
+}
+
+#[derive(clap::Args)]
+struct CliBlteDecodeArgs {
+    #[clap(value_parser)]
+    input: std::path::PathBuf,
+    /// Where to write the decoded data; stdout if omitted.
+    #[clap(long, short)]
+    output: Option<std::path::PathBuf>,
+    /// Skip all checksum verification.
+    #[clap(long)]
+    lenient: bool,
+    /// Keyring config to decrypt chunks with; may be repeated, and earlier
+    /// files win.
+    #[clap(long)]
+    keyring: Vec<std::path::PathBuf>,
+    /// Also print each chunk's sizes, checksum and framing to stderr.
+    #[clap(long)]
+    chunks: bool,
+}
+
+/// Decodes a local BLTE file. Unless lenient, chunk checksums are verified,
+/// and so is the header if the file is named after its encoding key.
+fn blte_decode(args: &CliBlteDecodeArgs) -> Result<()> {
+    let data =
+        std::fs::read(&args.input).with_context(|| format!("reading {}", args.input.display()))?;
+    let mut keys = keyring::KeyStore::default();
+    for path in &args.keyring {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        keys.add(path.display().to_string(), keyring::parse(&text)?);
+    }
+    let named = args
+        .input
+        .file_name()
+        .and_then(|n| n.to_str())
+        .filter(|n| n.len() == 32)
+        .and_then(|n| u128::from_str_radix(n, 16).ok());
+    let checksum = if args.lenient {
+        None
+    } else {
+        Some(
+            named
+                .or_else(|| blte::header_checksum(&data))
+                .context("not BLTE format")?,
+        )
+    };
+    let (decoded, chunks) = blte::parse_chunked(checksum, &keys, &data)?;
+    if args.chunks {
+        for (i, chunk) in chunks.iter().enumerate() {
+            eprintln!(
+                "chunk index={} frame={} compressed={} decoded={} checksum={:032x}",
+                i,
+                chunk.frame,
+                chunk.info.compressed_size,
+                chunk.info.decoded_size,
+                chunk.info.checksum
+            );
+        }
+    }
+    match &args.output {
+        Some(path) => shutdown::write_file(path, &decoded),
+        None => {
+            use std::io::Write;
+            std::io::stdout()
+                .write_all(&decoded)
+                .context("writing stdout")
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct CliDb2Args {
+    #[clap(subcommand)]
+    command: CliDb2Commands,
+}
+
+#[derive(clap::Subcommand)]
+enum CliDb2Commands {
+    /// Dumps a db2 made up solely of string columns.
+    #[clap(name = "strings")]
+    Strings(CliDb2StringsArgs),
+    /// Prints a db2's header, downloading as little of it as possible.
+    #[clap(name = "info")]
+    Info(CliDb2InfoArgs),
+}
+
+/// Where a db2 comes from: a file in a product's build, or a local path.
+#[derive(clap::Args)]
+struct CliDb2Source {
+    #[clap(value_parser, required_unless_present = "path")]
+    product: Option<String>,
+    /// FileDataID or path of the db2 within the build.
+    #[clap(value_parser, required_unless_present = "path")]
+    file: Option<String>,
+    /// Reads the db2 from this local file instead, without any network access.
+    #[clap(long, conflicts_with_all = ["product", "file"])]
+    path: Option<std::path::PathBuf>,
+    /// Which locale's copy of a localized db2 to fetch from the build.
+    #[clap(long, value_enum, conflicts_with = "path")]
+    locale: Option<locale::Locale>,
+    /// WoWDBDefs definition to validate the db2's layout hash against.
+    #[clap(long)]
+    dbd: Option<std::path::PathBuf>,
+    #[clap(flatten)]
+    build: CliBuildOptions,
+}
+
+impl CliDb2Source {
+    /// Reads the db2, checking its layout against the definition if given.
+    /// From a build, only the first `prefix` bytes are guaranteed, and fewer
+    /// may be downloaded.
+    async fn read(&self, output: OutputOptions, prefix: Option<u64>) -> Result<Vec<u8>> {
+        let build = self.open(output).await?;
+        let data = self.fetch(build.as_ref(), prefix).await?;
+        self.check_dbd(output, &data).await?;
+        Ok(data)
+    }
+
+    /// Like read, but also reads `join`, named as the db2 is: a file of the
+    /// same build, or with --path another local file.
+    async fn read_joined(&self, output: OutputOptions, join: &str) -> Result<(Vec<u8>, Vec<u8>)> {
+        let build = self.open(output).await?;
+        let data = self.fetch(build.as_ref(), None).await?;
+        self.check_dbd(output, &data).await?;
+        let joined = match &build {
+            Some(build) => build.fetch_file(join, self.locale).await?,
+            None => tokio::fs::read(join)
+                .await
+                .with_context(|| format!("reading {}", join))?,
+        };
+        Ok((data, joined))
+    }
+
+    /// The build the db2 comes from, unless it's a local file.
+    async fn open(&self, output: OutputOptions) -> Result<Option<Build>> {
+        match (&self.path, &self.product, &self.file) {
+            (Some(_), _, _) => Ok(None),
+            (None, Some(product), Some(_)) => Ok(Some(
+                Build::open(product, &self.build.options(output)).await?,
+            )),
+            _ => bail!("either a product and file or --path is required"),
+        }
+    }
+
+    async fn check_dbd(&self, output: OutputOptions, data: &[u8]) -> Result<()> {
+        if let Some(path) = &self.dbd {
+            let text = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("reading {}", path.display()))?;
+            let layout_hash = db2::info(data)?.layout_hash;
+            let version = dbd::parse(&text)?
+                .check_layout(layout_hash)
+                .with_context(|| format!("validating against {}", path.display()))?;
+            output.note(format!(
+                "layout {:08X} matches builds {}",
+                layout_hash,
+                version.builds.join(", ")
+            ));
+        }
+        Ok(())
+    }
+
+    async fn fetch(&self, build: Option<&Build>, prefix: Option<u64>) -> Result<Vec<u8>> {
+        let (build, file) = match (build, &self.path, &self.file) {
+            (None, Some(path), _) => {
+                return tokio::fs::read(path)
+                    .await
+                    .with_context(|| format!("reading {}", path.display()))
+            }
+            (Some(build), _, Some(file)) => (build, file),
+            _ => bail!("either a product and file or --path is required"),
+        };
+        let data = match prefix {
+            Some(len) => {
+                build
+                    .fetch_content_prefix(build.resolve(file, self.locale)?, len)
+                    .await?
+            }
+            None => build.fetch_file(file, self.locale).await?,
+        };
+        if let (Some(wanted), Ok(info)) = (self.locale, db2::info(&data)) {
+            if info.locale_name != Some(wanted) {
+                warn!(
+                    "asked for {} strings but {} holds locale {}",
+                    wanted, file, info.locale
+                );
+            }
+        }
+        Ok(data)
+    }
+}
+
+#[derive(clap::Args)]
+struct CliDb2InfoArgs {
+    #[clap(flatten)]
+    source: CliDb2Source,
+}
+
+#[derive(clap::Args)]
+struct CliDb2StringsArgs {
+    #[clap(flatten)]
+    source: CliDb2Source,
+    #[clap(long)]
+    json: bool,
+    /// The parent table the db2's relationship map refers to, whose strings
+    /// are appended to each row: a FileDataID or path within the build, or
+    /// with --path a local file.
+    #[clap(long)]
+    join: Option<String>,
+}
+
+/// Denormalizes a child table onto its parent through the child's
+/// relationship map. Rows without a parent, or whose parent is missing,
+/// get no parent strings.
+async fn db2_strings_joined(
+    args: &CliDb2StringsArgs,
+    join: &str,
+    output: OutputOptions,
+) -> Result<()> {
+    let limits = args.source.build.limits();
+    let (data, parent) = args.source.read_joined(output, join).await?;
+    let links = db2::relationships(&data, &limits)?;
+    ensure!(
+        !links.is_empty(),
+        "the db2 has no relationship map to join on"
+    );
+    let parents = db2::strings_shared(&parent, &limits)?;
+    let rows = db2::strings_shared(&data, &limits)?
+        .into_iter()
+        .map(|(id, values)| {
+            let parent_id = links.get(&id).copied();
+            let parent_values = parent_id.and_then(|p| parents.get(&p)).cloned();
+            (id, (values, parent_id, parent_values))
+        })
+        .collect::<std::collections::BTreeMap<_, _>>();
+    if args.json {
+        let rows = rows
+            .into_iter()
+            .map(|(id, (values, parent_id, parent_values))| {
+                let strs = |values: &[std::sync::Arc<str>]| {
+                    values.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+                };
+                let row = serde_json::json!({
+                    "values": strs(&values),
+                    "parent_id": parent_id,
+                    "parent": parent_values.as_deref().map(strs),
+                });
+                (id.to_string(), row)
+            })
+            .collect::<serde_json::Map<_, _>>();
+        println!("{}", serde_json::to_string_pretty(&rows)?);
+    } else {
+        for (id, (values, parent_id, parent_values)) in rows {
+            println!(
+                "{}\t{}\t{}\t{}",
+                id,
+                values.join("\t"),
+                parent_id.map(|p| p.to_string()).unwrap_or_default(),
+                parent_values.unwrap_or_default().join("\t")
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(clap::Args)]
+struct CliProductConfigArgs {
+    #[clap(value_parser)]
+    product: String,
+    #[clap(long, default_value = "us")]
+    region: String,
+    #[clap(long, value_enum, default_value_t = VersionSourceKind::HttpV1)]
+    version_source: VersionSourceKind,
+    #[clap(flatten)]
+    http: CliHttpArgs,
+}
+
+#[derive(clap::Args)]
+struct CliVersionsArgs {
+    #[clap(value_parser)]
+    product: String,
+    /// Only print this region's row, or the best fallback for it.
+    #[clap(long)]
+    region: Option<String>,
+    #[clap(long, value_enum, default_value_t = VersionSourceKind::HttpV1)]
+    version_source: VersionSourceKind,
+    #[clap(flatten)]
+    http: CliHttpArgs,
+}
+
+#[derive(clap::Args)]
+struct CliBgdlArgs {
+    #[clap(value_parser)]
+    product: String,
+    #[clap(long, default_value = "us")]
+    region: String,
+    /// Only list files carrying this download tag, e.g. Windows.
+    #[clap(long)]
+    tag: Option<String>,
+    #[clap(flatten)]
+    http: CliHttpArgs,
+}
+
+#[derive(clap::Args)]
+struct CliBuildArgs {
+    #[clap(value_parser)]
+    product: String,
+    #[clap(flatten)]
+    options: CliBuildOptions,
+}
+
+#[derive(clap::Args)]
+struct CliBuildOptions {
+    /// Preferred region; others are tried if the product lacks it.
+    #[clap(long, default_value = "us")]
+    region: String,
+    /// Where to look up versions first; the same version of the other
+    /// protocol is the fallback.
+    #[clap(long, value_enum, default_value_t = VersionSourceKind::HttpV1)]
+    version_source: VersionSourceKind,
+    #[clap(long, value_enum, default_value_t = VerifyLevel::Full)]
+    verify: VerifyLevel,
+    /// Decode and verify fetched content on this many background threads
+    /// while downloads continue, one per CPU by default; 0 verifies inline.
+    #[clap(long, default_value_t = default_verify_workers())]
+    verify_workers: usize,
+    #[clap(long, value_enum, default_value_t = IndexPolicy::Fail)]
+    index_policy: IndexPolicy,
+    /// Which archive to fetch from when a key is in several.
+    #[clap(long, value_enum, default_value_t = archive::DuplicatePolicy::First)]
+    duplicate_policy: archive::DuplicatePolicy,
+    /// Explain failed file lookups stage by stage, from root to archive.
+    #[clap(long)]
+    trace_lookups: bool,
+    /// Fetch files in download manifest priority order, most urgent
+    /// first, as the game client does.
+    #[clap(long)]
+    prioritize: bool,
+    /// Keep fetched CDN files and parsed archive indices here, so later
+    /// runs skip fetching them; everything is keyed by hash, so entries
+    /// never go stale. Defaults to the configured cache path, if any.
+    #[clap(long)]
+    cache_dir: Option<std::path::PathBuf>,
+    /// Fetches CDN files from this mirror instead of the product's CDN
+    /// hosts; may be repeated. Either a prefix such as
+    /// http://mirror/tpr/wow, followed by the usual tag/ab/cd/hash layout,
+    /// or a template naming {hash}, with {tag}, {ab}, {cd} and {suffix}
+    /// also filled in, e.g. http://mirror/blobs/{hash}{suffix}.
+    #[clap(long)]
+    cdn_url: Vec<String>,
+    /// Give up on the run after this many failed fetch attempts in all,
+    /// rather than retrying every host of every file.
+    #[clap(long)]
+    retry_budget: Option<usize>,
+    /// Give up on the run once this many seconds have passed since the
+    /// first failed fetch attempt.
+    #[clap(long)]
+    retry_time: Option<u64>,
+    /// Give up on the run at the first failed fetch attempt.
+    #[clap(long, conflicts_with = "retry_budget")]
+    fail_fast: bool,
+    /// Skip a CDN host after this many failed fetches in a row; a file
+    /// fails once every host has failed it this often or is being skipped.
+    #[clap(long, default_value_t = fetch::DEFAULT_TRIP_AFTER)]
+    host_failures: usize,
+    /// Seconds to skip a CDN host for once it trips --host-failures.
+    #[clap(long, default_value_t = fetch::DEFAULT_COOL_DOWN.as_secs())]
+    host_cool_down: u64,
+    /// Refuse tables whose headers claim more entries than this.
+    #[clap(long, default_value_t = limits::Limits::default().max_entries)]
+    max_entries: usize,
+    /// Refuse tables whose length fields claim more bytes than this.
+    #[clap(long, default_value_t = limits::Limits::default().max_bytes)]
+    max_bytes: usize,
+    /// Which locale's root entry to use for files with several, most
+    /// preferred first; entries for none of them come after.
+    #[clap(long, value_enum, value_delimiter = ',', default_value = "enUS")]
+    prefer_locale: Vec<locale::Locale>,
+    #[clap(flatten)]
+    http: CliHttpArgs,
+}
+
+#[derive(clap::Args)]
+struct CliHttpArgs {
+    /// Pins a hostname to an address, as host:ip; may be repeated.
+    #[clap(long, value_parser = parse_resolve)]
+    resolve: Vec<(String, std::net::IpAddr)>,
+    #[clap(long, value_enum, default_value_t = Http2Mode::Auto)]
+    http2: Http2Mode,
+    /// Seconds an idle pooled connection is kept; 0 disables the timeout.
+    #[clap(long, default_value_t = 90)]
+    pool_idle_timeout: u64,
+    #[clap(long)]
+    pool_max_idle_per_host: Option<usize>,
+    /// TCP keepalive interval in seconds.
+    #[clap(long)]
+    tcp_keepalive: Option<u64>,
+    /// How strictly to check Ribbit version lookups' checksums.
+    #[clap(long, value_enum, default_value = "require")]
+    ribbit_checksum: ribbit::ChecksumPolicy,
+    /// Ribbit connect timeout in seconds.
+    #[clap(long, default_value_t = 10)]
+    ribbit_connect_timeout: u64,
+    /// Ribbit read timeout in seconds; 0 disables it.
+    #[clap(long, default_value_t = 30)]
+    ribbit_read_timeout: u64,
+}
+
+impl CliHttpArgs {
+    fn options(&self) -> HttpOptions {
+        use std::time::Duration;
+        HttpOptions {
+            resolve: self.resolve.clone(),
+            http2: self.http2,
+            pool_idle_timeout: Some(Duration::from_secs(self.pool_idle_timeout))
+                .filter(|d| !d.is_zero()),
+            pool_max_idle_per_host: self.pool_max_idle_per_host,
+            tcp_keepalive: self.tcp_keepalive.map(Duration::from_secs),
+            ribbit: ribbit::Ribbit::new()
+                .with_checksum_policy(self.ribbit_checksum)
+                .with_connect_timeout(Duration::from_secs(self.ribbit_connect_timeout))
+                .with_read_timeout(
+                    Some(Duration::from_secs(self.ribbit_read_timeout)).filter(|d| !d.is_zero()),
+                ),
+        }
+    }
+}
+
+impl CliBuildArgs {
+    fn options(&self, output: OutputOptions) -> BuildOptions {
+        self.options.options(output)
+    }
+}
+
+impl CliBuildOptions {
+    fn limits(&self) -> limits::Limits {
+        limits::Limits {
+            max_entries: self.max_entries,
+            max_bytes: self.max_bytes,
+        }
+    }
+
+    fn options(&self, output: OutputOptions) -> BuildOptions {
+        BuildOptions {
+            output,
+            http: self.http.options(),
+            region: self.region.clone(),
+            version_source: self.version_source,
+            verify: self.verify,
+            verify_workers: self.verify_workers,
+            index_policy: self.index_policy,
+            duplicate_policy: self.duplicate_policy,
+            trace_lookups: self.trace_lookups,
+            prioritize: self.prioritize,
+            cache: self.cache_dir.as_ref().map(|dir| {
+                std::sync::Arc::new(cache::FsCache::new(dir))
+                    as std::sync::Arc<dyn CacheStore + Send + Sync>
+            }),
+            fetcher: None,
+            cdn_urls: self.cdn_url.clone(),
+            limits: self.limits(),
+            retry_budget: std::sync::Arc::new(fetch::RetryBudget::new(
+                if self.fail_fast {
+                    Some(0)
+                } else {
+                    self.retry_budget
+                },
+                self.retry_time.map(std::time::Duration::from_secs),
+            )),
+            host_failures: self.host_failures,
+            host_cool_down: std::time::Duration::from_secs(self.host_cool_down),
+            preferred_locales: self.prefer_locale.clone(),
+            pin: None,
+            shared: Default::default(),
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct CliFrameXmlArgs {
+    #[clap(flatten)]
+    build: CliBuildArgs,
+    /// Also exports this product in the same run, fetching the configs and
+    /// archive indices it shares with the others only once.
+    #[clap(long, value_name = "PRODUCT")]
+    also: Vec<String>,
+    /// FileDataID of ManifestInterfaceData.db2, overriding lookup by name.
+    #[clap(long)]
+    manifest_data_fdid: Option<u32>,
+    /// FileDataID of ManifestInterfaceTOCData.db2, overriding lookup by name.
+    #[clap(long)]
+    manifest_toc_fdid: Option<u32>,
+    /// Print how many files and bytes each archive contributes to the zip,
+    /// before writing it.
+    #[clap(long, value_enum)]
+    plan: Option<ReportFormat>,
+    /// Exit after printing the plan.
+    #[clap(long, requires = "plan")]
+    plan_only: bool,
+    /// Resolves every file the interface manifest reaches and prints where
+    /// each would be downloaded from and where the zip would be written,
+    /// without fetching file contents or writing anything.
+    #[clap(long, conflicts_with = "plan_only")]
+    dry_run: bool,
+    /// Rewrite UTF-16 and byte-order-marked Lua, XML and TOC files as plain
+    /// UTF-8; by default they are exported as shipped.
+    #[clap(long)]
+    normalize_text: bool,
+    /// Keep at most this much file content in memory, like 512M or 2G.
+    /// Past it, files are fetched in smaller batches and spooled to a
+    /// temporary directory until the zip is written.
+    #[clap(long, value_parser = spool::parse_size)]
+    max_memory: Option<usize>,
+    /// Where to write the zip instead of zips/<product>.zip or the config's
+    /// export path, with {product}, {build} and {date} filled in; - streams
+    /// it to stdout.
+    #[clap(short, long)]
+    output: Option<String>,
+    /// Where to write the index.json instead of next to the zip, with the
+    /// same placeholders as -o. With -o -, it otherwise goes next to where
+    /// the zip would have been written.
+    #[clap(long, value_name = "PATH")]
+    index: Option<String>,
+    /// Uploads the zip to object storage, e.g. s3://bucket/prefix or
+    /// gs://bucket/prefix, with credentials from the environment.
+    #[cfg(feature = "upload")]
+    #[clap(long)]
+    upload: Option<String>,
+    /// Also uploads each exported file under <prefix>/<product>/.
+    #[cfg(feature = "upload")]
+    #[clap(long, requires = "upload")]
+    upload_files: bool,
+}
+
+#[derive(Clone, clap::Args)]
+struct CliRibbitArgs {
+    #[clap(subcommand)]
+    command: CliRibbitCommands,
+    #[clap(long, value_enum, default_value = "require")]
+    checksum: ribbit::ChecksumPolicy,
+    /// Connect timeout in seconds.
+    #[clap(long, default_value_t = 10)]
+    connect_timeout: u64,
+    /// Read timeout in seconds; 0 disables it.
+    #[clap(long, default_value_t = 30)]
+    read_timeout: u64,
+    /// Caches versions and cdns responses here, keyed by seqn.
+    #[clap(long)]
+    cache_dir: Option<std::path::PathBuf>,
+}
+
+#[derive(Clone, clap::Subcommand)]
+enum CliRibbitCommands {
+    #[clap(name = "summary")]
+    Summary,
+    #[clap(name = "versions")]
+    Versions(CliRibbitVersionsArgs),
+    #[clap(name = "cdns")]
+    CDNs(CliRibbitCDNsArgs),
+    #[clap(name = "check")]
+    Check(CliRibbitCheckArgs),
+    #[clap(name = "bgdl")]
+    Bgdl(CliRibbitCDNsArgs),
+    #[clap(name = "raw")]
+    Raw(CliRibbitRawArgs),
+}
+
+#[derive(Clone, clap::Args)]
+struct CliRibbitCheckArgs {
+    /// Records the last build seen per product here, or in the configured
+    /// state path.
+    #[clap(long)]
+    state_dir: Option<std::path::PathBuf>,
+    /// Shell command to run for each new build, with RUSTYCASC_PRODUCT,
+    /// RUSTYCASC_BUILD_ID, RUSTYCASC_VERSION and RUSTYCASC_REGION set;
+    /// needs a state dir.
+    #[clap(long)]
+    exec: Option<String>,
+    /// Report products whose regions disagree on build id or config.
+    #[clap(long)]
+    compare_regions: bool,
+}
+
+#[derive(Clone, clap::Args)]
+struct CliRibbitRawArgs {
+    #[clap(value_parser)]
+    command: String,
+}
+
+#[derive(Clone, clap::Args)]
+struct CliRibbitVersionsArgs {
+    #[clap(value_parser)]
+    product: String,
+    /// Only print this region's row.
+    #[clap(long)]
+    region: Option<String>,
+}
+
+#[derive(Clone, clap::Args)]
+struct CliRibbitCDNsArgs {
+    #[clap(value_parser)]
+    product: String,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    use clap::Parser;
+    let started = std::time::Instant::now();
+    let cli = Cli::parse();
+    let mut console = stderrlog::new();
+    console
+        .module(module_path!())
+        .timestamp(stderrlog::Timestamp::Millisecond);
+    logging::init(
+        console,
+        logging::Filter::new(module_path!(), cli.verbose, &cli.log_levels),
+        cli.log_file
+            .as_deref()
+            .map(|path| logging::RotatingFile::open(path, cli.log_file_max_size, cli.log_file_keep))
+            .transpose()?,
+        module_path!(),
+    )?;
+    warnings::init(warnings::Policy::new(
+        cli.warning_rules.iter().copied(),
+        cli.quiet,
+    ));
+    paths::init(
+        cli.config
+            .as_deref()
+            .map(paths::load)
+            .transpose()?
+            .unwrap_or_default(),
+    );
+    let result = shutdown::unless_interrupted(run(&cli)).await;
+    // Commands without a report of their own list collected issues at exit.
+    for (issue, msg) in warnings::take_collected() {
+        eprintln!("warning: {} ({})", msg, issue);
+    }
+    if let Some(path) = &cli.summary_out {
+        let outcome = match &result {
+            Some(Ok(())) => summary::Outcome::Succeeded,
+            Some(Err(e)) => summary::Outcome::Failed(e),
+            None => summary::Outcome::Interrupted,
+        };
+        let args = std::env::args().collect::<Vec<_>>();
+        // A summary that can't be written shouldn't hide the run's own error.
+        if let Err(e) = summary::write(path, &args, outcome, started.elapsed()) {
+            match result {
+                Some(Ok(())) => return Err(e),
+                _ => warn!("{:#}", e),
+            }
+        }
+    }
+    match result {
+        Some(result) => result,
+        None => {
+            // The command's future has been dropped by now, cancelling its
+            // fetches; outputs only ever appear complete, so there is
+            // nothing half-written to remove.
+            eprintln!("interrupted");
+            log::logger().flush();
+            std::process::exit(shutdown::INTERRUPTED)
+        }
+    }
+}
+
+/// The ribbit commands, which block throughout, so they run off the async
+/// workers.
+fn ribbit_command(args: &CliRibbitArgs) -> Result<()> {
+    let mut ribbit = ribbit::Ribbit::new()
+        .with_checksum_policy(args.checksum)
+        .with_connect_timeout(std::time::Duration::from_secs(args.connect_timeout))
+        .with_read_timeout(
+            Some(std::time::Duration::from_secs(args.read_timeout)).filter(|d| !d.is_zero()),
+        )
+        .with_cache_dir(args.cache_dir.clone());
+    match &args.command {
+        CliRibbitCommands::Summary => {
+            println!("{:#?}", ribbit.summary()?);
+            Ok(())
+        }
+        CliRibbitCommands::Versions(args) => {
+            let versions = ribbit.versions(&args.product)?;
+            match &args.region {
+                Some(region) => println!(
+                    "{:#?}",
+                    versions
+                        .entry(region)
+                        .with_context(|| format!("no {} row for {}", region, args.product))?
+                ),
+                None => println!("{:#?}", versions),
+            }
+            Ok(())
+        }
+        CliRibbitCommands::CDNs(args) => {
+            println!("{:#?}", ribbit.cdns(&args.product)?);
+            Ok(())
+        }
+        CliRibbitCommands::Bgdl(args) => {
+            println!("{:#?}", ribbit.bgdl(&args.product)?);
+            Ok(())
+        }
+        CliRibbitCommands::Raw(args) => {
+            println!("{:#?}", ribbit.request(args.command.as_bytes())?);
+            Ok(())
+        }
+        CliRibbitCommands::Check(args) => {
+            let state = paths::resolve(
+                args.state_dir.as_deref(),
+                paths::get().state.as_deref(),
+                &paths::Vars::default(),
+            )?
+            .as_deref()
+            .map(hooks::BuildState::new)
+            .transpose()?;
+            ensure!(
+                state.is_some() || args.exec.is_none(),
+                "--exec needs --state-dir or a configured state path"
+            );
+            let summary = ribbit.summary()?;
+            println!("summary seqn = {}", summary.seqn);
+            for (k, v) in summary.entries {
+                println!("looking at {}", k);
+                for (flags, seqn) in &v.unknown {
+                    println!("{} has unknown summary flags {} at seqn {}", k, flags, seqn);
+                }
+                if let Some(seqn) = v.seqn {
+                    let versions = ribbit.versions_at(&k, seqn)?;
+                    println!("{} versions seqn = {}", k, versions.seqn);
+                    if args.compare_regions {
+                        if let Some(skew) = hooks::region_skew(&versions) {
+                            let describe = |entries: &[&ribbit::VersionsEntry]| {
+                                entries
+                                    .iter()
+                                    .map(|e| format!("{}={}", e.region, e.build_id))
+                                    .collect::<Vec<_>>()
+                                    .join(",")
+                            };
+                            println!(
+                                "{} regions disagree: ahead {} behind {}",
+                                k,
+                                describe(&skew.ahead),
+                                describe(&skew.behind)
+                            );
+                        }
+                    }
+                    if let (Some(state), Some(entry)) = (&state, hooks::newest_build(&versions)) {
+                        if state.update(&k, entry)? {
+                            println!("{} new build {} ({})", k, entry.build_id, entry.name);
+                            if let Some(exec) = &args.exec {
+                                hooks::run_exec(exec, &k, entry)?;
+                            }
+                        }
+                    }
+                }
+                if let Some(seqn) = v.cdn {
+                    println!("{} cdns seqn = {}", k, ribbit.cdns_at(&k, seqn)?.seqn);
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn run(cli: &Cli) -> Result<()> {
+    match &cli.command {
+        CliCommands::FrameXml(args) => {
+            ensure!(
+                args.output.as_deref() != Some("-") || args.plan.is_none() || args.dry_run,
+                "--plan prints to stdout, so it can't be used with -o -"
+            );
+            ensure!(
+                args.output.as_deref() != Some("-")
+                    || args.dry_run
+                    || !std::io::IsTerminal::is_terminal(&std::io::stdout()),
+                "refusing to write a zip to a terminal"
+            );
+            ensure!(
+                args.also.is_empty()
+                    || !args
+                        .output
+                        .as_deref()
+                        .is_some_and(|o| o == "-" || !o.contains("{product}")),
+                "with --also, -o must name each product's zip with {{product}}"
+            );
+            ensure!(
+                args.also.is_empty()
+                    || !args
+                        .index
+                        .as_deref()
+                        .is_some_and(|i| !i.contains("{product}")),
+                "with --also, --index must name each product's index with {{product}}"
+            );
+            let mut build = args.build.options(cli.output());
+            build.shared = std::sync::Arc::new(shared::Fetches::new(args.max_memory));
+            let opts = ProcessOptions {
+                build,
+                manifest_data_fdid: args.manifest_data_fdid.map(FileDataID),
+                manifest_toc_fdid: args.manifest_toc_fdid.map(FileDataID),
+                plan: args.plan,
+                plan_only: args.plan_only,
+                dry_run: args.dry_run,
+                normalize_text: args.normalize_text,
+                max_memory: args.max_memory,
+                output: args.output.clone(),
+                index: args.index.clone(),
+                #[cfg(feature = "upload")]
+                upload: args
+                    .upload
+                    .as_deref()
+                    .map(upload::Destination::parse)
+                    .transpose()?,
+                #[cfg(feature = "upload")]
+                upload_files: args.upload_files,
+            };
+            for product in std::iter::once(&args.build.product).chain(&args.also) {
+                process(product, &opts).await?;
+            }
+            Ok(())
+        }
+        CliCommands::Monitor(args) => monitor(args).await,
+        CliCommands::Products(args) => products(args).await,
+        CliCommands::Hash(args) => match &args.command {
+            CliHashCommands::Name(args) => {
+                println!("{:016x}", hash::name_hash(&args.path));
+                Ok(())
+            }
+            CliHashCommands::File(args) => {
+                let data = std::fs::read(&args.path)
+                    .with_context(|| format!("reading {}", args.path.display()))?;
+                println!("md5     {:032x}", hash::md5(&data));
+                println!("lookup3 {:016x}", hash::lookup3(&data));
+                println!("crc32   {:08x}", hash::crc32(&data));
+                Ok(())
+            }
+        },
+        CliCommands::Root(args) => match &args.command {
+            CliRootCommands::Dump(args) => root_dump(args, cli.output()).await,
+            CliRootCommands::Stats(args) => root_stats(args, cli.output()).await,
+            CliRootCommands::Coverage(args) => root_coverage(args, cli.output()).await,
+            #[cfg(feature = "sqlite")]
+            CliRootCommands::Export(args) => root_export(args, cli.output()).await,
+        },
+        CliCommands::Encoding(args) => match &args.command {
+            CliEncodingCommands::Export(args) => encoding_export(args, cli.output()).await,
+        },
+        CliCommands::FileInfo(args) => fileinfo(args, cli.output()).await,
+        CliCommands::Diff(args) => diff(args, cli.output()).await,
+        CliCommands::VerifyExport(args) => verify_export(args, cli.output()).await,
+        CliCommands::Archive(args) => match &args.command {
+            CliArchiveCommands::Dump(args) => archive_dump(args, cli.output()).await,
+        },
+        CliCommands::Patch(args) => match &args.command {
+            CliPatchCommands::List(args) => patch_list(args, cli.output()).await,
+        },
+        CliCommands::Report(args) => report(args, cli.output()).await,
+        CliCommands::BuildInfo(args) => buildinfo(args).await,
+        CliCommands::Install(args) => match &args.command {
+            CliInstallCommands::Simulate(args) => install_simulate(args, cli.output()).await,
+        },
+        CliCommands::Blte(args) => match &args.command {
+            CliBlteCommands::Decode(args) => blte_decode(args),
+            CliBlteCommands::Encode(args) => {
+                let data = std::fs::read(&args.input)
+                    .with_context(|| format!("reading {}", args.input.display()))?;
+                let encoded = blte::encode(&data, args.mode, args.chunk_size);
+                shutdown::write_file(&args.output, &encoded)?;
+                println!(
+                    "{:032x}",
+                    blte::header_checksum(&encoded).context("encoded invalid BLTE")?
+                );
+                Ok(())
+            }
+        },
+        CliCommands::Db2(args) => match &args.command {
+            CliDb2Commands::Info(args) => {
+                let data = args
+                    .source
+                    .read(cli.output(), Some(db2::HEADER_SIZE as u64))
+                    .await?;
+                println!("{:#?}", db2::info(&data)?);
+                Ok(())
+            }
+            CliDb2Commands::Strings(args) => {
+                if let Some(join) = &args.join {
+                    return db2_strings_joined(args, join, cli.output()).await;
+                }
+                let strings = db2::strings_shared(
+                    &args.source.read(cli.output(), None).await?,
+                    &args.source.build.limits(),
+                )?
+                .into_iter()
+                .collect::<std::collections::BTreeMap<_, _>>();
+                if args.json {
+                    let strings = strings
+                        .iter()
+                        .map(|(id, values)| (id, values.iter().map(|v| &**v).collect::<Vec<_>>()))
+                        .collect::<std::collections::BTreeMap<_, _>>();
+                    println!("{}", serde_json::to_string_pretty(&strings)?);
+                } else {
+                    for (id, values) in strings {
+                        println!("{}\t{}", id, values.join("\t"));
+                    }
+                }
+                Ok(())
+            }
+        },
+        CliCommands::Versions(args) => {
+            let http = args.http.options();
+            let source = args.version_source.source(
+                std::sync::Arc::new(metrics::Metered(http.client()?)),
+                &http.ribbit,
+            );
+            let versions = source.versions(&args.product).await?;
+            let versions = match &args.region {
+                Some(region) => {
+                    vec![
+                        select_by_region(versions, region, |v| Some(v.region.as_str()))
+                            .with_context(|| format!("missing {} version", region))?,
+                    ]
+                }
+                None => versions,
+            };
+            let hash = |h: Option<u128>| h.map(|h| format!("{:032x}", h)).unwrap_or_default();
+            for v in versions {
+                println!(
+                    "region={} build_config={:032x} cdn_config={:032x} key_ring={} product_config={} build_id={} version={}",
+                    v.region,
+                    v.build_config,
+                    v.cdn_config,
+                    hash(v.key_config),
+                    hash(v.product_config),
+                    v.build_id.map(|id| id.to_string()).unwrap_or_default(),
+                    v.name.unwrap_or_default()
+                );
+            }
+            Ok(())
+        }
+        CliCommands::ProductConfig(args) => {
+            let http = args.http.options();
+            let client: std::sync::Arc<dyn BytesFetcher + Send + Sync> =
+                std::sync::Arc::new(metrics::Metered(http.client()?));
+            let (version, cdns, _) = resolve_version(
+                client.clone(),
+                &http.ribbit,
+                &args.product,
+                &args.region,
+                args.version_source,
+            )
+            .await?;
+            let hash = version
+                .product_config
+                .context("product has no product config")?;
+            println!("{:#?}", client.fetch_product_config(&cdns, hash).await?);
+            Ok(())
+        }
+        CliCommands::Bgdl(args) => {
+            let http = args.http.options();
+            let client: std::sync::Arc<dyn BytesFetcher + Send + Sync> =
+                std::sync::Arc::new(metrics::Metered(http.client()?));
+            let (version, cdns) = futures::future::try_join(
+                client.fetch_bgdl(&args.product, &args.region),
+                VersionSourceKind::HttpV1
+                    .source(client.clone(), &http.ribbit)
+                    .cdns(&args.product),
+            )
+            .await?;
+            let cdns = select_by_region(cdns, &args.region, |c| Some(c.region.as_str()))
+                .with_context(|| format!("missing {} cdn", args.region))?;
+            let cdn = CdnClient {
+                client,
+                cdn_prefixes: cdns.prefixes(),
+                throttle: tokio::sync::Semaphore::new(5),
+                bench: fetch::HostBench::default(),
+                cache: None,
+                limits: limits::Limits::default(),
+                retry_budget: Default::default(),
+            };
+            let config = parse_build_config(&parse_config(from_utf8(
+                &cdn.fetch_cdn_bytes("config", version.build_config, None, None)
+                    .await?,
+            )?))?;
+            let download_key = config
+                .download
+                .context("bgdl build config has no download manifest")?;
+            let progress = progress::Progress::new(cli.output().progress);
+            let download = cdn
+                .fetch_download_manifest(
+                    download_key,
+                    config.download_size,
+                    VerifyLevel::Full,
+                    &progress.bytes_phase("download", None),
+                )
+                .await?;
+            let mut files = 0;
+            let mut bytes = 0;
+            for (i, entry) in download.entries.iter().enumerate() {
+                let tags = download.tags_of(i).collect::<Vec<_>>();
+                if args.tag.as_deref().is_some_and(|t| !tags.contains(&t)) {
+                    continue;
+                }
+                files += 1;
+                bytes += entry.size;
+                println!(
+                    "{} size={} priority={} tags={}",
+                    entry.ekey,
+                    entry.size,
+                    entry.priority,
+                    tags.join(",")
+                );
+            }
+            for tag in &download.tags {
+                println!(
+                    "tag={} kind={} files={}",
+                    tag.name,
+                    tag.kind,
+                    (0..download.entries.len())
+                        .filter(|i| tag.contains(*i))
+                        .count()
+                );
+            }
+            println!(
+                "product={} region={} build_config={:032x} files={} bytes={}",
+                args.product, version.region, version.build_config, files, bytes
+            );
+            Ok(())
+        }
+        CliCommands::Ribbit(args) => {
+            // On a blocking thread, Ctrl-C can still end the run while a
+            // request or exec hook is under way.
+            let args = args.clone();
+            tokio::task::spawn_blocking(move || ribbit_command(&args)).await?
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use velcro::hash_map as m;
+    use velcro::vec as v;
+
+    #[test]
+    fn test_parse_info() {
+        let tests = [
+            ("empty string", "", v![]),
+            ("space", " ", v![]),
             ("one field", "moo\n\ncow", v![m! {"moo":"cow"}]),
             (
                 "several fields",
@@ -547,6 +5578,193 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_sizes() {
+        use super::{parse_sizes, FileSizes};
+        let config = m! {
+            "encoding-size":"300 200",
+            "install-size":"12",
+            "download-size":"a b",
+        };
+        assert_eq!(
+            parse_sizes(&config, "encoding").unwrap(),
+            Some(FileSizes {
+                content: 300,
+                encoded: 200
+            })
+        );
+        assert_eq!(parse_sizes(&config, "root").unwrap(), None);
+        assert!(parse_sizes(&config, "install").is_err());
+        assert!(parse_sizes(&config, "download").is_err());
+        assert!(super::check_content_size("x", b"abc", Some(3)).is_ok());
+        assert!(super::check_content_size("x", b"abc", None).is_ok());
+        assert!(super::check_content_size("x", b"abc", Some(4)).is_err());
+    }
+
+    #[test]
+    fn test_parse_versions() {
+        let info = "Region!STRING:0|BuildConfig!HEX:16|CDNConfig!HEX:16|KeyRing!HEX:16\n\
+                    ## seqn = 1\n\
+                    us|01|02|\n\
+                    eu|03|04|05";
+        let versions = super::parse_versions(info).unwrap();
+        assert_eq!(
+            versions
+                .iter()
+                .map(|v| (
+                    v.region.as_str(),
+                    v.build_config,
+                    v.cdn_config,
+                    v.key_config
+                ))
+                .collect::<Vec<_>>(),
+            vec![("us", 1, 2, None), ("eu", 3, 4, Some(5))]
+        );
+        assert_eq!(super::parse_version(info, "eu").unwrap().build_config, 3);
+        assert_eq!(super::parse_version(info, "kr").unwrap().region, "us");
+        assert!(super::parse_versions("Region!STRING:0\n\nus").is_err());
+        let info = "Region!STRING:0|BuildConfig!HEX:16|CDNConfig!HEX:16|BuildId!DEC:4|VersionsName!String:0\n\
+                    ## seqn = 1\n\
+                    us|01|02|49821|1.14.3.49821\n\
+                    eu|03|04||";
+        let versions = super::parse_versions(info).unwrap();
+        assert_eq!(versions[0].build_id, Some(49821));
+        assert_eq!(versions[0].name.as_deref(), Some("1.14.3.49821"));
+        assert_eq!(
+            (versions[1].build_id, versions[1].name.as_deref()),
+            (None, None)
+        );
+        assert!(super::parse_versions(&info.replace("49821|", "x|")).is_err());
+    }
+
+    #[test]
+    fn test_parse_cdns() {
+        let info = "Name!STRING:0|Path!STRING:0|Hosts!STRING:0|ConfigPath!STRING:0\n\
+                    ## seqn = 1\n\
+                    eu|tpr/wow|a.com b.com|tpr/configs/data";
+        let cdns = super::parse_cdns(info).unwrap();
+        assert_eq!(cdns.len(), 1);
+        assert_eq!(
+            cdns[0].prefixes(),
+            vec!["http://a.com/tpr/wow", "http://b.com/tpr/wow"]
+        );
+        assert_eq!(cdns[0].config_path, "tpr/configs/data");
+        assert!(super::parse_cdns("Name!STRING:0\n\neu").is_err());
+    }
+
+    #[test]
+    fn test_build_info() {
+        let versions = "Region!STRING:0|BuildConfig!HEX:16|CDNConfig!HEX:16|KeyRing!HEX:16|BuildId!DEC:4|VersionsName!String:0\n\
+                        ## seqn = 1\n\
+                        us|01|02|03|49821|1.14.3.49821";
+        let cdns = "Name!STRING:0|Path!STRING:0|Hosts!STRING:0|ConfigPath!STRING:0\n\
+                    ## seqn = 1\n\
+                    us|tpr/wow|a.com b.com|tpr/configs/data";
+        let info = super::build_info(
+            &super::parse_version(versions, "us").unwrap(),
+            &super::parse_cdns(cdns).unwrap()[0],
+            Some(super::EncodingKey(4)),
+            "wow_classic",
+        );
+        let mut lines = info.lines();
+        let header = lines.next().unwrap().split('|').collect::<Vec<_>>();
+        let row = lines.next().unwrap().split('|').collect::<Vec<_>>();
+        assert_eq!(lines.next(), None);
+        assert_eq!(header.len(), row.len());
+        let fields = header
+            .iter()
+            .map(|h| h.split('!').next().unwrap())
+            .zip(row)
+            .filter(|(_, v)| !v.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            [
+                ("Branch", "us"),
+                ("Active", "1"),
+                ("Build Key", "00000000000000000000000000000001"),
+                ("CDN Key", "00000000000000000000000000000002"),
+                ("Install Key", "00000000000000000000000000000004"),
+                ("CDN Path", "tpr/wow"),
+                ("CDN Hosts", "a.com b.com"),
+                (
+                    "CDN Servers",
+                    "http://a.com/?maxhosts=4 http://b.com/?maxhosts=4"
+                ),
+                ("Version", "1.14.3.49821"),
+                ("KeyRing", "00000000000000000000000000000003"),
+                ("Product", "wow_classic"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_select_region() {
+        let rows = || v![m! {"Region":"eu", "X":"1"}, m! {"Region":"kr", "X":"2"}];
+        let tests = [
+            ("preferred", "kr", Some("2")),
+            ("fallback", "us", Some("1")),
+            ("unknown", "xx", Some("1")),
+        ];
+        for (name, region, output) in tests {
+            assert_eq!(
+                super::select_region(rows(), "Region", region).map(|m| m["X"]),
+                output,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(super::select_region(v![], "Region", "us"), None);
+    }
+
+    #[test]
+    fn test_verbosity_flags() {
+        use clap::Parser;
+        let tests = [
+            (v!["--verbose", "framexml", "wow"], 1, v![]),
+            (v!["framexml", "wow", "-vv"], 2, v![]),
+            (
+                v!["framexml", "wow", "--log-level", "blte=trace,warn"],
+                0,
+                v![
+                    (Some("blte".to_string()), log::LevelFilter::Trace),
+                    (None, log::LevelFilter::Warn)
+                ],
+            ),
+        ];
+        for (args, verbose, log_levels) in tests {
+            let cli = super::Cli::try_parse_from(std::iter::once("rustycasc").chain(args.clone()))
+                .unwrap_or_else(|e| panic!("{:?}: {}", args, e));
+            assert!(matches!(cli.command, super::CliCommands::FrameXml(_)));
+            assert_eq!(
+                (cli.verbose, cli.log_levels),
+                (verbose, log_levels),
+                "{:?}",
+                args
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_resolve() {
+        let tests = [
+            ("v4", "a.com:1.2.3.4", Some(("a.com", "1.2.3.4"))),
+            ("v6", "a.com:::1", Some(("a.com", "::1"))),
+            ("bracketed v6", "a.com:[::1]", Some(("a.com", "::1"))),
+            ("no ip", "a.com", None),
+            ("bad ip", "a.com:nope", None),
+            ("no host", ":1.2.3.4", None),
+        ];
+        for (name, input, output) in tests {
+            assert_eq!(
+                super::parse_resolve(input).ok(),
+                output.map(|(h, ip)| (h.to_string(), ip.parse().unwrap())),
+                "{}",
+                name
+            );
+        }
+    }
+
     #[test]
     fn test_normalize_path() {
         let tests = [
@@ -558,4 +5776,309 @@ mod tests {
             assert_eq!(super::normalize_path(in_base, in_file), output, "{}", name);
         }
     }
+
+    #[test]
+    fn test_checksums() {
+        // A one-byte ceiling spools x.lua, so it's hashed from disk.
+        let mut files = super::spool::Files::new(Some(1));
+        files
+            .insert("b\\x.lua".to_string(), b"abc".to_vec())
+            .unwrap();
+        files.insert("a.toc".to_string(), b"".to_vec()).unwrap();
+        assert_eq!(files.spooled(), 1);
+        assert_eq!(
+            super::checksums(&files).unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  a.toc\n\
+             ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  b/x.lua\n"
+        );
+    }
+
+    #[test]
+    fn test_check_export() {
+        let expected = m! {
+            "a.lua".to_string(): b"a".to_vec(),
+            "b.lua".to_string(): b"new".to_vec(),
+            "c.lua".to_string(): b"c".to_vec(),
+            "d.xml".to_string(): b"\xef\xbb\xbfd".to_vec(),
+            "e.lua".to_string(): b"e".to_vec(),
+        };
+        let sums = "ca978112ca1bbdcafac231b39a23dc4da786eff8a147c4e72b9807785afee48b  a.lua\n\
+                    0000000000000000000000000000000000000000000000000000000000000000  c.lua\n";
+        let zip = m! {
+            "a.lua".to_string(): b"a".to_vec(),
+            "b.lua".to_string(): b"old".to_vec(),
+            "c.lua".to_string(): b"x".to_vec(),
+            "d.xml".to_string(): b"d".to_vec(),
+            "f.lua".to_string(): b"f".to_vec(),
+            "CHECKSUMS.sha256".to_string(): sums.as_bytes().to_vec(),
+            "BUILD.json".to_string(): b"{}".to_vec(),
+        };
+        assert_eq!(
+            super::check_export(&expected, &zip),
+            super::ExportCheck {
+                matching: 2,
+                stale: v!["b.lua".to_string()],
+                missing: v!["e.lua".to_string()],
+                corrupted: v!["c.lua".to_string()],
+                extra: v!["f.lua".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_name_coverage() {
+        let entry = |fdid, name_hash| super::root::Entry {
+            fdid: FileDataID(fdid),
+            content_key: ContentKey(0),
+            name_hash,
+            content_flags: 0,
+            locale_flags: 0,
+        };
+        let named = super::hash::name_hash("Interface\\FrameXML\\UIParent.lua");
+        let entries = v![
+            entry(3, Some(named)),
+            entry(9, Some(7)),
+            entry(8, Some(7)),
+            entry(5, Some(6)),
+            entry(1, None),
+        ];
+        assert_eq!(
+            super::name_coverage(
+                entries.into_iter(),
+                ["interface/framexml/uiparent.lua", "Interface\\Other.lua"].into_iter()
+            ),
+            super::NameCoverage {
+                unhashed: 1,
+                matched: 1,
+                unmatched: v![(6, FileDataID(5)), (7, FileDataID(8))],
+            }
+        );
+    }
+
+    #[test]
+    fn test_index_path() {
+        assert_eq!(super::index_path("zips/wow.zip"), "zips/wow.index.json");
+        assert_eq!(super::index_path("out/wow"), "out/wow.index.json");
+    }
+
+    #[test]
+    fn test_encoding_csv() {
+        use super::{ContentKey, EncodingKey, EncodingRow};
+        let ekeys = [EncodingKey(2), EncodingKey(3)];
+        let rows = [
+            EncodingRow {
+                ckey: ContentKey(1),
+                ekeys: &ekeys,
+                size: 100,
+                encoded_size: Some(50),
+                espec: Some("b:{256K*=z,1M*=n}"),
+            },
+            EncodingRow {
+                ckey: ContentKey(4),
+                ekeys: &[],
+                size: 0,
+                encoded_size: None,
+                espec: None,
+            },
+        ];
+        let mut out = Vec::new();
+        super::write_encoding_csv(&mut out, &rows).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "ckey,ekeys,size,encoded_size,espec\n\
+                 {:032x},{:032x} {:032x},100,50,\"b:{{256K*=z,1M*=n}}\"\n\
+                 {:032x},,0,,\n",
+                1, 2, 3, 4
+            )
+        );
+        assert_eq!(super::csv_field("a\"b,c"), "\"a\"\"b,c\"");
+    }
+
+    #[test]
+    fn test_undeclared_encryption() {
+        use super::{blte, espec, keyring, ContentKey};
+        // A zlib espec, so nothing warns that the content is encrypted.
+        assert!(espec::parse("z").unwrap().encryption_keys().is_empty());
+        let mut data = b"BLTE\0\0\0\0E\x08".to_vec();
+        data.extend_from_slice(&7u64.to_le_bytes());
+        data.extend_from_slice(b"\x04\x01\x02\x03\x04Sciphertext");
+        let keys = keyring::KeyStore::default();
+        for full in [false, true] {
+            let err = super::decode_content(ContentKey(1), None, full, &keys, &data).unwrap_err();
+            assert_eq!(super::encrypted_with(&err), Some(7));
+            assert!(format!("{:#}", err).contains("is encrypted with key 0000000000000007"));
+        }
+        let plain = blte::encode(b"abc", blte::Mode::Raw, None);
+        let err = super::decode_content(ContentKey(1), None, true, &keys, &plain).unwrap_err();
+        assert_eq!(super::encrypted_with(&err), None);
+    }
+
+    #[test]
+    fn test_root_csv() {
+        use super::{root, ContentKey, FileDataID};
+        let entry = |fdid: u32, name_hash| root::Entry {
+            fdid: FileDataID(fdid),
+            content_key: ContentKey(fdid.into()),
+            name_hash,
+            content_flags: 8,
+            locale_flags: 2,
+        };
+        let tests = v![
+            (1, Some(0xab), "Interface/plain.lua", "Interface/plain.lua"),
+            (2, None, "a,b.lua", "\"a,b.lua\""),
+            (3, None, "say \"hi\".txt", "\"say \"\"hi\"\".txt\""),
+            (4, None, "line\r\nbreak", "\"line\r\nbreak\""),
+        ];
+        for (fdid, name_hash, name, field) in tests {
+            let names = m![FileDataID(fdid): name.to_string()];
+            let mut out = Vec::new();
+            super::write_root_csv(&mut out, std::iter::once(entry(fdid, name_hash)), &names)
+                .unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                format!(
+                    "fdid,ckey,name_hash,content_flags,locale_flags,name\n\
+                     {},{:032x},{},00000008,00000002,{}\n",
+                    fdid,
+                    fdid,
+                    name_hash.map(|h| format!("{:016x}", h)).unwrap_or_default(),
+                    field
+                ),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_glob_match() {
+        let tests = [
+            ("exact", "wow", "wow", true),
+            ("exact mismatch", "wow", "wowt", false),
+            ("prefix", "wow*", "wow_classic", true),
+            ("prefix mismatch", "wow*", "agent", false),
+            ("suffix", "*_ptr", "wow_classic_ptr", true),
+            ("infix", "wow*era*", "wow_classic_era_ptr", true),
+            ("overlapping", "a*ab", "ab", false),
+            ("star", "*", "anything", true),
+        ];
+        for (name, pattern, product, output) in tests {
+            assert_eq!(super::glob_match(pattern, product), output, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_diff_files() {
+        use super::ContentKey as K;
+        let old = v![
+            ("a".to_string(), K(1)),
+            ("b".to_string(), K(2)),
+            ("c".to_string(), K(3))
+        ]
+        .into_iter()
+        .collect();
+        let new = v![
+            ("b".to_string(), K(2)),
+            ("c".to_string(), K(4)),
+            ("d".to_string(), K(5))
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            super::diff_files(&old, &new),
+            super::FileDiff {
+                added: v![("d".to_string(), K(5))],
+                removed: v![("a".to_string(), K(1))],
+                changed: v![("c".to_string(), K(3), K(4))],
+            }
+        );
+        assert_eq!(
+            super::normalize_name("Interface/FrameXML/A.lua"),
+            "interface\\framexml\\a.lua"
+        );
+    }
+
+    #[test]
+    fn test_diff_selection() {
+        use super::{DiffScope, DiffSelection, FileDataID};
+        let selection = DiffSelection {
+            scope: DiffScope::Root,
+            names: m! { FileDataID(1): "Interface/AddOns/A/a.lua".to_string() },
+            prefix: Some("interface\\".to_string()),
+            globs: v!["*.lua".to_string(), "*.xml".to_string()],
+        };
+        assert_eq!(selection.name(FileDataID(1)), "interface\\addons\\a\\a.lua");
+        assert_eq!(selection.name(FileDataID(2)), "unknown\\2");
+        let tests = [
+            ("both", "interface\\a.lua", true),
+            ("second glob", "interface\\a.xml", true),
+            ("no glob", "interface\\a.toc", false),
+            ("no prefix", "fonts\\a.lua", false),
+        ];
+        for (name, path, output) in tests {
+            assert_eq!(selection.matches(path), output, "{}", name);
+        }
+        assert_eq!(
+            super::parse_fdid_list("# ids\n1\n\n 22 \n").unwrap(),
+            v![FileDataID(1), FileDataID(22)]
+        );
+        assert!(super::parse_fdid_list("1\nx").is_err());
+    }
+
+    #[test]
+    fn test_parse_build_spec() {
+        assert_eq!(
+            super::parse_build_spec("wow").unwrap(),
+            ("wow".to_string(), None)
+        );
+        assert_eq!(
+            super::parse_build_spec("wowt@0a:0b").unwrap(),
+            ("wowt".to_string(), Some((10, 11)))
+        );
+        assert!(super::parse_build_spec("wowt@0a").is_err());
+        assert!(super::parse_build_spec("wowt@xx:0b").is_err());
+    }
+
+    /// Counts the requests in flight, and the most there ever were.
+    #[derive(Default)]
+    struct Concurrency {
+        in_flight: std::sync::atomic::AtomicUsize,
+        peak: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl super::BytesFetcher for Concurrency {
+        async fn fetch_bytes(
+            &self,
+            _: String,
+            _: Option<(usize, usize)>,
+        ) -> anyhow::Result<bytes::Bytes> {
+            use std::sync::atomic::Ordering;
+            let n = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(n, Ordering::SeqCst);
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(bytes::Bytes::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cdn_throttle() {
+        use super::BytesFetcher;
+        let counter = std::sync::Arc::new(Concurrency::default());
+        let cdn = super::CdnClient {
+            client: counter.clone(),
+            cdn_prefixes: v![],
+            throttle: tokio::sync::Semaphore::new(2),
+            bench: super::fetch::HostBench::default(),
+            cache: None,
+            limits: super::limits::Limits::default(),
+            retry_budget: Default::default(),
+        };
+        futures::future::try_join_all((0..8).map(|i| cdn.fetch_bytes(i.to_string(), None)))
+            .await
+            .unwrap();
+        assert_eq!(counter.peak.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
 }
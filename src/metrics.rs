@@ -0,0 +1,173 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use rustycasc::fetch::{BytesFetcher, ChunkSink};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Process-wide counters, exported in the Prometheus text format.
+pub(crate) struct Metrics {
+    pub(crate) requests: AtomicU64,
+    pub(crate) bytes: AtomicU64,
+    pub(crate) cache_hits: AtomicU64,
+    pub(crate) build_changes: AtomicU64,
+    pub(crate) parse_failures: AtomicU64,
+}
+
+pub(crate) static METRICS: Metrics = Metrics {
+    requests: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+    cache_hits: AtomicU64::new(0),
+    build_changes: AtomicU64::new(0),
+    parse_failures: AtomicU64::new(0),
+};
+
+pub(crate) fn inc(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Counts the requests and bytes that go through a fetcher.
+pub(crate) struct Metered<F>(pub(crate) F);
+
+/// Counts the bytes a streamed body hands to its sink.
+struct MeteredSink<'a>(&'a mut (dyn ChunkSink + Send));
+
+impl ChunkSink for MeteredSink<'_> {
+    fn start(&mut self, len: Option<u64>) -> Result<()> {
+        self.0.start(len)
+    }
+    fn chunk(&mut self, data: &[u8]) -> Result<()> {
+        inc(&METRICS.bytes, data.len() as u64);
+        self.0.chunk(data)
+    }
+}
+
+#[async_trait]
+impl<F: BytesFetcher + Send + Sync> BytesFetcher for Metered<F> {
+    async fn fetch_bytes(&self, url: String, range: Option<(usize, usize)>) -> Result<Bytes> {
+        let data = self.0.fetch_bytes(url, range).await?;
+        inc(&METRICS.requests, 1);
+        inc(&METRICS.bytes, data.len() as u64);
+        Ok(data)
+    }
+    async fn fetch_streamed(&self, url: String, sink: &mut (dyn ChunkSink + Send)) -> Result<()> {
+        self.0.fetch_streamed(url, &mut MeteredSink(sink)).await?;
+        inc(&METRICS.requests, 1);
+        Ok(())
+    }
+}
+
+impl Metrics {
+    pub(crate) fn render(&self) -> String {
+        let counters = [
+            ("requests", "Network requests made.", &self.requests),
+            ("bytes", "Bytes received over the network.", &self.bytes),
+            (
+                "cache_hits",
+                "Responses served from cache.",
+                &self.cache_hits,
+            ),
+            ("build_changes", "New builds detected.", &self.build_changes),
+            (
+                "parse_failures",
+                "Responses that failed to parse.",
+                &self.parse_failures,
+            ),
+        ];
+        let mut out = String::new();
+        for (name, help, value) in counters {
+            out += &format!(
+                "# HELP rustycasc_{name}_total {help}\n# TYPE rustycasc_{name}_total counter\nrustycasc_{name}_total {value}\n",
+                name = name,
+                help = help,
+                value = value.load(Ordering::Relaxed),
+            );
+        }
+        out
+    }
+}
+
+async fn handle(mut stream: tokio::net::TcpStream) -> Result<()> {
+    let mut buf = vec![0u8; 4096];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    let request = String::from_utf8_lossy(&buf[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = if path == "/metrics" {
+        ("200 OK", METRICS.render())
+    } else {
+        ("404 Not Found", String::new())
+    };
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Serves `/metrics` on `addr` until the process exits.
+pub(crate) async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("binding metrics endpoint to {}", addr))?;
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream).await {
+                log::debug!("metrics request from {} failed: {:#}", peer, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics {
+            requests: AtomicU64::new(3),
+            bytes: AtomicU64::new(1024),
+            cache_hits: AtomicU64::new(4),
+            build_changes: AtomicU64::new(1),
+            parse_failures: AtomicU64::new(2),
+        };
+        assert_eq!(
+            metrics.render(),
+            "\
+# HELP rustycasc_requests_total Network requests made.
+# TYPE rustycasc_requests_total counter
+rustycasc_requests_total 3
+# HELP rustycasc_bytes_total Bytes received over the network.
+# TYPE rustycasc_bytes_total counter
+rustycasc_bytes_total 1024
+# HELP rustycasc_cache_hits_total Responses served from cache.
+# TYPE rustycasc_cache_hits_total counter
+rustycasc_cache_hits_total 4
+# HELP rustycasc_build_changes_total New builds detected.
+# TYPE rustycasc_build_changes_total counter
+rustycasc_build_changes_total 1
+# HELP rustycasc_parse_failures_total Responses that failed to parse.
+# TYPE rustycasc_parse_failures_total counter
+rustycasc_parse_failures_total 2
+"
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! Writing tables as Parquet, for loading into dataframes and query engines.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+
+use crate::EncodingRow;
+
+const ENCODING_SCHEMA: &str = "
+    message encoding {
+        REQUIRED BINARY ckey (UTF8);
+        REQUIRED BINARY ekeys (UTF8);
+        REQUIRED INT64 size;
+        OPTIONAL INT64 encoded_size;
+        OPTIONAL BINARY espec (UTF8);
+    }
+";
+
+/// Writes `rows` as one row group, with the columns `encoding export`
+/// writes as CSV.
+pub(crate) fn write_encoding(out: impl std::io::Write + Send, rows: &[EncodingRow]) -> Result<()> {
+    let schema = Arc::new(parse_message_type(ENCODING_SCHEMA)?);
+    let props = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build(),
+    );
+    let mut writer = SerializedFileWriter::new(out, schema, props)?;
+    let mut group = writer.next_row_group()?;
+    let text = |s: String| ByteArray::from(s.into_bytes());
+    let mut column = 0;
+    while let Some(mut col) = group.next_column()? {
+        match column {
+            0 => {
+                let values = rows.iter().map(|r| text(r.ckey.to_string()));
+                col.typed::<ByteArrayType>().write_batch(
+                    &values.collect::<Vec<_>>(),
+                    None,
+                    None,
+                )?;
+            }
+            1 => {
+                let values = rows.iter().map(|r| {
+                    text(
+                        r.ekeys
+                            .iter()
+                            .map(|e| e.to_string())
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                    )
+                });
+                col.typed::<ByteArrayType>().write_batch(
+                    &values.collect::<Vec<_>>(),
+                    None,
+                    None,
+                )?;
+            }
+            2 => {
+                let values = rows.iter().map(|r| r.size as i64);
+                col.typed::<Int64Type>()
+                    .write_batch(&values.collect::<Vec<_>>(), None, None)?;
+            }
+            // Optional columns take only the values rows have, plus a
+            // definition level per row: 1 if it has one, 0 if not.
+            3 => {
+                let values = rows.iter().filter_map(|r| r.encoded_size.map(|n| n as i64));
+                let levels = rows.iter().map(|r| i16::from(r.encoded_size.is_some()));
+                col.typed::<Int64Type>().write_batch(
+                    &values.collect::<Vec<_>>(),
+                    Some(levels.collect::<Vec<_>>().as_slice()),
+                    None,
+                )?;
+            }
+            4 => {
+                let values = rows
+                    .iter()
+                    .filter_map(|r| r.espec.map(|s| text(s.to_string())));
+                let levels = rows.iter().map(|r| i16::from(r.espec.is_some()));
+                col.typed::<ByteArrayType>().write_batch(
+                    &values.collect::<Vec<_>>(),
+                    Some(levels.collect::<Vec<_>>().as_slice()),
+                    None,
+                )?;
+            }
+            _ => unreachable!("the schema has five columns"),
+        }
+        col.close()?;
+        column += 1;
+    }
+    group.close()?;
+    writer.close().context("finishing parquet file")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ContentKey, EncodingKey};
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+
+    #[test]
+    fn test_write_encoding() {
+        let ekeys = [EncodingKey(2)];
+        let rows = [
+            EncodingRow {
+                ckey: ContentKey(1),
+                ekeys: &ekeys,
+                size: 100,
+                encoded_size: Some(50),
+                espec: Some("z"),
+            },
+            EncodingRow {
+                ckey: ContentKey(3),
+                ekeys: &[],
+                size: 0,
+                encoded_size: None,
+                espec: None,
+            },
+        ];
+        let mut out = Vec::new();
+        write_encoding(&mut out, &rows).unwrap();
+        let reader = SerializedFileReader::new(bytes::Bytes::from(out)).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let read = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(read[0].get_string(0).unwrap(), &format!("{:032x}", 1));
+        assert_eq!(read[0].get_long(3).unwrap(), 50);
+        assert_eq!(read[0].get_string(4).unwrap(), "z");
+        assert_eq!(read[1].get_long(2).unwrap(), 0);
+        assert!(read[1].get_long(3).is_err());
+    }
+}
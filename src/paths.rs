@@ -0,0 +1,203 @@
+//! Where the tool writes things, as path templates set once in a config
+//! file, so pipelines over several products keep their outputs apart.
+//!
+//! The config file holds `key = value` lines, like the CASC configs:
+//!
+//! ```text
+//! # Blank lines and lines starting with # are ignored.
+//! export = exports/{product}/{build}.zip
+//! cache = cache/{product}
+//! state = state
+//! report = reports/{product}-{date}.txt
+//! ```
+//!
+//! Templates may use `{product}`, `{build}` (the build id, or the build
+//! config hash for builds without one) and `{date}` (today, UTC, as
+//! YYYY-MM-DD). A placeholder that isn't known where a path is used, such
+//! as `{build}` in the cache path, which is needed before the build is
+//! opened, is an error.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, ensure, Context, Result};
+
+/// The templates a config file sets; unset ones keep the tool's defaults.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub(crate) struct Paths {
+    /// Where framexml writes its zip.
+    pub(crate) export: Option<String>,
+    /// The CDN file cache, for commands that take --cache-dir.
+    pub(crate) cache: Option<String>,
+    /// The state directory of monitor and ribbit check.
+    pub(crate) state: Option<String>,
+    /// Where framexml also writes its report.
+    pub(crate) report: Option<String>,
+}
+
+/// What a template's placeholders stand for where it's used.
+#[derive(Default)]
+pub(crate) struct Vars<'a> {
+    pub(crate) product: Option<&'a str>,
+    pub(crate) build: Option<String>,
+}
+
+/// Fills in `template`'s placeholders from `vars`.
+pub(crate) fn expand(template: &str, vars: &Vars) -> Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .with_context(|| format!("unclosed placeholder in {}", template))?;
+        let name = &rest[start + 1..start + end];
+        let value = match name {
+            "product" => vars.product.map(str::to_string),
+            "build" => vars.build.clone(),
+            "date" => Some(today()),
+            _ => bail!("unknown placeholder {{{}}} in {}", name, template),
+        };
+        out.push_str(&value.with_context(|| format!("{{{}}} isn't known for {}", name, template))?);
+        rest = &rest[start + end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Parses a config file, checking each template's placeholders.
+pub(crate) fn parse(text: &str) -> Result<Paths> {
+    let mut paths = Paths::default();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .with_context(|| format!("line {}: expected key = value", n + 1))?;
+        let value = value.trim().to_string();
+        let all = Vars {
+            product: Some(""),
+            build: Some(String::new()),
+        };
+        expand(&value, &all).with_context(|| format!("line {}", n + 1))?;
+        let slot = match key.trim() {
+            "export" => &mut paths.export,
+            "cache" => &mut paths.cache,
+            "state" => &mut paths.state,
+            "report" => &mut paths.report,
+            other => bail!("line {}: unknown path {}", n + 1, other),
+        };
+        ensure!(slot.is_none(), "line {}: {} set twice", n + 1, key.trim());
+        *slot = Some(value);
+    }
+    Ok(paths)
+}
+
+pub(crate) fn load(path: &Path) -> Result<Paths> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    parse(&text).with_context(|| format!("parsing {}", path.display()))
+}
+
+static PATHS: OnceLock<Paths> = OnceLock::new();
+
+/// Sets the process-wide templates; before this, every path has its default.
+pub(crate) fn init(paths: Paths) {
+    if PATHS.set(paths).is_err() {
+        log::warn!("output paths already set");
+    }
+}
+
+pub(crate) fn get() -> &'static Paths {
+    PATHS.get_or_init(Paths::default)
+}
+
+/// `explicit` if given, else the configured `template` filled in from
+/// `vars`, else nothing.
+pub(crate) fn resolve(
+    explicit: Option<&Path>,
+    template: Option<&str>,
+    vars: &Vars,
+) -> Result<Option<PathBuf>> {
+    if let Some(path) = explicit {
+        return Ok(Some(path.to_path_buf()));
+    }
+    template
+        .map(|t| expand(t, vars).map(PathBuf::from))
+        .transpose()
+}
+
+/// Today's date in UTC, as YYYY-MM-DD.
+fn today() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    civil_date((secs / 86400) as i64)
+}
+
+/// The proleptic Gregorian date `days` days after 1970-01-01.
+fn civil_date(days: i64) -> String {
+    // Howard Hinnant's days_from_civil, inverted.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let vars = Vars {
+            product: Some("wow"),
+            build: Some("49821".to_string()),
+        };
+        let tests = [
+            ("plain", "zips/out.zip", Some("zips/out.zip")),
+            ("product", "zips/{product}.zip", Some("zips/wow.zip")),
+            ("both", "{product}/{build}.zip", Some("wow/49821.zip")),
+            ("unknown", "{region}.zip", None),
+            ("unclosed", "{product.zip", None),
+        ];
+        for (name, template, output) in tests {
+            assert_eq!(expand(template, &vars).ok().as_deref(), output, "{}", name);
+        }
+        assert!(expand("{build}", &Vars::default()).is_err());
+        assert_eq!(expand("{date}", &Vars::default()).unwrap().len(), 10);
+    }
+
+    #[test]
+    fn test_parse() {
+        let paths =
+            parse("# outputs\n\nexport = out/{product}/{build}.zip\nstate=state\n").unwrap();
+        assert_eq!(
+            paths,
+            Paths {
+                export: Some("out/{product}/{build}.zip".to_string()),
+                state: Some("state".to_string()),
+                ..Default::default()
+            }
+        );
+        assert!(parse("zips = x").is_err());
+        assert!(parse("export").is_err());
+        assert!(parse("export = {region}").is_err());
+        assert!(parse("state = a\nstate = b").is_err());
+    }
+
+    #[test]
+    fn test_civil_date() {
+        assert_eq!(civil_date(0), "1970-01-01");
+        assert_eq!(civil_date(11016), "2000-02-29");
+        assert_eq!(civil_date(20742), "2026-10-16");
+    }
+}
@@ -0,0 +1,52 @@
+use std::collections::BTreeMap;
+
+use anyhow::{Context, Result};
+use serde_json::Value;
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ProductConfig {
+    /// Name of the armadillo key needed to decrypt the product's data, if any.
+    pub(crate) decryption_key_name: Option<String>,
+    pub(crate) product: Option<String>,
+    pub(crate) data_dir: Option<String>,
+    pub(crate) shared_container_default_subfolder: Option<String>,
+    /// Install tags per platform, e.g. `win` => `["Windows", "x86_64"]`.
+    pub(crate) platform_tags: BTreeMap<String, Vec<String>>,
+    pub(crate) raw: Value,
+}
+
+fn config_str(config: &Value, key: &str) -> Option<String> {
+    config.get(key)?.as_str().map(str::to_string)
+}
+
+pub(crate) fn parse(data: &str) -> Result<ProductConfig> {
+    let raw: Value = serde_json::from_str(data).context("product config json")?;
+    let all = &raw["all"]["config"];
+    let platform_tags = raw["platform"]
+        .as_object()
+        .map(|platforms| {
+            platforms
+                .iter()
+                .map(|(name, platform)| {
+                    let tags = platform["config"]["tags"]
+                        .as_array()
+                        .map(|tags| {
+                            tags.iter()
+                                .filter_map(|t| t.as_str().map(str::to_string))
+                                .collect()
+                        })
+                        .unwrap_or_default();
+                    (name.clone(), tags)
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(ProductConfig {
+        decryption_key_name: config_str(all, "decryption_key_name"),
+        product: config_str(all, "product"),
+        data_dir: config_str(all, "data_dir"),
+        shared_container_default_subfolder: config_str(all, "shared_container_default_subfolder"),
+        platform_tags,
+        raw,
+    })
+}
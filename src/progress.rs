@@ -0,0 +1,154 @@
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+/// A set of progress bars, one per phase of work, drawn together on stderr
+/// under a bar counting every byte downloaded against every byte expected.
+pub(crate) struct Progress {
+    multi: MultiProgress,
+    total: ProgressBar,
+}
+
+fn bytes_style() -> ProgressStyle {
+    ProgressStyle::with_template(
+        "{prefix:>8} [{bar:30}] {bytes}/{total_bytes} ({bytes_per_sec}) eta {eta}",
+    )
+    .expect("valid progress template")
+    .progress_chars("=> ")
+}
+
+impl Progress {
+    /// Creates a display that is hidden when disabled or when stderr is not a
+    /// terminal.
+    pub(crate) fn new(enabled: bool) -> Progress {
+        let target = if enabled && std::io::stderr().is_terminal() {
+            ProgressDrawTarget::stderr()
+        } else {
+            ProgressDrawTarget::hidden()
+        };
+        let multi = MultiProgress::with_draw_target(target);
+        let total = multi.add(ProgressBar::new(0));
+        total.set_style(bytes_style());
+        total.set_prefix("total");
+        Progress { multi, total }
+    }
+
+    pub(crate) fn phase(&self, name: &str, len: u64) -> Phase {
+        let bar = self.multi.add(ProgressBar::new(len));
+        bar.set_style(
+            ProgressStyle::with_template("{prefix:>8} [{bar:30}] {pos}/{len} {msg} eta {eta}")
+                .expect("valid progress template")
+                .progress_chars("=> "),
+        );
+        bar.set_prefix(name.to_string());
+        Phase {
+            bar,
+            total: self.total.clone(),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    /// A phase for one download, counted in bytes; `len` may be set later
+    /// with [`Phase::set_length`] once the response says how long it is.
+    pub(crate) fn bytes_phase(&self, name: &str, len: Option<u64>) -> Phase {
+        let bar = self.multi.add(ProgressBar::new(0));
+        bar.set_style(bytes_style());
+        bar.set_prefix(name.to_string());
+        let phase = Phase {
+            bar,
+            total: self.total.clone(),
+            bytes: AtomicU64::new(0),
+        };
+        if let Some(len) = len {
+            phase.set_length(len);
+        }
+        phase
+    }
+
+    /// Adds `bytes` to the total expected, once a download's size is known.
+    pub(crate) fn expect(&self, bytes: u64) {
+        self.total.inc_length(bytes);
+    }
+
+    /// Records `bytes` downloaded. Downloads nothing expected are counted
+    /// as they finish, so the total never runs past its length.
+    pub(crate) fn downloaded(&self, bytes: u64) {
+        self.total.inc(bytes);
+        let pos = self.total.position();
+        if self.total.length().is_some_and(|len| pos > len) {
+            self.total.set_length(pos);
+        }
+    }
+
+    /// Takes back a download attempt abandoned for another host: `expected`
+    /// bytes it added to the total and `received` bytes it got.
+    pub(crate) fn retract(&self, expected: u64, received: u64) {
+        self.total
+            .set_length(self.total.length().unwrap_or(0).saturating_sub(expected));
+        self.total
+            .set_position(self.total.position().saturating_sub(received));
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        self.total.finish();
+    }
+}
+
+/// One phase's bar, counting items along with the bytes they carried.
+pub(crate) struct Phase {
+    bar: ProgressBar,
+    total: ProgressBar,
+    bytes: AtomicU64,
+}
+
+impl Phase {
+    /// Records one finished item of `bytes` bytes.
+    pub(crate) fn inc(&self, bytes: u64) {
+        let total = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        let secs = self.bar.elapsed().as_secs_f64();
+        let rate = if secs > 0.0 {
+            (total as f64 / secs) as u64
+        } else {
+            0
+        };
+        self.bar
+            .set_message(format!("{} ({}/s)", HumanBytes(total), HumanBytes(rate)));
+        self.bar.inc(1);
+    }
+
+    /// Records `bytes` more bytes of a [`Progress::bytes_phase`] download,
+    /// which count toward the total too.
+    pub(crate) fn inc_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.bar.inc(bytes);
+        self.total.inc(bytes);
+    }
+
+    /// Sets the length of a [`Progress::bytes_phase`] download, moving the
+    /// total expected by the difference.
+    pub(crate) fn set_length(&self, len: u64) {
+        let old = self.bar.length().unwrap_or(0);
+        let total = self.total.length().unwrap_or(0);
+        self.total.set_length((total + len).saturating_sub(old));
+        self.bar.set_length(len);
+    }
+
+    /// Starts the bar over, for a download retried from another host.
+    pub(crate) fn reset(&self) {
+        let bytes = self.bytes.swap(0, Ordering::Relaxed);
+        self.total
+            .set_position(self.total.position().saturating_sub(bytes));
+        self.bar.set_position(0);
+    }
+
+    pub(crate) fn inc_length(&self, delta: u64) {
+        self.bar.inc_length(delta);
+    }
+
+    pub(crate) fn finish(&self) {
+        self.bar.finish();
+    }
+}
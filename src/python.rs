@@ -0,0 +1,160 @@
+//! The `rustycasc` Python module, for scripts that want the parsers without
+//! shelling out to the CLI. Errors surface as Python exceptions.
+//!
+//! Besides the parsers, `Build` fetches a build's files by FileDataID over
+//! the [`crate::blocking`] client, given its CDN prefixes and the build and
+//! CDN config hashes a versions table names.
+
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+
+use crate::blocking::Client;
+use crate::types::{ArchiveKey, ContentKey, EncodingKey, FileDataID};
+use crate::{blte, db2, encoding, hash, keyring, root};
+
+/// Maps each record ID of a db2 to its strings.
+#[pyfunction]
+fn db2_strings(data: &[u8]) -> Result<HashMap<u32, Vec<String>>> {
+    db2::strings(data)
+}
+
+/// Decodes a BLTE stream, decrypting with keys from keyring text if given.
+#[pyfunction]
+#[pyo3(signature = (data, keyring=None))]
+fn blte_decode<'py>(
+    py: Python<'py>,
+    data: &[u8],
+    keyring: Option<&str>,
+) -> Result<Bound<'py, PyBytes>> {
+    let mut keys = keyring::KeyStore::default();
+    if let Some(text) = keyring {
+        keys.add("keyring", keyring::parse(text)?);
+    }
+    Ok(PyBytes::new(py, &blte::parse_keyed(None, &keys, data)?))
+}
+
+/// Lists the entries of a decoded root file as dicts.
+#[pyfunction]
+fn root_entries<'py>(py: Python<'py>, data: &[u8]) -> Result<Vec<Bound<'py, PyDict>>> {
+    root::parse(data)?
+        .entries()
+        .map(|e| {
+            let d = PyDict::new(py);
+            d.set_item("fdid", u32::from(e.fdid))?;
+            d.set_item("ckey", format!("{:032x}", e.content_key.0))?;
+            d.set_item("name_hash", e.name_hash)?;
+            d.set_item("content_flags", e.content_flags)?;
+            d.set_item("locale_flags", e.locale_flags)?;
+            Ok(d)
+        })
+        .collect()
+}
+
+/// The root name hash of a file path.
+#[pyfunction]
+fn name_hash(path: &str) -> u64 {
+    hash::name_hash(path)
+}
+
+/// A `key = value` field of a build or CDN config.
+fn config_field<'a>(config: &'a str, key: &str) -> Option<&'a str> {
+    config
+        .lines()
+        .filter_map(|line| line.split_once(" = "))
+        .find_map(|(k, v)| (k == key).then_some(v))
+}
+
+fn parse_hash(s: &str) -> Result<u128> {
+    u128::from_str_radix(s, 16).with_context(|| format!("bad hash {}", s))
+}
+
+/// A build's encoding and root tables, for fetching its files by
+/// FileDataID. Archive indices are fetched as lookups need them, so a file
+/// in an early archive doesn't wait on all of them.
+#[pyclass]
+struct Build {
+    client: Client,
+    encoding: encoding::Encoding,
+    root: root::Root,
+    /// The archives whose indices haven't been fetched yet.
+    unindexed: Vec<ArchiveKey>,
+    index: HashMap<EncodingKey, (ArchiveKey, usize, usize)>,
+}
+
+impl Build {
+    /// Where `ekey` is archived, fetching more indices until one has it.
+    fn locate(&mut self, ekey: EncodingKey) -> Result<Option<(ArchiveKey, usize, usize)>> {
+        while !self.index.contains_key(&ekey) {
+            let archive = match self.unindexed.pop() {
+                Some(archive) => archive,
+                None => return Ok(None),
+            };
+            self.index.extend(self.client.fetch_index(archive)?.map);
+        }
+        Ok(self.index.get(&ekey).copied())
+    }
+}
+
+#[pymethods]
+impl Build {
+    #[new]
+    fn new(cdn_prefixes: Vec<String>, build_config: &str, cdn_config: &str) -> Result<Build> {
+        let client = Client::new(cdn_prefixes)?;
+        let build = client.fetch_config(parse_hash(build_config)?)?;
+        let field =
+            |key| config_field(&build, key).with_context(|| format!("build config: {}", key));
+        let encoding_key = field("encoding")?
+            .split(' ')
+            .nth(1)
+            .context("build config: encoding key")?;
+        let encoding =
+            encoding::parse(&client.fetch_decoded(EncodingKey(parse_hash(encoding_key)?))?)?;
+        let root_key = encoding.c2e(ContentKey(parse_hash(field("root")?)?))?;
+        let root = root::parse(&client.fetch_decoded(root_key)?)?;
+        let cdn = client.fetch_config(parse_hash(cdn_config)?)?;
+        let mut unindexed = config_field(&cdn, "archives")
+            .context("cdn config: archives")?
+            .split_whitespace()
+            .map(|h| parse_hash(h).map(ArchiveKey))
+            .collect::<Result<Vec<_>>>()?;
+        // Popped from the end, so the first archive is searched first.
+        unindexed.reverse();
+        Ok(Build {
+            client,
+            encoding,
+            root,
+            unindexed,
+            index: HashMap::new(),
+        })
+    }
+
+    /// The decoded content of a file, from its archive or stored loose.
+    fn fetch_fdid<'py>(&mut self, py: Python<'py>, fdid: u32) -> Result<Bound<'py, PyBytes>> {
+        let ekey = self.encoding.c2e(self.root.f2c(FileDataID(fdid))?)?;
+        let data = match self.locate(ekey)? {
+            Some((archive, size, offset)) => blte::parse(
+                Some(ekey.0),
+                &self.client.fetch_cdn_bytes(
+                    "data",
+                    archive.0,
+                    Some((offset, offset + size - 1)),
+                )?,
+            )?,
+            None => self.client.fetch_decoded(ekey)?,
+        };
+        Ok(PyBytes::new(py, &data))
+    }
+}
+
+#[pymodule]
+fn rustycasc(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Build>()?;
+    m.add_function(wrap_pyfunction!(db2_strings, m)?)?;
+    m.add_function(wrap_pyfunction!(blte_decode, m)?)?;
+    m.add_function(wrap_pyfunction!(root_entries, m)?)?;
+    m.add_function(wrap_pyfunction!(name_hash, m)?)?;
+    Ok(())
+}
@@ -1,12 +1,50 @@
-use std::{collections::HashMap, io::Read};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use anyhow::Result;
+use derive_more::Display;
+
+use crate::metrics;
+
+/// Which of a product's tables a summary row gives the seqn of, from its
+/// Flags column.
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+pub enum SummaryFlag {
+    /// No flags: the versions table.
+    #[display("")]
+    Versions,
+    #[display("cdn")]
+    Cdn,
+    #[display("bgdl")]
+    Bgdl,
+    /// A table this parser doesn't know, kept so it can be reported.
+    #[display("{}", _0)]
+    Unknown(String),
+}
+
+impl From<&str> for SummaryFlag {
+    fn from(flags: &str) -> SummaryFlag {
+        match flags {
+            "" => SummaryFlag::Versions,
+            "cdn" => SummaryFlag::Cdn,
+            "bgdl" => SummaryFlag::Bgdl,
+            other => SummaryFlag::Unknown(other.to_string()),
+        }
+    }
+}
 
 #[derive(Debug, Default, Eq, PartialEq)]
 pub struct SummaryEntry {
     pub seqn: Option<u32>,
     pub cdn: Option<u32>,
     pub bgdl: Option<u32>,
+    /// Seqns of rows with flags other than the known ones, by flags.
+    pub unknown: BTreeMap<String, u32>,
 }
 
 #[derive(Debug, Eq, PartialEq)]
@@ -15,43 +53,48 @@ pub struct Summary {
     pub entries: HashMap<String, SummaryEntry>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct VersionsEntry {
-    region: String,
-    build_config: u128,
-    cdn_config: u128,
-    key_config: Option<u128>,
-    build_id: u32,
-    name: String,
-    product_config: Option<u128>,
+    pub region: String,
+    pub build_config: u128,
+    pub cdn_config: u128,
+    pub key_config: Option<u128>,
+    pub build_id: u32,
+    pub name: String,
+    pub product_config: Option<u128>,
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct Versions {
     pub seqn: u32,
-    entries: HashMap<String, VersionsEntry>,
+    pub entries: HashMap<String, VersionsEntry>,
+}
+
+impl Versions {
+    pub fn entry(&self, region: &str) -> Option<&VersionsEntry> {
+        self.entries.get(region)
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct CDNsEntry {
-    region: String,
-    path: String,
-    hosts: Vec<String>,
-    servers: Vec<String>,
-    config_path: String,
+    pub region: String,
+    pub path: String,
+    pub hosts: Vec<String>,
+    pub servers: Vec<String>,
+    pub config_path: String,
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct CDNs {
     pub seqn: u32,
-    entries: HashMap<String, CDNsEntry>,
+    pub entries: HashMap<String, CDNsEntry>,
 }
 
 mod parsers {
     use std::collections::HashMap;
 
     use nom::{
-        branch::alt,
         bytes::complete::{is_not, tag, take_until},
         character::complete::{digit1, hex_digit1, newline},
         combinator::{eof, map, map_res, opt},
@@ -61,7 +104,7 @@ mod parsers {
     };
 
     use super::{CDNs, Summary, Versions};
-    use super::{CDNsEntry, SummaryEntry, VersionsEntry};
+    use super::{CDNsEntry, SummaryEntry, SummaryFlag, VersionsEntry};
 
     fn dec32(s: &str) -> IResult<&str, u32> {
         map_res(digit1, |s: &str| s.parse::<u32>())(s)
@@ -86,7 +129,7 @@ mod parsers {
                             tuple((
                                 is_not("|"),
                                 delimited(tag("|"), dec32, tag("|")),
-                                alt((tag("bgdl"), tag("cdn"), tag(""))),
+                                map(opt(is_not("\n")), |t| SummaryFlag::from(t.unwrap_or(""))),
                             )),
                             newline,
                         ),
@@ -94,10 +137,12 @@ mod parsers {
                         |mut m, (s, n, t)| {
                             let mut v: SummaryEntry = m.remove(s).unwrap_or_default();
                             match t {
-                                "" => v.seqn = Some(n),
-                                "cdn" => v.cdn = Some(n),
-                                "bgdl" => v.bgdl = Some(n),
-                                _ => panic!("internal error"),
+                                SummaryFlag::Versions => v.seqn = Some(n),
+                                SummaryFlag::Cdn => v.cdn = Some(n),
+                                SummaryFlag::Bgdl => v.bgdl = Some(n),
+                                SummaryFlag::Unknown(flags) => {
+                                    v.unknown.insert(flags, n);
+                                }
                             }
                             m.insert(s.to_owned(), v);
                             m
@@ -187,59 +232,414 @@ mod parsers {
     }
 }
 
-pub struct Ribbit {}
+/// How strictly to treat the `Checksum:` trailer of a Ribbit response.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum ChecksumPolicy {
+    /// The trailer must be present and match.
+    Require,
+    /// The trailer is verified if present, and skipped otherwise.
+    Tolerate,
+    /// The trailer is never checked.
+    Ignore,
+}
+
+/// Splits a response into the signed content and its `Checksum:` trailer
+/// digest, if there is one.
+fn split_checksum(content: &[u8]) -> Option<(&[u8], &[u8])> {
+    const PREFIX: &[u8] = b"Checksum: ";
+    let pos = content.windows(PREFIX.len()).rposition(|w| w == PREFIX)?;
+    if pos != 0 && content[pos - 1] != b'\n' {
+        return None;
+    }
+    let digest = &content[pos + PREFIX.len()..];
+    let end = digest
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |e| e + 1);
+    Some((&content[..pos], &digest[..end]))
+}
+
+fn verify_checksum(content: &[u8], policy: ChecksumPolicy) -> Result<()> {
+    use anyhow::{bail, ensure};
+
+    if policy == ChecksumPolicy::Ignore {
+        return Ok(());
+    }
+    match split_checksum(content) {
+        Some((signed, digest)) => {
+            let mut hasher = crate::hash::Sha256Hasher::new();
+            hasher.update(signed);
+            ensure!(
+                digest.eq_ignore_ascii_case(hex::encode(hasher.finish()).as_bytes()),
+                "ribbit checksum mismatch"
+            );
+            Ok(())
+        }
+        None if policy == ChecksumPolicy::Tolerate => {
+            log::warn!("ribbit response has no checksum trailer");
+            Ok(())
+        }
+        None => bail!("ribbit response has no checksum trailer"),
+    }
+}
+
+/// One leaf part of a Ribbit MIME response.
+#[derive(Debug)]
+pub struct ResponsePart {
+    pub headers: Vec<(String, String)>,
+    pub disposition: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// A Ribbit response broken into its MIME parts.
+#[derive(Debug)]
+pub struct Response {
+    pub headers: Vec<(String, String)>,
+    pub parts: Vec<ResponsePart>,
+    /// Text following the closing boundary, normally the checksum trailer.
+    pub epilogue: Option<String>,
+}
+
+impl Response {
+    pub fn part(&self, disposition: &str) -> Option<&ResponsePart> {
+        self.parts
+            .iter()
+            .find(|p| p.disposition.as_deref() == Some(disposition))
+    }
+    /// The payload part, i.e. the first part that is not a signature.
+    pub fn data(&self) -> Result<&str> {
+        use anyhow::Context;
+        let part = self
+            .parts
+            .iter()
+            .find(|p| p.disposition.as_deref() != Some("signature"))
+            .context("ribbit response has no data part")?;
+        std::str::from_utf8(&part.body).context("ribbit data part")
+    }
+    pub fn signature(&self) -> Option<&[u8]> {
+        self.part("signature").map(|p| p.body.as_slice())
+    }
+}
+
+fn parse_response(content: &[u8]) -> Result<Response> {
+    use anyhow::Context;
+    use mail_parser::{MimeHeaders, PartType};
+
+    let message = mail_parser::MessageParser::default()
+        .parse(content)
+        .context("mime parsing")?;
+    let headers = |hs: &[mail_parser::Header]| {
+        hs.iter()
+            .map(|h| {
+                let raw = &content[h.offset_start as usize..h.offset_end as usize];
+                (
+                    h.name.as_str().to_owned(),
+                    String::from_utf8_lossy(raw).trim().to_owned(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let epilogue = message
+        .content_type()
+        .and_then(|ct| ct.attribute("boundary"))
+        .and_then(|boundary| {
+            let close = format!("--{}--", boundary);
+            let pos = content
+                .windows(close.len())
+                .rposition(|w| w == close.as_bytes())?;
+            let rest = String::from_utf8_lossy(&content[pos + close.len()..])
+                .trim()
+                .to_owned();
+            Some(rest).filter(|s| !s.is_empty())
+        });
+    Ok(Response {
+        headers: headers(message.headers()),
+        parts: message
+            .parts
+            .iter()
+            .filter(|p| !matches!(p.body, PartType::Multipart(_)))
+            .map(|p| ResponsePart {
+                headers: headers(&p.headers),
+                disposition: p.content_disposition().map(|d| d.ctype().to_owned()),
+                body: p.contents().to_vec(),
+            })
+            .collect(),
+        epilogue,
+    })
+}
+
+/// How long to wait on one connection attempt before racing the next address.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to `host` in the style of happy eyeballs (RFC 8305): resolved
+/// addresses are tried alternating between IPv6 and IPv4, with each attempt
+/// given a short head start before the next one begins, and the first
+/// successful connection wins.
+fn connect(host: &str, timeout: Duration) -> Result<TcpStream> {
+    use anyhow::{anyhow, Context};
+    use std::net::ToSocketAddrs;
+    use std::sync::mpsc;
+
+    let (v6, v4): (Vec<_>, Vec<_>) = host
+        .to_socket_addrs()
+        .with_context(|| format!("resolving {}", host))?
+        .partition(|a| a.is_ipv6());
+    let (tx, rx) = mpsc::channel();
+    let mut pending = 0;
+    let mut last_err = None;
+    for addr in itertools::interleave(v6, v4) {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            let _ = tx.send(
+                TcpStream::connect_timeout(&addr, timeout)
+                    .with_context(|| format!("connecting to {}", addr)),
+            );
+        });
+        pending += 1;
+        match rx.recv_timeout(ATTEMPT_DELAY) {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last_err = Some(e);
+            }
+            Err(_) => {}
+        }
+    }
+    drop(tx);
+    while pending > 0 {
+        match rx.recv() {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => {
+                pending -= 1;
+                last_err = Some(e);
+            }
+            Err(_) => break,
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow!("no addresses for {}", host)))
+}
+
+/// Extracts the value of the `## seqn = N` line of a response body.
+fn data_seqn(text: &str) -> Option<u32> {
+    text.lines()
+        .find_map(|line| line.strip_prefix("## seqn = "))
+        .and_then(|n| n.trim().parse().ok())
+}
+
+fn cache_path(dir: &Path, product: &str, kind: &str, seqn: u32) -> PathBuf {
+    dir.join(product).join(format!("{}-{}", kind, seqn))
+}
+
+fn run_parser<T>(parser: fn(&str) -> nom::IResult<&str, T>, text: &str) -> Result<T> {
+    match parser(text) {
+        Ok((_, v)) => Ok(v),
+        Err(e) => {
+            metrics::inc(&metrics::METRICS.parse_failures, 1);
+            Err(e.to_owned().into())
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Ribbit {
+    checksum_policy: ChecksumPolicy,
+    connect_timeout: Duration,
+    read_timeout: Option<Duration>,
+    cache_dir: Option<PathBuf>,
+}
 
 impl Ribbit {
-    pub fn new() -> Result<Ribbit> {
-        Ok(Ribbit {})
+    pub fn new() -> Ribbit {
+        Ribbit {
+            checksum_policy: ChecksumPolicy::Require,
+            connect_timeout: Duration::from_secs(10),
+            read_timeout: Some(Duration::from_secs(30)),
+            cache_dir: None,
+        }
     }
-    fn command<T>(&mut self, cmd: &[u8], parser: fn(&str) -> nom::IResult<&str, T>) -> Result<T> {
-        use anyhow::{ensure, Context};
-        use sha2::Digest;
+    /// Caches versions and cdns responses under `dir`, keyed by product and
+    /// seqn.
+    pub fn with_cache_dir(mut self, dir: Option<PathBuf>) -> Ribbit {
+        self.cache_dir = dir;
+        self
+    }
+    pub fn with_checksum_policy(mut self, policy: ChecksumPolicy) -> Ribbit {
+        self.checksum_policy = policy;
+        self
+    }
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Ribbit {
+        self.connect_timeout = timeout;
+        self
+    }
+    pub fn with_read_timeout(mut self, timeout: Option<Duration>) -> Ribbit {
+        self.read_timeout = timeout;
+        self
+    }
+    /// Sends a raw Ribbit command and returns the decoded response.
+    pub fn request(&mut self, cmd: &[u8]) -> Result<Response> {
+        let content = self.exchange(cmd)?;
+        verify_checksum(&content, self.checksum_policy)?;
+        parse_response(&content)
+    }
+    /// Sends a Ribbit v2 command, whose response is the bare table with no
+    /// MIME wrapping, checksum or signature.
+    pub fn request_v2(&mut self, cmd: &[u8]) -> Result<String> {
+        Ok(String::from_utf8(self.exchange(cmd)?)?)
+    }
+    fn exchange(&self, cmd: &[u8]) -> Result<Vec<u8>> {
         use std::io::Write;
 
-        let mut stream = std::net::TcpStream::connect("us.version.battle.net:1119")?;
+        let mut stream = connect("us.version.battle.net:1119", self.connect_timeout)?;
+        stream.set_read_timeout(self.read_timeout)?;
         stream.write_all(cmd)?;
         stream.write_all(b"\r\n")?;
         stream.flush()?;
 
         let mut content = Vec::new();
         stream.read_to_end(&mut content)?;
-
-        let cn = content.len();
-        ensure!(cn > 76);
-        ensure!(&content[cn - 76..cn - 66] == b"Checksum: ");
-        ensure!(
-            &content[cn - 66..cn - 2]
-                == hex::encode(sha2::Sha256::digest(&content[0..cn - 76])).as_bytes()
-        );
-
-        let (_, v) = parser(
-            mail_parser::MessageParser::default()
-                .parse(&content)
-                .context("mime parsing")?
-                .part(1)
-                .context("mime part")?
-                .text_contents()
-                .context("mime text")?,
-        )
-        .map_err(|e| e.to_owned())?;
-        Ok(v)
+        metrics::inc(&metrics::METRICS.requests, 1);
+        metrics::inc(&metrics::METRICS.bytes, content.len() as u64);
+        Ok(content)
+    }
+    fn command<T>(&mut self, cmd: &[u8], parser: fn(&str) -> nom::IResult<&str, T>) -> Result<T> {
+        let response = self.request(cmd)?;
+        run_parser(parser, response.data()?)
     }
     pub fn summary(&mut self) -> Result<Summary> {
         self.command(b"v1/summary", parsers::summary)
     }
+    /// Runs a product command, serving it from the cache if `seqn` is
+    /// already there, and caching whatever is fetched.
+    fn product_command<T>(
+        &mut self,
+        product: &str,
+        kind: &str,
+        seqn: Option<u32>,
+        parser: fn(&str) -> nom::IResult<&str, T>,
+    ) -> Result<T> {
+        use anyhow::Context;
+
+        if let (Some(dir), Some(seqn)) = (&self.cache_dir, seqn) {
+            let path = cache_path(dir, product, kind, seqn);
+            if let Ok(text) = std::fs::read_to_string(&path) {
+                log::debug!("serving {} from cache", path.display());
+                metrics::inc(&metrics::METRICS.cache_hits, 1);
+                return run_parser(parser, &text);
+            }
+        }
+        let response = self.request(format!("v1/products/{}/{}", product, kind).as_bytes())?;
+        let text = response.data()?;
+        let v = run_parser(parser, text)?;
+        if let (Some(dir), Some(seqn)) = (&self.cache_dir, data_seqn(text)) {
+            let path = cache_path(dir, product, kind, seqn);
+            std::fs::create_dir_all(dir.join(product))
+                .with_context(|| format!("creating {}", dir.join(product).display()))?;
+            crate::shutdown::write_file(&path, text.as_bytes())?;
+        }
+        Ok(v)
+    }
     pub fn versions(&mut self, product: &str) -> Result<Versions> {
-        self.command(
-            format!("v1/products/{}/versions", product).as_bytes(),
-            parsers::versions,
-        )
+        self.product_command(product, "versions", None, parsers::versions)
     }
     pub fn cdns(&mut self, product: &str) -> Result<CDNs> {
-        self.command(
-            format!("v1/products/{}/cdns", product).as_bytes(),
-            parsers::cdns,
-        )
+        self.product_command(product, "cdns", None, parsers::cdns)
+    }
+    /// The build the launcher background-downloads, in versions format.
+    pub fn bgdl(&mut self, product: &str) -> Result<Versions> {
+        self.product_command(product, "bgdl", None, parsers::versions)
+    }
+    /// Like versions, but served from the cache when `seqn` is cached.
+    pub fn versions_at(&mut self, product: &str, seqn: u32) -> Result<Versions> {
+        self.product_command(product, "versions", Some(seqn), parsers::versions)
+    }
+    /// Like cdns, but served from the cache when `seqn` is cached.
+    pub fn cdns_at(&mut self, product: &str, seqn: u32) -> Result<CDNs> {
+        self.product_command(product, "cdns", Some(seqn), parsers::cdns)
+    }
+}
+
+/// Ribbit as a version source, whose v1 responses are signed MIME messages
+/// and whose v2 ones are the bare tables the HTTP endpoints serve. The
+/// client blocks, so each request runs off the async workers, on a copy of
+/// the one the source was built with.
+pub(crate) struct Source {
+    ribbit: Ribbit,
+    v2: bool,
+}
+
+impl Source {
+    pub(crate) fn new(ribbit: Ribbit, v2: bool) -> Source {
+        Source { ribbit, v2 }
+    }
+
+    async fn call<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Ribbit) -> Result<T> + Send + 'static,
+    {
+        let mut ribbit = self.ribbit.clone();
+        tokio::task::spawn_blocking(move || f(&mut ribbit)).await?
+    }
+
+    async fn v2_table(&self, product: &str, kind: &str) -> Result<String> {
+        let cmd = format!("v2/products/{}/{}", product, kind);
+        self.call(move |ribbit| ribbit.request_v2(cmd.as_bytes()))
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl crate::VersionSource for Source {
+    async fn versions(&self, product: &str) -> Result<Vec<crate::VersionInfo>> {
+        if self.v2 {
+            return crate::parse_versions(&self.v2_table(product, "versions").await?);
+        }
+        let product = product.to_string();
+        Ok(self
+            .call(move |ribbit| ribbit.versions(&product))
+            .await?
+            .entries
+            .into_values()
+            .map(crate::VersionInfo::from)
+            .collect())
+    }
+    async fn cdns(&self, product: &str) -> Result<Vec<crate::CdnInfo>> {
+        if self.v2 {
+            return crate::parse_cdns(&self.v2_table(product, "cdns").await?);
+        }
+        let product = product.to_string();
+        Ok(self
+            .call(move |ribbit| ribbit.cdns(&product))
+            .await?
+            .entries
+            .into_values()
+            .map(crate::CdnInfo::from)
+            .collect())
+    }
+}
+
+impl From<VersionsEntry> for crate::VersionInfo {
+    fn from(e: VersionsEntry) -> crate::VersionInfo {
+        crate::VersionInfo {
+            region: e.region,
+            build_config: e.build_config,
+            cdn_config: e.cdn_config,
+            key_config: e.key_config,
+            product_config: e.product_config,
+            build_id: Some(e.build_id),
+            name: Some(e.name),
+        }
+    }
+}
+
+impl From<CDNsEntry> for crate::CdnInfo {
+    fn from(e: CDNsEntry) -> crate::CdnInfo {
+        crate::CdnInfo {
+            region: e.region,
+            hosts: e.hosts,
+            path: e.path,
+            config_path: e.config_path,
+        }
     }
 }
 
@@ -257,6 +657,8 @@ mod tests {
             "moo|123|\n",
             "moo|456|cdn\n",
             "cow|789|\n",
+            "cow|790|bgdl\n",
+            "cow|791|keyring\n",
         );
         let expected = super::Summary {
             seqn: 42,
@@ -264,12 +666,13 @@ mod tests {
                 "moo".to_string(): super::SummaryEntry {
                     seqn: Some(123),
                     cdn: Some(456),
-                    bgdl: None,
+                    ..Default::default()
                 },
                 "cow".to_string(): super::SummaryEntry {
                     seqn: Some(789),
-                    cdn: None,
-                    bgdl: None,
+                    bgdl: Some(790),
+                    unknown: v![("keyring".to_string(), 791)].into_iter().collect(),
+                    ..Default::default()
                 },
             },
         };
@@ -277,6 +680,61 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn split_checksum() {
+        use super::split_checksum;
+        assert_eq!(None, split_checksum(b"no trailer here\n"));
+        assert_eq!(
+            Some((&b"body\n"[..], &b"abcd"[..])),
+            split_checksum(b"body\nChecksum: abcd\r\n")
+        );
+        assert_eq!(
+            Some((&b"body\n"[..], &b"abcd"[..])),
+            split_checksum(b"body\nChecksum: abcd")
+        );
+        assert_eq!(None, split_checksum(b"body Checksum: abcd\n"));
+    }
+
+    #[test]
+    fn response() -> Result<()> {
+        let input = concat!(
+            "MIME-Version: 1.0\r\n",
+            "Content-Type: multipart/alternative; boundary=\"XX\"\r\n",
+            "\r\n",
+            "--XX\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Disposition: version\r\n",
+            "\r\n",
+            "payload\r\n",
+            "--XX\r\n",
+            "Content-Type: application/cms\r\n",
+            "Content-Disposition: signature\r\n",
+            "\r\n",
+            "sig\r\n",
+            "--XX--\r\n",
+            "Checksum: abcd\r\n",
+        );
+        let response = super::parse_response(input.as_bytes())?;
+        assert_eq!(
+            v![Some("version"), Some("signature")],
+            response
+                .parts
+                .iter()
+                .map(|p| p.disposition.as_deref())
+                .collect::<Vec<_>>()
+        );
+        assert!(response.data()?.starts_with("payload"));
+        assert!(response.signature().is_some());
+        assert_eq!(Some("Checksum: abcd"), response.epilogue.as_deref());
+        Ok(())
+    }
+
+    #[test]
+    fn data_seqn() {
+        assert_eq!(Some(42), super::data_seqn("Header\n## seqn = 42\nrow\n"));
+        assert_eq!(None, super::data_seqn("Header\nrow\n"));
+    }
+
     #[test]
     fn strs() {
         assert_eq!(
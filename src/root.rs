@@ -1,40 +1,236 @@
-use std::{collections::HashMap, convert::TryInto};
+use std::convert::TryInto;
 
+use crate::limits::Limits;
+use crate::locale::Locale;
 use crate::types::{ContentKey, FileDataID};
+use crate::{diag, hash};
 use anyhow::{ensure, Context, Result};
 use bytes::Buf;
 
-struct RootData {
-    fdid: FileDataID,
-    content_key: ContentKey,
-    name_hash: Option<u64>,
+/// A run of entries sharing the flags of the root block they came from.
+struct Block {
+    start: u32,
+    content_flags: u32,
+    locale_flags: u32,
 }
 
-pub(crate) struct Root {
-    data: Vec<RootData>,
-    fmap: HashMap<FileDataID, usize>,
-    nmap: HashMap<u64, usize>,
+const WINDOWS: u32 = 0x1;
+const MACOS: u32 = 0x2;
+const LOW_VIOLENCE: u32 = 0x8;
+
+/// The content flags root blocks are known to use, by name.
+pub const CONTENT_FLAGS: [(u32, &str); 10] = [
+    (0x1, "windows"),
+    (0x2, "macos"),
+    (0x8, "low-violence"),
+    (0x200, "do-not-load"),
+    (0x800, "update-plugin"),
+    (0x8000000, "encrypted"),
+    (0x10000000, "no-name-hash"),
+    (0x20000000, "uncommon-resolution"),
+    (0x40000000, "bundle"),
+    (0x80000000, "no-compression"),
+];
+
+/// The names of the known flags set in `flags`, with any others in hex.
+pub fn content_flag_names(flags: u32) -> Vec<String> {
+    let known = CONTENT_FLAGS.iter().fold(0, |acc, (bit, _)| acc | bit);
+    let mut names = CONTENT_FLAGS
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect::<Vec<_>>();
+    if flags & !known != 0 {
+        names.push(format!("{:08x}", flags & !known));
+    }
+    names
+}
+
+/// One root entry, as exposed for dumps.
+pub struct Entry {
+    pub fdid: FileDataID,
+    pub content_key: ContentKey,
+    pub name_hash: Option<u64>,
+    pub content_flags: u32,
+    pub locale_flags: u32,
+}
+
+/// Root entries stored column by column in file order, with sorted indices
+/// for lookups. An entry without a name has a name hash of zero.
+///
+/// Files can appear several times with different locale and content flags.
+/// Lookups without a locale take the entry for the most preferred locale,
+/// then one that is neither low-violence nor macOS-only, then the last.
+pub struct Root {
+    fdids: Vec<FileDataID>,
+    content_keys: Vec<ContentKey>,
+    name_hashes: Vec<u64>,
+    blocks: Vec<Block>,
+    by_fdid: Vec<u32>,
+    by_name: Vec<u32>,
+    preferred: Vec<Locale>,
+}
+
+/// A file whose entries have different content keys.
+pub struct Conflict {
+    /// The entry lookups without a locale resolve to.
+    pub kept: Entry,
+    /// The file's other entries, in root file order.
+    pub others: Vec<Entry>,
+}
+
+/// The run of `index` whose keys equal `key`, in file order.
+fn equal_range<K: Ord>(index: &[u32], key: K, key_of: impl Fn(usize) -> K) -> &[u32] {
+    let lo = index.partition_point(|&i| key_of(i as usize) < key);
+    let hi = lo + index[lo..].partition_point(|&i| key_of(i as usize) <= key);
+    &index[lo..hi]
 }
 
 impl Root {
-    pub(crate) fn f2c(&self, fdid: FileDataID) -> Result<ContentKey> {
-        Ok(self.data[*self.fmap.get(&fdid).context("missing fdid in root")?].content_key)
-    }
-    pub(crate) fn n2c(&self, name: &str) -> Result<ContentKey> {
-        let hash: u64 = hashers::jenkins::lookup3(name.to_uppercase().as_bytes());
-        // The hi and lo words are swapped for some reason.
-        let hi = (hash >> 32) as u32;
-        let lo = (hash & 0xffffffff) as u32;
-        let hash: u64 = ((lo as u64) << 32) | (hi as u64);
-        Ok(self.data[*self
-            .nmap
-            .get(&hash)
-            .with_context(|| format!("missing name hash in root: {}", name))?]
-        .content_key)
+    fn block(&self, i: usize) -> &Block {
+        let b = self.blocks.partition_point(|b| b.start as usize <= i);
+        &self.blocks[b - 1]
+    }
+    fn entry(&self, i: usize) -> Entry {
+        let block = self.block(i);
+        Entry {
+            fdid: self.fdids[i],
+            content_key: self.content_keys[i],
+            name_hash: Some(self.name_hashes[i]).filter(|h| *h != 0),
+            content_flags: block.content_flags,
+            locale_flags: block.locale_flags,
+        }
+    }
+    /// All entries, in root file order.
+    pub fn entries(&self) -> impl Iterator<Item = Entry> + '_ {
+        (0..self.fdids.len()).map(move |i| self.entry(i))
+    }
+    /// Every entry for `fdid`, in root file order.
+    pub fn fdid_entries(&self, fdid: FileDataID) -> Vec<Entry> {
+        equal_range(&self.by_fdid, fdid, |i| self.fdids[i])
+            .iter()
+            .map(|&i| self.entry(i as usize))
+            .collect()
+    }
+    /// Every entry whose name hash matches `name`, in root file order.
+    pub fn name_entries(&self, name: &str) -> Vec<Entry> {
+        equal_range(&self.by_name, hash::name_hash(name), |i| {
+            self.name_hashes[i]
+        })
+        .iter()
+        .map(|&i| self.entry(i as usize))
+        .collect()
+    }
+    /// Sets the locales lookups without one prefer, most preferred first;
+    /// enUS by default.
+    pub fn with_preferred_locales(mut self, locales: Vec<Locale>) -> Root {
+        self.preferred = locales;
+        self
+    }
+    /// How well entry `i` suits a lookup without a locale; lower is better.
+    fn rank(&self, i: usize) -> (usize, bool, bool) {
+        let block = self.block(i);
+        let locale = self
+            .preferred
+            .iter()
+            .position(|l| block.locale_flags & l.root_flag() != 0)
+            .unwrap_or(self.preferred.len());
+        let flags = block.content_flags;
+        (
+            locale,
+            flags & LOW_VIOLENCE != 0,
+            flags & MACOS != 0 && flags & WINDOWS == 0,
+        )
+    }
+    fn pick_index(&self, entries: &[u32], locale: Option<Locale>) -> Option<u32> {
+        match locale {
+            None => entries
+                .iter()
+                .rev()
+                .min_by_key(|&&i| self.rank(i as usize))
+                .copied(),
+            Some(locale) => entries
+                .iter()
+                .find(|&&i| self.block(i as usize).locale_flags & locale.root_flag() != 0)
+                .copied(),
+        }
+    }
+    /// The entry a lookup of `fdid` resolves to, for `locale` if given.
+    pub fn fdid_entry(&self, fdid: FileDataID, locale: Option<Locale>) -> Option<Entry> {
+        let entries = equal_range(&self.by_fdid, fdid, |i| self.fdids[i]);
+        let index = self.pick_index(entries, locale)?;
+        Some(self.entry(index as usize))
+    }
+    /// The entry a lookup of `name` resolves to, for `locale` if given.
+    pub fn name_entry(&self, name: &str, locale: Option<Locale>) -> Option<Entry> {
+        let hash = hash::name_hash(name);
+        let entries = equal_range(&self.by_name, hash, |i| self.name_hashes[i]);
+        let index = self.pick_index(entries, locale)?;
+        Some(self.entry(index as usize))
+    }
+    fn pick(&self, entries: &[u32], locale: Option<Locale>) -> Option<ContentKey> {
+        let index = self.pick_index(entries, locale)?;
+        Some(self.content_keys[index as usize])
+    }
+    /// Every file whose entries disagree on its content, in fdid order.
+    pub fn conflicts(&self) -> impl Iterator<Item = Conflict> + '_ {
+        self.by_fdid
+            .chunk_by(|&a, &b| self.fdids[a as usize] == self.fdids[b as usize])
+            .filter(|run| {
+                let first = self.content_keys[run[0] as usize];
+                run.iter().any(|&i| self.content_keys[i as usize] != first)
+            })
+            .map(move |run| {
+                let kept = self.pick_index(run, None).expect("runs are nonempty");
+                Conflict {
+                    kept: self.entry(kept as usize),
+                    others: run
+                        .iter()
+                        .filter(|&&i| i != kept)
+                        .map(|&i| self.entry(i as usize))
+                        .collect(),
+                }
+            })
+    }
+    pub fn f2c(&self, fdid: FileDataID) -> Result<ContentKey> {
+        self.f2c_in(fdid, None)
     }
+    pub fn n2c(&self, name: &str) -> Result<ContentKey> {
+        self.n2c_in(name, None)
+    }
+    /// Like f2c, but for the entry carrying `locale`'s flag, if given.
+    pub fn f2c_in(&self, fdid: FileDataID, locale: Option<Locale>) -> Result<ContentKey> {
+        let entries = equal_range(&self.by_fdid, fdid, |i| self.fdids[i]);
+        ensure!(!entries.is_empty(), "missing fdid in root: {}", fdid);
+        self.pick(entries, locale).with_context(|| {
+            format!(
+                "no {} entry in root for fdid {}",
+                Locale::describe(locale),
+                fdid
+            )
+        })
+    }
+    /// Like n2c, but for the entry carrying `locale`'s flag, if given.
+    pub fn n2c_in(&self, name: &str, locale: Option<Locale>) -> Result<ContentKey> {
+        let hash = hash::name_hash(name);
+        let entries = equal_range(&self.by_name, hash, |i| self.name_hashes[i]);
+        ensure!(!entries.is_empty(), "missing name hash in root: {}", name);
+        self.pick(entries, locale)
+            .with_context(|| format!("no {} entry in root for {}", Locale::describe(locale), name))
+    }
+}
+
+pub fn parse(data: &[u8]) -> Result<Root> {
+    parse_limited(data, &Limits::default())
+}
+
+/// Like parse, but with the entry count held to `limits`.
+pub fn parse_limited(data: &[u8], limits: &Limits) -> Result<Root> {
+    let mut t = diag::Tracker::new(data, "root header");
+    parse_tracked(data, limits, &mut t).map_err(|e| t.locate(e))
 }
 
-pub(crate) fn parse(data: &[u8]) -> Result<Root> {
+fn parse_tracked(data: &[u8], limits: &Limits, t: &mut diag::Tracker) -> Result<Root> {
     let mut p = data;
     ensure!(p.remaining() >= 4, "empty root?");
     let interleave;
@@ -50,60 +246,200 @@ pub(crate) fn parse(data: &[u8]) -> Result<Root> {
         interleave = true;
         can_skip = false;
     }
-    let mut result = Vec::<RootData>::new();
+    let mut fdids = Vec::<FileDataID>::new();
+    let mut content_keys = Vec::<ContentKey>::new();
+    let mut name_hashes = Vec::<u64>::new();
+    let mut blocks = Vec::<Block>::new();
     while p.has_remaining() {
+        t.at("root cas block", p);
         ensure!(p.remaining() >= 12, "truncated root cas block");
-        let num_records: usize = p.get_u32_le().try_into()?;
+        let num_records =
+            limits.entries("root", u64::from(p.get_u32_le()) + fdids.len() as u64)? - fdids.len();
         let content_flags = p.get_u32_le();
-        let _locale_flags = p.get_u32_le();
+        let locale_flags = p.get_u32_le();
+        blocks.push(Block {
+            start: fdids.len().try_into()?,
+            content_flags,
+            locale_flags,
+        });
         ensure!(
-            p.remaining() >= 4 * num_records,
+            p.remaining() / 4 >= num_records,
             "truncated filedataid delta block"
         );
-        let mut fdids = Vec::<FileDataID>::new();
-        let mut fdid = -1;
+        let mut fdid = -1i64;
         for _ in 0..num_records {
-            fdid = fdid + p.get_i32_le() + 1;
-            fdids.push(FileDataID(fdid.try_into()?))
+            fdid += i64::from(p.get_i32_le()) + 1;
+            fdids.push(FileDataID(
+                fdid.try_into().context("filedataid delta out of range")?,
+            ))
         }
-        let mut content_keys = Vec::<ContentKey>::new();
-        let mut name_hashes = Vec::<Option<u64>>::new();
+        t.at("root cas block records", p);
         if interleave {
+            ensure!(p.remaining() / 24 >= num_records, "truncated root records");
             for _ in 0..num_records {
                 content_keys.push(ContentKey(p.get_u128()));
-                name_hashes.push(Some(p.get_u64_le()));
+                name_hashes.push(p.get_u64_le());
             }
         } else {
+            ensure!(
+                p.remaining() / 16 >= num_records,
+                "truncated root content keys"
+            );
             for _ in 0..num_records {
                 content_keys.push(ContentKey(p.get_u128()));
             }
             if !can_skip || content_flags & 0x10000000 == 0 {
+                t.at("root name hashes", p);
+                ensure!(
+                    p.remaining() / 8 >= num_records,
+                    "truncated root name hashes"
+                );
                 for _ in 0..num_records {
-                    name_hashes.push(Some(p.get_u64_le()));
+                    name_hashes.push(p.get_u64_le());
                 }
             } else {
-                name_hashes.resize(num_records, None);
+                name_hashes.resize(fdids.len(), 0);
             }
         }
-        for i in 0..num_records {
-            result.push(RootData {
-                fdid: fdids[i],
-                content_key: content_keys[i],
-                name_hash: name_hashes[i],
-            })
-        }
     }
+    let count: u32 = fdids.len().try_into()?;
+    let mut by_fdid = (0..count).collect::<Vec<_>>();
+    by_fdid.sort_unstable_by_key(|&i| (fdids[i as usize], i));
+    let mut by_name = (0..count)
+        .filter(|&i| name_hashes[i as usize] != 0)
+        .collect::<Vec<_>>();
+    by_name.sort_unstable_by_key(|&i| (name_hashes[i as usize], i));
     Ok(Root {
-        fmap: result
-            .iter()
-            .enumerate()
-            .map(|(k, d)| (d.fdid, k))
-            .collect(),
-        nmap: result
-            .iter()
-            .enumerate()
-            .filter_map(|(k, d)| d.name_hash.map(|h| (h, k)))
-            .collect(),
-        data: result,
+        fdids,
+        content_keys,
+        name_hashes,
+        blocks,
+        by_fdid,
+        by_name,
+        preferred: vec![Locale::EnUs],
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::BufMut;
+
+    #[test]
+    fn test_content_flag_names() {
+        assert!(content_flag_names(0).is_empty());
+        assert_eq!(
+            content_flag_names(0x10000001),
+            vec!["windows", "no-name-hash"]
+        );
+        assert_eq!(content_flag_names(0x4), vec!["00000004"]);
+    }
+
+    #[test]
+    fn test_lookups() {
+        let mut data = b"TSFM".to_vec();
+        data.put_u32_le(3);
+        data.put_u32_le(2);
+        // enUS block with names: fdids 5 and 9.
+        data.put_u32_le(2);
+        data.put_u32_le(0);
+        data.put_u32_le(Locale::EnUs.root_flag());
+        data.put_i32_le(5);
+        data.put_i32_le(3);
+        data.put_u128(1);
+        data.put_u128(2);
+        data.put_u64_le(hash::name_hash("a.lua"));
+        data.put_u64_le(hash::name_hash("b.lua"));
+        // deDE block without names: fdid 5 again.
+        data.put_u32_le(1);
+        data.put_u32_le(0x10000000);
+        data.put_u32_le(Locale::DeDe.root_flag());
+        data.put_i32_le(5);
+        data.put_u128(3);
+        let root = parse(&data).unwrap();
+        assert_eq!(root.f2c(FileDataID(5)).unwrap(), ContentKey(1));
+        assert_eq!(
+            root.f2c_in(FileDataID(5), Some(Locale::EnUs)).unwrap(),
+            ContentKey(1)
+        );
+        assert_eq!(root.f2c(FileDataID(9)).unwrap(), ContentKey(2));
+        assert!(root.f2c(FileDataID(7)).is_err());
+        assert!(root.f2c_in(FileDataID(9), Some(Locale::DeDe)).is_err());
+        assert_eq!(root.n2c("B.LUA").unwrap(), ContentKey(2));
+        assert!(root.n2c("c.lua").is_err());
+        let entries = root.entries().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].name_hash, None);
+        assert_eq!(entries[2].content_flags, 0x10000000);
+        assert_eq!(root.fdid_entries(FileDataID(5)).len(), 2);
+        assert_eq!(root.name_entries("a.lua")[0].fdid, FileDataID(5));
+        // The deDE entry comes last, but enUS is preferred.
+        let chosen = root.fdid_entry(FileDataID(5), None).unwrap();
+        assert_eq!(
+            (chosen.content_key, chosen.locale_flags),
+            (ContentKey(1), Locale::EnUs.root_flag())
+        );
+        assert_eq!(
+            root.fdid_entry(FileDataID(5), Some(Locale::DeDe))
+                .unwrap()
+                .content_key,
+            ContentKey(3)
+        );
+        assert_eq!(
+            root.name_entry("a.lua", None).unwrap().content_key,
+            ContentKey(1)
+        );
+        assert!(root.fdid_entry(FileDataID(9), Some(Locale::DeDe)).is_none());
+        let conflicts = root.conflicts().collect::<Vec<_>>();
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].kept.content_key, ContentKey(1));
+        assert_eq!(conflicts[0].others[0].content_key, ContentKey(3));
+        let root = root.with_preferred_locales(vec![Locale::DeDe]);
+        assert_eq!(root.f2c(FileDataID(5)).unwrap(), ContentKey(3));
+        let root = root.with_preferred_locales(vec![]);
+        assert_eq!(root.f2c(FileDataID(5)).unwrap(), ContentKey(3));
+    }
+
+    #[test]
+    fn test_flag_preference() {
+        let mut data = Vec::new();
+        for (flags, ckey) in [(0, 1), (LOW_VIOLENCE, 2), (MACOS, 3)] {
+            data.put_u32_le(1);
+            data.put_u32_le(flags);
+            data.put_u32_le(Locale::EnUs.root_flag());
+            data.put_i32_le(5);
+            data.put_u128(ckey);
+            data.put_u64_le(0);
+        }
+        assert_eq!(
+            parse(&data).unwrap().f2c(FileDataID(5)).unwrap(),
+            ContentKey(1)
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let block = |deltas: &[i32]| {
+            let mut data = Vec::new();
+            data.put_u32_le(deltas.len() as u32);
+            data.put_u32_le(0);
+            data.put_u32_le(0);
+            deltas.iter().for_each(|d| data.put_i32_le(*d));
+            for _ in deltas {
+                data.put_u128(1);
+                data.put_u64_le(0);
+            }
+            data
+        };
+        assert!(parse(&block(&[i32::MAX; 3])).is_err());
+        assert!(parse(&block(&[-5])).is_err());
+        let mut data = block(&[1, 2]);
+        data.extend(block(&[3]));
+        let limits = Limits {
+            max_entries: 2,
+            ..Limits::default()
+        };
+        assert_eq!(parse(&data).unwrap().entries().count(), 3);
+        assert!(parse_limited(&data, &limits).is_err());
+    }
+}
@@ -0,0 +1,70 @@
+//! Salsa20 with a 128-bit key, as used by TACT to encrypt BLTE chunks.
+
+const TAU: &[u8; 16] = b"expand 16-byte k";
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+    x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+    x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+    x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+}
+
+fn block(key: &[u8; 16], nonce: &[u8; 8], counter: u64) -> [u8; 64] {
+    let word = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    let mut input = [0u32; 16];
+    for i in 0..4 {
+        input[i * 5] = word(&TAU[i * 4..]);
+        input[1 + i] = word(&key[i * 4..]);
+        input[11 + i] = word(&key[i * 4..]);
+    }
+    input[6] = word(&nonce[0..]);
+    input[7] = word(&nonce[4..]);
+    input[8] = counter as u32;
+    input[9] = (counter >> 32) as u32;
+    let mut x = input;
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 5, 9, 13, 1);
+        quarter_round(&mut x, 10, 14, 2, 6);
+        quarter_round(&mut x, 15, 3, 7, 11);
+        quarter_round(&mut x, 0, 1, 2, 3);
+        quarter_round(&mut x, 5, 6, 7, 4);
+        quarter_round(&mut x, 10, 11, 8, 9);
+        quarter_round(&mut x, 15, 12, 13, 14);
+    }
+    let mut out = [0u8; 64];
+    for (i, chunk) in out.chunks_exact_mut(4).enumerate() {
+        chunk.copy_from_slice(&x[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    out
+}
+
+/// XORs `data` with the keystream; encryption and decryption are the same.
+pub fn apply(key: &[u8; 16], nonce: &[u8; 8], data: &mut [u8]) {
+    for (counter, chunk) in data.chunks_mut(64).enumerate() {
+        let stream = block(key, nonce, counter as u64);
+        chunk
+            .iter_mut()
+            .zip(stream.iter())
+            .for_each(|(b, s)| *b ^= s);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_apply() {
+        // ECRYPT Salsa20/20 128-bit set 1, vector 0.
+        let mut key = [0u8; 16];
+        key[0] = 0x80;
+        let mut data = [0u8; 64];
+        super::apply(&key, &[0; 8], &mut data);
+        assert_eq!(
+            data.iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<String>(),
+            "4DFA5E481DA23EA09A31022050859936DA52FCEE218005164F267CB65F5CFD7F\
+             2B4F97E0FF16924A52DF269515110A07F9E460BC65EF95DA58F740B7D1DBB0AA"
+        );
+    }
+}
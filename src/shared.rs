@@ -0,0 +1,200 @@
+//! Work that builds opened in one run have in common, done once: configs
+//! fetched by hash, and archive indices parsed by archive key, which
+//! products on the same CDN mostly share.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::archive::Index;
+use crate::types::{ArchiveKey, EncodingKey};
+use anyhow::Result;
+use bytes::Bytes;
+use tokio::sync::OnceCell;
+
+struct Entry<V> {
+    cell: Arc<OnceCell<V>>,
+    /// When the entry was last asked for, for evicting the least recent.
+    used: u64,
+    /// What the value weighs, once it's been computed.
+    weight: usize,
+}
+
+struct Cells<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    clock: u64,
+    /// The weight of every computed value held.
+    weight: usize,
+}
+
+/// Values by key, each computed at most once however many callers ask for
+/// it at a time. A failed computation is left for the next caller to retry.
+/// Once the values held weigh more than the budget, the least recently used
+/// are dropped, to be computed again if they're asked for.
+pub(crate) struct Once<K, V> {
+    cells: Mutex<Cells<K, V>>,
+    budget: Option<usize>,
+    weigh: fn(&V) -> usize,
+    /// Values handed out without being computed again.
+    reused: AtomicUsize,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> Once<K, V> {
+    pub(crate) fn new(budget: Option<usize>, weigh: fn(&V) -> usize) -> Self {
+        Once {
+            cells: Mutex::new(Cells {
+                entries: HashMap::new(),
+                clock: 0,
+                weight: 0,
+            }),
+            budget,
+            weigh,
+            reused: AtomicUsize::new(0),
+        }
+    }
+
+    /// The value for `key`, computing it with `init` unless it already was.
+    pub(crate) async fn get_or_try_init<F, Fut>(&self, key: K, init: F) -> Result<V>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V>>,
+    {
+        let cell = {
+            let mut cells = self.cells.lock().expect("shared lock");
+            cells.clock += 1;
+            let used = cells.clock;
+            let entry = cells.entries.entry(key.clone()).or_insert_with(|| Entry {
+                cell: Default::default(),
+                used,
+                weight: 0,
+            });
+            entry.used = used;
+            entry.cell.clone()
+        };
+        let mut computed = false;
+        let value = cell
+            .get_or_try_init(|| {
+                computed = true;
+                init()
+            })
+            .await?
+            .clone();
+        if computed {
+            self.hold(key, (self.weigh)(&value));
+        } else {
+            self.reused.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(value)
+    }
+
+    /// Counts a newly computed value against the budget, dropping the least
+    /// recently used others until it fits.
+    fn hold(&self, key: K, weight: usize) {
+        let mut cells = self.cells.lock().expect("shared lock");
+        match cells.entries.get_mut(&key) {
+            Some(entry) => entry.weight = weight,
+            None => return,
+        }
+        cells.weight += weight;
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        while cells.weight > budget {
+            let oldest = cells
+                .entries
+                .iter()
+                .filter(|(k, e)| **k != key && e.cell.initialized())
+                .min_by_key(|(_, e)| e.used)
+                .map(|(k, _)| k.clone());
+            match oldest.and_then(|k| cells.entries.remove(&k)) {
+                Some(entry) => cells.weight -= entry.weight,
+                None => break,
+            }
+        }
+    }
+
+    pub(crate) fn reused(&self) -> usize {
+        self.reused.load(Ordering::Relaxed)
+    }
+}
+
+/// What builds opened with the same options share.
+pub(crate) struct Fetches {
+    /// Build, CDN and key configs by hash.
+    pub(crate) configs: Once<u128, Bytes>,
+    /// Parsed archive indices, before each build merges its own set.
+    pub(crate) indices: Once<ArchiveKey, Arc<Index>>,
+}
+
+impl Fetches {
+    /// Shares at most `budget` bytes of indices, and as much again of
+    /// configs, which are far smaller; with no budget, everything is kept
+    /// for the whole run.
+    pub(crate) fn new(budget: Option<usize>) -> Fetches {
+        Fetches {
+            configs: Once::new(budget, Bytes::len),
+            indices: Once::new(budget, |index: &Arc<Index>| {
+                index.map.len() * std::mem::size_of::<(EncodingKey, (ArchiveKey, usize, usize))>()
+            }),
+        }
+    }
+}
+
+impl Default for Fetches {
+    fn default() -> Fetches {
+        Fetches::new(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_once() {
+        let once = Once::<u32, String>::new(None, String::len);
+        let calls = &AtomicUsize::new(0);
+        let init = |value: &'static str| {
+            move || async move {
+                calls.fetch_add(1, Ordering::Relaxed);
+                Ok(value.to_string())
+            }
+        };
+        // Concurrent asks for one key compute it once.
+        let (a, b) = futures::future::join(
+            once.get_or_try_init(1, init("a")),
+            once.get_or_try_init(1, init("b")),
+        )
+        .await;
+        assert_eq!((a.unwrap(), b.unwrap()), ("a".to_string(), "a".to_string()));
+        assert_eq!(once.get_or_try_init(2, init("c")).await.unwrap(), "c");
+        assert_eq!((calls.load(Ordering::Relaxed), once.reused()), (2, 1));
+        // Failures aren't kept.
+        assert!(once
+            .get_or_try_init(3, || async { anyhow::bail!("unavailable") })
+            .await
+            .is_err());
+        assert_eq!(once.get_or_try_init(3, init("d")).await.unwrap(), "d");
+    }
+
+    #[tokio::test]
+    async fn test_once_budget() {
+        let once = Once::<u32, String>::new(Some(4), String::len);
+        let init = |value: &'static str| move || async move { Ok(value.to_string()) };
+        once.get_or_try_init(1, init("ab")).await.unwrap();
+        once.get_or_try_init(2, init("cd")).await.unwrap();
+        // Asking for 1 again makes 2 the least recently used, so it's the
+        // one dropped to fit 3.
+        once.get_or_try_init(1, init("xx")).await.unwrap();
+        once.get_or_try_init(3, init("ef")).await.unwrap();
+        assert_eq!(once.get_or_try_init(1, init("xx")).await.unwrap(), "ab");
+        assert_eq!(once.get_or_try_init(2, init("gh")).await.unwrap(), "gh");
+        assert_eq!(once.reused(), 2);
+        let cells = once.cells.lock().unwrap();
+        assert_eq!(cells.weight, 4);
+        assert!(!cells.entries.contains_key(&3));
+    }
+}
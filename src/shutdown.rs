@@ -0,0 +1,77 @@
+//! Ctrl-C handling. An interrupted command's future is dropped, which
+//! cancels its in-flight fetches, and files are written beside their
+//! destination and renamed into place, so an interrupt never leaves a
+//! half-written output or state file behind.
+
+use std::fs::File;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// The exit status of an interrupted run, as shells report for SIGINT.
+pub(crate) const INTERRUPTED: i32 = 130;
+
+/// Runs `command` to completion, or returns None once Ctrl-C is pressed.
+pub(crate) async fn unless_interrupted<T>(command: impl Future<Output = T>) -> Option<T> {
+    let interrupt = async {
+        // Without a handler Ctrl-C kills the process as before.
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            log::warn!("unable to listen for ctrl-c: {:#}", e);
+            futures::future::pending::<()>().await;
+        }
+    };
+    tokio::select! {
+        result = command => Some(result),
+        () = interrupt => None,
+    }
+}
+
+/// Writes `data` to `path` by way of a `.part` file, which is removed again
+/// if the write fails.
+pub(crate) fn write_file(path: &Path, data: &[u8]) -> Result<()> {
+    write_with(path, |mut file| {
+        Ok(std::io::Write::write_all(&mut file, data)?)
+    })
+}
+
+/// Like [`write_file`], for output too big to build in memory first:
+/// `write` streams it into the `.part` file.
+pub(crate) fn write_with(path: &Path, write: impl FnOnce(File) -> Result<()>) -> Result<()> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let result = File::create(&part)
+        .map_err(anyhow::Error::from)
+        .and_then(write)
+        .and_then(|()| Ok(std::fs::rename(&part, path)?));
+    match result {
+        Ok(()) => crate::summary::wrote(path),
+        Err(_) => {
+            let _ = std::fs::remove_file(&part);
+        }
+    }
+    result.with_context(|| format!("writing {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_file() {
+        let dir = std::env::temp_dir().join(format!("rustycasc-shutdown-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.zip");
+        write_file(&path, b"one").unwrap();
+        write_file(&path, b"two").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"two");
+        assert!(!dir.join("out.zip.part").exists());
+        assert!(write_file(&dir.join("missing").join("out.zip"), b"x").is_err());
+        // A failed streaming write leaves the old file in place.
+        assert!(write_with(&path, |_| anyhow::bail!("interrupted")).is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"two");
+        assert!(!dir.join("out.zip.part").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+//! The files of an export, held in memory up to a ceiling and spooled to a
+//! temporary directory past it, so large builds export on small machines.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::{bail, Context, Result};
+
+enum Held {
+    Memory(Vec<u8>),
+    Spooled(PathBuf, usize),
+}
+
+impl Held {
+    fn len(&self) -> usize {
+        match self {
+            Held::Memory(data) => data.len(),
+            Held::Spooled(_, len) => *len,
+        }
+    }
+}
+
+/// Files by name, spooled to disk once the ones in memory reach `ceiling`
+/// bytes. The spool directory is removed when the store is dropped.
+pub(crate) struct Files {
+    files: HashMap<String, Held>,
+    /// Bytes held in memory.
+    held: usize,
+    ceiling: Option<usize>,
+    dir: Option<PathBuf>,
+    /// Files written to the spool, for naming the next.
+    spooled: usize,
+}
+
+/// Tells apart the spool directories of stores in one process.
+static STORES: AtomicUsize = AtomicUsize::new(0);
+
+impl Files {
+    /// An empty store; with no ceiling, everything stays in memory.
+    pub(crate) fn new(ceiling: Option<usize>) -> Files {
+        Files {
+            files: HashMap::new(),
+            held: 0,
+            ceiling,
+            dir: None,
+            spooled: 0,
+        }
+    }
+
+    /// Adds `name`, replacing any file already stored under it.
+    pub(crate) fn insert(&mut self, name: String, data: Vec<u8>) -> Result<()> {
+        self.remove(&name)?;
+        let held = match self.ceiling {
+            Some(ceiling) if self.held + data.len() > ceiling => {
+                let n = self.spooled;
+                let path = self.spool_dir()?.join(n.to_string());
+                std::fs::write(&path, &data)
+                    .with_context(|| format!("spooling {} to {}", name, path.display()))?;
+                self.spooled += 1;
+                Held::Spooled(path, data.len())
+            }
+            _ => {
+                self.held += data.len();
+                Held::Memory(data)
+            }
+        };
+        self.files.insert(name, held);
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> Result<()> {
+        match self.files.remove(name) {
+            Some(Held::Memory(data)) => self.held -= data.len(),
+            Some(Held::Spooled(path, _)) => std::fs::remove_file(&path)
+                .with_context(|| format!("removing {}", path.display()))?,
+            None => {}
+        }
+        Ok(())
+    }
+
+    fn spool_dir(&mut self) -> Result<&PathBuf> {
+        if self.dir.is_none() {
+            let dir = std::env::temp_dir().join(format!(
+                "rustycasc-spool-{}-{}",
+                std::process::id(),
+                STORES.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+            log::info!(
+                "spooling files past the memory ceiling to {}",
+                dir.display()
+            );
+            self.dir = Some(dir);
+        }
+        Ok(self.dir.as_ref().expect("just set"))
+    }
+
+    /// Where the caller can write a scratch file of its own, `name`, which
+    /// is removed along with the spool when the store is dropped.
+    pub(crate) fn scratch_path(&mut self, name: &str) -> Result<PathBuf> {
+        Ok(self.spool_dir()?.join(format!("scratch-{}", name)))
+    }
+
+    /// The content of `name`, read back from the spool if it was spooled.
+    pub(crate) fn get(&self, name: &str) -> Result<Option<Cow<'_, [u8]>>> {
+        Ok(match self.files.get(name) {
+            Some(Held::Memory(data)) => Some(Cow::Borrowed(data)),
+            Some(Held::Spooled(path, _)) => Some(Cow::Owned(
+                std::fs::read(path).with_context(|| format!("reading back {}", name))?,
+            )),
+            None => None,
+        })
+    }
+
+    pub(crate) fn size(&self, name: &str) -> Option<usize> {
+        self.files.get(name).map(Held::len)
+    }
+
+    /// The stored names, sorted.
+    pub(crate) fn names(&self) -> Vec<&String> {
+        let mut names = self.files.keys().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The total size of every file, in memory or not.
+    pub(crate) fn bytes(&self) -> usize {
+        self.files.values().map(Held::len).sum()
+    }
+
+    /// How many files are on disk rather than in memory.
+    pub(crate) fn spooled(&self) -> usize {
+        self.files
+            .values()
+            .filter(|held| matches!(held, Held::Spooled(..)))
+            .count()
+    }
+
+    /// Reads everything back into memory.
+    pub(crate) fn into_memory(mut self) -> Result<HashMap<String, Vec<u8>>> {
+        std::mem::take(&mut self.files)
+            .into_iter()
+            .map(|(name, held)| {
+                let data = match held {
+                    Held::Memory(data) => data,
+                    Held::Spooled(path, _) => {
+                        std::fs::read(&path).with_context(|| format!("reading back {}", name))?
+                    }
+                };
+                Ok((name, data))
+            })
+            .collect()
+    }
+}
+
+impl Drop for Files {
+    fn drop(&mut self) {
+        if let Some(dir) = &self.dir {
+            if let Err(e) = std::fs::remove_dir_all(dir) {
+                log::warn!("unable to remove spool {}: {}", dir.display(), e);
+            }
+        }
+    }
+}
+
+/// Splits `items` into runs whose `size`s add up to at most `budget`, in
+/// order; an item over the budget on its own gets a run to itself. With no
+/// budget, everything is one run.
+pub(crate) fn batches<T>(
+    items: Vec<T>,
+    budget: Option<u64>,
+    size: impl Fn(&T) -> u64,
+) -> Vec<Vec<T>> {
+    let budget = match budget {
+        Some(budget) => budget,
+        None => return vec![items],
+    };
+    let mut batches = Vec::new();
+    let (mut batch, mut total) = (Vec::new(), 0);
+    for item in items {
+        let n = size(&item);
+        if !batch.is_empty() && total + n > budget {
+            batches.push(std::mem::take(&mut batch));
+            total = 0;
+        }
+        total += n;
+        batch.push(item);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Parses a byte count like `512M`, with an optional K, M or G suffix
+/// (binary multiples).
+pub(crate) fn parse_size(s: &str) -> Result<usize> {
+    let (digits, shift) = match s.trim().to_ascii_uppercase() {
+        t if t.ends_with('K') => (t[..t.len() - 1].to_string(), 10),
+        t if t.ends_with('M') => (t[..t.len() - 1].to_string(), 20),
+        t if t.ends_with('G') => (t[..t.len() - 1].to_string(), 30),
+        t => (t, 0),
+    };
+    let n = digits
+        .parse::<usize>()
+        .with_context(|| format!("bad size {}", s))?;
+    match n.checked_mul(1 << shift) {
+        Some(0) | None => bail!("size {} out of range", s),
+        Some(bytes) => Ok(bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_files() {
+        let mut files = Files::new(Some(4));
+        files.insert("a".to_string(), b"abc".to_vec()).unwrap();
+        files.insert("b".to_string(), b"de".to_vec()).unwrap();
+        assert_eq!(files.spooled(), 1);
+        let dir = files.dir.clone().unwrap();
+        assert!(dir.exists());
+        assert_eq!(files.get("b").unwrap().unwrap().as_ref(), b"de");
+        assert_eq!(files.size("b"), Some(2));
+        assert!(files.get("c").unwrap().is_none());
+        // Replacing a file frees its room in memory.
+        files.insert("a".to_string(), b"x".to_vec()).unwrap();
+        files.insert("c".to_string(), b"yz".to_vec()).unwrap();
+        assert_eq!(files.spooled(), 1);
+        assert_eq!((files.len(), files.bytes()), (3, 5));
+        assert_eq!(files.names(), ["a", "b", "c"]);
+        let scratch = files.scratch_path("wow.zip").unwrap();
+        std::fs::write(&scratch, b"zip").unwrap();
+        assert_eq!(files.names(), ["a", "b", "c"]);
+        let memory = files.into_memory().unwrap();
+        assert_eq!(memory["b"], b"de");
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_batches() {
+        let sizes = vec![3, 1, 5, 2, 2];
+        assert_eq!(batches(sizes.clone(), None, |&n| n), [sizes.clone()]);
+        assert_eq!(
+            batches(sizes, Some(4), |&n| n),
+            [vec![3, 1], vec![5], vec![2, 2]]
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        let tests = [
+            ("bytes", "4096", Some(4096)),
+            ("kilobytes", "4k", Some(4096)),
+            ("megabytes", "512M", Some(512 << 20)),
+            ("gigabytes", "2G", Some(2 << 30)),
+            ("zero", "0", None),
+            ("junk", "lots", None),
+        ];
+        for (name, input, output) in tests {
+            assert_eq!(parse_size(input).ok(), output, "{}", name);
+        }
+    }
+}
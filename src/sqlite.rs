@@ -0,0 +1,115 @@
+//! Writing the root table to SQLite, for querying a build's files without
+//! parsing CASC.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+
+use crate::root::Entry;
+
+const SCHEMA: &str = "
+    CREATE TABLE root (
+        fdid INTEGER NOT NULL,
+        ckey TEXT NOT NULL,
+        name_hash TEXT,
+        flags INTEGER NOT NULL,
+        locale INTEGER NOT NULL
+    );
+";
+
+/// Built after the rows are in, which is much faster than maintaining them
+/// row by row.
+const INDICES: &str = "
+    CREATE INDEX root_fdid ON root (fdid);
+    CREATE INDEX root_ckey ON root (ckey);
+    CREATE INDEX root_name_hash ON root (name_hash);
+";
+
+/// Writes `entries` to a new database at `path`, replacing any there, by
+/// way of a `.part` file as other outputs are. Keys and name hashes are
+/// stored as hex, as the other dumps print them. Returns the rows written.
+pub(crate) fn write_root(path: &Path, entries: impl Iterator<Item = Entry>) -> Result<usize> {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+    let _ = std::fs::remove_file(&part);
+    let result = fill(&part, entries).and_then(|rows| {
+        std::fs::rename(&part, path)?;
+        Ok(rows)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&part);
+    }
+    result.with_context(|| format!("writing {}", path.display()))
+}
+
+fn fill(path: &Path, entries: impl Iterator<Item = Entry>) -> Result<usize> {
+    let mut db = Connection::open(path)?;
+    db.execute_batch(SCHEMA)?;
+    let tx = db.transaction()?;
+    let mut rows = 0;
+    {
+        let mut insert = tx.prepare(
+            "INSERT INTO root (fdid, ckey, name_hash, flags, locale) VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?;
+        for e in entries {
+            insert.execute(params![
+                u32::from(e.fdid),
+                e.content_key.to_string(),
+                e.name_hash.map(|h| format!("{:016x}", h)),
+                e.content_flags,
+                e.locale_flags,
+            ])?;
+            rows += 1;
+        }
+    }
+    tx.execute_batch(INDICES)?;
+    tx.commit()?;
+    db.close().map_err(|(_, e)| e)?;
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{ContentKey, FileDataID};
+
+    #[test]
+    fn test_write_root() {
+        let dir = std::env::temp_dir().join(format!("rustycasc-sqlite-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("root.db");
+        let entry = |fdid, name_hash, locale_flags| Entry {
+            fdid: FileDataID(fdid),
+            content_key: ContentKey(fdid.into()),
+            name_hash,
+            content_flags: 0,
+            locale_flags,
+        };
+        let entries = vec![
+            entry(1, Some(0xab), 2),
+            entry(1, Some(0xab), 4),
+            entry(2, None, 2),
+        ];
+        assert_eq!(write_root(&path, entries.into_iter()).unwrap(), 3);
+        // A second write replaces the first.
+        assert_eq!(
+            write_root(&path, vec![entry(3, None, 2)].into_iter()).unwrap(),
+            1
+        );
+        let db = Connection::open(&path).unwrap();
+        let row = db
+            .query_row("SELECT fdid, ckey, name_hash FROM root", [], |r| {
+                Ok((
+                    r.get::<_, u32>(0)?,
+                    r.get::<_, String>(1)?,
+                    r.get::<_, Option<String>>(2)?,
+                ))
+            })
+            .unwrap();
+        assert_eq!(row, (3, format!("{:032x}", 3), None));
+        assert!(!dir.join("root.db.part").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! The JSON summary `--summary-out` writes when a run ends, so schedulers
+//! can tell what it did without scraping its logs.
+
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Result;
+use serde_json::{json, Value};
+
+use crate::metrics::METRICS;
+use crate::{shutdown, warnings};
+
+/// Fields commands add as they go, in the order they were first set.
+static FIELDS: Mutex<Vec<(String, Value)>> = Mutex::new(Vec::new());
+/// Every output file the run wrote, in order.
+static WRITTEN: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Sets `key` of the summary, replacing any earlier value.
+pub(crate) fn set(key: &str, value: Value) {
+    let mut fields = FIELDS.lock().expect("summary lock");
+    match fields.iter_mut().find(|(k, _)| k == key) {
+        Some((_, v)) => *v = value,
+        None => fields.push((key.to_string(), value)),
+    }
+}
+
+/// Appends `value` to the list under `key`, e.g. each build a run opens.
+pub(crate) fn add(key: &str, value: Value) {
+    let mut fields = FIELDS.lock().expect("summary lock");
+    match fields.iter_mut().find(|(k, _)| k == key) {
+        Some((_, Value::Array(values))) => values.push(value),
+        Some((_, v)) => *v = json!([v.take(), value]),
+        None => fields.push((key.to_string(), json!([value]))),
+    }
+}
+
+/// Notes an output file the run wrote.
+pub(crate) fn wrote(path: &Path) {
+    WRITTEN
+        .lock()
+        .expect("summary lock")
+        .push(path.to_path_buf());
+}
+
+/// How a run ended.
+pub(crate) enum Outcome<'a> {
+    Succeeded,
+    Failed(&'a anyhow::Error),
+    Interrupted,
+}
+
+/// The summary of the run so far, which was started with `args` and has
+/// taken `duration`.
+pub(crate) fn render(args: &[String], outcome: Outcome, duration: Duration) -> Value {
+    let (status, error) = match outcome {
+        Outcome::Succeeded => ("succeeded", None),
+        Outcome::Failed(e) => ("failed", Some(format!("{:#}", e))),
+        Outcome::Interrupted => ("interrupted", None),
+    };
+    let mut summary = json!({
+        "args": args,
+        "status": status,
+        "error": error,
+        "duration_secs": duration.as_secs_f64(),
+        "requests": METRICS.requests.load(Ordering::Relaxed),
+        "bytes_downloaded": METRICS.bytes.load(Ordering::Relaxed),
+        "files_written": WRITTEN
+            .lock()
+            .expect("summary lock")
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<_>>(),
+        "warnings": warnings::reported()
+            .into_iter()
+            .map(|(issue, msg)| json!({ "issue": issue.to_string(), "message": msg }))
+            .collect::<Vec<_>>(),
+    });
+    let map = summary.as_object_mut().expect("summary is an object");
+    for (key, value) in FIELDS.lock().expect("summary lock").iter() {
+        map.insert(key.clone(), value.clone());
+    }
+    summary
+}
+
+/// Writes the summary to `path`.
+pub(crate) fn write(
+    path: &Path,
+    args: &[String],
+    outcome: Outcome,
+    duration: Duration,
+) -> Result<()> {
+    let summary = render(args, outcome, duration);
+    shutdown::write_file(path, &serde_json::to_vec_pretty(&summary)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        set("export", json!({ "files": 1 }));
+        set("export", json!({ "files": 2 }));
+        add("builds", json!("a"));
+        add("builds", json!("b"));
+        let args = ["rustycasc".to_string(), "framexml".to_string()];
+        let error = anyhow::anyhow!("no such product");
+        let summary = render(&args, Outcome::Failed(&error), Duration::from_secs(2));
+        assert_eq!(summary["status"], "failed");
+        assert_eq!(summary["error"], "no such product");
+        assert_eq!(summary["duration_secs"], 2.0);
+        assert_eq!(summary["args"], json!(args));
+        assert_eq!(summary["export"], json!({ "files": 2 }));
+        assert_eq!(summary["builds"], json!(["a", "b"]));
+        let summary = render(&args, Outcome::Interrupted, Duration::ZERO);
+        assert_eq!(summary["status"], "interrupted");
+        assert!(summary["error"].is_null());
+    }
+}
@@ -0,0 +1,78 @@
+//! Detection of the text encodings shipped Lua, XML and TOC files use. Most
+//! are plain UTF-8, but some carry a byte order mark or are UTF-16.
+
+use std::str::from_utf8;
+
+use anyhow::{bail, ensure, Context, Result};
+use derive_more::Display;
+
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+pub enum TextEncoding {
+    #[display("utf-8")]
+    Utf8,
+    #[display("utf-8-bom")]
+    Utf8Bom,
+    #[display("utf-16le")]
+    Utf16Le,
+    #[display("utf-16be")]
+    Utf16Be,
+    /// Neither valid UTF-8 nor marked as UTF-16.
+    #[display("unknown")]
+    Unknown,
+}
+
+/// Tells the encoding of `data` from its byte order mark, if any.
+pub fn detect(data: &[u8]) -> TextEncoding {
+    if data.starts_with(b"\xef\xbb\xbf") {
+        TextEncoding::Utf8Bom
+    } else if data.starts_with(b"\xff\xfe") {
+        TextEncoding::Utf16Le
+    } else if data.starts_with(b"\xfe\xff") {
+        TextEncoding::Utf16Be
+    } else if from_utf8(data).is_ok() {
+        TextEncoding::Utf8
+    } else {
+        TextEncoding::Unknown
+    }
+}
+
+fn decode_utf16(data: &[u8], unit: fn([u8; 2]) -> u16) -> Result<String> {
+    ensure!(data.len() % 2 == 0, "odd length for UTF-16 text");
+    let units = data
+        .chunks_exact(2)
+        .map(|c| unit([c[0], c[1]]))
+        .collect::<Vec<_>>();
+    String::from_utf16(&units).context("invalid UTF-16 text")
+}
+
+/// Decodes `data` in its detected encoding, dropping any byte order mark.
+pub fn decode(data: &[u8]) -> Result<String> {
+    Ok(match detect(data) {
+        TextEncoding::Utf8 => from_utf8(data)?.to_string(),
+        TextEncoding::Utf8Bom => from_utf8(&data[3..])?.to_string(),
+        TextEncoding::Utf16Le => decode_utf16(&data[2..], u16::from_le_bytes)?,
+        TextEncoding::Utf16Be => decode_utf16(&data[2..], u16::from_be_bytes)?,
+        TextEncoding::Unknown => bail!("text is neither UTF-8 nor marked as UTF-16"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, detect, TextEncoding};
+
+    #[test]
+    fn test_decode() {
+        let tests: [(&str, &[u8], TextEncoding, Option<&str>); 6] = [
+            ("plain", b"a\xc3\xa9", TextEncoding::Utf8, Some("a\u{e9}")),
+            ("bom", b"\xef\xbb\xbfab", TextEncoding::Utf8Bom, Some("ab")),
+            ("le", b"\xff\xfea\0b\0", TextEncoding::Utf16Le, Some("ab")),
+            ("be", b"\xfe\xff\0a\0b", TextEncoding::Utf16Be, Some("ab")),
+            ("odd", b"\xff\xfea\0b", TextEncoding::Utf16Le, None),
+            ("latin-1", b"a\xe9", TextEncoding::Unknown, None),
+        ];
+        for (name, input, encoding, output) in tests {
+            assert_eq!(detect(input), encoding, "{}", name);
+            assert_eq!(decode(input).ok().as_deref(), output, "{}", name);
+        }
+    }
+}
@@ -1,16 +1,16 @@
-use derive_more::Display;
+use derive_more::{Display, From, Into};
 
 #[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
 #[display("{:032x}", _0)]
-pub(crate) struct ArchiveKey(pub(crate) u128);
+pub struct ArchiveKey(pub u128);
 
 #[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
 #[display("{:032x}", _0)]
-pub(crate) struct ContentKey(pub(crate) u128);
+pub struct ContentKey(pub u128);
 
 #[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq)]
 #[display("{:032x}", _0)]
-pub(crate) struct EncodingKey(pub(crate) u128);
+pub struct EncodingKey(pub u128);
 
-#[derive(Clone, Copy, Eq, Hash, PartialEq)]
-pub(crate) struct FileDataID(pub(crate) u32);
+#[derive(Clone, Copy, Debug, Display, Eq, From, Hash, Into, Ord, PartialEq, PartialOrd)]
+pub struct FileDataID(pub u32);
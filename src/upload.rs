@@ -0,0 +1,108 @@
+//! Publishing exports to S3, GCS or other object_store backends.
+
+use anyhow::{Context, Result};
+use futures::stream::{StreamExt, TryStreamExt};
+use object_store::{path::Path, ObjectStore, PutPayload, WriteMultipart};
+use tokio::io::AsyncReadExt;
+
+use crate::spool;
+
+/// A store and the prefix within it that exports are uploaded under.
+pub(crate) struct Destination {
+    store: Box<dyn ObjectStore>,
+    prefix: Path,
+}
+
+impl Destination {
+    /// Parses a URL such as `s3://bucket/prefix` or `gs://bucket/prefix`.
+    /// Credentials and regions are read from the usual environment variables,
+    /// e.g. `AWS_ACCESS_KEY_ID` or `GOOGLE_SERVICE_ACCOUNT`.
+    pub(crate) fn parse(url: &str) -> Result<Destination> {
+        let parsed = url::Url::parse(url).with_context(|| format!("invalid upload url {}", url))?;
+        let (store, prefix) = object_store::parse_url_opts(
+            &parsed,
+            std::env::vars().map(|(k, v)| (k.to_lowercase(), v)),
+        )
+        .with_context(|| format!("unsupported upload url {}", url))?;
+        Ok(Destination { store, prefix })
+    }
+
+    fn path(&self, name: &str) -> Path {
+        Path::from_iter(self.prefix.parts().chain(Path::from(name).parts()))
+    }
+
+    /// Uploads `data` as `name` under the prefix, returning where it went.
+    pub(crate) async fn put(&self, name: &str, data: Vec<u8>) -> Result<Path> {
+        let path = self.path(name);
+        self.store
+            .put(&path, PutPayload::from(data))
+            .await
+            .with_context(|| format!("uploading {}", path))?;
+        Ok(path)
+    }
+
+    /// Uploads the file at `local` as `name` under the prefix, a part at a
+    /// time, so it's never read into memory whole.
+    pub(crate) async fn put_file_streamed(
+        &self,
+        name: &str,
+        local: &std::path::Path,
+    ) -> Result<Path> {
+        let path = self.path(name);
+        let mut file = tokio::fs::File::open(local)
+            .await
+            .with_context(|| format!("opening {}", local.display()))?;
+        let upload = self
+            .store
+            .put_multipart(&path)
+            .await
+            .with_context(|| format!("starting upload of {}", path))?;
+        let mut writer = WriteMultipart::new(upload);
+        let mut buf = vec![0; 1 << 20];
+        let result = async {
+            loop {
+                let n = file
+                    .read(&mut buf)
+                    .await
+                    .with_context(|| format!("reading {}", local.display()))?;
+                if n == 0 {
+                    return Ok(());
+                }
+                // Parts upload in the background; at most a few are held.
+                writer.wait_for_capacity(4).await?;
+                writer.write(&buf[..n]);
+            }
+        }
+        .await;
+        match result {
+            Ok(()) => {
+                writer
+                    .finish()
+                    .await
+                    .with_context(|| format!("uploading {}", path))?;
+                Ok(path)
+            }
+            Err(e) => {
+                let _ = writer.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Uploads each of `files` under `dir`, a few at a time.
+    pub(crate) async fn put_all(&self, dir: &str, files: &spool::Files) -> Result<()> {
+        futures::stream::iter(files.names())
+            .map(|name| async move {
+                let data = files.get(name)?.context("file vanished")?;
+                self.put_file(dir, name, &data).await
+            })
+            .buffer_unordered(8)
+            .try_collect()
+            .await
+    }
+
+    async fn put_file(&self, dir: &str, name: &str, data: &[u8]) -> Result<()> {
+        let name = format!("{}/{}", dir, name.replace('\\', "/"));
+        self.put(&name, data.to_vec()).await.map(|_| ())
+    }
+}
@@ -0,0 +1,160 @@
+//! What to do about each class of recoverable issue: warn and carry on,
+//! collect it into the run's report, or fail the run.
+
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{bail, Context, Result};
+use derive_more::Display;
+
+#[derive(Clone, Copy, Debug, Display, Eq, Hash, PartialEq, clap::ValueEnum)]
+pub(crate) enum Issue {
+    /// A CDN host failed a fetch that is retried elsewhere.
+    #[display("mirror-fetch")]
+    MirrorFetch,
+    /// A file was left out of an export.
+    #[display("skipped-file")]
+    SkippedFile,
+    /// The product config couldn't be fetched.
+    #[display("product-config")]
+    ProductConfig,
+    /// A text file couldn't be normalized and was exported as shipped.
+    #[display("text-normalize")]
+    TextNormalize,
+    /// The preferred version source failed and the other one was used.
+    #[display("version-fallback")]
+    VersionFallback,
+}
+
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq, clap::ValueEnum)]
+pub(crate) enum Action {
+    #[display("warn")]
+    Warn,
+    #[display("collect")]
+    Collect,
+    #[display("fail")]
+    Fail,
+}
+
+/// Parses an `issue=action` rule, e.g. `skipped-file=fail`.
+pub(crate) fn parse_rule(s: &str) -> Result<(Issue, Action)> {
+    use clap::ValueEnum;
+    let (issue, action) = s.split_once('=').context("expected issue=action")?;
+    Ok((
+        Issue::from_str(issue, false).map_err(anyhow::Error::msg)?,
+        Action::from_str(action, false).map_err(anyhow::Error::msg)?,
+    ))
+}
+
+pub(crate) struct Policy {
+    actions: HashMap<Issue, Action>,
+    /// Warnings only go to the log on quiet runs.
+    quiet: bool,
+    collected: Mutex<Vec<(Issue, String)>>,
+    /// Every issue reported, whatever was done about it.
+    reported: Mutex<Vec<(Issue, String)>>,
+}
+
+impl Policy {
+    pub(crate) fn new(rules: impl IntoIterator<Item = (Issue, Action)>, quiet: bool) -> Policy {
+        Policy {
+            actions: rules.into_iter().collect(),
+            quiet,
+            collected: Mutex::new(Vec::new()),
+            reported: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn report(&self, issue: Issue, msg: impl std::fmt::Display) -> Result<()> {
+        self.reported
+            .lock()
+            .expect("warnings lock")
+            .push((issue, msg.to_string()));
+        match self.actions.get(&issue).copied().unwrap_or(Action::Warn) {
+            Action::Warn if self.quiet => log::warn!("{}", msg),
+            Action::Warn => eprintln!("warning: {}", msg),
+            Action::Collect => self
+                .collected
+                .lock()
+                .expect("warnings lock")
+                .push((issue, msg.to_string())),
+            Action::Fail => bail!("{} ({} is set to fail)", msg, issue),
+        }
+        Ok(())
+    }
+
+    /// Takes the issues collected so far, in the order they were reported.
+    pub(crate) fn take_collected(&self) -> Vec<(Issue, String)> {
+        std::mem::take(&mut self.collected.lock().expect("warnings lock"))
+    }
+
+    /// Every issue reported so far, collected or not.
+    pub(crate) fn reported(&self) -> Vec<(Issue, String)> {
+        self.reported.lock().expect("warnings lock").clone()
+    }
+}
+
+static POLICY: OnceLock<Policy> = OnceLock::new();
+
+/// Sets the process-wide policy; issues before this just warn.
+pub(crate) fn init(policy: Policy) {
+    if POLICY.set(policy).is_err() {
+        log::warn!("warning policy already set");
+    }
+}
+
+fn policy() -> &'static Policy {
+    POLICY.get_or_init(|| Policy::new([], false))
+}
+
+/// Reports `msg` as an instance of `issue`, failing if the policy says so.
+pub(crate) fn report(issue: Issue, msg: impl std::fmt::Display) -> Result<()> {
+    policy().report(issue, msg)
+}
+
+pub(crate) fn take_collected() -> Vec<(Issue, String)> {
+    policy().take_collected()
+}
+
+pub(crate) fn reported() -> Vec<(Issue, String)> {
+    policy().reported()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_rule, Action, Issue, Policy};
+
+    #[test]
+    fn test_policy() {
+        assert_eq!(
+            parse_rule("skipped-file=collect").unwrap(),
+            (Issue::SkippedFile, Action::Collect)
+        );
+        assert!(parse_rule("skipped-file").is_err());
+        assert!(parse_rule("nonsense=warn").is_err());
+        assert!(parse_rule("mirror-fetch=ignore").is_err());
+        let policy = Policy::new(
+            [
+                (Issue::SkippedFile, Action::Collect),
+                (Issue::ProductConfig, Action::Fail),
+            ],
+            true,
+        );
+        policy.report(Issue::MirrorFetch, "a").unwrap();
+        policy.report(Issue::SkippedFile, "b").unwrap();
+        assert!(policy.report(Issue::ProductConfig, "c").is_err());
+        assert_eq!(
+            policy.take_collected(),
+            vec![(Issue::SkippedFile, "b".to_string())]
+        );
+        assert!(policy.take_collected().is_empty());
+        assert_eq!(
+            policy
+                .reported()
+                .into_iter()
+                .map(|(issue, _)| issue)
+                .collect::<Vec<_>>(),
+            [Issue::MirrorFetch, Issue::SkippedFile, Issue::ProductConfig]
+        );
+    }
+}